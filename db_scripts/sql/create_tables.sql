@@ -4,20 +4,26 @@ CREATE TABLE t_commit_ownership (
     -- can be a github login or org (prefixed with `gh:`)
     -- or the public key of the member for inbox submissions, no prefix
     -- e.g. `gh:stackmuncher` or `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
-  owner_id varchar(200),
+  owner_id varchar(200) NOT NULL,
   -- can be a github project name or a guid in base58 for private member projects
     -- e.g. `stm` or `Wgx98Rbi8nQuL9ddn3mTk1`
   project_id varchar(150) NOT NULL,
-  -- e.g. e29d17e6
-  commit_hash varchar(8),
+  -- the first 8 chars of the SHA1 used for matching reports with and without full SHA1s, e.g. e29d17e6
+  commit_hash varchar(8) NOT NULL,
   -- e.g. 1627380297
   commit_ts bigint NOT NULL,
-
-  PRIMARY KEY (owner_id,commit_hash)
+  -- the full SHA1 if the report had it, e.g. e29d17e6f0b7a8e3ed7b5fc5f7c4cc1bdbd47474
+  -- NULL for commits added from older reports, see migrate_commit_sha1.sql
+  commit_sha1 varchar(40)
 );
 
+-- commits with the full SHA1 are keyed on it, so different commits with the same prefix can be stored
+-- the prefix is the key only for commits without the SHA1, see migrate_commit_sha1_key.sql
+CREATE UNIQUE INDEX idx_commit_ownership_sha1 ON t_commit_ownership (owner_id, commit_sha1);
+CREATE UNIQUE INDEX idx_commit_ownership_prefix ON t_commit_ownership (owner_id, commit_hash) WHERE commit_sha1 IS NULL;
+
 DROP INDEX IF EXISTS idx_commits_by_owner;
-CREATE INDEX idx_commits_by_owner ON t_commit_ownership (commit_hash) INCLUDE (owner_id, project_id, commit_ts);
+CREATE INDEX idx_commits_by_owner ON t_commit_ownership (commit_hash) INCLUDE (owner_id, project_id, commit_ts, commit_sha1);
 
 ---------------------------------------------------------------------------------------------------------------
 
@@ -35,7 +41,11 @@ CREATE TABLE t_email_ownership (
   -- when the email was confirmed to be owned by the owner of the key
   confirmed_ts timestamptz,
   -- an arbitrary sequence of chars used in the confirmation link, contains the timestamp of when it was generated
+  -- e.g. `1627380297_Wgx98Rbi8nQuL9ddn3mTk1`, set by `email_confirmation` flow of stm_inbox_flows
   confirmation_id varchar,
+  -- why the latest confirmation email could not be delivered, e.g. an invalid address or an SMTP 5xx reply
+  -- the email is retried with the next confirmation_id, NULL = the latest email was sent
+  confirmation_error varchar,
   -- a ts of when it was set as the primary email for the owner of the key
   is_primary timestamptz,
 
@@ -75,7 +85,10 @@ CREATE TABLE t_dev (
   -- the timestamp when the login was validated last time
   gh_login_validation_ts timestamp with time zone,
   -- the gist ID from the latest private submission
-  gh_login_gist_latest varchar
+  gh_login_gist_latest varchar,
+  -- an unvalidated github login from a noreply commit email, e.g. `rimutaka` for `12345+rimutaka@users.noreply.github.com`
+  -- a hint for linking the member to gh_login, anyone can put any noreply email in their commits
+  gh_login_noreply varchar
 );
 
 -- find devs with different combo of report_ts, in_flight and fail counter
@@ -87,6 +100,285 @@ WHERE (report_ts IS NULL or report_ts < last_submission_ts)  and report_in_fligh
 
 ---------------------------------------------------------------------------------------------------------------
 
+-- nonces of signed requests accepted by stm_inbox, used to reject replays of the same request
+DROP TABLE IF EXISTS t_nonce CASCADE;
+CREATE TABLE t_nonce (
+    -- the public key of the member who signed the request, no prefix
+    -- e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  owner_id varchar,
+  -- a random string generated by the app for every request, e.g. `hQ2rDiTn6pKx5vWs`
+  nonce varchar(64),
+  -- when the nonce was first seen, old nonces are purged by stm_add_nonce
+  added_ts timestamp with time zone NOT NULL DEFAULT now(),
+
+  PRIMARY KEY (owner_id,nonce)
+);
+
+DROP INDEX IF EXISTS idx_nonce_added_ts;
+CREATE INDEX idx_nonce_added_ts ON t_nonce (owner_id, added_ts);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- additional public keys linked to a member account after a key rotation
+-- the account is identified by its canonical owner_id, which is the very first key of the member
+DROP TABLE IF EXISTS t_key_link CASCADE;
+CREATE TABLE t_key_link (
+    -- the linked public key, no prefix, e.g. `EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB`
+  owner_id varchar PRIMARY KEY,
+  -- the key the member account is known by in t_dev, t_commit_ownership, t_email_ownership, S3 and ES
+  -- e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  canonical_owner_id varchar NOT NULL,
+  -- the key that signed the rotation statement together with owner_id, kept for audit
+  linked_by_owner_id varchar NOT NULL,
+  -- base58 signatures of the rotation statement by both keys, kept for audit
+  linked_by_sig varchar NOT NULL,
+  owner_sig varchar NOT NULL,
+  -- when the link was created
+  added_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- when the ES profile the linked key had before it was linked was removed by `dev_queue` flow, NULL until then
+  profile_removed_ts timestamp with time zone
+);
+
+DROP INDEX IF EXISTS idx_key_link_canonical;
+CREATE INDEX idx_key_link_canonical ON t_key_link (canonical_owner_id);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- the processing history of every report submission, one record per stage
+-- the member can query it via the inbox status endpoint using the submission_id from the receipt
+DROP TABLE IF EXISTS t_submission_status CASCADE;
+CREATE TABLE t_submission_status (
+  -- a base58 encoded UUID generated by the inbox, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+  submission_id varchar NOT NULL,
+  -- the public key that signed the submission, which may be different from the canonical owner_id after key rotation
+  -- e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  owner_id varchar NOT NULL,
+  -- one of stored, spooled, routed, project_assigned, unchanged, queued, regenerated, failed
+  stage varchar NOT NULL,
+  -- the project the report was assigned to, if known at this stage
+  project_id varchar,
+  -- an explanation of the failure or any other details worth sharing with the member
+  details varchar,
+  -- when the stage was reached
+  added_ts timestamp with time zone NOT NULL DEFAULT now()
+);
+
+DROP INDEX IF EXISTS idx_submission_status;
+CREATE INDEX idx_submission_status ON t_submission_status (submission_id, added_ts);
+
+DROP INDEX IF EXISTS idx_submission_status_queued;
+CREATE INDEX idx_submission_status_queued ON t_submission_status (owner_id) WHERE stage = 'queued';
+
+---------------------------------------------------------------------------------------------------------------
+
+-- the hash of the latest processed report per owner and project
+-- the router skips reports identical to the previous one to avoid re-processing unchanged projects
+DROP TABLE IF EXISTS t_report_hash CASCADE;
+CREATE TABLE t_report_hash (
+  -- the canonical owner_id of the member, e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  owner_id varchar NOT NULL,
+  -- the project the report was assigned to
+  project_id varchar NOT NULL,
+  -- base58 encoded SHA256 of the unzipped report
+  report_hash varchar NOT NULL,
+  -- when the report was processed
+  added_ts timestamp with time zone NOT NULL DEFAULT now(),
+  PRIMARY KEY (owner_id, project_id)
+);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- projects merged by the router when a report matched more than one project
+-- the commits and S3 reports of the merged project are moved to the surviving one
+DROP TABLE IF EXISTS t_project_merge CASCADE;
+CREATE TABLE t_project_merge (
+  -- the project that no longer exists, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+  merged_project_id varchar PRIMARY KEY,
+  -- the project it was merged into, e.g. `FZ8zezMFji6VXcWEDxckwy`
+  project_id varchar NOT NULL,
+  -- when the merge happened
+  merged_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- the number of commits moved to project_id by the merge
+  commits_moved bigint NOT NULL DEFAULT 0
+);
+
+DROP INDEX IF EXISTS idx_project_merge;
+CREATE INDEX idx_project_merge ON t_project_merge (project_id);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- merges the router could not apply on its own because the merged project has owners outside of the member account
+-- that sent the report, e.g. a colleague who reported a different branch of the same repo
+DROP TABLE IF EXISTS t_project_merge_review CASCADE;
+CREATE TABLE t_project_merge_review (
+  -- the project the report matched with the most commits, e.g. `FZ8zezMFji6VXcWEDxckwy`
+  project_id varchar NOT NULL,
+  -- the project that would be merged into project_id, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+  merged_project_id varchar NOT NULL,
+  -- the canonical owner_id of the member whose report matched both projects
+  owner_id varchar NOT NULL,
+  -- owners of merged_project_id outside of the account of owner_id at the time of the report
+  other_owner_ids varchar[] NOT NULL,
+  -- when the conflict was first found
+  added_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- when the conflict was last found, it is repeated by every report that matches both projects
+  updated_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- when an admin merged the projects or decided to keep them apart, NULL = pending
+  resolved_ts timestamp with time zone,
+  PRIMARY KEY (project_id, merged_project_id)
+);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- project IDs assigned by the router to reports without commit history, e.g. from fresh repos or shallow clones
+-- the fingerprint is kept for all projects with a known remote, so that reports with and without commits
+-- from the same repo end up in the same project
+DROP TABLE IF EXISTS t_project_fingerprint CASCADE;
+CREATE TABLE t_project_fingerprint (
+  -- the canonical owner_id of the member, e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  -- fingerprints are not shared between members because anyone can claim any remote URL
+  owner_id varchar NOT NULL,
+  -- base58 encoded SHA256 of the normalised remote URL, e.g. `github.com/stackmuncher/stm_server`
+  fingerprint varchar NOT NULL,
+  -- the project the reports with this fingerprint are assigned to
+  project_id varchar NOT NULL,
+  -- when the fingerprint was last seen in a report
+  updated_ts timestamp with time zone NOT NULL DEFAULT now(),
+  PRIMARY KEY (owner_id, fingerprint)
+);
+
+DROP INDEX IF EXISTS idx_project_fingerprint;
+CREATE INDEX idx_project_fingerprint ON t_project_fingerprint (project_id);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- projects with commits already owned by other members, flagged by the router for review
+-- suspicious claims are excluded from the member's profile until resolved via `disputes` flow of stm_inbox_flows
+DROP TABLE IF EXISTS t_commit_dispute CASCADE;
+CREATE TABLE t_commit_dispute (
+  -- the canonical owner_id of the member who submitted the report, e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  owner_id varchar NOT NULL,
+  -- the project the report was assigned to, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+  project_id varchar NOT NULL,
+  -- `co_contributors` = the project is shared with other members, which is normal for team projects
+  -- `full_history_claim` = the report is made of commits owned by others, e.g. a clone of someone else's repo
+  -- a dispute is never downgraded from `full_history_claim` by later reports
+  dispute_type varchar NOT NULL,
+  -- the owners of the commits at the time of the last report, members or GitHub owners (prefixed with `gh:`)
+  other_owner_ids varchar[] NOT NULL,
+  -- the number of report commits already owned by other owners
+  shared_commits integer NOT NULL,
+  -- the number of report commits checked for ownership
+  report_commits integer NOT NULL,
+  -- when the dispute was flagged or last updated by a new report
+  flagged_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- NULL = pending review, `accepted` = the member is a genuine contributor, `rejected` = the claim is false
+  resolution varchar,
+  -- when the resolution was set
+  resolved_ts timestamp with time zone,
+  PRIMARY KEY (owner_id, project_id)
+);
+
+DROP INDEX IF EXISTS idx_commit_dispute_pending;
+CREATE INDEX idx_commit_dispute_pending ON t_commit_dispute (flagged_ts) WHERE resolution IS NULL;
+
+---------------------------------------------------------------------------------------------------------------
+
+-- accounts of the same person with different keys, e.g. from different machines, proposed for merging
+-- by `identities` flow of stm_inbox_flows because they share confirmed emails or a validated github login
+-- the accounts are linked in t_key_link once both approve the merge with a signed request via stm_inbox
+DROP TABLE IF EXISTS t_identity_merge CASCADE;
+CREATE TABLE t_identity_merge (
+  -- canonical owner_ids of the two accounts, owner_id is the lower of the two in the sort order
+  -- e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  owner_id varchar NOT NULL,
+  other_owner_id varchar NOT NULL,
+  -- confirmed emails found in both accounts at the time of the last check
+  shared_emails varchar[] NOT NULL,
+  -- the validated github login of both accounts, if they have the same one
+  shared_gh_login varchar,
+  -- when the merge was first proposed
+  proposed_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- base58 signatures of the merge statement by a key of each account and when they were received
+  owner_sig varchar,
+  owner_approved_ts timestamp with time zone,
+  other_owner_sig varchar,
+  other_owner_approved_ts timestamp with time zone,
+  -- the account that was linked to the other one after both approvals, either owner_id or other_owner_id
+  merged_owner_id varchar,
+  -- when the accounts were linked in t_key_link
+  merged_ts timestamp with time zone,
+  -- when the ES profile of merged_owner_id was removed by `identities` flow
+  profile_removed_ts timestamp with time zone,
+  PRIMARY KEY (owner_id, other_owner_id)
+);
+
+DROP INDEX IF EXISTS idx_identity_merge_other;
+CREATE INDEX idx_identity_merge_other ON t_identity_merge (other_owner_id);
+
+---------------------------------------------------------------------------------------------------------------
+
+-- account and project deletion requests signed by the members and accepted by stm_inbox
+-- the requests are processed by `deletion` flow of stm_inbox_flows and kept after completion for audit
+DROP TABLE IF EXISTS t_deletion_queue CASCADE;
+CREATE TABLE t_deletion_queue (
+  -- a base58 encoded UUID generated by the inbox, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+  deletion_id varchar PRIMARY KEY,
+  -- the canonical owner_id of the account to be deleted, e.g. `9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+  owner_id varchar NOT NULL,
+  -- NULL = delete the entire account, otherwise only this project is removed from the account
+  project_id varchar,
+  -- the key that signed the request, which may be a linked key of the account
+  requested_by_owner_id varchar NOT NULL,
+  -- base58 signature of the request, kept for audit
+  request_sig varchar NOT NULL,
+  -- when the request was received
+  added_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- the number of times the flow attempted to process the request
+  attempts integer NOT NULL DEFAULT (0),
+  -- when the latest attempt started, the request is retried if it is not completed within a timeout
+  in_flight_ts timestamp with time zone,
+  -- when all the data of the member was purged, NULL = pending
+  completed_ts timestamp with time zone,
+  -- what was deleted, e.g. `s3 objects: 25, es docs: 1, t_commit_ownership: 1532, ...`
+  details varchar,
+  -- why the latest attempt failed, e.g. `S3: failed to delete reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/`
+  last_error varchar,
+  -- when the latest attempt failed
+  last_error_ts timestamp with time zone,
+  -- all keys of the account purged by a completed account deletion, NULL for project deletions
+  -- the links are deleted with the account, so this is the only record of which stored submissions belonged to it
+  deleted_keys varchar[]
+);
+
+DROP INDEX IF EXISTS idx_deletion_queue_pending;
+CREATE INDEX idx_deletion_queue_pending ON t_deletion_queue (added_ts) WHERE completed_ts IS NULL;
+
+---------------------------------------------------------------------------------------------------------------
+
+-- a log of inbox requests used for sliding window rate limiting per public key and per IP
+-- accepted requests are purged once they fall out of the window, rejected requests are kept for review
+DROP TABLE IF EXISTS t_rate_limit_log CASCADE;
+CREATE TABLE t_rate_limit_log (
+  -- what the limit is applied to, `key` for submissions, `key_query` for read-only requests or `ip`
+  limit_type varchar NOT NULL,
+  -- the public key of the sender or the IP address, depending on limit_type
+  limit_value varchar NOT NULL,
+  -- when the request was received
+  added_ts timestamp with time zone NOT NULL DEFAULT now(),
+  -- TRUE if the request was rejected for exceeding the limit
+  is_rejected boolean NOT NULL DEFAULT FALSE
+);
+
+DROP INDEX IF EXISTS idx_rate_limit_log;
+CREATE INDEX idx_rate_limit_log ON t_rate_limit_log (limit_type, limit_value, added_ts);
+
+-- for purging requests that fell out of the window
+DROP INDEX IF EXISTS idx_rate_limit_log_ts;
+CREATE INDEX idx_rate_limit_log_ts ON t_rate_limit_log (added_ts);
+
+---------------------------------------------------------------------------------------------------------------
+
 -- contains details for some IPs of interest, e.g. bots or rate limit breakers
 DROP TABLE IF EXISTS t_ip_log CASCADE;
 CREATE TABLE t_ip_log (
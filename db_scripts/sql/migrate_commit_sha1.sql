@@ -0,0 +1,22 @@
+-- Adds full SHA1s to t_commit_ownership created before the column existed.
+-- There is no backfill: the full SHA1 cannot be derived from the 8-char prefix, so existing rows keep NULL.
+-- Hash collisions cannot be detected on such rows by the router (see `is_hash_collision`) or by stm_add_commits,
+-- they are matched by the 8-char prefix and the timestamp as before.
+-- They get the SHA1 when a newer report with the same commit is processed by stm_inbox_router
+-- or when the stored reports are re-processed with `-flow replay` of stm_inbox_flows.
+-- The script can be run more than once.
+
+ALTER TABLE t_commit_ownership ADD COLUMN IF NOT EXISTS commit_sha1 varchar(40);
+
+DROP INDEX IF EXISTS idx_commits_by_owner;
+CREATE INDEX idx_commits_by_owner ON t_commit_ownership (commit_hash) INCLUDE (owner_id, project_id, commit_ts, commit_sha1);
+
+-- the old signature without _commit_sha1 has to go to avoid ambiguous calls
+DROP FUNCTION IF EXISTS stm_add_commits(varchar,varchar,varchar[],bigint[]);
+-- the return type of stm_find_projects_by_commits changed with the table, so it has to be re-created
+DROP FUNCTION IF EXISTS stm_find_projects_by_commits(varchar[]);
+
+-- run stm_add_commit.sql and stm_find_projects_by_commits.sql after this script
+
+/*** TESTING ***/
+-- select count(*), count(commit_sha1) from t_commit_ownership
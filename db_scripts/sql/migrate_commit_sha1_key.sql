@@ -0,0 +1,17 @@
+-- Keys t_commit_ownership on the full SHA1 instead of the 8-char prefix in a DB created before that.
+-- The prefix remains the key only for commits without the SHA1, so different commits with the same prefix
+-- are no longer dropped by stm_add_commits if the report has their SHA1s.
+-- The existing rows are unique on (owner_id, commit_hash), so the new indexes cannot fail on them.
+-- The script can be run more than once.
+
+ALTER TABLE t_commit_ownership DROP CONSTRAINT IF EXISTS t_commit_ownership_pkey;
+ALTER TABLE t_commit_ownership ALTER COLUMN owner_id SET NOT NULL, ALTER COLUMN commit_hash SET NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_commit_ownership_sha1 ON t_commit_ownership (owner_id, commit_sha1);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_commit_ownership_prefix ON t_commit_ownership (owner_id, commit_hash) WHERE commit_sha1 IS NULL;
+
+-- run stm_add_commit.sql and stm_link_keys.sql after this script
+
+/*** TESTING ***/
+-- select indexname, indexdef from pg_indexes where tablename = 't_commit_ownership'
+-- select owner_id, commit_hash, count(*) from t_commit_ownership group by owner_id, commit_hash having count(*) > 1
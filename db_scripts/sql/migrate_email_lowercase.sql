@@ -0,0 +1,32 @@
+-- Lowercases emails in t_email_ownership stored by the version of stm_inbox_router that kept the case of the local part.
+-- A mixed case email of an owner who also has it in lower case is merged into the lower case row:
+-- the earliest added_ts, confirmed_ts and is_primary win. Pending confirmation IDs of merged rows are dropped
+-- and the email gets a new link from `email_confirmation` flow if it is still unconfirmed.
+-- The script can be run more than once.
+
+UPDATE t_email_ownership AS l SET
+  added_ts = least(l.added_ts, m.added_ts),
+  confirmed_ts = least(l.confirmed_ts, m.confirmed_ts),
+  is_primary = least(l.is_primary, m.is_primary)
+FROM (
+  select owner_id, lower(email) as email, min(added_ts) as added_ts, min(confirmed_ts) as confirmed_ts,
+    min(is_primary) as is_primary
+  from t_email_ownership where email <> lower(email) group by owner_id, lower(email)
+) AS m
+WHERE l.owner_id = m.owner_id AND l.email = m.email;
+
+DELETE FROM t_email_ownership AS m
+WHERE m.email <> lower(m.email)
+  AND EXISTS (select 1 from t_email_ownership l where l.owner_id = m.owner_id and l.email = lower(m.email));
+
+-- the same owner may have the email in more than one mixed case spelling, only the earliest one is kept
+DELETE FROM t_email_ownership AS m
+WHERE m.email <> lower(m.email)
+  AND EXISTS (select 1 from t_email_ownership o where o.owner_id = m.owner_id and o.email <> m.email
+    and lower(o.email) = lower(m.email)
+    and (coalesce(o.added_ts, 'epoch'), o.email) < (coalesce(m.added_ts, 'epoch'), m.email));
+
+UPDATE t_email_ownership SET email = lower(email) WHERE email <> lower(email);
+
+/*** TESTING ***/
+-- select count(*) from t_email_ownership where email <> lower(email)
@@ -0,0 +1,13 @@
+-- Adds the GitHub login from noreply commit emails to t_dev created before the column existed.
+-- Existing rows keep NULL until the next report of the member with a GitHub noreply email is processed by stm_inbox_router.
+-- The script can be run more than once.
+
+ALTER TABLE t_dev ADD COLUMN IF NOT EXISTS gh_login_noreply varchar;
+
+-- the old signature without _gh_login_noreply has to go to avoid ambiguous calls
+DROP FUNCTION IF EXISTS stm_commit_report(varchar,varchar,varchar[],bigint[],varchar[],varchar[],boolean[],varchar,varchar,boolean);
+
+-- run stm_commit_report.sql after this script
+
+/*** TESTING ***/
+-- select count(*), count(gh_login_noreply) from t_dev
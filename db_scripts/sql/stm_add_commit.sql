@@ -1,22 +1,84 @@
 -- Inserts multiple commits into t_commit_ownership table
-CREATE OR REPLACE FUNCTION stm_add_commits(_owner_id varchar, _project_id varchar, _commit_hash varchar[], _commit_ts bigint[])
+-- _commit_sha1 has the full SHA1 or NULL for every member of _commit_hash
+-- commits with the SHA1 are keyed on (owner_id, commit_sha1), so different commits with the same 8-char prefix are stored side by side
+-- commits without the SHA1 are keyed on (owner_id, commit_hash) among the rows without the SHA1
+-- existing commits without SHA1 get it from newer reports with the same prefix and timestamp, but the SHA1 is never overwritten
+-- a commit without the SHA1 with the same prefix, but a different timestamp than a stored commit without the SHA1 of the same owner
+-- cannot be stored: it is skipped and logged as a PG warning, which the router logs via the PG connection
+CREATE OR REPLACE FUNCTION stm_add_commits(_owner_id varchar, _project_id varchar, _commit_hash varchar[], _commit_ts bigint[], _commit_sha1 varchar[])
 RETURNS void AS $$ --
+DECLARE
+  _collisions varchar;
 BEGIN --
 --
-INSERT INTO t_commit_ownership (owner_id, project_id, commit_hash, commit_ts)
-select _owner_id, _project_id, * from  unnest (_commit_hash, _commit_ts) on conflict do nothing;
+-- fill in the SHA1 of the same commit stored from an older report, unless the SHA1 is already stored in another row
+-- two report commits with the same prefix and timestamp are very unlikely, the other one is inserted as a new row below
+UPDATE t_commit_ownership c set commit_sha1 = n.commit_sha1
+from (select distinct on (u.commit_hash, u.commit_ts) u.commit_hash, u.commit_ts, u.commit_sha1
+  from unnest(_commit_hash, _commit_ts, _commit_sha1) as u(commit_hash, commit_ts, commit_sha1)
+  where u.commit_sha1 is not null
+  order by u.commit_hash, u.commit_ts, u.commit_sha1) n
+where c.owner_id = _owner_id and c.commit_sha1 is null and c.commit_hash = n.commit_hash and c.commit_ts = n.commit_ts
+  and not exists (select 1 from t_commit_ownership s where s.owner_id = _owner_id and s.commit_sha1 = n.commit_sha1);
+
+INSERT INTO t_commit_ownership (owner_id, project_id, commit_hash, commit_ts, commit_sha1)
+select _owner_id, _project_id, n.commit_hash, n.commit_ts, n.commit_sha1
+from unnest(_commit_hash, _commit_ts, _commit_sha1) as n(commit_hash, commit_ts, commit_sha1)
+where n.commit_sha1 is not null
+on conflict (owner_id, commit_sha1) do nothing;
+
+-- a commit without the SHA1 with the same prefix and timestamp as a stored commit is the same commit
+-- a different timestamp is a collision only with the rows without the SHA1 because those have the prefix as the key
+select string_agg(n.commit_hash, ', ') into _collisions
+from unnest(_commit_hash, _commit_ts, _commit_sha1) as n(commit_hash, commit_ts, commit_sha1)
+where n.commit_sha1 is null
+  and exists (select 1 from t_commit_ownership c where c.owner_id = _owner_id and c.commit_sha1 is null
+    and c.commit_hash = n.commit_hash and c.commit_ts <> n.commit_ts);
+
+IF _collisions IS NOT NULL THEN
+  RAISE WARNING 'Commit prefix collision without SHA1 for %/%, not stored: %', _owner_id, _project_id, _collisions;
+END IF;
+
+INSERT INTO t_commit_ownership (owner_id, project_id, commit_hash, commit_ts, commit_sha1)
+select _owner_id, _project_id, n.commit_hash, n.commit_ts, null
+from unnest(_commit_hash, _commit_ts, _commit_sha1) as n(commit_hash, commit_ts, commit_sha1)
+where n.commit_sha1 is null
+  and not exists (select 1 from t_commit_ownership c where c.owner_id = _owner_id
+    and c.commit_hash = n.commit_hash and c.commit_ts = n.commit_ts)
+on conflict (owner_id, commit_hash) where commit_sha1 is null do nothing;
 --
 END --
 $$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
-GRANT EXECUTE ON FUNCTION stm_add_commits(varchar,varchar,varchar[],bigint[]) to public;
+GRANT EXECUTE ON FUNCTION stm_add_commits(varchar,varchar,varchar[],bigint[],varchar[]) to public;
+-- DROP FUNCTION IF EXISTS stm_add_commits(varchar,varchar,varchar[],bigint[])
 -- DROP FUNCTION IF EXISTS stm_add_commits
 
 /*** TESTING ***/
--- select stm_add_commits('o1','p1', array['c1','c2','c3'],array[1627380297,1627338215,1627176058])
--- select stm_add_commits('o1','p2', array['c4','c5','c6'],array[1627380297,1627338215,1627176058])
--- select stm_add_commits('o2','p3', array['c7','c8'],array[1627380297,1627338215])
--- select stm_add_commits('o2','p3', array['c1','c2'],array[1627380297,1627338215])
+-- select stm_add_commits('o1','p1', array['c1','c2','c3'],array[1627380297,1627338215,1627176058],array[null,null,null]::varchar[])
+-- select stm_add_commits('o1','p2', array['c4','c5','c6'],array[1627380297,1627338215,1627176058],array[null,null,null]::varchar[])
+-- select stm_add_commits('o2','p3', array['c7','c8'],array[1627380297,1627338215],array['c7000000000000000000000000000000000000000',null])
+-- select stm_add_commits('o2','p3', array['c1','c2'],array[1627380297,1627338215],array['c1000000000000000000000000000000000000000','c2000000000000000000000000000000000000000'])
+
+-- collision tests, each expects the result in the comment after it
+-- a different commit with the same prefix as the SHA1 of c7 stored above is stored as a new row
+-- select stm_add_commits('o2','p3', array['c7'],array[1627380297],array['c7000000000000000000000000000000000000001'])
+-- select count(*) from t_commit_ownership where owner_id = 'o2' and commit_hash = 'c7' -- 2
+-- the same SHA1 again is not duplicated
+-- select stm_add_commits('o2','p3', array['c7'],array[1627380297],array['c7000000000000000000000000000000000000001'])
+-- select count(*) from t_commit_ownership where owner_id = 'o2' and commit_hash = 'c7' -- 2
+-- the same commit without the SHA1 from an older report is not duplicated either
+-- select stm_add_commits('o2','p3', array['c7'],array[1627380297],array[null]::varchar[])
+-- select count(*) from t_commit_ownership where owner_id = 'o2' and commit_hash = 'c7' -- 2
+-- c8 was stored without the SHA1 and gets it from a report with the same timestamp
+-- select stm_add_commits('o2','p3', array['c8'],array[1627338215],array['c8000000000000000000000000000000000000000'])
+-- select commit_sha1 from t_commit_ownership where owner_id = 'o2' and commit_hash = 'c8' -- c8000000000000000000000000000000000000000
+-- a commit with the SHA1 and the prefix of c1 of o1, which has no SHA1, but a different timestamp is a different commit
+-- select stm_add_commits('o1','p1', array['c1'],array[1600000000],array['c1000000000000000000000000000000000000001'])
+-- select count(*) from t_commit_ownership where owner_id = 'o1' and commit_hash = 'c1' -- 2
+-- without the SHA1 it cannot be told apart from c1 of o1 and is skipped with a warning
+-- select stm_add_commits('o1','p1', array['c1'],array[1600000001],array[null]::varchar[])
+-- select count(*) from t_commit_ownership where owner_id = 'o1' and commit_hash = 'c1' -- 2
 
--- select * from t_commit_ownership limit 100 
+-- select * from t_commit_ownership limit 100
 -- explain analyze select * from t_commit_ownership where commit_hash = any(array['c1', 'c2'])
 -- explain analyze select * from t_commit_ownership where commit_hash in ('c1', 'c2')
@@ -0,0 +1,22 @@
+-- Stores a nonce of a signed request and returns TRUE if it was not seen before for the same owner.
+-- Returns FALSE if it is a replay. Nonces older than _retention_secs are purged for the owner.
+CREATE OR REPLACE FUNCTION stm_add_nonce(_owner_id varchar, _nonce varchar, _retention_secs bigint)
+RETURNS boolean AS $$ --
+BEGIN --
+--
+DELETE FROM t_nonce WHERE owner_id = _owner_id AND added_ts < now() - make_interval(secs => _retention_secs);
+--
+INSERT INTO t_nonce (owner_id, nonce, added_ts)
+VALUES (_owner_id, _nonce, now()) on conflict (owner_id, nonce) do nothing;
+--
+RETURN FOUND;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_add_nonce(varchar,varchar,bigint) to public;
+-- DROP FUNCTION IF EXISTS stm_add_nonce
+
+/*** TESTING ***/
+-- select stm_add_nonce('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','hQ2rDiTn6pKx5vWs', 600)
+
+-- select * from t_nonce limit 100
+-- select * from t_nonce where owner_id ='9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK'
@@ -0,0 +1,21 @@
+-- Records a merge of _merged_project_id into _project_id that needs an admin review because _merged_project_id
+-- has owners outside of the member account of _owner_id. The router does not merge such projects on its own,
+-- so that a member cannot move the projects of other members by sending a report that matches them.
+-- A repeated conflict only refreshes the owners and updated_ts of a pending review.
+CREATE OR REPLACE FUNCTION stm_add_project_merge_review(_owner_id varchar, _project_id varchar, _merged_project_id varchar, _other_owner_ids varchar[])
+RETURNS void AS $$ --
+BEGIN --
+  INSERT INTO t_project_merge_review (project_id, merged_project_id, owner_id, other_owner_ids)
+  VALUES (_project_id, _merged_project_id, _owner_id, _other_owner_ids)
+  on conflict (project_id, merged_project_id) do
+  UPDATE set owner_id = excluded.owner_id, other_owner_ids = excluded.other_owner_ids, updated_ts = now()
+  where t_project_merge_review.resolved_ts IS NULL;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_add_project_merge_review(varchar,varchar,varchar,varchar[]) to public;
+-- DROP FUNCTION IF EXISTS stm_add_project_merge_review
+
+/*** TESTING ***/
+-- select stm_add_project_merge_review('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', 'FZ8zezMFji6VXcWEDxckwy', 'Wgx98Rbi8nQuL9ddn3mTk1', array['7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7'])
+
+-- select * from t_project_merge_review where resolved_ts IS NULL
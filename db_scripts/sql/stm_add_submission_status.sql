@@ -0,0 +1,19 @@
+-- Records a new processing stage of a report submission.
+-- _project_id and _details are optional and can be NULL.
+CREATE OR REPLACE FUNCTION stm_add_submission_status(
+  _submission_id varchar, _owner_id varchar, _stage varchar, _project_id varchar, _details varchar) RETURNS void AS $$ --
+BEGIN --
+--
+INSERT INTO t_submission_status (submission_id, owner_id, stage, project_id, details, added_ts)
+VALUES (_submission_id, _owner_id, _stage, _project_id, _details, now());
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_add_submission_status(varchar,varchar,varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_add_submission_status
+
+/*** TESTING ***/
+-- select stm_add_submission_status('Wgx98Rbi8nQuL9ddn3mTk1', '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', 'stored', NULL, NULL)
+-- select stm_add_submission_status('Wgx98Rbi8nQuL9ddn3mTk1', '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', 'project_assigned', 'Wgx98Rbi8nQuL9ddn3mTk1', NULL)
+
+-- select * from t_submission_status order by added_ts desc limit 100
@@ -0,0 +1,73 @@
+-- Records the approval of a proposed merge by a key of one of the two accounts and merges them once both approved.
+-- _signer may be any key linked to either account. _sig is its base58 signature of the merge statement.
+-- The account with the earliest email in t_email_ownership is kept, the other one is linked to it via stm_link_keys,
+-- so that a single profile is built from the reports of all keys of both accounts. stm_link_keys moves the commits,
+-- project fingerprints and emails of the merged account to the kept one and removes its t_dev record in the same transaction.
+-- Other pending proposals for the merged account are dropped and get re-proposed for the kept account.
+-- Returns `merged`, `pending` if the other account has not approved yet or `not_found` if there is no such proposal
+-- for the account of _signer.
+CREATE OR REPLACE FUNCTION stm_approve_identity_merge(_signer varchar, _owner_id varchar, _other_owner_id varchar, _sig varchar)
+RETURNS varchar AS $$ --
+DECLARE
+  _canonical_owner_id varchar;
+  _merge t_identity_merge%ROWTYPE;
+  _kept_owner_id varchar;
+  _merged_owner_id varchar;
+BEGIN --
+  _canonical_owner_id := stm_get_canonical_owner_id(_signer);
+
+  select * into _merge from t_identity_merge where owner_id = _owner_id and other_owner_id = _other_owner_id FOR UPDATE;
+  IF NOT FOUND THEN
+    RETURN 'not_found';
+  END IF;
+
+  IF _merge.merged_ts IS NOT NULL THEN
+    RETURN 'merged';
+  END IF;
+
+  IF _canonical_owner_id = _merge.owner_id THEN
+    UPDATE t_identity_merge set owner_sig = _sig, owner_approved_ts = now()
+    WHERE owner_id = _owner_id and other_owner_id = _other_owner_id
+    RETURNING * into _merge;
+  ELSIF _canonical_owner_id = _merge.other_owner_id THEN
+    UPDATE t_identity_merge set other_owner_sig = _sig, other_owner_approved_ts = now()
+    WHERE owner_id = _owner_id and other_owner_id = _other_owner_id
+    RETURNING * into _merge;
+  ELSE
+    RETURN 'not_found';
+  END IF;
+
+  IF _merge.owner_approved_ts IS NULL OR _merge.other_owner_approved_ts IS NULL THEN
+    RETURN 'pending';
+  END IF;
+
+  -- the older account is kept, which is the one with the earliest email on record
+  IF coalesce((select min(added_ts) from t_email_ownership where owner_id in (select * from stm_get_linked_keys(_merge.other_owner_id))), 'infinity')
+    < coalesce((select min(added_ts) from t_email_ownership where owner_id in (select * from stm_get_linked_keys(_merge.owner_id))), 'infinity') THEN
+    _kept_owner_id := _merge.other_owner_id;
+    _merged_owner_id := _merge.owner_id;
+    PERFORM stm_link_keys(_kept_owner_id, _merged_owner_id, _merge.other_owner_sig, _merge.owner_sig);
+  ELSE
+    _kept_owner_id := _merge.owner_id;
+    _merged_owner_id := _merge.other_owner_id;
+    PERFORM stm_link_keys(_kept_owner_id, _merged_owner_id, _merge.owner_sig, _merge.other_owner_sig);
+  END IF;
+
+  UPDATE t_identity_merge set merged_owner_id = _merged_owner_id, merged_ts = now()
+  WHERE owner_id = _owner_id and other_owner_id = _other_owner_id;
+
+  DELETE FROM t_identity_merge
+  WHERE merged_ts IS NULL and (owner_id = _merged_owner_id or other_owner_id = _merged_owner_id);
+
+  RETURN 'merged';
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_approve_identity_merge(varchar,varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_approve_identity_merge
+
+/*** TESTING ***/
+-- select stm_approve_identity_merge('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7','9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','sig1')
+-- select stm_approve_identity_merge('7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7','7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7','9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','sig2')
+
+-- select * from t_identity_merge where merged_ts is not null limit 100
+-- select * from t_key_link where owner_id = '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK'
@@ -0,0 +1,40 @@
+-- Logs a request for _limit_type/_limit_value and checks if it fits into the sliding window of _window_secs.
+-- Returns NULL if the request is allowed or the age of the oldest accepted request in the window in seconds
+-- if there were _max_requests or more accepted requests already. The caller works out Retry-After from it.
+-- Rejected requests are logged, but do not count towards the limit.
+-- Accepted requests of all types and values older than the window are purged, so that IPs that are never seen again
+-- do not grow the table beyond what fits into one window. Rejected requests are kept for review for _review_secs
+-- or for the length of the window, whichever is longer.
+CREATE OR REPLACE FUNCTION stm_check_rate_limit(_limit_type varchar, _limit_value varchar, _window_secs bigint, _max_requests bigint, _review_secs bigint)
+RETURNS bigint AS $$ --
+DECLARE --
+  _window_start timestamptz := now() - make_interval(secs => _window_secs);
+  _review_start timestamptz := now() - make_interval(secs => greatest(_window_secs, _review_secs));
+  _cnt bigint;
+  _oldest_ts timestamptz;
+BEGIN --
+--
+DELETE FROM t_rate_limit_log WHERE added_ts < _window_start AND (is_rejected = FALSE OR added_ts < _review_start);
+--
+SELECT count(*), min(added_ts) INTO _cnt, _oldest_ts
+FROM t_rate_limit_log
+WHERE limit_type = _limit_type AND limit_value = _limit_value AND is_rejected = FALSE AND added_ts >= _window_start;
+--
+IF _cnt >= _max_requests THEN
+  INSERT INTO t_rate_limit_log (limit_type, limit_value, added_ts, is_rejected) VALUES (_limit_type, _limit_value, now(), TRUE);
+  RETURN floor(extract(epoch from (now() - _oldest_ts)))::bigint;
+END IF;
+--
+INSERT INTO t_rate_limit_log (limit_type, limit_value, added_ts, is_rejected) VALUES (_limit_type, _limit_value, now(), FALSE);
+RETURN NULL;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_check_rate_limit(varchar,varchar,bigint,bigint,bigint) to public;
+-- DROP FUNCTION IF EXISTS stm_check_rate_limit
+
+/*** TESTING ***/
+-- select stm_check_rate_limit('key', '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', 3600, 3, 604800)
+-- select stm_check_rate_limit('ip', '1.2.3.4', 3600, 3, 604800)
+
+-- select * from t_rate_limit_log order by added_ts desc limit 100
+-- select limit_type, limit_value, count(*), max(added_ts) from t_rate_limit_log where is_rejected = TRUE group by limit_type, limit_value order by 3 desc
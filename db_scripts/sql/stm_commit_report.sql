@@ -0,0 +1,71 @@
+-- Records a routed report in a single transaction, so that a failure at any step leaves no partial writes:
+-- the projects in _merged_project_ids are merged into _project_id, the projects shared with other members are sent for review,
+-- the fingerprint is assigned to _project_id, the dispute is flagged, the commits go into t_commit_ownership, and if it is the latest report for the project the emails are added
+-- to t_email_ownership, the dev is queued up in t_dev and the report hash is stored in t_report_hash.
+-- _review_project_ids and _review_owner_ids are (merged project, other owner) pairs, one per other owner of the project.
+-- _fingerprint and _dispute_type are NULL if the report has no remote URL or no commits owned by others.
+-- _gh_login_noreply is a login from a GitHub noreply commit email, it is kept in t_dev until a newer report has another one.
+-- Out of order reports only merge the projects, record the reviews, the fingerprint and the dispute, and add their commits.
+-- _commit_ts and _commit_sha1 must have a member for every _commit_hash, _is_primary for every _emails.
+-- All steps are idempotent, so the call can be repeated for the same report.
+CREATE OR REPLACE FUNCTION stm_commit_report(_owner_id varchar, _project_id varchar, _merged_project_ids varchar[],
+  _review_project_ids varchar[], _review_owner_ids varchar[], _fingerprint varchar,
+  _dispute_type varchar, _dispute_owner_ids varchar[], _dispute_shared_commits integer, _dispute_report_commits integer,
+  _commit_hash varchar[], _commit_ts bigint[], _commit_sha1 varchar[],
+  _emails varchar[], _is_primary boolean[], _gh_login_gist_latest varchar, _gh_login_noreply varchar, _report_hash varchar, _is_latest boolean)
+RETURNS void AS $$ --
+DECLARE
+  _idx integer;
+  _review_project_id varchar;
+BEGIN --
+  -- the S3 reports of the merged projects are already copied by the router and their commits must move with them
+  IF coalesce(cardinality(_merged_project_ids), 0) > 0 THEN
+    PERFORM stm_merge_projects(_project_id, _merged_project_ids);
+  END IF;
+
+  FOR _review_project_id IN SELECT DISTINCT unnest(_review_project_ids) LOOP
+    PERFORM stm_add_project_merge_review(_owner_id, _project_id, _review_project_id,
+      array(SELECT r.owner_id FROM unnest(_review_project_ids, _review_owner_ids) WITH ORDINALITY AS r(project_id, owner_id, idx)
+        WHERE r.project_id = _review_project_id ORDER BY r.idx));
+  END LOOP;
+
+  IF _fingerprint IS NOT NULL THEN
+    PERFORM stm_set_fingerprint_project(_owner_id, _fingerprint, _project_id);
+  END IF;
+
+  IF _dispute_type IS NOT NULL THEN
+    PERFORM stm_flag_commit_dispute(_owner_id, _project_id, _dispute_type, _dispute_owner_ids, _dispute_shared_commits, _dispute_report_commits);
+  END IF;
+
+  IF coalesce(cardinality(_commit_hash), 0) > 0 THEN
+    PERFORM stm_add_commits(_owner_id, _project_id, _commit_hash, _commit_ts, _commit_sha1);
+  END IF;
+
+  IF NOT _is_latest THEN
+    RETURN;
+  END IF;
+
+  FOR _idx IN 1 .. coalesce(cardinality(_emails), 0) LOOP
+    PERFORM stm_add_email(_owner_id, _emails[_idx], _is_primary[_idx]);
+  END LOOP;
+
+  PERFORM stm_queue_up_dev_report(_owner_id, _gh_login_gist_latest);
+  IF _gh_login_noreply IS NOT NULL THEN
+    UPDATE t_dev SET gh_login_noreply = _gh_login_noreply WHERE owner_id = _owner_id;
+  END IF;
+  PERFORM stm_set_report_hash(_owner_id, _project_id, _report_hash);
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_commit_report(varchar,varchar,varchar[],varchar[],varchar[],varchar,varchar,varchar[],integer,integer,varchar[],bigint[],varchar[],varchar[],boolean[],varchar,varchar,varchar,boolean) to public;
+-- DROP FUNCTION IF EXISTS stm_commit_report(varchar,varchar,varchar[],varchar[],bigint[],varchar[],varchar[],boolean[],varchar,varchar,varchar,boolean)
+
+/*** TESTING ***/
+-- select stm_commit_report('o1','p1', array[]::varchar[], array[]::varchar[], array[]::varchar[], 'f1', null, array[]::varchar[], null, null, array['c1','c2'],array[1627380297,1627338215],array[null,null]::varchar[],array['max@onebro.me'],array[true],null,'rimutaka','h1',true)
+-- select stm_commit_report('o1','p1', array['p2'], array['p3','p3'], array['o2','o3'], null, 'co_contributors', array['o2'], 1, 1, array['c3'],array[1627176058],array[null]::varchar[],array[]::varchar[],array[]::boolean[],null,null,'h2',false)
+
+-- select * from t_commit_ownership where owner_id = 'o1'
+-- select * from t_report_hash where owner_id = 'o1'
+-- select * from t_project_merge_review where owner_id = 'o1'
+-- select * from t_project_fingerprint where owner_id = 'o1'
+-- select * from t_commit_dispute where owner_id = 'o1'
+-- select owner_id, gh_login, gh_login_noreply from t_dev where owner_id = 'o1'
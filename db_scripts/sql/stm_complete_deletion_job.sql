@@ -0,0 +1,93 @@
+-- Purges all PG records of the account and all keys linked to it and marks the deletion request as completed.
+-- If the request is for a single project only the records of that project are purged and the dev is queued up
+-- for the profile to be regenerated without it. The project ID stays in t_deletion_queue and is excluded from
+-- the profile by stm_get_excluded_projects, so that later reports for the same project do not bring it back.
+-- t_project_merge is shared by all members and is left as is, merged IDs resolve to the excluded project.
+-- _details lists what was deleted outside of PG (S3, ES) and is stored together with the PG row counts for audit.
+-- The request itself is kept in t_deletion_queue. Account deletions keep the list of purged keys in deleted_keys
+-- for the redrive and replay flows to skip stored submissions of the deleted account.
+CREATE OR REPLACE FUNCTION stm_complete_deletion_job(_deletion_id varchar, _details varchar)
+RETURNS void AS $$ --
+DECLARE
+  _owner_id varchar;
+  _project_id varchar;
+  _keys varchar[];
+  _cnt bigint;
+  _pg_details varchar := '';
+BEGIN --
+  select owner_id, project_id into _owner_id, _project_id from t_deletion_queue where deletion_id = _deletion_id;
+  IF _owner_id IS NULL THEN
+    RAISE EXCEPTION 'Unknown deletion_id %', _deletion_id;
+  END IF;
+
+  -- the account may have multiple keys after key rotation
+  _keys := array(select * from stm_get_linked_keys(_owner_id));
+
+  IF _project_id IS NOT NULL THEN
+    DELETE FROM t_commit_ownership WHERE owner_id = ANY(_keys) AND project_id = _project_id;
+    GET DIAGNOSTICS _cnt = ROW_COUNT;
+    _pg_details := _pg_details || ', t_commit_ownership: ' || _cnt;
+
+    DELETE FROM t_report_hash WHERE owner_id = ANY(_keys) AND project_id = _project_id;
+    DELETE FROM t_project_fingerprint WHERE owner_id = ANY(_keys) AND project_id = _project_id;
+    DELETE FROM t_commit_dispute WHERE owner_id = ANY(_keys) AND project_id = _project_id;
+    DELETE FROM t_submission_status WHERE owner_id = ANY(_keys) AND project_id = _project_id;
+
+    -- regenerate the profile without the project
+    UPDATE t_dev set last_submission_ts = now(), report_fail_counter = 0 WHERE owner_id = _owner_id;
+
+    UPDATE t_deletion_queue SET completed_ts = now(), details = _details || _pg_details
+    WHERE deletion_id = _deletion_id;
+    RETURN;
+  END IF;
+
+  DELETE FROM t_commit_ownership WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_commit_ownership: ' || _cnt;
+
+  DELETE FROM t_email_ownership WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_email_ownership: ' || _cnt;
+
+  DELETE FROM t_dev WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_dev: ' || _cnt;
+
+  DELETE FROM t_report_hash WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_report_hash: ' || _cnt;
+
+  DELETE FROM t_project_fingerprint WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_project_fingerprint: ' || _cnt;
+
+  DELETE FROM t_commit_dispute WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_commit_dispute: ' || _cnt;
+
+  DELETE FROM t_identity_merge WHERE owner_id = ANY(_keys) OR other_owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_identity_merge: ' || _cnt;
+
+  DELETE FROM t_submission_status WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_submission_status: ' || _cnt;
+
+  DELETE FROM t_nonce WHERE owner_id = ANY(_keys);
+  DELETE FROM t_rate_limit_log WHERE limit_type IN ('key', 'key_query') AND limit_value = ANY(_keys);
+
+  DELETE FROM t_key_link WHERE owner_id = ANY(_keys);
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+  _pg_details := _pg_details || ', t_key_link: ' || _cnt;
+
+  UPDATE t_deletion_queue SET completed_ts = now(), details = _details || _pg_details, deleted_keys = _keys
+  WHERE deletion_id = _deletion_id;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_complete_deletion_job(varchar, varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_complete_deletion_job
+
+/*** TESTING ***/
+-- select stm_complete_deletion_job('Wgx98Rbi8nQuL9ddn3mTk1', 's3 objects: 0, es docs: 0')
+
+-- select * from t_deletion_queue where deletion_id = 'Wgx98Rbi8nQuL9ddn3mTk1'
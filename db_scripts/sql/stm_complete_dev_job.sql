@@ -2,13 +2,30 @@
 -- if the in-flight-id matches.
 CREATE OR REPLACE FUNCTION stm_complete_dev_job(
   _owner_id varchar, _report_in_flight_id uuid, _gh_login varchar, _gh_login_gist_validation varchar) RETURNS void AS $$
+DECLARE
+  _report_in_flight_ts timestamptz;
 BEGIN
 
+-- the job includes all submissions queued up before it started
+SELECT report_in_flight_ts INTO _report_in_flight_ts
+  FROM t_dev WHERE owner_id = _owner_id AND report_in_flight_id = _report_in_flight_id;
+
 -- update the queue details - happens on every call
 UPDATE t_dev
   SET report_ts = now(), report_in_flight_id = NULL
   WHERE owner_id = _owner_id AND report_in_flight_id = _report_in_flight_id;
 
+-- mark the submissions from all the keys of the dev as included in the profile
+IF FOUND THEN
+  INSERT INTO t_submission_status (submission_id, owner_id, stage, added_ts)
+  SELECT q.submission_id, q.owner_id, 'regenerated', now()
+    FROM t_submission_status q
+    WHERE q.stage = 'queued'
+      AND (q.owner_id = _owner_id OR q.owner_id IN (SELECT owner_id FROM t_key_link WHERE canonical_owner_id = _owner_id))
+      AND (_report_in_flight_ts IS NULL OR q.added_ts <= _report_in_flight_ts)
+      AND NOT EXISTS (SELECT 1 FROM t_submission_status r WHERE r.submission_id = q.submission_id AND r.stage = 'regenerated');
+END IF;
+
 -- update GH login validation - happens once in a while
 UPDATE t_dev
   SET gh_login = _gh_login, gh_login_gist_validation = _gh_login_gist_validation, gh_login_validation_ts = now()
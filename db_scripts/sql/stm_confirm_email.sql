@@ -0,0 +1,41 @@
+-- Marks the email with _confirmation_id as confirmed and queues up the dev for the profile to be regenerated.
+-- Returns the email or NULL if the ID is unknown or was generated more than _valid_for_secs ago.
+-- Repeated confirmations with the same ID return the email without changing anything.
+CREATE OR REPLACE FUNCTION stm_confirm_email(_confirmation_id varchar, _valid_for_secs bigint)
+RETURNS varchar AS $$ --
+DECLARE
+  _owner_id varchar;
+  _email varchar;
+  _confirmed_ts timestamptz;
+BEGIN --
+  select owner_id, email, confirmed_ts into _owner_id, _email, _confirmed_ts
+    from t_email_ownership where confirmation_id = _confirmation_id limit 1;
+
+  IF _email IS NULL THEN
+    RETURN NULL;
+  END IF;
+
+  IF _confirmed_ts IS NOT NULL THEN
+    RETURN _email;
+  END IF;
+
+  IF split_part(_confirmation_id, '_', 1)::bigint < extract(epoch from now())::bigint - _valid_for_secs THEN
+    RETURN NULL;
+  END IF;
+
+  UPDATE t_email_ownership set confirmed_ts = now() where owner_id = _owner_id and email = _email;
+
+  -- the profile has to be regenerated to include the confirmed email
+  UPDATE t_dev set last_submission_ts = now(), report_fail_counter = 0
+  where owner_id = stm_get_canonical_owner_id(_owner_id);
+
+  RETURN _email;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_confirm_email(varchar,bigint) to public;
+-- DROP FUNCTION IF EXISTS stm_confirm_email
+
+/*** TESTING ***/
+-- select stm_confirm_email('1627380297_Wgx98Rbi8nQuL9ddn3mTk1', 2592000)
+
+-- select * from t_email_ownership where confirmed_ts is not null limit 100
@@ -0,0 +1,15 @@
+-- Records why the latest attempt to process the deletion request failed.
+-- The request stays in flight and is picked up again by stm_get_deletion_jobs after the retry timeout.
+CREATE OR REPLACE FUNCTION stm_fail_deletion_job(_deletion_id varchar, _error varchar)
+RETURNS void AS $$ --
+BEGIN --
+UPDATE t_deletion_queue SET last_error = _error, last_error_ts = now()
+WHERE deletion_id = _deletion_id AND completed_ts IS NULL;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_fail_deletion_job(varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_fail_deletion_job
+
+/*** TESTING ***/
+-- select stm_fail_deletion_job('Wgx98Rbi8nQuL9ddn3mTk1', 'S3: failed to list reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/')
+-- select deletion_id, owner_id, attempts, last_error, last_error_ts from t_deletion_queue where completed_ts is null order by added_ts
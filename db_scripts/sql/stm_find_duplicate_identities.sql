@@ -0,0 +1,43 @@
+-- Returns pairs of member accounts that are likely to belong to the same person: they share confirmed emails
+-- or have the same validated github login. Emails and logins of linked keys count towards their canonical accounts.
+-- owner_id is always the lower of the two canonical owner_ids, so every pair is returned once.
+-- Pairs that were already merged are not returned because their keys have the same canonical owner_id.
+CREATE OR REPLACE FUNCTION stm_find_duplicate_identities()
+RETURNS TABLE (owner_id varchar, other_owner_id varchar, shared_emails varchar[], shared_gh_login varchar) AS $$ --
+BEGIN --
+  RETURN QUERY
+  WITH confirmed_emails AS (
+    select distinct coalesce(kl.canonical_owner_id, e.owner_id) as canonical_owner_id, e.email
+    from t_email_ownership e left join t_key_link kl on kl.owner_id = e.owner_id
+    where e.confirmed_ts is not null
+  ),
+  gh_logins AS (
+    select distinct coalesce(kl.canonical_owner_id, d.owner_id) as canonical_owner_id, lower(d.gh_login) as gh_login
+    from t_dev d left join t_key_link kl on kl.owner_id = d.owner_id
+    where d.gh_login is not null
+  ),
+  email_pairs AS (
+    select a.canonical_owner_id as owner_id, b.canonical_owner_id as other_owner_id,
+      array_agg(a.email order by a.email)::varchar[] as shared_emails
+    from confirmed_emails a join confirmed_emails b
+      on b.email = a.email and b.canonical_owner_id > a.canonical_owner_id
+    group by a.canonical_owner_id, b.canonical_owner_id
+  ),
+  gh_login_pairs AS (
+    select a.canonical_owner_id as owner_id, b.canonical_owner_id as other_owner_id, min(a.gh_login)::varchar as gh_login
+    from gh_logins a join gh_logins b
+      on b.gh_login = a.gh_login and b.canonical_owner_id > a.canonical_owner_id
+    group by a.canonical_owner_id, b.canonical_owner_id
+  )
+  select coalesce(e.owner_id, g.owner_id)::varchar, coalesce(e.other_owner_id, g.other_owner_id)::varchar,
+    coalesce(e.shared_emails, array[]::varchar[]), g.gh_login
+  from email_pairs e full outer join gh_login_pairs g
+    on g.owner_id = e.owner_id and g.other_owner_id = e.other_owner_id
+  order by 1, 2;
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_find_duplicate_identities() to public;
+-- DROP FUNCTION IF EXISTS stm_find_duplicate_identities
+
+/*** TESTING ***/
+-- select * from stm_find_duplicate_identities()
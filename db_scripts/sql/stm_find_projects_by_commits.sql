@@ -1,11 +1,15 @@
 -- returns all records matching any of the specified commits
 -- it is up to the caller to sort out which project id to use
+-- merged projects are replaced with the project they were merged into in case some commits were added after the merge
+-- commit_sha1 is NULL for commits added from older reports, so the caller has to fall back onto the hash prefix and ts
 CREATE OR REPLACE FUNCTION stm_find_projects_by_commits(_commit_hash varchar[])
 RETURNS SETOF t_commit_ownership AS $$ --
 BEGIN --
 --
 RETURN QUERY
-select distinct * from t_commit_ownership where commit_hash = any(_commit_hash);
+select distinct c.owner_id, coalesce(m.project_id, c.project_id)::varchar(150), c.commit_hash, c.commit_ts, c.commit_sha1
+from t_commit_ownership c left join t_project_merge m on m.merged_project_id = c.project_id
+where c.commit_hash = any(_commit_hash);
 --
 END --
 $$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
@@ -0,0 +1,25 @@
+-- Records a project of _owner_id with commits owned by other owners for review.
+-- A repeated flag updates the counts and the owners, but keeps the resolution and never downgrades
+-- `full_history_claim` to `co_contributors`.
+CREATE OR REPLACE FUNCTION stm_flag_commit_dispute(_owner_id varchar, _project_id varchar, _dispute_type varchar,
+  _other_owner_ids varchar[], _shared_commits integer, _report_commits integer)
+RETURNS void AS $$ --
+BEGIN --
+--
+INSERT INTO t_commit_dispute (owner_id, project_id, dispute_type, other_owner_ids, shared_commits, report_commits, flagged_ts)
+VALUES (_owner_id, _project_id, _dispute_type, _other_owner_ids, _shared_commits, _report_commits, now())
+on conflict (owner_id, project_id) do
+UPDATE set dispute_type = case when t_commit_dispute.dispute_type = 'full_history_claim'
+    then t_commit_dispute.dispute_type else excluded.dispute_type end,
+  other_owner_ids = excluded.other_owner_ids, shared_commits = excluded.shared_commits,
+  report_commits = excluded.report_commits, flagged_ts = excluded.flagged_ts;
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_flag_commit_dispute(varchar,varchar,varchar,varchar[],integer,integer) to public;
+-- DROP FUNCTION IF EXISTS stm_flag_commit_dispute
+
+/*** TESTING ***/
+-- select stm_flag_commit_dispute('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','Wgx98Rbi8nQuL9ddn3mTk1','full_history_claim',array['gh:stackmuncher'],48,50)
+
+-- select * from t_commit_dispute limit 100
@@ -0,0 +1,12 @@
+-- Returns the canonical owner_id for any key linked to a member account or the key itself if it is not linked.
+CREATE OR REPLACE FUNCTION stm_get_canonical_owner_id(_owner_id varchar)
+RETURNS varchar AS $$ --
+BEGIN --
+  RETURN coalesce((select canonical_owner_id from t_key_link where owner_id = _owner_id), _owner_id);
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_canonical_owner_id(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_canonical_owner_id
+
+/*** TESTING ***/
+-- select stm_get_canonical_owner_id('EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB')
@@ -0,0 +1,20 @@
+-- Returns disputes pending review, oldest first, or all disputes of _owner_id and its linked keys if it is not NULL.
+CREATE OR REPLACE FUNCTION stm_get_commit_disputes(_owner_id varchar)
+RETURNS SETOF t_commit_dispute AS $$ --
+BEGIN --
+--
+IF _owner_id IS NULL THEN
+  RETURN QUERY select * from t_commit_dispute where resolution IS NULL order by flagged_ts;
+ELSE
+  RETURN QUERY select * from t_commit_dispute where owner_id in (select * from stm_get_linked_keys(_owner_id))
+  order by flagged_ts;
+END IF;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_commit_disputes(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_commit_disputes
+
+/*** TESTING ***/
+-- select * from stm_get_commit_disputes(null)
+-- select * from stm_get_commit_disputes('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
@@ -0,0 +1,16 @@
+-- Returns all confirmed emails of the member account _owner_id belongs to, including all linked keys.
+CREATE OR REPLACE FUNCTION stm_get_confirmed_emails(_owner_id varchar)
+RETURNS SETOF varchar AS $$ --
+BEGIN --
+--
+RETURN QUERY
+select distinct e.email::varchar from t_email_ownership e
+where e.owner_id in (select * from stm_get_linked_keys(_owner_id)) and e.confirmed_ts is not null;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_confirmed_emails(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_confirmed_emails
+
+/*** TESTING ***/
+-- select * from stm_get_confirmed_emails('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
@@ -0,0 +1,18 @@
+-- Returns all keys of accounts purged by completed account deletions.
+-- Requests completed before deleted_keys was added only have the canonical key and the key that signed the request.
+CREATE OR REPLACE FUNCTION stm_get_deleted_owners()
+RETURNS SETOF varchar AS $$ --
+BEGIN --
+--
+RETURN QUERY
+select distinct unnest(coalesce(deleted_keys, ARRAY[owner_id, requested_by_owner_id]))
+from t_deletion_queue
+where project_id IS NULL and completed_ts IS NOT NULL;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_deleted_owners() to public;
+-- DROP FUNCTION IF EXISTS stm_get_deleted_owners
+
+/*** TESTING ***/
+-- select * from stm_get_deleted_owners()
@@ -0,0 +1,28 @@
+-- Selects pending account and project deletion requests for processing, marks them as in-flight and returns the list to the caller.
+-- Requests that were in flight for longer than _retry_after_secs are retried up to _max_attempts times.
+-- gh_login comes from t_dev and is needed to find the public profile of the member in ES.
+CREATE OR REPLACE FUNCTION stm_get_deletion_jobs(_jobs_max integer, _retry_after_secs bigint, _max_attempts integer)
+RETURNS TABLE (deletion_id varchar, owner_id varchar, project_id varchar, gh_login varchar, attempts integer) AS $$ --
+BEGIN --
+
+RETURN QUERY
+WITH d as (select q.deletion_id from t_deletion_queue q where q.completed_ts is null
+    and (q.in_flight_ts is null or q.in_flight_ts < now() - make_interval(secs => _retry_after_secs))
+    and q.attempts < _max_attempts
+    order by q.added_ts
+    FOR UPDATE SKIP LOCKED
+    LIMIT _jobs_max)
+  UPDATE t_deletion_queue
+    SET in_flight_ts = now(),
+      attempts = t_deletion_queue.attempts + 1
+    FROM d WHERE t_deletion_queue.deletion_id = d.deletion_id
+  RETURNING t_deletion_queue.deletion_id, t_deletion_queue.owner_id, t_deletion_queue.project_id,
+    (select t_dev.gh_login from t_dev where t_dev.owner_id = t_deletion_queue.owner_id), t_deletion_queue.attempts;
+
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_deletion_jobs(integer, bigint, integer) to public;
+-- DROP FUNCTION IF EXISTS stm_get_deletion_jobs
+
+/*** TESTING ***/
+-- select * from stm_get_deletion_jobs(10, 3600, 10)
@@ -0,0 +1,24 @@
+-- Returns a snapshot of the deletion queue for `stm_stats_deletion_queue_counts` ES index used by the stats page.
+CREATE OR REPLACE FUNCTION stm_get_deletion_queue_counts()
+RETURNS TABLE (queue_total bigint, to_delete bigint, failed bigint, oldest timestamp with time zone,
+  newest timestamp with time zone, added_10m bigint, added_1hr bigint, added_24hr bigint) AS $$ --
+BEGIN --
+
+RETURN QUERY
+select count(*),
+  count(*) filter (where q.attempts = 0),
+  count(*) filter (where q.attempts > 0),
+  min(q.added_ts),
+  max(q.added_ts),
+  count(*) filter (where q.added_ts > now() - interval '10 minutes'),
+  count(*) filter (where q.added_ts > now() - interval '1 hour'),
+  count(*) filter (where q.added_ts > now() - interval '24 hours')
+from t_deletion_queue q where q.completed_ts is null;
+
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_deletion_queue_counts() to public;
+-- DROP FUNCTION IF EXISTS stm_get_deletion_queue_counts
+
+/*** TESTING ***/
+-- select * from stm_get_deletion_queue_counts()
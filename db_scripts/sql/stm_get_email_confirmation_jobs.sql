@@ -0,0 +1,24 @@
+-- Returns unconfirmed primary emails that need a confirmation link sent to them.
+-- An email gets a new link if it never had one or the previous one was generated more than _resend_after_secs ago.
+-- confirmation_id starts with the unix timestamp of when it was generated, e.g. `1627380297_Wgx98Rbi8nQuL9ddn3mTk1`
+CREATE OR REPLACE FUNCTION stm_get_email_confirmation_jobs(_jobs_max integer, _resend_after_secs bigint)
+RETURNS TABLE (owner_id varchar, email varchar) AS $$ --
+BEGIN --
+--
+RETURN QUERY
+select e.owner_id::varchar, e.email::varchar from t_email_ownership e
+where e.is_primary is not null and e.confirmed_ts is null
+  and (e.confirmation_id is null
+    or split_part(e.confirmation_id, '_', 1)::bigint < extract(epoch from now())::bigint - _resend_after_secs)
+order by e.added_ts
+limit _jobs_max;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_email_confirmation_jobs(integer,bigint) to public;
+-- DROP FUNCTION IF EXISTS stm_get_email_confirmation_jobs
+
+/*** TESTING ***/
+-- select * from stm_get_email_confirmation_jobs(10, 2592000)
+
+-- select * from t_email_ownership where is_primary is not null and confirmed_ts is null limit 100
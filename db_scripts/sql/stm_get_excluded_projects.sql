@@ -0,0 +1,22 @@
+-- Returns the projects of _owner_id and its linked keys that must not be merged into the dev profile:
+-- full history claims pending review or rejected by an admin and projects deleted by the member.
+-- A deleted project stays excluded even if the member submits more reports for it later.
+CREATE OR REPLACE FUNCTION stm_get_excluded_projects(_owner_id varchar)
+RETURNS SETOF varchar AS $$ --
+BEGIN --
+--
+RETURN QUERY
+select project_id from t_commit_dispute
+where owner_id in (select * from stm_get_linked_keys(_owner_id)) and dispute_type = 'full_history_claim'
+  and (resolution IS NULL or resolution = 'rejected')
+union
+select project_id from t_deletion_queue
+where owner_id in (select * from stm_get_linked_keys(_owner_id)) and project_id IS NOT NULL;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_excluded_projects(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_excluded_projects
+
+/*** TESTING ***/
+-- select * from stm_get_excluded_projects('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
@@ -0,0 +1,17 @@
+-- Returns the project ID assigned to the fingerprint of the owner and a flag if the project has any commits.
+-- Returns no rows if the fingerprint is not known.
+CREATE OR REPLACE FUNCTION stm_get_fingerprint_project(_owner_id varchar, _fingerprint varchar)
+RETURNS TABLE (project_id varchar, has_commits boolean) AS $$ --
+BEGIN --
+--
+RETURN QUERY
+select f.project_id::varchar, exists(select 1 from t_commit_ownership c where c.project_id = f.project_id)
+from t_project_fingerprint f where f.owner_id = _owner_id and f.fingerprint = _fingerprint;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_fingerprint_project(varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_fingerprint_project
+
+/*** TESTING ***/
+-- select * from stm_get_fingerprint_project('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','8sHzVnWSxMgfBRc3oNJ6udnEdbSYDCp3Kqn94oejDnWq')
@@ -0,0 +1,28 @@
+-- Returns all merges proposed for the account of _owner_id, which may be any of its keys, including completed ones.
+-- With _owner_id = NULL returns all pending merges and completed merges with the profile of the merged account
+-- still in ES, oldest first.
+CREATE OR REPLACE FUNCTION stm_get_identity_merges(_owner_id varchar)
+RETURNS SETOF t_identity_merge AS $$ --
+DECLARE
+  _canonical_owner_id varchar;
+BEGIN --
+  IF _owner_id IS NULL THEN
+    RETURN QUERY select * from t_identity_merge where profile_removed_ts IS NULL order by proposed_ts;
+    RETURN;
+  END IF;
+
+  _canonical_owner_id := stm_get_canonical_owner_id(_owner_id);
+
+  -- a completed merge is recorded under the owner_id of the merged account, which is now linked to the kept one
+  RETURN QUERY select * from t_identity_merge
+    where owner_id in (select * from stm_get_linked_keys(_canonical_owner_id))
+      or other_owner_id in (select * from stm_get_linked_keys(_canonical_owner_id))
+    order by proposed_ts;
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_identity_merges(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_identity_merges
+
+/*** TESTING ***/
+-- select * from stm_get_identity_merges(null)
+-- select * from stm_get_identity_merges('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
@@ -1,17 +1,19 @@
--- returns the timestamp of the latest project commit
-CREATE OR REPLACE FUNCTION stm_get_latest_project_commit(_owner_id varchar, _project_id varchar)
+-- returns the timestamp of the latest project commit within _min_ts .. _max_ts range or 0 if there are none
+-- commits with implausible timestamps, e.g. from a machine with a wrong clock, are outside of the range and are ignored
+CREATE OR REPLACE FUNCTION stm_get_latest_project_commit(_owner_id varchar, _project_id varchar, _min_ts bigint, _max_ts bigint)
 RETURNS bigint AS $$ --
 DECLARE
   latest_commit bigint;
 BEGIN
-  select max(commit_ts) into latest_commit from t_commit_ownership where owner_id=_owner_id and project_id=_project_id;
+  select coalesce(max(commit_ts), 0) into latest_commit from t_commit_ownership
+    where owner_id=_owner_id and project_id=_project_id and commit_ts between _min_ts and _max_ts;
   RETURN latest_commit;
 END --
 $$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
 
-GRANT EXECUTE ON FUNCTION stm_get_latest_project_commit(varchar, varchar) to public;
--- DROP FUNCTION IF EXISTS stm_get_latest_project_commit
+GRANT EXECUTE ON FUNCTION stm_get_latest_project_commit(varchar, varchar, bigint, bigint) to public;
+-- DROP FUNCTION IF EXISTS stm_get_latest_project_commit(varchar, varchar)
 
 /*** TESTING ***/
--- select * from stm_get_latest_project_commit('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', 'LJq8YVWJt7C9Sxa4poJKea')
+-- select * from stm_get_latest_project_commit('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', 'LJq8YVWJt7C9Sxa4poJKea', 1104537600, extract(epoch from now())::bigint + 86400)
 -- explain analyze select max(commit_ts) from t_commit_ownership where owner_id='9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK' and project_id='LJq8YVWJt7C9Sxa4poJKea'
@@ -0,0 +1,18 @@
+-- Returns the canonical owner_id of the member account of _owner_id followed by all keys linked to it.
+-- Returns just _owner_id if the key is not linked to anything.
+CREATE OR REPLACE FUNCTION stm_get_linked_keys(_owner_id varchar)
+RETURNS SETOF varchar AS $$ --
+DECLARE
+  _canonical_owner_id varchar;
+BEGIN --
+  _canonical_owner_id := stm_get_canonical_owner_id(_owner_id);
+
+  RETURN NEXT _canonical_owner_id;
+  RETURN QUERY select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id order by added_ts;
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_linked_keys(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_linked_keys
+
+/*** TESTING ***/
+-- select * from stm_get_linked_keys('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
@@ -0,0 +1,15 @@
+-- Returns the keys linked to the canonical account of _owner_id that may still have ES profiles of their own
+-- from before they were linked. The canonical owner_id is never returned.
+CREATE OR REPLACE FUNCTION stm_get_linked_profiles(_owner_id varchar)
+RETURNS SETOF varchar AS $$ --
+BEGIN --
+  RETURN QUERY select owner_id from t_key_link
+  where canonical_owner_id = stm_get_canonical_owner_id(_owner_id) and profile_removed_ts IS NULL
+  order by added_ts;
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_linked_profiles(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_linked_profiles
+
+/*** TESTING ***/
+-- select * from stm_get_linked_profiles('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
@@ -0,0 +1,20 @@
+-- Returns all owner/project pairs for the specified projects.
+-- Used to find the S3 report folders of projects that are about to be merged and to check that the projects
+-- have no owners outside of the member account that sent the report.
+-- Projects without commits are found via t_project_fingerprint.
+CREATE OR REPLACE FUNCTION stm_get_project_owners(_project_ids varchar[])
+RETURNS TABLE (owner_id varchar, project_id varchar) AS $$ --
+BEGIN --
+--
+RETURN QUERY
+select c.owner_id::varchar, c.project_id::varchar from t_commit_ownership c where c.project_id = any(_project_ids)
+union
+select f.owner_id::varchar, f.project_id::varchar from t_project_fingerprint f where f.project_id = any(_project_ids);
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_project_owners(varchar[]) to public;
+-- DROP FUNCTION IF EXISTS stm_get_project_owners
+
+/*** TESTING ***/
+-- select * from stm_get_project_owners(array['FZ8zezMFji6VXcWEDxckwy','Wgx98Rbi8nQuL9ddn3mTk1'])
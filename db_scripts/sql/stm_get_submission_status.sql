@@ -0,0 +1,20 @@
+-- Returns the processing history of the submission, oldest stage first.
+-- Only submissions signed by _owner_id or any other key linked to the same account are returned.
+CREATE OR REPLACE FUNCTION stm_get_submission_status(_submission_id varchar, _owner_id varchar)
+RETURNS TABLE (stage varchar, project_id varchar, details varchar, added_ts bigint) AS $$ --
+BEGIN --
+--
+RETURN QUERY
+SELECT ss.stage, ss.project_id, ss.details, extract(epoch from ss.added_ts)::bigint
+FROM t_submission_status ss
+WHERE ss.submission_id = _submission_id
+  AND stm_get_canonical_owner_id(ss.owner_id) = stm_get_canonical_owner_id(_owner_id)
+ORDER BY ss.added_ts;
+--
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_get_submission_status(varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_get_submission_status
+
+/*** TESTING ***/
+-- select * from stm_get_submission_status('Wgx98Rbi8nQuL9ddn3mTk1', '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
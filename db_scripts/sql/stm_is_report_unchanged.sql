@@ -0,0 +1,12 @@
+-- Returns TRUE if the latest processed report for the owner/project has the same hash as _report_hash.
+CREATE OR REPLACE FUNCTION stm_is_report_unchanged(_owner_id varchar, _project_id varchar, _report_hash varchar)
+RETURNS boolean AS $$ --
+BEGIN --
+  RETURN EXISTS (select 1 from t_report_hash where owner_id = _owner_id and project_id = _project_id and report_hash = _report_hash);
+END --
+$$ COST 100 STABLE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_is_report_unchanged(varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_is_report_unchanged
+
+/*** TESTING ***/
+-- select stm_is_report_unchanged('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','Wgx98Rbi8nQuL9ddn3mTk1','GKot5hBsd81kMupNCXHaqbhv3huEbxAFMLnpcX2hniwn')
@@ -0,0 +1,99 @@
+-- Links _new_key to the canonical account of _old_key and returns the canonical owner_id.
+-- Any keys previously linked to _new_key are re-linked to the same canonical account.
+-- Commits and project fingerprints of the linked keys are moved to the canonical account, so that the router
+-- finds the existing projects when it looks them up by the canonical owner_id. Where both have the same commit
+-- or fingerprint the record of the canonical account is kept.
+-- Emails of the linked keys are moved to the canonical account as well, keeping the earliest confirmation of each.
+-- The t_dev records of the linked keys are removed, so that only the canonical account has a profile.
+-- Their ES profiles are removed once by `dev_queue` flow of stm_inbox_flows when it rebuilds the canonical profile,
+-- see stm_get_linked_profiles.
+-- The canonical dev is queued up for a profile update to merge reports from all linked keys.
+CREATE OR REPLACE FUNCTION stm_link_keys(_old_key varchar, _new_key varchar, _old_key_sig varchar, _new_key_sig varchar)
+RETURNS varchar AS $$ --
+DECLARE
+  _canonical_owner_id varchar;
+  _project record;
+BEGIN --
+  _canonical_owner_id := stm_get_canonical_owner_id(_old_key);
+
+  -- the keys are already linked the other way around - nothing to do
+  IF _canonical_owner_id = _new_key THEN
+    RETURN _canonical_owner_id;
+  END IF;
+
+  -- _new_key may have a profile of its own in ES, which has to be removed once whether it is a new or a re-linked key
+  INSERT INTO t_key_link (owner_id, canonical_owner_id, linked_by_owner_id, linked_by_sig, owner_sig, added_ts, profile_removed_ts)
+  VALUES (_new_key, _canonical_owner_id, _old_key, _old_key_sig, _new_key_sig, now(), NULL) on conflict (owner_id) do
+  UPDATE set canonical_owner_id = excluded.canonical_owner_id, linked_by_owner_id = excluded.linked_by_owner_id,
+    linked_by_sig = excluded.linked_by_sig, owner_sig = excluded.owner_sig, added_ts = excluded.added_ts,
+    profile_removed_ts = NULL;
+
+  -- keys that were linked to _new_key as their canonical account follow it
+  UPDATE t_key_link set canonical_owner_id = _canonical_owner_id WHERE canonical_owner_id = _new_key;
+
+  -- re-key the ownership records of all linked keys, the unique keys do not allow updating them in place
+  -- if the canonical account or another linked key already has the same commit
+  -- stm_add_commits applies the same SHA1 and prefix rules as for the commits of a report
+  FOR _project IN
+    SELECT project_id, array_agg(commit_hash) as commit_hash, array_agg(commit_ts) as commit_ts, array_agg(commit_sha1) as commit_sha1
+    FROM t_commit_ownership
+    WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id)
+    GROUP BY project_id
+  LOOP
+    PERFORM stm_add_commits(_canonical_owner_id, _project.project_id, _project.commit_hash, _project.commit_ts, _project.commit_sha1);
+  END LOOP;
+
+  DELETE FROM t_commit_ownership
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id);
+
+  -- the most recently seen fingerprint wins between the linked keys
+  INSERT INTO t_project_fingerprint (owner_id, fingerprint, project_id, updated_ts)
+  SELECT DISTINCT ON (fingerprint) _canonical_owner_id, fingerprint, project_id, updated_ts
+  FROM t_project_fingerprint
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id)
+  ORDER BY fingerprint, updated_ts desc
+  on conflict (owner_id, fingerprint) do nothing;
+
+  DELETE FROM t_project_fingerprint
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id);
+
+  -- confirmed emails stay confirmed and primary emails stay primary after the move
+  INSERT INTO t_email_ownership (owner_id, email, added_ts, confirmed_ts, confirmation_id, confirmation_error, is_primary)
+  SELECT DISTINCT ON (email) _canonical_owner_id, email, added_ts, confirmed_ts, confirmation_id, confirmation_error, is_primary
+  FROM t_email_ownership
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id)
+  ORDER BY email, confirmed_ts NULLS LAST, added_ts
+  on conflict (owner_id, email) do
+  UPDATE set added_ts = least(t_email_ownership.added_ts, excluded.added_ts),
+    confirmed_ts = coalesce(t_email_ownership.confirmed_ts, excluded.confirmed_ts),
+    is_primary = coalesce(t_email_ownership.is_primary, excluded.is_primary);
+
+  DELETE FROM t_email_ownership
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id);
+
+  -- regenerate the profile of the canonical account, it is created if only the linked keys submitted reports so far
+  INSERT INTO t_dev (owner_id, last_submission_ts, gh_login_gist_latest, gh_login_noreply)
+  SELECT _canonical_owner_id, now(), gh_login_gist_latest, gh_login_noreply
+  FROM t_dev
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id)
+  ORDER BY last_submission_ts desc NULLS LAST
+  LIMIT 1
+  on conflict (owner_id) do nothing;
+
+  UPDATE t_dev set last_submission_ts = now(), report_fail_counter = 0 WHERE owner_id = _canonical_owner_id;
+
+  -- the linked keys no longer have profiles of their own
+  DELETE FROM t_dev
+  WHERE owner_id in (select owner_id from t_key_link where canonical_owner_id = _canonical_owner_id);
+
+  RETURN _canonical_owner_id;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_link_keys(varchar,varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_link_keys
+
+/*** TESTING ***/
+-- select stm_link_keys('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB', 'sig1', 'sig2')
+
+-- select * from t_key_link limit 100
+-- select * from t_commit_ownership where owner_id = 'EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB' limit 100
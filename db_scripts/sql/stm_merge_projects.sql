@@ -0,0 +1,48 @@
+-- Moves all commits of _merged_project_ids into _project_id and records the merge in t_project_merge.
+-- Projects that were previously merged into any of _merged_project_ids are re-pointed to _project_id.
+-- All owners of the merged projects are queued up for their profiles to be regenerated.
+-- Called by stm_commit_report, so the merge is applied in the same transaction as the report that caused it.
+-- The router only passes projects owned by the member account of the report, see stm_add_project_merge_review.
+-- Returns the number of commits moved.
+CREATE OR REPLACE FUNCTION stm_merge_projects(_project_id varchar, _merged_project_ids varchar[])
+RETURNS bigint AS $$ --
+DECLARE
+  _commits_moved bigint;
+BEGIN --
+  -- the owners have to be found before their commits are moved
+  UPDATE t_dev set last_submission_ts = now(), report_fail_counter = 0
+  WHERE owner_id in (select distinct owner_id from t_commit_ownership where project_id = any(_merged_project_ids)
+    union select owner_id from t_project_fingerprint where project_id = any(_merged_project_ids));
+
+  UPDATE t_commit_ownership set project_id = _project_id where project_id = any(_merged_project_ids);
+  GET DIAGNOSTICS _commits_moved = ROW_COUNT;
+
+  -- reports without commits from the same remote go to the surviving project from now on
+  UPDATE t_project_fingerprint set project_id = _project_id where project_id = any(_merged_project_ids);
+
+  -- disputes follow the commits unless the owner already has one for the surviving project
+  UPDATE t_commit_dispute d set project_id = _project_id where d.project_id = any(_merged_project_ids)
+    and not exists (select 1 from t_commit_dispute d2 where d2.owner_id = d.owner_id and d2.project_id = _project_id);
+  DELETE FROM t_commit_dispute where project_id = any(_merged_project_ids);
+
+  -- the hashes of the merged projects are meaningless now
+  DELETE FROM t_report_hash where project_id = any(_merged_project_ids);
+
+  -- keep the chain of merges flat, so that any merged ID resolves to the surviving project in one step
+  UPDATE t_project_merge set project_id = _project_id where project_id = any(_merged_project_ids);
+
+  INSERT INTO t_project_merge (merged_project_id, project_id, merged_ts, commits_moved)
+  select unnest(_merged_project_ids), _project_id, now(), _commits_moved
+  on conflict (merged_project_id) do
+  UPDATE set project_id = excluded.project_id, merged_ts = excluded.merged_ts, commits_moved = excluded.commits_moved;
+
+  RETURN _commits_moved;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_merge_projects(varchar,varchar[]) to public;
+-- DROP FUNCTION IF EXISTS stm_merge_projects
+
+/*** TESTING ***/
+-- select stm_merge_projects('FZ8zezMFji6VXcWEDxckwy', array['Wgx98Rbi8nQuL9ddn3mTk1'])
+
+-- select * from t_project_merge limit 100
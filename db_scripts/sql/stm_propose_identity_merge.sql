@@ -0,0 +1,29 @@
+-- Records a proposal to merge two member accounts or refreshes the shared emails and login of an existing one.
+-- _owner_id must be the lower of the two canonical owner_ids. Merged proposals are left as they are.
+-- Returns TRUE if the proposal is new.
+CREATE OR REPLACE FUNCTION stm_propose_identity_merge(_owner_id varchar, _other_owner_id varchar, _shared_emails varchar[], _shared_gh_login varchar)
+RETURNS boolean AS $$ --
+DECLARE
+  _is_new boolean;
+BEGIN --
+  IF _owner_id >= _other_owner_id THEN
+    RAISE EXCEPTION 'owner_id % must be lower than other_owner_id %', _owner_id, _other_owner_id;
+  END IF;
+
+  _is_new := NOT EXISTS (select 1 from t_identity_merge where owner_id = _owner_id and other_owner_id = _other_owner_id);
+
+  INSERT INTO t_identity_merge (owner_id, other_owner_id, shared_emails, shared_gh_login, proposed_ts)
+  VALUES (_owner_id, _other_owner_id, _shared_emails, _shared_gh_login, now()) on conflict (owner_id, other_owner_id) do
+  UPDATE set shared_emails = excluded.shared_emails, shared_gh_login = excluded.shared_gh_login
+  WHERE t_identity_merge.merged_ts IS NULL;
+
+  RETURN _is_new;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_propose_identity_merge(varchar,varchar,varchar[],varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_propose_identity_merge
+
+/*** TESTING ***/
+-- select stm_propose_identity_merge('7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7','9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK',array['max@onebro.me'],null)
+
+-- select * from t_identity_merge limit 100
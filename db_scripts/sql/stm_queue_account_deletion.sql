@@ -0,0 +1,30 @@
+-- Adds a deletion request for the account of _owner_id and returns its deletion_id.
+-- Returns the deletion_id of the pending request for the same account if there is one.
+CREATE OR REPLACE FUNCTION stm_queue_account_deletion(_deletion_id varchar, _owner_id varchar, _request_sig varchar)
+RETURNS varchar AS $$ --
+DECLARE
+  _canonical_owner_id varchar;
+  _pending_deletion_id varchar;
+BEGIN --
+  _canonical_owner_id := stm_get_canonical_owner_id(_owner_id);
+
+  -- there is no point deleting the same account twice
+  select deletion_id into _pending_deletion_id from t_deletion_queue
+    where owner_id = _canonical_owner_id and project_id is null and completed_ts is null limit 1;
+  IF _pending_deletion_id IS NOT NULL THEN
+    RETURN _pending_deletion_id;
+  END IF;
+
+  INSERT INTO t_deletion_queue (deletion_id, owner_id, requested_by_owner_id, request_sig, added_ts)
+  VALUES (_deletion_id, _canonical_owner_id, _owner_id, _request_sig, now());
+
+  RETURN _deletion_id;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_queue_account_deletion(varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_queue_account_deletion
+
+/*** TESTING ***/
+-- select stm_queue_account_deletion('Wgx98Rbi8nQuL9ddn3mTk1','9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','sig')
+
+-- select * from t_deletion_queue limit 100
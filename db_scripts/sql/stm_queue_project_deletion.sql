@@ -0,0 +1,39 @@
+-- Adds a deletion request for a single project of the account of _owner_id and returns its deletion_id.
+-- Returns the deletion_id of the pending request for the same project if there is one.
+-- Returns NULL if the project does not belong to the account.
+CREATE OR REPLACE FUNCTION stm_queue_project_deletion(_deletion_id varchar, _owner_id varchar, _project_id varchar, _request_sig varchar)
+RETURNS varchar AS $$ --
+DECLARE
+  _canonical_owner_id varchar;
+  _pending_deletion_id varchar;
+BEGIN --
+  _canonical_owner_id := stm_get_canonical_owner_id(_owner_id);
+
+  -- only projects with commits owned by one of the keys of the account or projects without commits
+  -- assigned to the account via t_project_fingerprint can be deleted
+  IF NOT EXISTS (select 1 from t_commit_ownership where project_id = _project_id
+      and owner_id in (select * from stm_get_linked_keys(_canonical_owner_id)))
+    AND NOT EXISTS (select 1 from t_project_fingerprint where project_id = _project_id
+      and owner_id = _canonical_owner_id) THEN
+    RETURN NULL;
+  END IF;
+
+  select deletion_id into _pending_deletion_id from t_deletion_queue
+    where owner_id = _canonical_owner_id and project_id = _project_id and completed_ts is null limit 1;
+  IF _pending_deletion_id IS NOT NULL THEN
+    RETURN _pending_deletion_id;
+  END IF;
+
+  INSERT INTO t_deletion_queue (deletion_id, owner_id, project_id, requested_by_owner_id, request_sig, added_ts)
+  VALUES (_deletion_id, _canonical_owner_id, _project_id, _owner_id, _request_sig, now());
+
+  RETURN _deletion_id;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_queue_project_deletion(varchar,varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_queue_project_deletion
+
+/*** TESTING ***/
+-- select stm_queue_project_deletion('Wgx98Rbi8nQuL9ddn3mTk1','9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','FZ8zezMFji6VXcWEDxckwy','sig')
+
+-- select * from t_deletion_queue where project_id is not null limit 100
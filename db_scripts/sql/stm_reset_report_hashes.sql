@@ -0,0 +1,23 @@
+-- Deletes the hashes of the latest processed reports of the account _owner_id belongs to and returns the deleted hashes.
+-- Used before replaying stored reports through the router, otherwise the router skips them as unchanged.
+-- The hashes can be put back with stm_restore_report_hashes if none of the reports could be replayed.
+CREATE OR REPLACE FUNCTION stm_reset_report_hashes(_owner_id varchar)
+RETURNS TABLE (project_id varchar, report_hash varchar) AS $$ --
+BEGIN --
+--
+RETURN QUERY
+with deleted as (
+  DELETE FROM t_report_hash h WHERE h.owner_id = stm_get_canonical_owner_id(_owner_id)
+  RETURNING h.project_id, h.report_hash
+)
+select d.project_id::varchar, d.report_hash::varchar from deleted d;
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_reset_report_hashes(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_reset_report_hashes
+
+/*** TESTING ***/
+-- select * from stm_reset_report_hashes('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
+
+-- select * from t_report_hash where owner_id = '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK'
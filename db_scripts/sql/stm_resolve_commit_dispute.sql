@@ -0,0 +1,30 @@
+-- Sets the resolution of the dispute to `accepted` or `rejected` and queues up the dev for the profile
+-- to be regenerated with or without the project. Returns the number of disputes updated, 0 or 1.
+CREATE OR REPLACE FUNCTION stm_resolve_commit_dispute(_owner_id varchar, _project_id varchar, _resolution varchar)
+RETURNS bigint AS $$ --
+DECLARE
+  _cnt bigint;
+BEGIN --
+  IF _resolution NOT IN ('accepted', 'rejected') THEN
+    RAISE EXCEPTION 'Invalid resolution %', _resolution;
+  END IF;
+
+  UPDATE t_commit_dispute set resolution = _resolution, resolved_ts = now()
+  WHERE owner_id in (select * from stm_get_linked_keys(_owner_id)) and project_id = _project_id;
+  GET DIAGNOSTICS _cnt = ROW_COUNT;
+
+  IF _cnt > 0 THEN
+    UPDATE t_dev set last_submission_ts = now(), report_fail_counter = 0
+    WHERE owner_id = stm_get_canonical_owner_id(_owner_id);
+  END IF;
+
+  RETURN _cnt;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_resolve_commit_dispute(varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_resolve_commit_dispute
+
+/*** TESTING ***/
+-- select stm_resolve_commit_dispute('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','Wgx98Rbi8nQuL9ddn3mTk1','rejected')
+
+-- select * from t_commit_dispute where resolution is not null limit 100
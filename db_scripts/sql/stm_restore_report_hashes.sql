@@ -0,0 +1,20 @@
+-- Puts back the hashes deleted by stm_reset_report_hashes for the account _owner_id belongs to
+-- if none of its reports could be replayed. A hash stored by the router in the meantime is newer and is kept.
+-- _report_hashes must have a member for every _project_ids.
+CREATE OR REPLACE FUNCTION stm_restore_report_hashes(_owner_id varchar, _project_ids varchar[], _report_hashes varchar[])
+RETURNS void AS $$ --
+BEGIN --
+--
+INSERT INTO t_report_hash (owner_id, project_id, report_hash)
+select stm_get_canonical_owner_id(_owner_id), * from unnest(_project_ids, _report_hashes)
+on conflict (owner_id, project_id) do nothing;
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_restore_report_hashes(varchar,varchar[],varchar[]) to public;
+-- DROP FUNCTION IF EXISTS stm_restore_report_hashes
+
+/*** TESTING ***/
+-- select stm_restore_report_hashes('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK', array['Wgx98Rbi8nQuL9ddn3mTk1'], array['GKot5hBsd81kMupNCXHaqbhv3huEbxAFMLnpcX2hniwn'])
+
+-- select * from t_report_hash where owner_id = '9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK'
@@ -0,0 +1,21 @@
+-- Stores the confirmation ID sent to the email, replacing any previous one.
+-- _error is the reason the email could not be delivered and will never be delivered as is, e.g. an invalid address.
+-- The ID is stored either way, so that an undeliverable email is not retried until a new link is due.
+CREATE OR REPLACE FUNCTION stm_set_email_confirmation_id(_owner_id varchar, _email varchar, _confirmation_id varchar, _error varchar)
+RETURNS void AS $$ --
+BEGIN --
+--
+UPDATE t_email_ownership set confirmation_id = _confirmation_id, confirmation_error = _error
+where owner_id = _owner_id and email = _email and confirmed_ts is null;
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_set_email_confirmation_id(varchar,varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_set_email_confirmation_id(varchar,varchar,varchar)
+-- DROP FUNCTION IF EXISTS stm_set_email_confirmation_id
+
+/*** TESTING ***/
+-- select stm_set_email_confirmation_id('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','max@onebro.me','1627380297_Wgx98Rbi8nQuL9ddn3mTk1',null)
+-- select stm_set_email_confirmation_id('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','max@onebro','1627380297_Wgx98Rbi8nQuL9ddn3mTk1','Invalid recipient address')
+
+-- select email, confirmation_id, confirmation_error from t_email_ownership where confirmation_error is not null
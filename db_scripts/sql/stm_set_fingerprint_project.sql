@@ -0,0 +1,18 @@
+-- Assigns the fingerprint of the owner to the project, replacing the previous assignment.
+CREATE OR REPLACE FUNCTION stm_set_fingerprint_project(_owner_id varchar, _fingerprint varchar, _project_id varchar)
+RETURNS void AS $$ --
+BEGIN --
+--
+INSERT INTO t_project_fingerprint (owner_id, fingerprint, project_id, updated_ts)
+VALUES (_owner_id, _fingerprint, _project_id, now()) on conflict (owner_id, fingerprint) do
+UPDATE set project_id = excluded.project_id, updated_ts = excluded.updated_ts;
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_set_fingerprint_project(varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_set_fingerprint_project
+
+/*** TESTING ***/
+-- select stm_set_fingerprint_project('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','8sHzVnWSxMgfBRc3oNJ6udnEdbSYDCp3Kqn94oejDnWq','Wgx98Rbi8nQuL9ddn3mTk1')
+
+-- select * from t_project_fingerprint limit 100
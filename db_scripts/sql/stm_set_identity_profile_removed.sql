@@ -0,0 +1,13 @@
+-- Marks the ES profile of the merged account as removed after a completed merge.
+CREATE OR REPLACE FUNCTION stm_set_identity_profile_removed(_owner_id varchar, _other_owner_id varchar)
+RETURNS void AS $$ --
+BEGIN --
+  UPDATE t_identity_merge set profile_removed_ts = now()
+  WHERE owner_id = _owner_id and other_owner_id = _other_owner_id and merged_ts IS NOT NULL;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_set_identity_profile_removed(varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_set_identity_profile_removed
+
+/*** TESTING ***/
+-- select stm_set_identity_profile_removed('7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7','9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK')
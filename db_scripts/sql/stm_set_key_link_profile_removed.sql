@@ -0,0 +1,13 @@
+-- Marks the ES profile of the linked key _owner_id as removed, so that `dev_queue` flow does not delete it again.
+CREATE OR REPLACE FUNCTION stm_set_key_link_profile_removed(_owner_id varchar)
+RETURNS void AS $$ --
+BEGIN --
+  UPDATE t_key_link set profile_removed_ts = now() WHERE owner_id = _owner_id;
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_set_key_link_profile_removed(varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_set_key_link_profile_removed
+
+/*** TESTING ***/
+-- select stm_set_key_link_profile_removed('EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB')
+-- select * from t_key_link where profile_removed_ts IS NULL limit 100
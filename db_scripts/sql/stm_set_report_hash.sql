@@ -0,0 +1,18 @@
+-- Stores the hash of the latest processed report for the owner/project, replacing the previous one.
+CREATE OR REPLACE FUNCTION stm_set_report_hash(_owner_id varchar, _project_id varchar, _report_hash varchar)
+RETURNS void AS $$ --
+BEGIN --
+--
+INSERT INTO t_report_hash (owner_id, project_id, report_hash, added_ts)
+VALUES (_owner_id, _project_id, _report_hash, now()) on conflict (owner_id, project_id) do
+UPDATE set report_hash = excluded.report_hash, added_ts = excluded.added_ts;
+--
+END --
+$$ COST 100 VOLATILE LANGUAGE plpgsql SECURITY DEFINER;
+GRANT EXECUTE ON FUNCTION stm_set_report_hash(varchar,varchar,varchar) to public;
+-- DROP FUNCTION IF EXISTS stm_set_report_hash
+
+/*** TESTING ***/
+-- select stm_set_report_hash('9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK','Wgx98Rbi8nQuL9ddn3mTk1','GKot5hBsd81kMupNCXHaqbhv3huEbxAFMLnpcX2hniwn')
+
+-- select * from t_report_hash limit 100
@@ -0,0 +1,110 @@
+use crate::auth::authenticate;
+use crate::config::Config;
+use crate::handler::{gw_response, ApiGatewayRequestHeaders, RequestError, ERROR_500_MSG};
+use crate::postgres::DeletionQueue;
+use lambda_runtime::Error;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+/// The URL path for account deletion requests, lower case without the trailing `/`.
+pub(crate) const PATH: &str = "/delete_account";
+
+/// A request to delete all data of the account the signing key belongs to, including all linked keys.
+/// The key is repeated in the body as a confirmation that the app meant to delete this particular account.
+#[derive(Deserialize, Debug)]
+struct AccountDeletionRequest {
+    /// Must be the same as the key that signed the request, base58 encoded.
+    owner_id: String,
+}
+
+/// Validates an account deletion request and adds it to the deletion queue.
+/// The data is purged later by `deletion` flow of stm_inbox_flows.
+pub(crate) async fn delete_account(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    body: Option<Vec<u8>>,
+) -> Result<Value, Error> {
+    let body = body.unwrap_or_default();
+
+    // check the signature, the timestamp and the nonce of the request
+    let pub_key_bs58 = match authenticate(config, headers, &body).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Err(e) = validate_deletion_request(&body, &pub_key_bs58) {
+        return e.into_response();
+    }
+
+    // the signature was already validated, so it's safe to store it for audit
+    let request_sig = headers.stackmuncher_sig.clone().unwrap_or_default();
+    let deletion_id = bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string();
+
+    let deletion_id =
+        match DeletionQueue::queue_account_deletion(&config.pg_client, &deletion_id, &pub_key_bs58, &request_sig).await
+        {
+            Ok(v) => v,
+            Err(_) => return gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+        };
+
+    info!("Account deletion {} queued for {}", deletion_id, pub_key_bs58);
+
+    gw_response(
+        Some(format!(
+            "Deletion request {} accepted. All reports, profile data and linked keys of this account will be deleted shortly.",
+            deletion_id
+        )),
+        202,
+    )
+}
+
+/// Checks that the body is a deletion request for the account of the key that signed it.
+fn validate_deletion_request(body: &[u8], pub_key_bs58: &str) -> Result<(), RequestError> {
+    let deletion = match serde_json::from_slice::<AccountDeletionRequest>(body) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid account deletion request: {}", e);
+            return Err(RequestError::new(
+                400,
+                &format!("stackmuncher.com rejected the account deletion request: {}", e),
+            ));
+        }
+    };
+
+    if deletion.owner_id != pub_key_bs58 {
+        warn!("Account deletion for {} signed by {}", deletion.owner_id, pub_key_bs58);
+        return Err(RequestError::new(403, "stackmuncher.com rejected the account deletion request: `owner_id` must be the same as the key that signed the request."));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn validate_deletion_request_test() {
+    let owner_id = "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK";
+    let status_code = |body: &str| {
+        validate_deletion_request(body.as_bytes(), owner_id)
+            .err()
+            .map(|e| e.status_code)
+    };
+
+    assert_eq!(status_code(r#"{"owner_id":"9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"}"#), None);
+    // someone else's account
+    assert_eq!(status_code(r#"{"owner_id":"7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7"}"#), Some(403));
+    // the key is compared as is
+    assert_eq!(status_code(r#"{"owner_id":" 9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"}"#), Some(403));
+    assert_eq!(status_code(r#"{"owner_id":""}"#), Some(403));
+    // other fields are ignored, e.g. from a newer version of the app
+    assert_eq!(
+        status_code(r#"{"owner_id":"9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK","reason":"moving on"}"#),
+        None
+    );
+    // not a deletion request
+    assert_eq!(status_code(r#"{"project_id":"Wgx98Rbi8nQuL9ddn3mTk1"}"#), Some(400));
+    assert_eq!(status_code(r#"{"owner_id":null}"#), Some(400));
+    assert_eq!(status_code(r#"{"owner_id":["9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"]}"#), Some(400));
+    assert_eq!(status_code(r#""9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK""#), Some(400));
+    assert_eq!(status_code(r#"{"owner_id":"9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK""#), Some(400));
+    assert_eq!(status_code(""), Some(400));
+}
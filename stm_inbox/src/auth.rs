@@ -0,0 +1,263 @@
+use crate::config::Config;
+use crate::handler::{ApiGatewayRequestHeaders, RequestError, ERROR_500_MSG};
+use crate::postgres::Nonce;
+use crate::rate_limit;
+use chrono::Utc;
+use ring::signature;
+use stm_shared::validate_owner_id;
+use tracing::{error, info, warn};
+
+/// Nonces shorter than this are too easy to repeat by chance.
+const MIN_NONCE_LEN: usize = 8;
+/// Longer nonces are not needed and would bloat the DB.
+const MAX_NONCE_LEN: usize = 64;
+/// Returned with 401 if the signature cannot be decoded or does not match the key and the content.
+const INVALID_SIGNATURE_MSG: &str = "stackmuncher.com rejected the request: invalid signature. The request must be signed by the key from `stackmuncher_key` header.";
+
+/// Validates the signature, the timestamp and the nonce of the request and returns the public key of the sender
+/// as base58 string (owner_id) if the request is genuine and was not seen before.
+/// * `content`: the payload covered by the signature, e.g. the body of the request
+///
+/// The signature must cover `timestamp + "\n" + nonce + "\n" + content`. See `build_signed_message()`.
+/// Genuine requests are counted towards the rate limit of the key before the nonce is stored.
+pub(crate) async fn authenticate(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    content: &[u8],
+) -> Result<String, RequestError> {
+    authenticate_request(config, headers, content, false).await
+}
+
+/// Same as `authenticate`, but for read-only requests, e.g. status polls. They are counted towards a separate
+/// rate limit of the key, so that polling does not use up the quota for submissions.
+pub(crate) async fn authenticate_query(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    content: &[u8],
+) -> Result<String, RequestError> {
+    authenticate_request(config, headers, content, true).await
+}
+
+/// Does the work for `authenticate` and `authenticate_query`.
+/// * `is_query`: TRUE to count the request towards the limit for read-only requests
+async fn authenticate_request(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    content: &[u8],
+    is_query: bool,
+) -> Result<String, RequestError> {
+    // these 2 headers are required no matter what
+    let (pub_key_bs58, signature_bs58) = match (&headers.stackmuncher_key, &headers.stackmuncher_sig) {
+        (Some(key), Some(sig)) => (key.clone(), sig.clone()),
+        _ => {
+            error!(
+                "Missing a header. Key: {:?}, Sig: {:?}",
+                headers.stackmuncher_key, headers.stackmuncher_sig
+            );
+            return Err(RequestError::new(500, "stackmuncher.com failed to process the report: missing required HTTP headers. If you have not modified the source code it's a bug at stackmuncher.com end."));
+        }
+    };
+
+    // the timestamp and the nonce protect against replay attacks and are required as well
+    let (ts, nonce) = match (&headers.stackmuncher_ts, &headers.stackmuncher_nonce) {
+        (Some(ts), Some(nonce)) => (ts.trim().to_owned(), nonce.trim().to_owned()),
+        _ => {
+            error!(
+                "Missing a header. Ts: {:?}, Nonce: {:?}",
+                headers.stackmuncher_ts, headers.stackmuncher_nonce
+            );
+            return Err(RequestError::new(400, "stackmuncher.com rejected the request: missing timestamp or nonce HTTP headers. Please, update the app to the latest version."));
+        }
+    };
+
+    info!("Request for pub key: {}", pub_key_bs58);
+
+    // convert the public key from base58 into bytes
+    if !validate_owner_id(&pub_key_bs58) {
+        error!("Invalid pub key length: {}", pub_key_bs58.len());
+        return Err(RequestError::new(403, "Invalid public key length. Expecting 32 bytes as base58."));
+    }
+
+    let pub_key = match bs58::decode(pub_key_bs58.clone()).into_vec() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to decode the stackmuncher_key from based58 due to: {}", e);
+            return Err(RequestError::new(403, "Failed to decode public key from based58"));
+        }
+    };
+
+    // convert the signature from base58 into bytes
+    let signature = match bs58::decode(signature_bs58).into_vec() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to decode the stackmuncher_sig from based58 due to: {}", e);
+            return Err(RequestError::new(401, INVALID_SIGNATURE_MSG));
+        }
+    };
+
+    // the timestamp is checked before the signature because it is cheaper
+    let ts_epoch = match ts.parse::<i64>() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid stackmuncher_ts {}: {}", ts, e);
+            return Err(RequestError::new(400, "stackmuncher.com rejected the request: the timestamp must be a number of seconds since the Unix epoch."));
+        }
+    };
+    let now = Utc::now().timestamp();
+    if !is_within_ts_window(ts_epoch, now, config.sig_ts_window) {
+        // a difference that does not fit into i64 is reported as the max u64
+        let ts_diff = ts_diff_secs(ts_epoch, now).unwrap_or(u64::MAX);
+        warn!("Request ts {} is {}s away from the server time", ts_epoch, ts_diff);
+        return Err(RequestError::new(401, &format!("stackmuncher.com rejected the request: its timestamp is {}s away from the server time. The max allowed difference is {}s. Check the clock on your machine.", ts_diff, config.sig_ts_window)));
+    }
+
+    if !validate_nonce(&nonce) {
+        error!("Invalid nonce: {}", nonce);
+        return Err(RequestError::new(
+            400,
+            &format!(
+                "stackmuncher.com rejected the request: the nonce must be {}-{} chars of [a-zA-Z0-9_-].",
+                MIN_NONCE_LEN, MAX_NONCE_LEN
+            ),
+        ));
+    }
+
+    // validate the signature
+    let pub_key = signature::UnparsedPublicKey::new(&signature::ED25519, pub_key);
+    match pub_key.verify(&build_signed_message(&ts, &nonce, content), &signature) {
+        Ok(_) => {
+            info!("Signature OK");
+        }
+        Err(e) => {
+            error!("Invalid signature: {}", e);
+            return Err(RequestError::new(401, INVALID_SIGNATURE_MSG));
+        }
+    };
+
+    // the key is only rate limited after the signature was validated, otherwise anyone could exhaust it
+    // it is checked before the nonce is stored, so that a request rejected with 429 can be retried with the same nonce
+    if is_query {
+        rate_limit::check_key_queries(config, &pub_key_bs58).await?;
+    } else {
+        rate_limit::check_key(config, &pub_key_bs58).await?;
+    }
+
+    // the nonce is only stored after the signature was validated, otherwise anyone could burn someone else's nonces
+    // keep nonces for longer than the ts window to make sure a replay is rejected by one check or the other
+    match Nonce::add_nonce(&config.pg_client, &pub_key_bs58, &nonce, config.sig_ts_window * 2).await {
+        Ok(true) => Ok(pub_key_bs58),
+        Ok(false) => {
+            warn!("Replayed nonce {} for {}", nonce, pub_key_bs58);
+            Err(RequestError::new(409, "stackmuncher.com rejected the request: this nonce was already used. It may be a replay of an earlier request."))
+        }
+        Err(_) => Err(RequestError::new(500, ERROR_500_MSG)),
+    }
+}
+
+/// Returns TRUE if `signature_bs58` is a valid ED25519 signature of `msg` by `pub_key_bs58`.
+/// Both, the key and the signature are expected to be base58 encoded. Any decoding error is logged and returns FALSE.
+pub(crate) fn verify_detached_signature(pub_key_bs58: &str, signature_bs58: &str, msg: &[u8]) -> bool {
+    let pub_key = match bs58::decode(pub_key_bs58).into_vec() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Failed to decode pub key {} from base58 due to: {}", pub_key_bs58, e);
+            return false;
+        }
+    };
+
+    let signature = match bs58::decode(signature_bs58).into_vec() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Failed to decode signature {} from base58 due to: {}", signature_bs58, e);
+            return false;
+        }
+    };
+
+    match signature::UnparsedPublicKey::new(&signature::ED25519, pub_key).verify(msg, &signature) {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Invalid signature by {}: {}", pub_key_bs58, e);
+            false
+        }
+    }
+}
+
+/// Returns the message the app is expected to sign: `timestamp + "\n" + nonce + "\n" + content`.
+fn build_signed_message(ts: &str, nonce: &str, content: &[u8]) -> Vec<u8> {
+    let mut msg: Vec<u8> = Vec::with_capacity(ts.len() + nonce.len() + content.len() + 2);
+    msg.extend_from_slice(ts.as_bytes());
+    msg.push(b'\n');
+    msg.extend_from_slice(nonce.as_bytes());
+    msg.push(b'\n');
+    msg.extend_from_slice(content);
+
+    msg
+}
+
+/// Returns TRUE if the request timestamp is no further than `window_secs` from `now` in either direction.
+fn is_within_ts_window(ts_epoch: i64, now: i64, window_secs: i64) -> bool {
+    window_secs >= 0
+        && ts_diff_secs(ts_epoch, now)
+            .map(|d| d <= window_secs as u64)
+            .unwrap_or(false)
+}
+
+/// Returns the number of seconds between the request timestamp and `now` or None if it overflows.
+/// The timestamp comes from the client and can be any i64.
+fn ts_diff_secs(ts_epoch: i64, now: i64) -> Option<u64> {
+    now.checked_sub(ts_epoch).map(|d| d.unsigned_abs())
+}
+
+/// Returns TRUE if the nonce has the right length and contains only URL-safe chars.
+fn validate_nonce(nonce: &str) -> bool {
+    nonce.len() >= MIN_NONCE_LEN
+        && nonce.len() <= MAX_NONCE_LEN
+        && nonce.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[test]
+fn build_signed_message_test() {
+    assert_eq!(
+        build_signed_message("1621680890", "abcdefgh", b"{}"),
+        b"1621680890\nabcdefgh\n{}".to_vec()
+    );
+    // GET requests sign an ID or the key instead of a body, which may be empty
+    assert_eq!(build_signed_message("1621680890", "abcdefgh", b""), b"1621680890\nabcdefgh\n".to_vec());
+    // the content is signed as is, including any line breaks
+    assert_eq!(build_signed_message("1", "abcdefgh", b"a\nb"), b"1\nabcdefgh\na\nb".to_vec());
+}
+
+#[test]
+fn is_within_ts_window_test() {
+    let now = 1621680890;
+    assert!(is_within_ts_window(now, now, 300));
+    assert!(is_within_ts_window(now - 300, now, 300));
+    assert!(is_within_ts_window(now + 300, now, 300));
+    assert!(!is_within_ts_window(now - 301, now, 300));
+    assert!(!is_within_ts_window(now + 301, now, 300));
+    assert!(!is_within_ts_window(0, now, 300));
+    // client-supplied timestamps must not overflow or wrap around into the window
+    assert!(!is_within_ts_window(i64::MIN, now, 300));
+    assert!(!is_within_ts_window(i64::MAX, now, 300));
+    assert!(!is_within_ts_window(now + i64::MIN, now, 300));
+    assert!(!is_within_ts_window(now.wrapping_sub(i64::MIN), now, 300));
+    assert!(!is_within_ts_window(-1, now, 300));
+    assert_eq!(ts_diff_secs(i64::MIN, now), None);
+    assert_eq!(ts_diff_secs(now + i64::MIN, now), None);
+    assert_eq!(ts_diff_secs(now - 5, now), Some(5));
+    assert_eq!(ts_diff_secs(now + 5, now), Some(5));
+}
+
+#[test]
+fn validate_nonce_test() {
+    assert!(validate_nonce("abcdefgh"));
+    assert!(validate_nonce("Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(validate_nonce("a-b_c-d_e"));
+    assert!(validate_nonce(&"a".repeat(MAX_NONCE_LEN)));
+    assert!(!validate_nonce(&"a".repeat(MIN_NONCE_LEN - 1)));
+    assert!(!validate_nonce(&"a".repeat(MAX_NONCE_LEN + 1)));
+    assert!(!validate_nonce(""));
+    assert!(!validate_nonce("abcd efgh"));
+    assert!(!validate_nonce("abcd/efgh"));
+    assert!(!validate_nonce("abcdefgé"));
+}
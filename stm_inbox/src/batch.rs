@@ -0,0 +1,222 @@
+use crate::auth::authenticate;
+use crate::config::Config;
+use crate::handler::{
+    gw_json_response, store_report, ApiGatewayRequestHeaders, ReportRejection, STORAGE_UNAVAILABLE_MSG,
+};
+use crate::rate_limit;
+use crate::report_validation::{ValidationError, ValidationResponse};
+use crate::submission_status::SubmissionReceipt;
+use lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info};
+
+/// The URL path for multi-report submissions, lower case without the trailing `/`.
+pub(crate) const PATH: &str = "/batch";
+
+/// The max number of reports in a single batch. Larger batches are rejected as a whole.
+const MAX_BATCH_REPORTS: usize = 50;
+/// The max length of the manifest line in bytes, enough for `MAX_BATCH_REPORTS` entries with any valid size.
+const MAX_MANIFEST_SIZE: usize = 4096;
+
+/// The first line of the envelope. Lists the sizes of the gzip parts that follow it in the same order.
+/// E.g. `{"reports":[{"size":1234},{"size":567}]}`
+#[derive(Deserialize, Debug)]
+struct BatchManifest {
+    reports: Vec<BatchManifestEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchManifestEntry {
+    /// The length of the gzipped report in bytes
+    size: usize,
+}
+
+/// The outcome of processing a single report from the batch.
+#[derive(Serialize, Debug)]
+struct BatchReportResult {
+    /// The position of the report in the manifest, starting from 0
+    index: usize,
+    /// The same HTTP status code as for a single report submission, e.g. 200, 400, 413 or 503
+    status_code: u32,
+    /// Present if the report was accepted
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    receipt: Option<SubmissionReceipt>,
+    /// Present if the report was rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Validation problems, if any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<ValidationError>,
+    /// The number of seconds to wait before resubmitting the report if it was rejected with 429
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct BatchResponse {
+    reports: Vec<BatchReportResult>,
+}
+
+/// Accepts multiple gzipped reports in a single request signed once over the entire envelope:
+/// a JSON manifest terminated by `\n`, followed by the gzip parts concatenated in the order of the manifest.
+/// Every part is validated and stored as a separate queue object with its own submission ID.
+/// Every report counts towards the rate limit of the key, same as if it was submitted on its own.
+/// Returns 200 with a result per report, even if some of them were rejected.
+pub(crate) async fn submit_batch(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    body: Option<Vec<u8>>,
+) -> Result<Value, Error> {
+    let body = body.unwrap_or_default();
+    info!("Batch len: {}", body.len());
+
+    // the signature covers the manifest and all the parts, so there is no need to check each report separately
+    let pub_key_bs58 = match authenticate(config, headers, &body).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let parts = match split_envelope(&body, config.max_compressed_size) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid batch envelope: {}", e);
+            return gw_json_response(&ValidationResponse::new(vec![ValidationError::new("manifest", None, &e)]), 400);
+        }
+    };
+
+    info!("Reports in batch: {}", parts.len());
+
+    let mut results: Vec<BatchReportResult> = Vec::with_capacity(parts.len());
+    // the first report was counted by `authenticate`, the rest are rejected once the limit is reached
+    let mut rate_limit_error: Option<(String, Option<i64>)> = None;
+    for (index, part) in parts.into_iter().enumerate() {
+        if index > 0 && rate_limit_error.is_none() {
+            if let Err(e) = rate_limit::check_key(config, &pub_key_bs58).await {
+                rate_limit_error = Some((e.msg, e.retry_after));
+            }
+        }
+
+        if let Some((msg, retry_after)) = rate_limit_error.as_ref() {
+            info!("Batch report {}: 429", index);
+            results.push(BatchReportResult {
+                index,
+                status_code: 429,
+                receipt: None,
+                message: Some(msg.clone()),
+                errors: Vec::new(),
+                retry_after: *retry_after,
+            });
+            continue;
+        }
+
+        let result = match store_report(config, &pub_key_bs58, &part).await {
+            Ok(receipt) => BatchReportResult {
+                index,
+                status_code: 200,
+                receipt: Some(receipt),
+                message: None,
+                errors: Vec::new(),
+                retry_after: None,
+            },
+            Err(ReportRejection::Invalid(errors)) => BatchReportResult {
+                index,
+                status_code: 400,
+                receipt: None,
+                message: Some(format!("stackmuncher.com rejected the report: {} problem(s) found.", errors.len())),
+                errors,
+                retry_after: None,
+            },
+            Err(ReportRejection::TooLarge(e)) => BatchReportResult {
+                index,
+                status_code: 413,
+                receipt: None,
+                message: Some(format!("stackmuncher.com rejected the report: {}.", e)),
+                errors: Vec::new(),
+                retry_after: None,
+            },
+            Err(ReportRejection::Unavailable) => BatchReportResult {
+                index,
+                status_code: 503,
+                receipt: None,
+                message: Some(STORAGE_UNAVAILABLE_MSG.to_owned()),
+                errors: Vec::new(),
+                retry_after: None,
+            },
+        };
+        info!("Batch report {}: {}", index, result.status_code);
+        results.push(result);
+    }
+
+    gw_json_response(&BatchResponse { reports: results }, 200)
+}
+
+/// Returns the max size of an envelope with `MAX_BATCH_REPORTS` parts of `max_part_size` bytes each.
+pub(crate) fn max_envelope_size(max_part_size: usize) -> usize {
+    max_part_size
+        .saturating_mul(MAX_BATCH_REPORTS)
+        .saturating_add(MAX_MANIFEST_SIZE)
+}
+
+/// Splits the envelope into the gzip parts listed in the manifest.
+/// Returns a user-friendly explanation if the envelope does not match the manifest.
+fn split_envelope(body: &[u8], max_part_size: usize) -> Result<Vec<Vec<u8>>, String> {
+    let manifest_len = match body.iter().position(|b| *b == b'\n') {
+        Some(v) => v,
+        None => return Err("the manifest must be terminated by a new line".to_owned()),
+    };
+
+    let manifest = match serde_json::from_slice::<BatchManifest>(&body[..manifest_len]) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("not a valid manifest: {}", e)),
+    };
+
+    if manifest.reports.is_empty() || manifest.reports.len() > MAX_BATCH_REPORTS {
+        return Err(format!("the batch must contain from 1 to {} reports", MAX_BATCH_REPORTS));
+    }
+
+    // the sizes are checked before slicing to avoid overflows and out-of-bounds panics
+    let payload = &body[manifest_len + 1..];
+    let mut total_size = 0usize;
+    for entry in manifest.reports.iter() {
+        if entry.size == 0 || entry.size > max_part_size {
+            return Err(format!("report size must be from 1 to {} bytes", max_part_size));
+        }
+        total_size += entry.size;
+    }
+    if total_size != payload.len() {
+        return Err(format!(
+            "the manifest lists {} bytes of reports, but the envelope has {}",
+            total_size,
+            payload.len()
+        ));
+    }
+
+    let mut parts: Vec<Vec<u8>> = Vec::with_capacity(manifest.reports.len());
+    let mut offset = 0usize;
+    for entry in manifest.reports.iter() {
+        parts.push(payload[offset..offset + entry.size].to_vec());
+        offset += entry.size;
+    }
+
+    Ok(parts)
+}
+
+#[test]
+fn split_envelope_test() {
+    let mut envelope = br#"{"reports":[{"size":3},{"size":2}]}"#.to_vec();
+    envelope.push(b'\n');
+    envelope.extend_from_slice(b"abcde");
+
+    let parts = split_envelope(&envelope, 10).expect("valid envelope");
+    assert_eq!(parts, vec![b"abc".to_vec(), b"de".to_vec()]);
+
+    // sizes do not add up
+    assert!(split_envelope(&envelope[..envelope.len() - 1], 10).is_err());
+    // a part is larger than allowed
+    assert!(split_envelope(&envelope, 2).is_err());
+    // no manifest terminator
+    assert!(split_envelope(b"abcde", 10).is_err());
+    // empty manifest
+    assert!(split_envelope(b"{\"reports\":[]}\n", 10).is_err());
+}
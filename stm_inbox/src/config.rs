@@ -1,10 +1,15 @@
+use crate::postgres::get_pg_client;
 use hyper_rustls::HttpsConnector;
+use regex::Regex;
 use rusoto_core::credential::DefaultCredentialsProvider;
 use rusoto_core::HttpClient;
 use rusoto_core::Region;
 use rusoto_s3::S3Client;
+use rusoto_sqs::SqsClient;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
+use stm_shared::gzip::{MAX_COMPRESSED_REPORT_SIZE_DEFAULT, MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT};
 
 /// Name of a required env variable (STM_INBOX_S3_REGION)
 /// E.g. `us-east-1`
@@ -16,9 +21,76 @@ pub const S3_BUCKET_ENV: &str = "STM_INBOX_S3_BUCKET";
 /// The storage tree with any additional folders is placed under this prefix.
 /// E.g. `queue`, leading/trailing `/` are removed
 pub const S3_PREFIX_ENV: &str = "STM_INBOX_S3_PREFIX";
+/// Name of an optional env variable (STM_INBOX_STORAGE_DIR) with a local directory for storing reports instead of S3.
+/// S3 env vars are optional if this one is set. Intended for local debugging and self-hosting with `--serve`.
+/// E.g. `/var/stm/inbox`
+pub const STORAGE_DIR_ENV: &str = "STM_INBOX_STORAGE_DIR";
+/// The full connection string for the Postgres DB (STM_INBOX_PG_CON_STRING)
+/// E.g. `host=stm-prod.xxxxxxxxx.us-east-1.rds.amazonaws.com dbname=aaa_bbb user=uuu_vvv password='*#blA()Bla' connect_timeout=15`
+pub const PG_CONN_STR: &str = "STM_INBOX_PG_CON_STRING";
+/// Name of an optional env variable (STM_INBOX_SIG_TS_WINDOW) with the max allowed difference in seconds
+/// between the request timestamp and the server time. Defaults to `SIG_TS_WINDOW_DEFAULT`.
+/// E.g. `300`
+pub const SIG_TS_WINDOW_ENV: &str = "STM_INBOX_SIG_TS_WINDOW";
+/// The default value for `SIG_TS_WINDOW_ENV` in seconds.
+const SIG_TS_WINDOW_DEFAULT: i64 = 300;
+/// Name of an optional env variable (STM_INBOX_RATE_LIMIT_WINDOW) with the length of the sliding window
+/// for rate limiting in seconds. Defaults to `RATE_LIMIT_WINDOW_DEFAULT`.
+/// E.g. `3600`
+pub const RATE_LIMIT_WINDOW_ENV: &str = "STM_INBOX_RATE_LIMIT_WINDOW";
+/// The default value for `RATE_LIMIT_WINDOW_ENV` in seconds.
+const RATE_LIMIT_WINDOW_DEFAULT: i64 = 3600;
+/// Name of an optional env variable (STM_INBOX_RATE_LIMIT_PER_KEY) with the max number of requests
+/// from the same public key within the rate limit window. `0` disables the limit. Defaults to `RATE_LIMIT_PER_KEY_DEFAULT`.
+/// E.g. `60`
+pub const RATE_LIMIT_PER_KEY_ENV: &str = "STM_INBOX_RATE_LIMIT_PER_KEY";
+/// The default value for `RATE_LIMIT_PER_KEY_ENV`.
+const RATE_LIMIT_PER_KEY_DEFAULT: i64 = 60;
+/// Name of an optional env variable (STM_INBOX_RATE_LIMIT_QUERIES_PER_KEY) with the max number of read-only requests,
+/// e.g. status polls, from the same public key within the rate limit window. They do not count towards `RATE_LIMIT_PER_KEY_ENV`.
+/// `0` disables the limit. Defaults to `RATE_LIMIT_QUERIES_PER_KEY_DEFAULT`.
+/// E.g. `600`
+pub const RATE_LIMIT_QUERIES_PER_KEY_ENV: &str = "STM_INBOX_RATE_LIMIT_QUERIES_PER_KEY";
+/// The default value for `RATE_LIMIT_QUERIES_PER_KEY_ENV`.
+const RATE_LIMIT_QUERIES_PER_KEY_DEFAULT: i64 = 600;
+/// Name of an optional env variable (STM_INBOX_RATE_LIMIT_PER_IP) with the max number of requests
+/// from the same IP within the rate limit window. `0` disables the limit. Defaults to `RATE_LIMIT_PER_IP_DEFAULT`.
+/// E.g. `120`
+pub const RATE_LIMIT_PER_IP_ENV: &str = "STM_INBOX_RATE_LIMIT_PER_IP";
+/// The default value for `RATE_LIMIT_PER_IP_ENV`.
+const RATE_LIMIT_PER_IP_DEFAULT: i64 = 120;
+/// Name of an optional env variable (STM_INBOX_RATE_LIMIT_REVIEW_PERIOD) with the number of seconds rejected requests
+/// are kept in `t_rate_limit_log` for review. Accepted requests are purged once they fall out of the rate limit window.
+/// Defaults to `RATE_LIMIT_REVIEW_PERIOD_DEFAULT`.
+/// E.g. `604800`
+pub const RATE_LIMIT_REVIEW_PERIOD_ENV: &str = "STM_INBOX_RATE_LIMIT_REVIEW_PERIOD";
+/// The default value for `RATE_LIMIT_REVIEW_PERIOD_ENV` in seconds, 7 days.
+const RATE_LIMIT_REVIEW_PERIOD_DEFAULT: i64 = 604800;
+/// Name of an optional env variable (STM_INBOX_MAX_COMPRESSED_SIZE) with the max size of the gzipped report in bytes.
+/// Defaults to `stm_shared::gzip::MAX_COMPRESSED_REPORT_SIZE_DEFAULT`. Must be the same for stm_inbox and stm_inbox_router.
+/// E.g. `5242880`
+pub const MAX_COMPRESSED_SIZE_ENV: &str = "STM_INBOX_MAX_COMPRESSED_SIZE";
+/// Name of an optional env variable (STM_INBOX_MAX_DECOMPRESSED_SIZE) with the max size of the unzipped report in bytes.
+/// Defaults to `stm_shared::gzip::MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT`. Must be the same for stm_inbox and stm_inbox_router.
+/// E.g. `52428800`
+pub const MAX_DECOMPRESSED_SIZE_ENV: &str = "STM_INBOX_MAX_DECOMPRESSED_SIZE";
+/// Name of an optional env variable (STM_INBOX_SPOOL_SQS_URL) with the URL of an SQS queue for reports that could not
+/// be saved in S3. The spool is not used if the variable is not set. The queue is drained by `stm_inbox_flows -flow spool_drain`.
+/// E.g. `https://sqs.us-east-1.amazonaws.com/028534811986/stm_inbox_spool`
+pub const SPOOL_SQS_URL_ENV: &str = "STM_INBOX_SPOOL_SQS_URL";
+
+/// Where the validated reports are stored for the router to pick them up.
+pub enum Storage {
+    /// `s3_bucket` with `s3_client`
+    S3,
+    /// A local directory with the same folder structure as the S3 bucket, e.g. `/var/stm/inbox/queue/1621680890_...gz`
+    LocalDir(PathBuf),
+}
 
 /// A struct with all the config info passed around as a single param
 pub struct Config {
+    /// S3 or a local directory, depending on STM_INBOX_STORAGE_DIR env var
+    pub storage: Storage,
     /// The name of the bucket for storing contributor reports.
     /// E.g. `stm-subs-j5awwhv9pb9np7d`
     pub s3_bucket: String,
@@ -28,43 +100,143 @@ pub struct Config {
     pub s3_prefix: String,
     /// Contains an initialized S3 Client for reuse. Doesn't need to be public.
     pub s3_client: S3Client,
+    /// An initialized Postgres client
+    pub pg_client: tokio_postgres::Client,
+    /// The URL of the SQS queue for reports that could not be saved in S3, if any
+    pub spool_sqs_url: Option<String>,
+    /// An initialized SQS client for the spool
+    pub sqs_client: SqsClient,
+    /// Requests with a timestamp further away from the server time than this number of seconds are rejected.
+    /// Nonces are kept in the DB for twice as long.
+    pub sig_ts_window: i64,
+    /// The length of the sliding window for rate limiting in seconds.
+    pub rate_limit_window: i64,
+    /// Max number of requests per public key within `rate_limit_window`. `0` means no limit.
+    pub rate_limit_per_key: i64,
+    /// Max number of read-only requests per public key within `rate_limit_window`. `0` means no limit.
+    pub rate_limit_queries_per_key: i64,
+    /// Max number of requests per IP within `rate_limit_window`. `0` means no limit.
+    pub rate_limit_per_ip: i64,
+    /// Rejected requests are kept in `t_rate_limit_log` for this number of seconds for review.
+    pub rate_limit_review_period: i64,
+    /// Reports larger than this number of bytes are rejected before unzipping.
+    pub max_compressed_size: usize,
+    /// Reports that unzip into more than this number of bytes are rejected.
+    pub max_decompressed_size: usize,
+    /// A compiled regex for validating 8-char commit hashes
+    pub commit_hash_regex_short: Regex,
+    /// A compiled regex for validating full-length commit hashes
+    pub commit_hash_regex_full: Regex,
 }
 
 impl Config {
     /// Initializes a new Config struct from the environment. Panics on invalid config values.
-    pub fn new() -> Self {
-        let s3_region = std::env::var(S3_REGION_ENV)
-            .expect(&format!(
-                "Missing {} env var with S3 region name, e.g. us-east-1",
-                S3_REGION_ENV
-            ))
-            .trim()
-            .to_string();
+    pub async fn new() -> Self {
+        // S3 settings are not required if the reports are stored locally
+        let storage = match std::env::var(STORAGE_DIR_ENV) {
+            Ok(v) if !v.trim().is_empty() => Storage::LocalDir(PathBuf::from(v.trim())),
+            _ => Storage::S3,
+        };
+        let is_s3 = matches!(storage, Storage::S3);
+
+        let s3_region = match std::env::var(S3_REGION_ENV) {
+            Ok(v) => v.trim().to_string(),
+            Err(_) if !is_s3 => "us-east-1".to_owned(),
+            Err(_) => panic!("Missing {} env var with S3 region name, e.g. us-east-1", S3_REGION_ENV),
+        };
 
         let s3_region = Region::from_str(&s3_region).expect("Invalid S3 Region value. Must look like `us-east-1`.");
 
-        Config {
-            s3_bucket: std::env::var(S3_BUCKET_ENV)
-                .expect(&format!(
-                    "Missing {} env var with S3 bucket name, e.g. stm-subs-j5awwhv9pb9np7d",
-                    S3_BUCKET_ENV
-                ))
-                .trim()
-                .trim_end_matches("/")
-                .to_string(),
-            s3_prefix: std::env::var(S3_PREFIX_ENV)
-                .expect(&format!(
-                    "Missing {} env var with S3 prefix, e.g. `queue`",
-                    S3_PREFIX_ENV
-                ))
+        let spool_sqs_url = match std::env::var(SPOOL_SQS_URL_ENV) {
+            Ok(v) if !v.trim().is_empty() => Some(v.trim().to_string()),
+            _ => None,
+        };
+
+        let pg_connection_string = std::env::var(PG_CONN_STR)
+        .expect(&format!(
+            "Missing {} env var with Postgres DB connection string. E.g. `host=stm-prod.xxxxxxxxx.us-east-1.rds.amazonaws.com dbname=aaa_bbb user=uuu_vvv password='*#blA()Bla' connect_timeout=15`",
+            PG_CONN_STR
+        ))
+        .trim()
+        .trim_end_matches("/")
+        .to_string();
+
+        let sig_ts_window = match std::env::var(SIG_TS_WINDOW_ENV) {
+            Ok(v) => v
                 .trim()
-                .trim_end_matches("/")
-                .to_string(),
+                .parse::<i64>()
+                .expect(&format!("Invalid {} value. Must be a number of seconds, e.g. 300", SIG_TS_WINDOW_ENV)),
+            Err(_) => SIG_TS_WINDOW_DEFAULT,
+        };
+
+        let rate_limit_window = get_optional_i64(RATE_LIMIT_WINDOW_ENV, RATE_LIMIT_WINDOW_DEFAULT);
+        if rate_limit_window < 1 {
+            panic!("Invalid {} value. Must be a positive number of seconds, e.g. 3600", RATE_LIMIT_WINDOW_ENV);
+        }
+        let rate_limit_per_key = get_optional_i64(RATE_LIMIT_PER_KEY_ENV, RATE_LIMIT_PER_KEY_DEFAULT);
+        let rate_limit_queries_per_key =
+            get_optional_i64(RATE_LIMIT_QUERIES_PER_KEY_ENV, RATE_LIMIT_QUERIES_PER_KEY_DEFAULT);
+        let rate_limit_per_ip = get_optional_i64(RATE_LIMIT_PER_IP_ENV, RATE_LIMIT_PER_IP_DEFAULT);
+        let rate_limit_review_period = get_optional_i64(RATE_LIMIT_REVIEW_PERIOD_ENV, RATE_LIMIT_REVIEW_PERIOD_DEFAULT);
+        if rate_limit_review_period < 0 {
+            panic!("Invalid {} value. Must be a number of seconds, e.g. 604800", RATE_LIMIT_REVIEW_PERIOD_ENV);
+        }
+
+        let max_compressed_size = get_optional_i64(MAX_COMPRESSED_SIZE_ENV, MAX_COMPRESSED_REPORT_SIZE_DEFAULT as i64);
+        let max_decompressed_size =
+            get_optional_i64(MAX_DECOMPRESSED_SIZE_ENV, MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT as i64);
+        if max_compressed_size < 1 || max_decompressed_size < 1 {
+            panic!(
+                "Invalid {} or {} value. Must be a positive number of bytes.",
+                MAX_COMPRESSED_SIZE_ENV, MAX_DECOMPRESSED_SIZE_ENV
+            );
+        }
+
+        let s3_bucket = match std::env::var(S3_BUCKET_ENV) {
+            Ok(v) => v.trim().trim_end_matches("/").to_string(),
+            Err(_) if !is_s3 => String::new(),
+            Err(_) => panic!("Missing {} env var with S3 bucket name, e.g. stm-subs-j5awwhv9pb9np7d", S3_BUCKET_ENV),
+        };
+
+        let s3_prefix = match std::env::var(S3_PREFIX_ENV) {
+            Ok(v) => v.trim().trim_end_matches("/").to_string(),
+            Err(_) if !is_s3 => "queue".to_owned(),
+            Err(_) => panic!("Missing {} env var with S3 prefix, e.g. `queue`", S3_PREFIX_ENV),
+        };
+
+        Config {
+            storage,
+            s3_bucket,
+            s3_prefix,
+            sqs_client: SqsClient::new(s3_region.clone()),
             s3_client: generate_s3_client(s3_region),
+            pg_client: get_pg_client(&pg_connection_string).await,
+            spool_sqs_url,
+            sig_ts_window,
+            rate_limit_window,
+            rate_limit_per_key,
+            rate_limit_queries_per_key,
+            rate_limit_per_ip,
+            rate_limit_review_period,
+            max_compressed_size: max_compressed_size as usize,
+            max_decompressed_size: max_decompressed_size as usize,
+            commit_hash_regex_short: Regex::new("[a-f0-9]{8}").expect("Invalid commit_hash_regex. It's a bug."),
+            commit_hash_regex_full: Regex::new("[a-f0-9]{40}").expect("Invalid commit_hash_regex. It's a bug."),
         }
     }
 }
 
+/// Returns the value of an optional numeric env var or the default if the var is not set. Panics if the value is not a number.
+fn get_optional_i64(env_var: &str, default: i64) -> i64 {
+    match std::env::var(env_var) {
+        Ok(v) => v
+            .trim()
+            .parse::<i64>()
+            .expect(&format!("Invalid {} value. Must be a whole number, e.g. {}", env_var, default)),
+        Err(_) => default,
+    }
+}
+
 /// Generates an S3Client with custom settings to match AWS server defaults.
 fn generate_s3_client(s3_region: Region) -> S3Client {
     let https_connector = HttpsConnector::with_native_roots();
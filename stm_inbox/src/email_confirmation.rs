@@ -0,0 +1,94 @@
+use crate::config::Config;
+use crate::handler::{gw_response, ERROR_500_MSG};
+use crate::postgres::EmailOwnership;
+use lambda_runtime::Error;
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// The URL path for email confirmation links, lower case without the trailing `/`.
+/// E.g. `GET /confirm_email?id=1627380297_Wgx98Rbi8nQuL9ddn3mTk1`
+pub(crate) const PATH: &str = "/confirm_email";
+
+/// The name of the query string parameter with the confirmation ID.
+const CONFIRMATION_ID_PARAM: &str = "id";
+
+/// Confirmation links older than this are rejected. A new link is sent by `email_confirmation` flow of
+/// stm_inbox_flows after the same period, so it must be in sync with `CONFIRMATION_RESEND_AFTER_SECS` there.
+const CONFIRMATION_VALID_FOR_SECS: i64 = 30 * 24 * 3600;
+
+/// Marks the email the confirmation link was sent to as confirmed.
+/// The request is not signed because it comes from a link in the email, so the confirmation ID is the only proof.
+pub(crate) async fn confirm_email(
+    config: &Config,
+    query_string_parameters: &Option<HashMap<String, String>>,
+) -> Result<Value, Error> {
+    let confirmation_id = match query_string_parameters
+        .as_ref()
+        .and_then(|params| params.get(CONFIRMATION_ID_PARAM))
+        .map(|v| v.trim().to_owned())
+    {
+        Some(v) if validate_confirmation_id(&v) => v,
+        v => {
+            warn!("Invalid confirmation ID: {:?}", v);
+            return gw_response(
+                Some(
+                    "stackmuncher.com rejected the request: the confirmation link is incomplete or invalid.".to_owned(),
+                ),
+                400,
+            );
+        }
+    };
+
+    info!("Email confirmation {}", confirmation_id);
+
+    match EmailOwnership::confirm_email(&config.pg_client, &confirmation_id, CONFIRMATION_VALID_FOR_SECS).await {
+        Ok(Some(email)) => gw_response(Some(format!("Thank you. {} is now confirmed.", email)), 200),
+        Ok(None) => gw_response(
+            Some("stackmuncher.com could not find this confirmation link or it has expired. A new link will be emailed to you after your next report.".to_owned()),
+            404,
+        ),
+        Err(_) => gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+    }
+}
+
+/// Returns TRUE if the ID looks like `1627380297_Wgx98Rbi8nQuL9ddn3mTk1`: a unix timestamp and a base58 encoded UUID.
+fn validate_confirmation_id(confirmation_id: &str) -> bool {
+    let (ts, uuid) = match confirmation_id.split_once("_") {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if ts.is_empty() || ts.len() > 12 || !ts.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+
+    match bs58::decode(uuid).into_vec() {
+        Ok(v) => v.len() == 16,
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn validate_confirmation_id_test() {
+    assert!(validate_confirmation_id("1627380297_Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(validate_confirmation_id("0_Wgx98Rbi8nQuL9ddn3mTk1"));
+
+    // the timestamp must be a positive number of up to 12 digits
+    assert!(!validate_confirmation_id("_Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(!validate_confirmation_id("-1627380297_Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(!validate_confirmation_id("16273802970000_Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(!validate_confirmation_id("1627380297a_Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(!validate_confirmation_id(" 1627380297_Wgx98Rbi8nQuL9ddn3mTk1"));
+
+    // the UUID must be base58 and 16 bytes long
+    assert!(!validate_confirmation_id("1627380297_"));
+    assert!(!validate_confirmation_id("1627380297_Wgx98Rbi8nQu"));
+    assert!(!validate_confirmation_id("1627380297_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"));
+    assert!(!validate_confirmation_id("1627380297_Wgx98Rbi8nQuL9ddn3mTk0"));
+    assert!(!validate_confirmation_id("1627380297_Wgx98Rbi8nQuL9ddn3mTk1_1"));
+
+    // no separator
+    assert!(!validate_confirmation_id("1627380297Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(!validate_confirmation_id(""));
+}
@@ -1,12 +1,24 @@
+use crate::account_deletion;
+use crate::auth::authenticate;
+use crate::batch;
 use crate::config::Config;
+use crate::email_confirmation;
+use crate::identity_merge;
+use crate::key_rotation;
+use crate::postgres::SubmissionStatus;
+use crate::project_deletion;
+use crate::rate_limit;
+use crate::report_validation::{validate_report, ValidationError, ValidationResponse};
 use crate::s3;
+use crate::spool;
+use crate::submission_status::{self, new_submission_id, SubmissionReceipt};
 use base64::decode;
 use lambda_runtime::{Context, Error};
-use ring::signature;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use tracing::{debug, error, info, warn};
+use stm_shared::gzip::{decompress_with_limit, GzipError};
+use tracing::{debug, error, info};
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -19,17 +31,37 @@ struct ApiGatewayResponse {
 }
 
 #[derive(Deserialize, Debug)]
-struct ApiGatewayRequestHeaders {
+pub(crate) struct ApiGatewayRequestHeaders {
     /// The user public key, which may or may not be known to us at the time of submission.
     /// A base58 encoded string, e.g. "EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB"
-    stackmuncher_key: Option<String>,
-    /// The signature for the content sent in the body, base58 encoded, e.g.
+    pub stackmuncher_key: Option<String>,
+    /// The signature for the timestamp, nonce and the content sent in the body, base58 encoded, e.g.
     /// "3phLLQyiquyX4xge3CXYGCfb1KdrXQ8cTgBbvE8obwCkcm7vPdLsKT6JtNCdF9qeyjcgF2b4kTRXEsoMTHcQr43n"
-    stackmuncher_sig: Option<String>,
-    /// The IP address of the user. Apparently it is preferred over sourceIp field.
+    pub stackmuncher_sig: Option<String>,
+    /// The time of the request as an epoch timestamp in seconds, covered by the signature, e.g. "1621680890"
+    pub stackmuncher_ts: Option<String>,
+    /// A random string unique for every request from the same key, covered by the signature, e.g. "hQ2rDiTn6pKx5vWs"
+    pub stackmuncher_nonce: Option<String>,
+    /// The list of IPs the request went through. The first entry is set by the client and cannot be trusted.
+    /// It is only logged. Use `sourceIp` from the request context for anything else.
     /// See https://docs.aws.amazon.com/elasticloadbalancing/latest/classic/x-forwarded-headers.html
     #[serde(rename = "x-forwarded-for")]
-    x_forwarded_for: Option<String>,
+    pub x_forwarded_for: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiGatewayRequestContextHttp {
+    /// E.g. `POST`
+    method: Option<String>,
+    /// The IP of the TCP client that connected to API Gateway, e.g. `203.0.113.7`. Unlike `x-forwarded-for`
+    /// it cannot be set by the client.
+    #[serde(rename = "sourceIp")]
+    source_ip: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiGatewayRequestContext {
+    http: Option<ApiGatewayRequestContextHttp>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -38,10 +70,82 @@ struct ApiGatewayRequest {
     headers: ApiGatewayRequestHeaders,
     is_base64_encoded: bool,
     body: Option<String>,
+    /// The path part of the URL, e.g. `/` for report submissions or `/rotate_key`
+    raw_path: Option<String>,
+    /// E.g. `{"id": "Wgx98Rbi8nQuL9ddn3mTk1"}` for `?id=Wgx98Rbi8nQuL9ddn3mTk1`
+    query_string_parameters: Option<HashMap<String, String>>,
+    request_context: Option<ApiGatewayRequestContext>,
 }
 
+impl ApiGatewayRequest {
+    /// Returns the HTTP method in upper case, defaults to `POST` if it's missing from the request.
+    fn method(&self) -> String {
+        self.request_context
+            .as_ref()
+            .and_then(|ctx| ctx.http.as_ref())
+            .and_then(|http| http.method.as_ref())
+            .map(|method| method.to_uppercase())
+            .unwrap_or_else(|| "POST".to_owned())
+    }
+
+    /// Returns the IP of the client as seen by API Gateway, if any.
+    fn source_ip(&self) -> Option<String> {
+        self.request_context
+            .as_ref()
+            .and_then(|ctx| ctx.http.as_ref())
+            .and_then(|http| http.source_ip.as_ref())
+            .map(|ip| ip.trim().to_owned())
+            .filter(|ip| !ip.is_empty())
+    }
+
+    /// Returns the path without the trailing `/`, e.g. `/rotate_key`. The root path is returned as an empty string.
+    fn path(&self) -> String {
+        self.raw_path
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches("/")
+            .to_lowercase()
+    }
+}
+
+/// A rejected request with the HTTP status code and the message to be returned to the user.
+pub(crate) struct RequestError {
+    pub status_code: u32,
+    pub msg: String,
+    /// The number of seconds for `Retry-After` header, if any
+    pub retry_after: Option<i64>,
+}
+
+impl RequestError {
+    pub(crate) fn new(status_code: u32, msg: &str) -> Self {
+        Self {
+            status_code,
+            msg: msg.to_owned(),
+            retry_after: None,
+        }
+    }
+
+    /// Adds `Retry-After` header to the response, e.g. for 429 or 503.
+    pub(crate) fn with_retry_after(mut self, retry_after: i64) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Converts the error into an APIGW response.
+    pub(crate) fn into_response(self) -> Result<Value, Error> {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        if let Some(retry_after) = self.retry_after {
+            headers.insert("Retry-After".to_owned(), retry_after.to_string());
+        }
+        gw_response_with_headers(Some(self.msg), self.status_code, headers)
+    }
+}
+
+/// The value of `Retry-After` header sent with 503 if the report could not be saved.
+pub(crate) const UPLOAD_RETRY_AFTER_SECS: i64 = 60;
+
 /// A generic error message sent to the user when the request cannot be processed for a reason the user can't do much about.
-const ERROR_500_MSG: &str = "stackmuncher.com failed to process the report. If the error persists, can you log an issue at https://github.com/stackmuncher/stm_inbox/issues?";
+pub(crate) const ERROR_500_MSG: &str = "stackmuncher.com failed to process the report. If the error persists, can you log an issue at https://github.com/stackmuncher/stm_inbox/issues?";
 
 pub(crate) async fn my_handler(event: Value, ctx: Context, config: &Config) -> Result<Value, Error> {
     // these 2 lines are for debugging only to see the raw APIGW request
@@ -57,22 +161,61 @@ pub(crate) async fn my_handler(event: Value, ctx: Context, config: &Config) -> R
         Ok(v) => v,
     };
 
-    info!("Report from IP: {:?}", api_request.headers.x_forwarded_for);
+    let source_ip = api_request.source_ip();
+    info!("Request from IP: {:?}, x-forwarded-for: {:?}", source_ip, api_request.headers.x_forwarded_for);
 
-    // these 2 headers are required no matter what
-    if api_request.headers.stackmuncher_key.is_none() || api_request.headers.stackmuncher_sig.is_none() {
-        error!(
-            "Missing a header. Key: {:?}, Sig: {:?}",
-            api_request.headers.stackmuncher_key, api_request.headers.stackmuncher_sig
-        );
-        return gw_response(
-            Some("stackmuncher.com failed to process the report: missing required HTTP headers. If you have not modified the source code it's a bug at stackmuncher.com end.".to_owned()),
-            500,
-        );
+    // stop floods before doing anything else
+    if let Err(e) = rate_limit::check_ip(config, &source_ip).await {
+        return e.into_response();
     }
 
     // get the body contents and decode it if needed
-    let body = match api_request.body {
+    let body = match api_request.body.as_ref() {
+        Some(v) => {
+            if api_request.is_base64_encoded {
+                match decode(v) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        error!("Failed to decode the body due to: {}", e);
+                        return gw_response(Some(ERROR_500_MSG.to_owned()), 500);
+                    }
+                }
+            } else {
+                Some(v.as_bytes().to_vec())
+            }
+        }
+        None => None,
+    };
+
+    let (method, path) = (api_request.method(), api_request.path());
+    info!("{} {}", method, path);
+
+    // anything that is not a known path is treated as a report submission for compatibility with older apps
+    match (method.as_str(), path.as_str()) {
+        ("POST", key_rotation::PATH) => key_rotation::rotate_key(config, &api_request.headers, body).await,
+        ("POST", account_deletion::PATH) => account_deletion::delete_account(config, &api_request.headers, body).await,
+        ("POST", project_deletion::PATH) => project_deletion::delete_project(config, &api_request.headers, body).await,
+        ("POST", batch::PATH) => batch::submit_batch(config, &api_request.headers, body).await,
+        ("POST", identity_merge::PATH) => identity_merge::approve_merge(config, &api_request.headers, body).await,
+        ("GET", identity_merge::PATH) => identity_merge::get_merges(config, &api_request.headers).await,
+        ("GET", email_confirmation::PATH) => {
+            email_confirmation::confirm_email(config, &api_request.query_string_parameters).await
+        }
+        ("GET", submission_status::PATH) => {
+            submission_status::get_submission_status(config, &api_request.headers, &api_request.query_string_parameters)
+                .await
+        }
+        _ => submit_report(config, &api_request.headers, body).await,
+    }
+}
+
+/// Validates the submission and stores the report in S3 for the router to pick it up.
+async fn submit_report(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    body: Option<Vec<u8>>,
+) -> Result<Value, Error> {
+    let body = match body {
         Some(v) => v,
         None => {
             error!("Empty body");
@@ -82,84 +225,119 @@ pub(crate) async fn my_handler(event: Value, ctx: Context, config: &Config) -> R
         );
         }
     };
-    let body = if api_request.is_base64_encoded {
-        match decode(body) {
-            Ok(v) => v,
-            Err(e) => {
-                error!("Failed to decode the body due to: {}", e);
-                return gw_response(Some(ERROR_500_MSG.to_owned()), 500);
-            }
-        }
-    } else {
-        body.as_bytes().into()
-    };
 
     info!("Body len: {}", body.len());
     debug!("Body: {}", String::from_utf8_lossy(&body));
 
-    // convert the public key from base58 into bytes
-    let pub_key_bs58 = api_request
-        .headers
-        .stackmuncher_key
-        .expect("Cannot unwrap stackmuncher_key. It's a bug.");
-
-    info!("Report for pub key: {}", pub_key_bs58);
+    // check the signature, the timestamp and the nonce
+    let pub_key_bs58 = match authenticate(config, headers, &body).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
 
-    if !validate_owner_id(&pub_key_bs58) {
-        error!("Invalid pub key length: {}", pub_key_bs58.len());
-        return gw_response(Some("Invalid public key length. Expecting 32 bytes as base58.".to_owned()), 403);
+    match store_report(config, &pub_key_bs58, &body).await {
+        // Submission accepted - return 200 with the receipt
+        Ok(receipt) => gw_json_response(&receipt, 200),
+        Err(ReportRejection::Invalid(errors)) => gw_json_response(&ValidationResponse::new(errors), 400),
+        Err(ReportRejection::TooLarge(e)) => {
+            gw_response(Some(format!("stackmuncher.com rejected the report: {}.", e)), 413)
+        }
+        Err(ReportRejection::Unavailable) => RequestError::new(503, STORAGE_UNAVAILABLE_MSG)
+            .with_retry_after(UPLOAD_RETRY_AFTER_SECS)
+            .into_response(),
     }
+}
 
-    let pub_key = match bs58::decode(pub_key_bs58.clone()).into_vec() {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Failed to decode the stackmuncher_key from based58 due to: {}", e);
-            return gw_response(Some("Failed to decode public key from based58".to_owned()), 403);
-        }
-    };
+/// The reason a single report from an authenticated request was not accepted.
+pub(crate) enum ReportRejection {
+    /// Not a valid gzip or report, the user has to fix it - 400
+    Invalid(Vec<ValidationError>),
+    /// Exceeds one of the size limits - 413
+    TooLarge(String),
+    /// Could not be saved in S3 or the spool, the user should retry later - 503
+    Unavailable,
+}
+
+/// The message sent with 503 if the report could not be saved.
+pub(crate) const STORAGE_UNAVAILABLE_MSG: &str =
+    "stackmuncher.com is temporarily unable to store the report. Please try again later.";
 
-    // convert the signature from base58 into bytes
-    let signature = match bs58::decode(
-        api_request
-            .headers
-            .stackmuncher_sig
-            .expect("Cannot unwrap stackmuncher_sig. It's a bug."),
-    )
-    .into_vec()
-    {
+/// Unzips and validates a single gzipped report from an authenticated request and stores it in S3 for the router.
+/// Returns the receipt with the submission ID for the app to track the progress.
+pub(crate) async fn store_report(
+    config: &Config,
+    pub_key_bs58: &String,
+    report_gz: &Vec<u8>,
+) -> Result<SubmissionReceipt, ReportRejection> {
+    // unzipping is done after the signature check to avoid spending resources on anonymous payloads
+    let report = match decompress_with_limit(report_gz, config.max_compressed_size, config.max_decompressed_size) {
         Ok(v) => v,
-        Err(e) => {
-            error!("Failed to decode the stackmuncher_key from based58 due to: {}", e);
-            return gw_response(Some(ERROR_500_MSG.to_owned()), 500);
+        Err(GzipError::Corrupt(e)) => {
+            return Err(ReportRejection::Invalid(vec![ValidationError::new(
+                "body",
+                None,
+                &format!("not a valid gzip file: {}", e),
+            )]));
         }
+        Err(e) => return Err(ReportRejection::TooLarge(e.to_string())),
     };
 
-    // validate the signature
-    let pub_key = signature::UnparsedPublicKey::new(&signature::ED25519, pub_key);
-    match pub_key.verify(&body, &signature) {
-        Ok(_) => {
-            info!("Signature OK");
-        }
-        Err(e) => {
-            error!("Invalid signature: {}", e);
-            return gw_response(Some("Invalid StackMuncher signature. If the error persists, can you log an issue at https://github.com/stackmuncher/stm_inbox/issues?".to_owned()), 500);
-        }
-    };
+    // check the report is something the router can process to let the user know about any problems straight away
+    if let Err(errors) = validate_report(&report, &config.commit_hash_regex_short, &config.commit_hash_regex_full) {
+        return Err(ReportRejection::Invalid(errors));
+    }
 
-    s3::upload_to_s3(&config, body, pub_key_bs58).await;
+    // the app can use this ID to check on the progress of the submission
+    let submission_id = new_submission_id();
+    info!("Submission ID: {}", submission_id);
 
-    // render the prepared data as HTML
-    info!("Report stored");
+    // the report is either stored in S3, spooled for later or the user is asked to retry
+    let s3_key = s3::build_s3_key(config, pub_key_bs58, &submission_id);
+    let stage = if s3::upload_to_s3(config, report_gz, &s3_key).await.is_ok() {
+        info!("Report stored");
+        SubmissionStatus::STAGE_STORED
+    } else if spool::spool_report(config, report_gz, &s3_key).await.is_ok() {
+        SubmissionStatus::STAGE_SPOOLED
+    } else {
+        return Err(ReportRejection::Unavailable);
+    };
+
+    // the report is already saved - a failure to record the status should not fail the submission
+    let _ = SubmissionStatus::add_status(&config.pg_client, &submission_id, pub_key_bs58, stage).await;
 
-    // Submission accepted - return 200 with no body
-    gw_response(None, 200)
+    Ok(SubmissionReceipt {
+        submission_id,
+        stage: stage.to_owned(),
+        history: Vec::new(),
+    })
 }
 
 /// Prepares the response with the status and text or json body. May fail and return an error.
-fn gw_response(body: Option<String>, status_code: u32) -> Result<Value, Error> {
+pub(crate) fn gw_response(body: Option<String>, status_code: u32) -> Result<Value, Error> {
+    gw_response_with_headers(body, status_code, HashMap::new())
+}
+
+/// Prepares the response with the status and the body serialized into JSON.
+pub(crate) fn gw_json_response<T: Serialize>(body: &T, status_code: u32) -> Result<Value, Error> {
     let mut headers: HashMap<String, String> = HashMap::new();
-    headers.insert("Content-Type".to_owned(), "text/text".to_owned());
-    headers.insert("Cache-Control".to_owned(), "no-store".to_owned());
+    headers.insert("Content-Type".to_owned(), "application/json".to_owned());
+
+    let body = serde_json::to_string(body).expect("Failed to serialize response body");
+
+    gw_response_with_headers(Some(body), status_code, headers)
+}
+
+/// Same as `gw_response`, but with additional headers. The default headers can be overwritten by `headers`.
+pub(crate) fn gw_response_with_headers(
+    body: Option<String>,
+    status_code: u32,
+    headers: HashMap<String, String>,
+) -> Result<Value, Error> {
+    let mut default_headers: HashMap<String, String> = HashMap::new();
+    default_headers.insert("Content-Type".to_owned(), "text/text".to_owned());
+    default_headers.insert("Cache-Control".to_owned(), "no-store".to_owned());
+    default_headers.extend(headers);
+    let headers = default_headers;
 
     let resp = ApiGatewayResponse {
         is_base64_encoded: false,
@@ -170,23 +348,3 @@ fn gw_response(body: Option<String>, status_code: u32) -> Result<Value, Error> {
 
     Ok(serde_json::to_value(resp).expect("Failed to serialize response"))
 }
-
-/// Returns TRUE if the owner_id decodes from base58 into exactly 256 bytes.
-/// Logs a warning and returns FALSE otherwise.
-/// TODO: this should be a shared utility function!!!
-fn validate_owner_id(owner_id: &str) -> bool {
-    match bs58::decode(owner_id).into_vec() {
-        Err(e) => {
-            warn!("Invalid owner_id: {}. Cannot decode from bs58: {}", owner_id, e);
-            false
-        }
-        Ok(v) => {
-            if v.len() == 32 {
-                true
-            } else {
-                warn!("Invalid owner_id: {}. Decoded to {} bytes", owner_id, v.len());
-                false
-            }
-        }
-    }
-}
@@ -0,0 +1,228 @@
+use crate::auth::{authenticate, authenticate_query, verify_detached_signature};
+use crate::config::Config;
+use crate::handler::{gw_json_response, gw_response, ApiGatewayRequestHeaders, RequestError, ERROR_500_MSG};
+use crate::postgres::IdentityMerge;
+use lambda_runtime::Error;
+use serde::Deserialize;
+use serde_json::Value;
+use stm_shared::validate_owner_id;
+use tracing::{error, info, warn};
+
+/// The URL path for listing and approving merges of duplicate accounts, lower case without the trailing `/`.
+pub(crate) const PATH: &str = "/merge_identity";
+
+/// The statement signed by a key of each account is this prefix followed by `owner_id:other_owner_id` of the proposal, e.g.
+/// `stackmuncher:merge_identity:7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7:9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK`
+const MERGE_STATEMENT_PREFIX: &str = "stackmuncher:merge_identity:";
+
+/// An approval of a merge proposed by `identities` flow of stm_inbox_flows.
+#[derive(Deserialize, Debug)]
+struct MergeApprovalRequest {
+    /// `owner_id` of the proposal, base58 encoded.
+    owner_id: String,
+    /// `other_owner_id` of the proposal, base58 encoded.
+    other_owner_id: String,
+    /// The signature of the merge statement by the key that signed the request, base58 encoded.
+    sig: String,
+}
+
+/// Returns all merges proposed for the account of the signing key. There is no body in GET requests,
+/// so the signature covers the signing key itself.
+pub(crate) async fn get_merges(config: &Config, headers: &ApiGatewayRequestHeaders) -> Result<Value, Error> {
+    let signed_content = headers.stackmuncher_key.clone().unwrap_or_default();
+    let pub_key_bs58 = match authenticate_query(config, headers, signed_content.as_bytes()).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    info!("Merges requested by {}", pub_key_bs58);
+
+    match IdentityMerge::get_merges(&config.pg_client, &pub_key_bs58).await {
+        Ok(v) => gw_json_response(&v, 200),
+        Err(_) => gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+    }
+}
+
+/// Records the approval of a merge by one of the two accounts. The accounts are merged once a key
+/// of each of them approved the same proposal.
+pub(crate) async fn approve_merge(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    body: Option<Vec<u8>>,
+) -> Result<Value, Error> {
+    let body = body.unwrap_or_default();
+
+    // check the signature, the timestamp and the nonce of the request itself
+    let pub_key_bs58 = match authenticate(config, headers, &body).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let approval = match validate_merge_approval(&body, &pub_key_bs58) {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    info!("Merge approval for {}/{} by {}", approval.owner_id, approval.other_owner_id, pub_key_bs58);
+
+    let status = match IdentityMerge::approve(
+        &config.pg_client,
+        &pub_key_bs58,
+        &approval.owner_id,
+        &approval.other_owner_id,
+        &approval.sig,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(_) => return gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+    };
+
+    info!("Merge {}/{}: {}", approval.owner_id, approval.other_owner_id, status);
+
+    let (msg, status_code) = approval_response(&status, &approval.owner_id, &approval.other_owner_id);
+    if status_code == 404 {
+        warn!("No merge {}/{} for {}", approval.owner_id, approval.other_owner_id, pub_key_bs58);
+    }
+
+    gw_response(Some(msg), status_code)
+}
+
+/// Checks that the body is an approval of a proposal as it was listed by `get_merges` and that the merge statement
+/// is signed by the same key as the request. The order of the IDs is not checked here because PG sorted them
+/// under the DB collation, which differs from the byte order for base58. IDs in the wrong order get `not_found` from PG.
+fn validate_merge_approval(body: &[u8], pub_key_bs58: &str) -> Result<MergeApprovalRequest, RequestError> {
+    let approval = match serde_json::from_slice::<MergeApprovalRequest>(body) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid merge approval: {}", e);
+            return Err(RequestError::new(400, &format!("stackmuncher.com rejected the merge approval: {}", e)));
+        }
+    };
+
+    if approval.owner_id == approval.other_owner_id
+        || !validate_owner_id(&approval.owner_id)
+        || !validate_owner_id(&approval.other_owner_id)
+    {
+        return Err(RequestError::new(400, "stackmuncher.com rejected the merge approval: `owner_id` and `other_owner_id` must be copied from the proposal as they are."));
+    }
+
+    // the approval must be signed separately, so that it can be kept for audit as proof of consent
+    let statement = build_merge_statement(&approval.owner_id, &approval.other_owner_id);
+    if !verify_detached_signature(pub_key_bs58, &approval.sig, statement.as_bytes()) {
+        return Err(RequestError::new(
+            403,
+            "stackmuncher.com rejected the merge approval: invalid signature of the merge statement.",
+        ));
+    }
+
+    Ok(approval)
+}
+
+/// Returns the message and the HTTP status code for one of `IdentityMerge::STATUS_*` returned by
+/// `stm_approve_identity_merge`. Anything unexpected is treated as `not_found`.
+fn approval_response(status: &str, owner_id: &str, other_owner_id: &str) -> (String, u32) {
+    match status {
+        IdentityMerge::STATUS_MERGED => (
+            format!(
+                "Accounts {} and {} are now merged. Reports submitted with any of their keys will be merged into the same profile.",
+                owner_id, other_owner_id
+            ),
+            200,
+        ),
+        IdentityMerge::STATUS_PENDING => (
+            "The approval was recorded. The accounts will be merged once the other account approves it too.".to_owned(),
+            202,
+        ),
+        _ => (
+            format!(
+                "stackmuncher.com has no record of a merge of {} and {} for this key.",
+                owner_id, other_owner_id
+            ),
+            404,
+        ),
+    }
+}
+
+/// Returns the statement a key of each account has to sign, e.g. `stackmuncher:merge_identity:OWNER_ID:OTHER_OWNER_ID`.
+fn build_merge_statement(owner_id: &str, other_owner_id: &str) -> String {
+    [MERGE_STATEMENT_PREFIX, owner_id, ":", other_owner_id].concat()
+}
+
+#[test]
+fn build_merge_statement_test() {
+    assert_eq!(
+        build_merge_statement(
+            "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7",
+            "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"
+        ),
+        "stackmuncher:merge_identity:7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7:9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"
+    );
+}
+
+#[test]
+fn validate_merge_approval_test() {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let signer = bs58::encode(key_pair.public_key().as_ref()).into_string();
+
+    let owner_id = "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7";
+    let other_owner_id = "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK";
+    let sign = |statement: String| bs58::encode(key_pair.sign(statement.as_bytes()).as_ref()).into_string();
+    let status_code = |owner_id: &str, other_owner_id: &str, sig: &str| {
+        let body = format!(r#"{{"owner_id":"{}","other_owner_id":"{}","sig":"{}"}}"#, owner_id, other_owner_id, sig);
+        validate_merge_approval(body.as_bytes(), &signer)
+            .err()
+            .map(|e| e.status_code)
+    };
+
+    let sig = sign(build_merge_statement(owner_id, other_owner_id));
+    assert_eq!(status_code(owner_id, other_owner_id, &sig), None);
+
+    // the order depends on the DB collation, e.g. `a...` goes before `E...` under en_US, so it is left to PG
+    let collated_owner_id = "a8Em5upv4X5MwPf5xKfgtSW7bPjeDToGjS1CoGJXzcU";
+    let collated_other_owner_id = "EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB";
+    let collated_sig = sign(build_merge_statement(collated_owner_id, collated_other_owner_id));
+    assert_eq!(status_code(collated_owner_id, collated_other_owner_id, &collated_sig), None);
+    // the signature still has to match the order of the IDs in the request
+    assert_eq!(status_code(other_owner_id, owner_id, &sig), Some(403));
+    assert_eq!(status_code(owner_id, owner_id, &sig), Some(400));
+    assert_eq!(status_code("", other_owner_id, &sig), Some(400));
+    assert_eq!(status_code(owner_id, "not-a-key", &sig), Some(400));
+
+    // the statement of another proposal or the raw IDs without the prefix
+    let other_sig = sign(build_merge_statement(owner_id, "EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB"));
+    assert_eq!(status_code(owner_id, other_owner_id, &other_sig), Some(403));
+    let raw_sig = sign([owner_id, ":", other_owner_id].concat());
+    assert_eq!(status_code(owner_id, other_owner_id, &raw_sig), Some(403));
+    assert_eq!(status_code(owner_id, other_owner_id, ""), Some(403));
+
+    // not an approval
+    assert_eq!(
+        validate_merge_approval(br#"{"owner_id":"x"}"#, &signer)
+            .err()
+            .map(|e| e.status_code),
+        Some(400)
+    );
+}
+
+#[test]
+fn approval_response_test() {
+    let owner_id = "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7";
+    let other_owner_id = "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK";
+    let status_code = |status: &str| approval_response(status, owner_id, other_owner_id).1;
+
+    // stm_approve_identity_merge returns `pending` for the first approval, `merged` for the second one
+    // and for any repeated approval after that, `not_found` if the signer is not a party to the proposal
+    assert_eq!(status_code(IdentityMerge::STATUS_PENDING), 202);
+    assert_eq!(status_code(IdentityMerge::STATUS_MERGED), 200);
+    assert_eq!(status_code(IdentityMerge::STATUS_NOT_FOUND), 404);
+    assert_eq!(status_code(""), 404);
+    assert_eq!(status_code("MERGED"), 404);
+
+    assert!(approval_response(IdentityMerge::STATUS_MERGED, owner_id, other_owner_id)
+        .0
+        .contains(other_owner_id));
+}
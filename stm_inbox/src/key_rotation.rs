@@ -0,0 +1,119 @@
+use crate::auth::{authenticate, verify_detached_signature};
+use crate::config::Config;
+use crate::handler::{gw_response, ApiGatewayRequestHeaders, ERROR_500_MSG};
+use crate::postgres::KeyLink;
+use lambda_runtime::Error;
+use serde::Deserialize;
+use serde_json::Value;
+use stm_shared::validate_owner_id;
+use tracing::{error, info, warn};
+
+/// The URL path for key rotation requests, lower case without the trailing `/`.
+pub(crate) const PATH: &str = "/rotate_key";
+
+/// The statement signed by both keys is this prefix followed by `old_key:new_key`, e.g.
+/// `stackmuncher:rotate_key:9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK:EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB`
+const ROTATION_STATEMENT_PREFIX: &str = "stackmuncher:rotate_key:";
+
+/// A request to link a new public key to the account of an existing key.
+/// Both keys sign the same rotation statement to prove that the request comes from the owner of both keys.
+/// The request itself must be signed by either of the two keys as any other request.
+#[derive(Deserialize, Debug)]
+struct KeyRotationRequest {
+    /// The key the account is currently known by, base58 encoded.
+    old_key: String,
+    /// The key to be linked to the account of `old_key`, base58 encoded.
+    new_key: String,
+    /// The signature of the rotation statement by `old_key`, base58 encoded.
+    old_key_sig: String,
+    /// The signature of the rotation statement by `new_key`, base58 encoded.
+    new_key_sig: String,
+}
+
+/// Validates a key rotation request and links the new key to the canonical account of the old key.
+/// All future submissions from either key are merged into the same dev profile.
+pub(crate) async fn rotate_key(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    body: Option<Vec<u8>>,
+) -> Result<Value, Error> {
+    let body = body.unwrap_or_default();
+
+    // check the signature, the timestamp and the nonce of the request itself
+    let pub_key_bs58 = match authenticate(config, headers, &body).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let rotation = match serde_json::from_slice::<KeyRotationRequest>(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid key rotation request: {}", e);
+            return gw_response(Some(format!("stackmuncher.com rejected the key rotation request: {}", e)), 400);
+        }
+    };
+
+    info!("Key rotation from {} to {}", rotation.old_key, rotation.new_key);
+
+    if rotation.old_key == rotation.new_key
+        || !validate_owner_id(&rotation.old_key)
+        || !validate_owner_id(&rotation.new_key)
+    {
+        return gw_response(
+            Some("stackmuncher.com rejected the key rotation request: the old and the new keys must be two different 32-byte keys encoded as base58.".to_owned()),
+            400,
+        );
+    }
+
+    // the request is signed by one of the keys from the statement, not by some third party
+    if pub_key_bs58 != rotation.old_key && pub_key_bs58 != rotation.new_key {
+        warn!("Key rotation request signed by a third key {}", pub_key_bs58);
+        return gw_response(
+            Some("stackmuncher.com rejected the key rotation request: it must be signed by either the old or the new key.".to_owned()),
+            403,
+        );
+    }
+
+    // both keys must sign the same statement
+    let statement = build_rotation_statement(&rotation.old_key, &rotation.new_key);
+    if !verify_detached_signature(&rotation.old_key, &rotation.old_key_sig, statement.as_bytes()) {
+        return gw_response(
+            Some("stackmuncher.com rejected the key rotation request: invalid signature by the old key.".to_owned()),
+            403,
+        );
+    }
+    if !verify_detached_signature(&rotation.new_key, &rotation.new_key_sig, statement.as_bytes()) {
+        return gw_response(
+            Some("stackmuncher.com rejected the key rotation request: invalid signature by the new key.".to_owned()),
+            403,
+        );
+    }
+
+    let canonical_owner_id = match KeyLink::link_keys(
+        &config.pg_client,
+        &rotation.old_key,
+        &rotation.new_key,
+        &rotation.old_key_sig,
+        &rotation.new_key_sig,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(_) => return gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+    };
+
+    info!("Key {} linked to {}", rotation.new_key, canonical_owner_id);
+
+    gw_response(
+        Some(format!(
+            "Key {} is now linked to account {}. Reports submitted with either key will be merged into the same profile.",
+            rotation.new_key, canonical_owner_id
+        )),
+        200,
+    )
+}
+
+/// Returns the statement both keys have to sign, e.g. `stackmuncher:rotate_key:OLD_KEY:NEW_KEY`.
+fn build_rotation_statement(old_key: &str, new_key: &str) -> String {
+    [ROTATION_STATEMENT_PREFIX, old_key, ":", new_key].concat()
+}
@@ -0,0 +1,402 @@
+//! A plain HTTP listener for running the inbox outside of AWS Lambda, e.g. for local debugging or self-hosting.
+//! Every HTTP request is converted into an API Gateway HTTP API v2 event and passed to the same handler
+//! as in Lambda, so the behavior is identical in both modes.
+
+use crate::batch;
+use crate::config::Config;
+use crate::handler::my_handler;
+use hyper::body::HttpBody;
+use hyper::http::request::Parts;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use lambda_runtime::{Context, Error};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// The CLI flag that starts the inbox in the local server mode, e.g. `stm_inbox --serve 0.0.0.0:8080`.
+const SERVE_FLAG: &str = "--serve";
+/// The address the server listens on if `--serve` has no value.
+const DEFAULT_SERVE_ADDR: &str = "127.0.0.1:3000";
+/// The CLI flag with a comma-separated list of reverse proxy IPs allowed to set `X-Forwarded-For`,
+/// e.g. `stm_inbox --serve 127.0.0.1:3000 --trusted-proxy 127.0.0.1`.
+const TRUSTED_PROXY_FLAG: &str = "--trusted-proxy";
+
+/// Returns the address to listen on if the app was started with `--serve [addr]` or None otherwise.
+/// Panics if the address is not a valid `ip:port` pair.
+pub(crate) fn serve_addr() -> Option<SocketAddr> {
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == SERVE_FLAG {
+            let addr = match args.next() {
+                Some(v) if !v.starts_with("--") => v,
+                _ => DEFAULT_SERVE_ADDR.to_owned(),
+            };
+
+            return Some(
+                addr.parse::<SocketAddr>()
+                    .expect("Invalid --serve value. Must look like `127.0.0.1:3000`."),
+            );
+        }
+    }
+
+    None
+}
+
+/// Returns the list of IPs from `--trusted-proxy ip1,ip2` or an empty list if the flag is not set.
+/// Panics if any of the values is not a valid IP address.
+fn trusted_proxies() -> Vec<IpAddr> {
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == TRUSTED_PROXY_FLAG {
+            return args
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| {
+                    v.trim()
+                        .parse::<IpAddr>()
+                        .expect("Invalid --trusted-proxy value. Must look like `127.0.0.1` or `10.0.0.1,10.0.0.2`.")
+                })
+                .collect();
+        }
+    }
+
+    Vec::new()
+}
+
+/// Listens for HTTP requests until the process is killed.
+pub(crate) async fn run(config: Config, addr: SocketAddr) -> Result<(), Error> {
+    let config = Arc::new(config);
+    let trusted_proxies = Arc::new(trusted_proxies());
+
+    let make_svc = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+        let config = config.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let config = config.clone();
+                let trusted_proxies = trusted_proxies.clone();
+                async move { Ok::<_, Infallible>(handle_request(req, remote_addr, &trusted_proxies, &config).await) }
+            }))
+        }
+    });
+
+    info!("Listening on http://{}", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+
+    Ok(())
+}
+
+/// Converts the HTTP request into an API Gateway event, invokes the handler and converts its response back into HTTP.
+async fn handle_request(
+    req: Request<Body>,
+    remote_addr: SocketAddr,
+    trusted_proxies: &[IpAddr],
+    config: &Config,
+) -> Response<Body> {
+    info!("{} {}", req.method(), req.uri());
+
+    // there is no API Gateway payload limit in front of this server, so the body is read only up to the size
+    // the handler can accept
+    let (parts, mut body) = req.into_parts();
+    let max_size = max_body_size(parts.uri.path(), config);
+    let event = match read_body(&mut body, max_size).await {
+        Ok(Some(body)) => build_event(&parts, &body, remote_addr, trusted_proxies),
+        Ok(None) => {
+            warn!("Request body over {} bytes", max_size);
+            return plain_response(
+                413,
+                &format!("stackmuncher.com rejected the request: the body is larger than {} bytes.", max_size),
+            );
+        }
+        Err(e) => {
+            error!("Cannot read the request: {}", e);
+            return plain_response(400, "Cannot read the request");
+        }
+    };
+
+    match my_handler(event, Context::default(), config).await {
+        Ok(v) => build_response(v),
+        Err(e) => {
+            error!("Handler failed: {}", e);
+            plain_response(500, crate::handler::ERROR_500_MSG)
+        }
+    }
+}
+
+/// Returns the max body size in bytes for the request path. A batch envelope holds up to `MAX_BATCH_REPORTS`
+/// reports and a manifest, anything else is either a single report or a small JSON request.
+fn max_body_size(path: &str, config: &Config) -> usize {
+    if path.trim_end_matches("/").to_lowercase() == batch::PATH {
+        batch::max_envelope_size(config.max_compressed_size)
+    } else {
+        config.max_compressed_size
+    }
+}
+
+/// Reads the body chunk by chunk and returns None as soon as it exceeds `max_size`, without reading the rest.
+async fn read_body(body: &mut Body, max_size: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    // a declared `Content-Length` over the limit is rejected without reading anything
+    if body.size_hint().lower() > max_size as u64 {
+        return Ok(None);
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > max_size {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(bytes))
+}
+
+/// Builds an API Gateway HTTP API v2 event with the fields the handler relies on.
+/// The body is always base64 encoded, same as API Gateway does for binary payloads.
+/// `X-Forwarded-For` from the client is only kept if the request came via one of `trusted_proxies`,
+/// otherwise it is replaced with the IP of the TCP client.
+/// `sourceIp` for the IP rate limit is the last entry of `X-Forwarded-For` appended by a trusted proxy
+/// or the IP of the TCP client, same as API Gateway does, so that it cannot be set by the client.
+fn build_event(parts: &Parts, body: &[u8], remote_addr: SocketAddr, trusted_proxies: &[IpAddr]) -> Value {
+    // API Gateway lower-cases header names and joins repeated headers with a comma
+    let mut headers: Map<String, Value> = Map::new();
+    for (name, value) in parts.headers.iter() {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        let name = name.as_str().to_lowercase();
+        let value = match headers.get(&name).and_then(|v| v.as_str()) {
+            Some(existing) => [existing, ",", &value].concat(),
+            None => value,
+        };
+        headers.insert(name, Value::String(value));
+    }
+
+    let is_trusted_proxy = trusted_proxies.contains(&remote_addr.ip());
+    if !is_trusted_proxy || !headers.contains_key("x-forwarded-for") {
+        if headers.contains_key("x-forwarded-for") {
+            warn!("X-Forwarded-For from untrusted {} replaced", remote_addr.ip());
+        }
+        headers.insert("x-forwarded-for".to_owned(), Value::String(remote_addr.ip().to_string()));
+    }
+
+    // rate limiting by IP relies on this value, the earlier entries of the header may come from the client
+    let source_ip = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.rsplit(',').next())
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| remote_addr.ip().to_string());
+
+    // API Gateway passes decoded values to the handler
+    let query_string_parameters = parts.uri.query().map(|q| {
+        q.split('&')
+            .filter(|kv| !kv.is_empty())
+            .map(|kv| {
+                let mut kv = kv.splitn(2, '=');
+                (
+                    percent_decode(kv.next().unwrap_or_default()),
+                    percent_decode(kv.next().unwrap_or_default()),
+                )
+            })
+            .collect::<HashMap<String, String>>()
+    });
+
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        Value::String(base64::encode(body))
+    };
+
+    json!({
+        "version": "2.0",
+        "rawPath": parts.uri.path(),
+        "headers": headers,
+        "queryStringParameters": query_string_parameters,
+        "requestContext": { "http": { "method": parts.method.as_str(), "sourceIp": source_ip } },
+        "isBase64Encoded": true,
+        "body": body,
+    })
+}
+
+/// Decodes `%XX` sequences and `+` in a query string key or value. Invalid sequences are left as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut idx = 0usize;
+
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' if idx + 2 < bytes.len()
+                && bytes[idx + 1].is_ascii_hexdigit()
+                && bytes[idx + 2].is_ascii_hexdigit() =>
+            {
+                // both chars are ASCII hex digits, so neither the slice nor the parsing can fail
+                let hex = std::str::from_utf8(&bytes[idx + 1..idx + 3]).unwrap_or_default();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                idx += 2;
+            }
+            v => decoded.push(v),
+        }
+        idx += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Converts the API Gateway response from the handler into an HTTP response.
+fn build_response(gw_response: Value) -> Response<Body> {
+    let status_code = gw_response["statusCode"].as_u64().unwrap_or(500) as u16;
+
+    let body = match gw_response["body"].as_str() {
+        Some(v) if gw_response["isBase64Encoded"].as_bool().unwrap_or_default() => {
+            base64::decode(v).unwrap_or_default()
+        }
+        Some(v) => v.as_bytes().to_vec(),
+        None => Vec::new(),
+    };
+
+    let mut resp = Response::builder().status(status_code);
+    if let Some(headers) = gw_response["headers"].as_object() {
+        for (name, value) in headers {
+            if let Some(value) = value.as_str() {
+                resp = resp.header(name.as_str(), value);
+            }
+        }
+    }
+
+    match resp.body(Body::from(body)) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Cannot build the response: {}", e);
+            plain_response(500, crate::handler::ERROR_500_MSG)
+        }
+    }
+}
+
+/// A text response for errors that happen outside of the handler.
+fn plain_response(status_code: u16, msg: &str) -> Response<Body> {
+    let mut resp = Response::new(Body::from(msg.to_owned()));
+    *resp.status_mut() = hyper::StatusCode::from_u16(status_code).unwrap_or(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    resp
+}
+
+#[test]
+fn percent_decode_test() {
+    assert_eq!(percent_decode("Wgx98Rbi8nQuL9ddn3mTk1"), "Wgx98Rbi8nQuL9ddn3mTk1");
+    assert_eq!(percent_decode("Wgx98Rbi8nQuL9ddn3mTk1%0A"), "Wgx98Rbi8nQuL9ddn3mTk1\n");
+    assert_eq!(percent_decode("a%20b+c"), "a b c");
+    assert_eq!(percent_decode("%E2%9C%93"), "\u{2713}");
+    // invalid or incomplete sequences are kept
+    assert_eq!(percent_decode("100%"), "100%");
+    assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    assert_eq!(percent_decode("%+1"), "% 1");
+}
+
+#[test]
+fn build_event_test() {
+    let client: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    let trusted_proxies: Vec<IpAddr> = vec![proxy.ip()];
+
+    let (parts, _) = Request::builder()
+        .method("GET")
+        .uri("/status?id=Wgx98Rbi8nQuL9ddn3mTk1%0A&x=")
+        .header("StackMuncher_Key", "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK")
+        .header("X-Forwarded-For", "198.51.100.1")
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    // a direct client cannot set its own IP
+    let event = build_event(&parts, b"", client, &trusted_proxies);
+    assert_eq!(event["rawPath"], "/status");
+    assert_eq!(event["requestContext"]["http"]["method"], "GET");
+    assert_eq!(event["headers"]["stackmuncher_key"], "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK");
+    assert_eq!(event["headers"]["x-forwarded-for"], "203.0.113.7");
+    assert_eq!(event["requestContext"]["http"]["sourceIp"], "203.0.113.7");
+    assert_eq!(event["queryStringParameters"]["id"], "Wgx98Rbi8nQuL9ddn3mTk1\n");
+    assert_eq!(event["queryStringParameters"]["x"], "");
+    assert_eq!(event["body"], Value::Null);
+
+    // a trusted proxy passes the client IP on
+    let event = build_event(&parts, b"", proxy, &trusted_proxies);
+    assert_eq!(event["headers"]["x-forwarded-for"], "198.51.100.1");
+    assert_eq!(event["requestContext"]["http"]["sourceIp"], "198.51.100.1");
+
+    // the client prepended a fake IP, the proxy appended the real one
+    let (parts, _) = Request::builder()
+        .method("GET")
+        .uri("/status")
+        .header("X-Forwarded-For", "192.0.2.1, 198.51.100.1")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let event = build_event(&parts, b"", proxy, &trusted_proxies);
+    assert_eq!(event["requestContext"]["http"]["sourceIp"], "198.51.100.1");
+
+    // the body is base64 encoded and there is no query string
+    let (parts, _) = Request::builder()
+        .method("POST")
+        .uri("/")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let event = build_event(&parts, b"abc", proxy, &trusted_proxies);
+    assert_eq!(event["body"], "YWJj");
+    assert_eq!(event["queryStringParameters"], Value::Null);
+    // the proxy did not set the header, so its own IP is used
+    assert_eq!(event["headers"]["x-forwarded-for"], "127.0.0.1");
+    assert_eq!(event["requestContext"]["http"]["sourceIp"], "127.0.0.1");
+}
+
+#[test]
+fn read_body_test() {
+    let rt = tokio::runtime::Runtime::new().expect("Failed to start a runtime");
+    // the chunks are sent as the body is read, the same as they arrive from the client
+    let read = |chunks: Vec<&'static str>, max_size: usize| {
+        let (mut sender, body) = Body::channel();
+        let reader = async move {
+            let mut body = body;
+            read_body(&mut body, max_size).await.expect("Failed to read the body")
+        };
+        let writer = async move {
+            for chunk in chunks {
+                // the reader stops early if the body is too large
+                if sender
+                    .send_data(hyper::body::Bytes::from_static(chunk.as_bytes()))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        };
+        rt.block_on(async { tokio::join!(reader, writer).0 })
+    };
+
+    assert_eq!(read(vec!["abc", "de"], 5), Some(b"abcde".to_vec()));
+    assert_eq!(read(vec![], 5), Some(Vec::new()));
+    // the limit is checked as the chunks arrive
+    assert_eq!(read(vec!["abc", "def"], 5), None);
+    assert_eq!(read(vec!["abcdef"], 5), None);
+
+    // the length is known upfront
+    let mut body = Body::from("abcdef");
+    assert_eq!(rt.block_on(read_body(&mut body, 5)).expect("Failed to read the body"), None);
+    let mut body = Body::from("abcdef");
+    assert_eq!(
+        rt.block_on(read_body(&mut body, 6)).expect("Failed to read the body"),
+        Some(b"abcdef".to_vec())
+    );
+}
@@ -1,9 +1,22 @@
 use crate::config::Config;
 use lambda_runtime::Error;
 
+mod account_deletion;
+mod auth;
+mod batch;
 mod config;
+mod email_confirmation;
 mod handler;
+mod identity_merge;
+mod key_rotation;
+mod local_server;
+mod postgres;
+mod project_deletion;
+mod rate_limit;
+mod report_validation;
 mod s3;
+mod spool;
+mod submission_status;
 
 /// Boilerplate Lambda runtime code with conditional debug proxy
 #[tokio::main]
@@ -20,8 +33,15 @@ async fn main() -> Result<(), Error> {
     // prepare cached config to save on the init time
     // it may backfire if the S3 connector token expire while it is being cached
     // let's hope that the function gets recycled before that happens
-    // if the token expire we'll get an error in the log and loose the report because there is no retry
-    let config_owned = Config::new();
+    // if the token expire the upload is retried, spooled to SQS, if configured, or the user gets 503 to try again later
+    let config_owned = Config::new().await;
+
+    // run as a standalone HTTP server if started with `--serve [addr]`, e.g. for local debugging or self-hosting
+    // it needs the same config, but S3 settings are optional with STM_INBOX_STORAGE_DIR - see the minimum env set in README
+    if let Some(addr) = local_server::serve_addr() {
+        return local_server::run(config_owned, addr).await;
+    }
+
     let config_shared = &config_owned;
 
     // call the proxy - development only
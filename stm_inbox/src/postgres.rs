@@ -0,0 +1,449 @@
+use lambda_runtime::Error;
+use serde::Serialize;
+use tokio_postgres::{Client, NoTls, Row};
+use tracing::{debug, error, info};
+
+/// Corresponds to `t_nonce` table
+pub(crate) struct Nonce {}
+
+/// Corresponds to `t_key_link` table
+pub(crate) struct KeyLink {}
+
+/// Corresponds to `t_rate_limit_log` table
+pub(crate) struct RateLimit {}
+
+/// Corresponds to `t_deletion_queue` table
+pub(crate) struct DeletionQueue {}
+
+/// Corresponds to `t_email_ownership` table
+pub(crate) struct EmailOwnership {}
+
+/// Corresponds to `t_submission_status` table
+#[derive(Serialize, Debug)]
+pub(crate) struct SubmissionStatus {
+    /// One of `stored`, `spooled`, `routed`, `project_assigned`, `unchanged`, `queued`, `regenerated`, `failed`
+    pub stage: String,
+    /// The project the report was assigned to, if known at this stage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    /// The reason of the failure or other details
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    /// When the stage was reached as an epoch timestamp in seconds
+    pub ts: i64,
+}
+
+impl From<&Row> for SubmissionStatus {
+    /// Creates a new structure from tokio_postgres::Row
+    fn from(row: &Row) -> Self {
+        Self {
+            stage: row.get("stage"),
+            project_id: row.get("project_id"),
+            details: row.get("details"),
+            ts: row.get("added_ts"),
+        }
+    }
+}
+
+/// Corresponds to `t_identity_merge` table
+#[derive(Serialize, Debug)]
+pub(crate) struct IdentityMerge {
+    /// The lower of the two canonical owner_ids in the sort order
+    pub owner_id: String,
+    pub other_owner_id: String,
+    /// Confirmed emails found in both accounts
+    pub shared_emails: Vec<String>,
+    /// The validated GitHub login of both accounts, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_gh_login: Option<String>,
+    /// When the merge was proposed as an epoch timestamp in seconds
+    pub proposed_ts: i64,
+    pub owner_approved: bool,
+    pub other_owner_approved: bool,
+    /// The account that was linked to the other one, None until both approved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merged_owner_id: Option<String>,
+}
+
+impl From<&Row> for IdentityMerge {
+    /// Creates a new structure from tokio_postgres::Row
+    fn from(row: &Row) -> Self {
+        Self {
+            owner_id: row.get("owner_id"),
+            other_owner_id: row.get("other_owner_id"),
+            shared_emails: row.get("shared_emails"),
+            shared_gh_login: row.get("shared_gh_login"),
+            proposed_ts: row.get("proposed_ts"),
+            owner_approved: row.get("owner_approved"),
+            other_owner_approved: row.get("other_owner_approved"),
+            merged_owner_id: row.get("merged_owner_id"),
+        }
+    }
+}
+
+impl Nonce {
+    /// Stores the nonce for the owner and returns TRUE if it was not seen before.
+    /// Returns FALSE if the same nonce was already used by this owner within the retention period.
+    /// Nonces older than `retention_secs` are purged for the owner as part of this call.
+    pub(crate) async fn add_nonce(
+        pg_client: &Client,
+        owner_id: &String,
+        nonce: &String,
+        retention_secs: i64,
+    ) -> Result<bool, Error> {
+        info!("Adding nonce {}", nonce);
+
+        let rows = match pg_client
+            .query(
+                "select stm_add_nonce($1::varchar, $2::varchar, $3::bigint)",
+                &[owner_id, nonce, &retention_secs],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_add_nonce failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP always returns a single boolean
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, bool>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(Error::from(format!("Cannot convert stm_add_nonce result to bool: {}", e))),
+            },
+            None => Err(Error::from("stm_add_nonce returned no rows")),
+        }
+    }
+}
+
+impl KeyLink {
+    /// Links `new_key` to the canonical account of `old_key` and returns the canonical owner_id.
+    /// The signatures of the rotation statement are stored for audit.
+    pub(crate) async fn link_keys(
+        pg_client: &Client,
+        old_key: &String,
+        new_key: &String,
+        old_key_sig: &String,
+        new_key_sig: &String,
+    ) -> Result<String, Error> {
+        info!("Linking key {} to {}", new_key, old_key);
+
+        let rows = match pg_client
+            .query(
+                "select stm_link_keys($1::varchar, $2::varchar, $3::varchar, $4::varchar)",
+                &[old_key, new_key, old_key_sig, new_key_sig],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_link_keys failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP always returns the canonical owner_id
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, String>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    error!("Cannot convert stm_link_keys result to String: {}", e);
+                    Err(Error::from(e))
+                }
+            },
+            None => {
+                error!("stm_link_keys returned no rows");
+                Err(Error::from("stm_link_keys returned no rows"))
+            }
+        }
+    }
+}
+
+impl IdentityMerge {
+    /// The other account has not approved the merge yet.
+    pub(crate) const STATUS_PENDING: &'static str = "pending";
+    /// Both accounts approved and they are now linked.
+    pub(crate) const STATUS_MERGED: &'static str = "merged";
+    /// There is no such proposal for the account of the signer.
+    pub(crate) const STATUS_NOT_FOUND: &'static str = "not_found";
+
+    /// Returns all merges proposed for the account `owner_id` belongs to, oldest first.
+    pub(crate) async fn get_merges(pg_client: &Client, owner_id: &String) -> Result<Vec<IdentityMerge>, Error> {
+        let rows = match pg_client
+            .query(
+                "select owner_id, other_owner_id, shared_emails, shared_gh_login, \
+                extract(epoch from proposed_ts)::bigint as proposed_ts, \
+                owner_approved_ts is not null as owner_approved, other_owner_approved_ts is not null as other_owner_approved, \
+                merged_owner_id from stm_get_identity_merges($1::varchar)",
+                &[owner_id],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_identity_merges failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        Ok(rows.iter().map(|row| IdentityMerge::from(row)).collect())
+    }
+
+    /// Records the approval of the merge by `signer`, which may be any key of either account, and links the accounts
+    /// if the other one approved already. Returns one of `STATUS_*` constants.
+    pub(crate) async fn approve(
+        pg_client: &Client,
+        signer: &String,
+        owner_id: &String,
+        other_owner_id: &String,
+        sig: &String,
+    ) -> Result<String, Error> {
+        info!("Approving merge {}/{} by {}", owner_id, other_owner_id, signer);
+
+        let rows = match pg_client
+            .query(
+                "select stm_approve_identity_merge($1::varchar, $2::varchar, $3::varchar, $4::varchar)",
+                &[signer, owner_id, other_owner_id, sig],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_approve_identity_merge failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP always returns the status
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, String>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    Err(Error::from(format!("Cannot convert stm_approve_identity_merge result to String: {}", e)))
+                }
+            },
+            None => Err(Error::from("stm_approve_identity_merge returned no rows")),
+        }
+    }
+}
+
+impl RateLimit {
+    /// Logs the request and returns `None` if it is within the limit of `max_requests` per `window_secs`
+    /// or the age of the oldest request counted towards the limit in seconds.
+    /// * `limit_type`: `key`, `key_query` or `ip`
+    /// * `limit_value`: the public key or the IP address of the sender
+    /// * `review_secs`: how long rejected requests are kept for review
+    pub(crate) async fn check_rate_limit(
+        pg_client: &Client,
+        limit_type: &str,
+        limit_value: &String,
+        window_secs: i64,
+        max_requests: i64,
+        review_secs: i64,
+    ) -> Result<Option<i64>, Error> {
+        let rows = match pg_client
+            .query(
+                "select stm_check_rate_limit($1::varchar, $2::varchar, $3::bigint, $4::bigint, $5::bigint)",
+                &[&limit_type, limit_value, &window_secs, &max_requests, &review_secs],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_check_rate_limit failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP always returns a single row with NULL for allowed requests
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, Option<i64>>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(Error::from(format!("Cannot convert stm_check_rate_limit result to i64: {}", e))),
+            },
+            None => Err(Error::from("stm_check_rate_limit returned no rows")),
+        }
+    }
+}
+
+impl DeletionQueue {
+    /// Adds a deletion request for the account `owner_id` belongs to and returns the deletion_id.
+    /// If there is already a pending request for the same account its deletion_id is returned instead.
+    pub(crate) async fn queue_account_deletion(
+        pg_client: &Client,
+        deletion_id: &String,
+        owner_id: &String,
+        request_sig: &String,
+    ) -> Result<String, Error> {
+        info!("Queueing account deletion for {}", owner_id);
+
+        let rows = match pg_client
+            .query(
+                "select stm_queue_account_deletion($1::varchar, $2::varchar, $3::varchar)",
+                &[deletion_id, owner_id, request_sig],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_queue_account_deletion failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP always returns the deletion_id
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, String>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    Err(Error::from(format!("Cannot convert stm_queue_account_deletion result to String: {}", e)))
+                }
+            },
+            None => Err(Error::from("stm_queue_account_deletion returned no rows")),
+        }
+    }
+
+    /// Adds a deletion request for a single project of the account `owner_id` belongs to and returns the deletion_id.
+    /// If there is already a pending request for the same project its deletion_id is returned instead.
+    /// Returns None if the project does not belong to the account.
+    pub(crate) async fn queue_project_deletion(
+        pg_client: &Client,
+        deletion_id: &String,
+        owner_id: &String,
+        project_id: &String,
+        request_sig: &String,
+    ) -> Result<Option<String>, Error> {
+        info!("Queueing project deletion for {}/{}", owner_id, project_id);
+
+        let rows = match pg_client
+            .query(
+                "select stm_queue_project_deletion($1::varchar, $2::varchar, $3::varchar, $4::varchar)",
+                &[deletion_id, owner_id, project_id, request_sig],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_queue_project_deletion failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP returns the deletion_id or NULL if the project is not known
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, Option<String>>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    Err(Error::from(format!("Cannot convert stm_queue_project_deletion result to String: {}", e)))
+                }
+            },
+            None => Err(Error::from("stm_queue_project_deletion returned no rows")),
+        }
+    }
+}
+
+impl EmailOwnership {
+    /// Marks the email the confirmation ID was sent to as confirmed and returns the email.
+    /// Returns None if the ID is unknown or older than `valid_for_secs`.
+    pub(crate) async fn confirm_email(
+        pg_client: &Client,
+        confirmation_id: &String,
+        valid_for_secs: i64,
+    ) -> Result<Option<String>, Error> {
+        let rows = match pg_client
+            .query("select stm_confirm_email($1::varchar, $2::bigint)", &[confirmation_id, &valid_for_secs])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_confirm_email failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP returns the email or NULL if the ID is not known
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, Option<String>>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(Error::from(format!("Cannot convert stm_confirm_email result to String: {}", e))),
+            },
+            None => Err(Error::from("stm_confirm_email returned no rows")),
+        }
+    }
+}
+
+impl SubmissionStatus {
+    /// The report was validated and saved in the inbox.
+    pub(crate) const STAGE_STORED: &'static str = "stored";
+    /// S3 was unavailable and the report was sent to the SQS spool to be saved in the inbox later.
+    pub(crate) const STAGE_SPOOLED: &'static str = "spooled";
+
+    /// Records a new stage for the submission.
+    pub(crate) async fn add_status(
+        pg_client: &Client,
+        submission_id: &String,
+        owner_id: &String,
+        stage: &str,
+    ) -> Result<(), Error> {
+        info!("Submission {} stage: {}", submission_id, stage);
+
+        if let Err(e) = pg_client
+            .execute(
+                "select stm_add_submission_status($1::varchar, $2::varchar, $3::varchar, NULL, NULL)",
+                &[submission_id, owner_id, &stage],
+            )
+            .await
+        {
+            error!("stm_add_submission_status failed with {}", e);
+            return Err(Error::from(e));
+        };
+
+        Ok(())
+    }
+
+    /// Returns the history of the submission, oldest first. The list is empty if the submission does not exist
+    /// or it was signed by a key that is not linked to the account of `owner_id`.
+    pub(crate) async fn get_status(
+        pg_client: &Client,
+        submission_id: &String,
+        owner_id: &String,
+    ) -> Result<Vec<SubmissionStatus>, Error> {
+        let rows = match pg_client
+            .query(
+                "select * from stm_get_submission_status($1::varchar, $2::varchar)",
+                &[submission_id, owner_id],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_submission_status failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        Ok(rows.iter().map(|row| SubmissionStatus::from(row)).collect())
+    }
+}
+
+/// Prepare a client for Postgres connection. Panics if cannot connect to the PG DB.
+pub(crate) async fn get_pg_client(connection_string: &String) -> tokio_postgres::Client {
+    // try to connect to PG
+    let (client, connection) = tokio_postgres::connect(connection_string, NoTls)
+        .await
+        .expect("Cannot connect to the DB.");
+
+    // Spawn the object that performs the actual comms with the DB into its own thread.
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("PG connection error: {}", e);
+            panic!();
+        }
+    });
+    debug!("client connected");
+
+    // return the client to the caller
+    client
+}
@@ -0,0 +1,114 @@
+use crate::auth::authenticate;
+use crate::config::Config;
+use crate::handler::{gw_response, ApiGatewayRequestHeaders, ERROR_500_MSG};
+use crate::postgres::DeletionQueue;
+use lambda_runtime::Error;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+/// The URL path for project deletion requests, lower case without the trailing `/`.
+pub(crate) const PATH: &str = "/delete_project";
+
+/// A request to remove a single project from the account the signing key belongs to.
+#[derive(Deserialize, Debug)]
+struct ProjectDeletionRequest {
+    /// The ID assigned to the project by the router, base58 encoded, e.g. `FZ8zezMFji6VXcWEDxckwy`.
+    project_id: String,
+}
+
+/// Validates a project deletion request and adds it to the deletion queue.
+/// The project reports are deleted and the profile is regenerated without it by `deletion` flow of stm_inbox_flows.
+pub(crate) async fn delete_project(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    body: Option<Vec<u8>>,
+) -> Result<Value, Error> {
+    let body = body.unwrap_or_default();
+
+    // check the signature, the timestamp and the nonce of the request
+    let pub_key_bs58 = match authenticate(config, headers, &body).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let deletion = match serde_json::from_slice::<ProjectDeletionRequest>(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid project deletion request: {}", e);
+            return gw_response(Some(format!("stackmuncher.com rejected the project deletion request: {}", e)), 400);
+        }
+    };
+
+    // project IDs are used as S3 folder names, so only valid IDs are allowed any further
+    if !validate_project_id(&deletion.project_id) {
+        warn!("Invalid project_id: {}", deletion.project_id);
+        return gw_response(
+            Some(
+                "stackmuncher.com rejected the project deletion request: `project_id` must be a base58 encoded UUID."
+                    .to_owned(),
+            ),
+            400,
+        );
+    }
+
+    // the signature was already validated, so it's safe to store it for audit
+    let request_sig = headers.stackmuncher_sig.clone().unwrap_or_default();
+    let deletion_id = bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string();
+
+    let deletion_id = match DeletionQueue::queue_project_deletion(
+        &config.pg_client,
+        &deletion_id,
+        &pub_key_bs58,
+        &deletion.project_id,
+        &request_sig,
+    )
+    .await
+    {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return gw_response(
+                Some(format!(
+                    "stackmuncher.com has no record of project {} for this account.",
+                    deletion.project_id
+                )),
+                404,
+            )
+        }
+        Err(_) => return gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+    };
+
+    info!("Project deletion {} queued for {}/{}", deletion_id, pub_key_bs58, deletion.project_id);
+
+    gw_response(
+        Some(format!(
+            "Deletion request {} accepted. Project {} will be removed from your profile shortly.",
+            deletion_id, deletion.project_id
+        )),
+        202,
+    )
+}
+
+/// Returns TRUE if the ID decodes from base58 into a 16-byte UUID.
+fn validate_project_id(project_id: &str) -> bool {
+    match bs58::decode(project_id).into_vec() {
+        Ok(v) => v.len() == 16,
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn validate_project_id_test() {
+    // a base58 encoded UUID
+    assert!(validate_project_id("Wgx98Rbi8nQuL9ddn3mTk1"));
+    assert!(validate_project_id(&bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string()));
+
+    // not a UUID
+    assert!(!validate_project_id(""));
+    assert!(!validate_project_id("Wgx98Rbi8nQu"));
+    assert!(!validate_project_id("9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK"));
+    // not base58
+    assert!(!validate_project_id("Wgx98Rbi8nQuL9ddn3mTk0"));
+    assert!(!validate_project_id("../9PdHabyyhf4KhHAE1Sqd"));
+    assert!(!validate_project_id("Wgx98Rbi8nQuL9ddn3mTk1/"));
+}
@@ -0,0 +1,146 @@
+use crate::config::Config;
+use crate::handler::RequestError;
+use crate::postgres::RateLimit;
+use tracing::{error, info, warn};
+
+/// `limit_type` value in `t_rate_limit_log` for limits per public key
+const LIMIT_TYPE_KEY: &str = "key";
+/// `limit_type` value in `t_rate_limit_log` for limits of read-only requests per public key
+const LIMIT_TYPE_KEY_QUERY: &str = "key_query";
+/// `limit_type` value in `t_rate_limit_log` for limits per IP address
+const LIMIT_TYPE_IP: &str = "ip";
+
+/// Checks the number of recent requests from the IP of the sender. It is done before the signature is validated
+/// to stop a flood as early as possible.
+/// * `source_ip`: `requestContext.http.sourceIp` of the request. `x-forwarded-for` is not used because its first entry
+/// is set by the client and can be rotated to dodge the limit or spoofed to exhaust the limit of someone else's IP.
+pub(crate) async fn check_ip(config: &Config, source_ip: &Option<String>) -> Result<(), RequestError> {
+    let ip = match source_ip {
+        Some(v) => v,
+        None => {
+            warn!("No sourceIp in the request. Skipping IP rate limit.");
+            return Ok(());
+        }
+    };
+
+    check(config, LIMIT_TYPE_IP, ip, config.rate_limit_per_ip).await
+}
+
+/// Checks the number of recent requests signed by the key. It should only be called after the signature was
+/// validated, otherwise anyone could exhaust the limit of someone else's key.
+pub(crate) async fn check_key(config: &Config, pub_key_bs58: &String) -> Result<(), RequestError> {
+    check(config, LIMIT_TYPE_KEY, pub_key_bs58, config.rate_limit_per_key).await
+}
+
+/// Same as `check_key`, but for read-only requests, e.g. status polls, which have a limit of their own.
+pub(crate) async fn check_key_queries(config: &Config, pub_key_bs58: &String) -> Result<(), RequestError> {
+    check(config, LIMIT_TYPE_KEY_QUERY, pub_key_bs58, config.rate_limit_queries_per_key).await
+}
+
+/// Logs the request in the DB and returns 429 with the number of seconds to wait if the limit was exceeded.
+/// A DB failure is logged and the request is let through to avoid rejecting everything while the DB is unavailable.
+async fn check(config: &Config, limit_type: &str, limit_value: &String, max_requests: i64) -> Result<(), RequestError> {
+    // 0 disables the limit
+    if max_requests < 1 {
+        return Ok(());
+    }
+
+    match RateLimit::check_rate_limit(
+        &config.pg_client,
+        limit_type,
+        limit_value,
+        config.rate_limit_window,
+        max_requests,
+        config.rate_limit_review_period,
+    )
+    .await
+    {
+        Ok(None) => {
+            info!("Rate limit OK for {} {}", limit_type, limit_value);
+            Ok(())
+        }
+        Ok(Some(oldest_age)) => {
+            let retry_after = retry_after_secs(oldest_age, config.rate_limit_window);
+            warn!(
+                "Rate limit of {} per {}s exceeded for {} {}. Retry after {}s.",
+                max_requests, config.rate_limit_window, limit_type, limit_value, retry_after
+            );
+            Err(too_many_requests(limit_type, config.rate_limit_window, retry_after))
+        }
+        Err(e) => {
+            error!("Rate limit check failed for {} {}: {}", limit_type, limit_value, e);
+            Ok(())
+        }
+    }
+}
+
+/// Returns the number of seconds until the oldest counted request falls out of the window and frees up a slot.
+/// It is never less than 1s, so that the client does not retry straight away, or more than the window.
+/// * `oldest_age`: the age of the oldest request counted towards the limit in seconds
+fn retry_after_secs(oldest_age: i64, window_secs: i64) -> i64 {
+    (window_secs - oldest_age).max(1).min(window_secs.max(1))
+}
+
+/// Returns 429 with `Retry-After` header.
+fn too_many_requests(limit_type: &str, window_secs: i64, retry_after: i64) -> RequestError {
+    RequestError::new(
+        429,
+        &format!(
+            "stackmuncher.com rejected the request: too many {} in the last {}s. Try again in {}s.",
+            describe_limit_type(limit_type),
+            window_secs,
+            retry_after
+        ),
+    )
+    .with_retry_after(retry_after)
+}
+
+/// Returns what is counted towards the limit in words the member can understand, e.g. `status queries from this key`.
+fn describe_limit_type(limit_type: &str) -> &'static str {
+    match limit_type {
+        LIMIT_TYPE_KEY => "requests signed by this key",
+        LIMIT_TYPE_KEY_QUERY => "status queries from this key",
+        LIMIT_TYPE_IP => "requests from this IP address",
+        _ => "requests",
+    }
+}
+
+#[test]
+fn retry_after_secs_test() {
+    // the oldest request has just been logged - wait for the whole window
+    assert_eq!(retry_after_secs(0, 3600), 3600);
+    assert_eq!(retry_after_secs(600, 3600), 3000);
+    // the oldest request is about to fall out of the window
+    assert_eq!(retry_after_secs(3599, 3600), 1);
+    // the request fell out of the window between the DB purge and the check
+    assert_eq!(retry_after_secs(3600, 3600), 1);
+    assert_eq!(retry_after_secs(4000, 3600), 1);
+    // clock skew between the DB and the lambda
+    assert_eq!(retry_after_secs(-5, 3600), 3600);
+}
+
+#[test]
+fn too_many_requests_test() {
+    let resp = too_many_requests(LIMIT_TYPE_KEY, 3600, 120)
+        .into_response()
+        .expect("Failed to build the response");
+
+    assert_eq!(resp["statusCode"], 429);
+    assert_eq!(resp["headers"]["Retry-After"], "120");
+    assert!(resp["body"].as_str().unwrap_or_default().contains("Try again in 120s"));
+
+    // the internal limit types are not shown to the member
+    for (limit_type, expected) in vec![
+        (LIMIT_TYPE_KEY, "too many requests signed by this key in the last 3600s"),
+        (LIMIT_TYPE_KEY_QUERY, "too many status queries from this key in the last 3600s"),
+        (LIMIT_TYPE_IP, "too many requests from this IP address in the last 3600s"),
+    ] {
+        let resp = too_many_requests(limit_type, 3600, 120)
+            .into_response()
+            .expect("Failed to build the response");
+        let body = resp["body"].as_str().unwrap_or_default().to_owned();
+        assert_eq!(resp["statusCode"], 429);
+        assert!(body.contains(expected), "{}", body);
+        assert!(!body.contains(LIMIT_TYPE_KEY_QUERY), "{}", body);
+    }
+}
@@ -0,0 +1,230 @@
+use regex::Regex;
+use serde::Serialize;
+use stackmuncher_lib::report::Report;
+use stm_shared::email::{normalize_email, ReportEmail};
+use stm_shared::{validate_commit_hash_with_ts, validate_full_commit_hash};
+use tracing::warn;
+
+/// A single problem found in the submitted report. It is returned to the app as part of `ValidationResponse`.
+#[derive(Serialize, Debug)]
+pub(crate) struct ValidationError {
+    /// The name of the report field or `body` if the report could not be read at all, e.g. `recent_project_commits`
+    pub field: String,
+    /// The offending value, if there is one, e.g. `7474684a_`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// A human-readable explanation of what's wrong
+    pub problem: String,
+}
+
+/// The body of a 400 response for a report that failed validation.
+#[derive(Serialize, Debug)]
+pub(crate) struct ValidationResponse {
+    pub message: String,
+    pub errors: Vec<ValidationError>,
+}
+
+impl ValidationError {
+    pub(crate) fn new(field: &str, value: Option<&String>, problem: &str) -> Self {
+        Self {
+            field: field.to_owned(),
+            value: value.cloned(),
+            problem: problem.to_owned(),
+        }
+    }
+}
+
+impl ValidationResponse {
+    pub(crate) fn new(errors: Vec<ValidationError>) -> Self {
+        Self {
+            message: format!("stackmuncher.com rejected the report: {} problem(s) found.", errors.len()),
+            errors,
+        }
+    }
+}
+
+/// Deserializes the unzipped report and checks the fields the router relies on.
+/// Returns all the problems found, not just the first one, so the user can see the full picture.
+/// Invalid addresses in `git_ids_included` come from the git history and cannot be fixed by the user. They are logged
+/// and left for the router to skip.
+/// * `commit_hash_regex_short`, `commit_hash_regex_full`: same as in `Config`
+pub(crate) fn validate_report(
+    report: &[u8],
+    commit_hash_regex_short: &Regex,
+    commit_hash_regex_full: &Regex,
+) -> Result<Report, Vec<ValidationError>> {
+    let report = match serde_json::from_slice::<Report>(report) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Cannot deser the report: {}", e);
+            return Err(vec![ValidationError::new(
+                "body",
+                None,
+                &format!("not a valid StackMuncher report: {}", e),
+            )]);
+        }
+    };
+
+    let mut errors: Vec<ValidationError> = Vec::new();
+
+    // fresh repos and shallow clones may have no commits, in which case the router identifies the project by its remote
+    let has_commit_history = match report.recent_project_commits.as_ref() {
+        Some(commits) => !commits.is_empty(),
+        None => false,
+    };
+    let has_remote = report.github_user_name.as_ref().map_or(false, |v| !v.trim().is_empty())
+        && report.github_repo_name.as_ref().map_or(false, |v| !v.trim().is_empty());
+
+    // the full hash of the last commit is used as part of the report name in S3
+    match report.last_contributor_commit_sha1.as_ref() {
+        Some(v) if validate_full_commit_hash(v, commit_hash_regex_full) => {}
+        Some(v) => errors.push(ValidationError::new(
+            "last_contributor_commit_sha1",
+            Some(v),
+            "must be a 40-char lower case hex SHA1 hash",
+        )),
+        None if !has_commit_history => {}
+        None => errors.push(ValidationError::new("last_contributor_commit_sha1", None, "missing")),
+    }
+
+    // commits are used to match the report to a project
+    match report.recent_project_commits.as_ref() {
+        Some(commits) if !commits.is_empty() => {
+            for commit in commits {
+                if validate_commit_hash_with_ts(commit, commit_hash_regex_short, commit_hash_regex_full).is_none() {
+                    errors.push(ValidationError::new(
+                        "recent_project_commits",
+                        Some(commit),
+                        "must be an 8 or 40-char lower case hex hash and an epoch timestamp separated by _, e.g. 7474684a_1595904770",
+                    ));
+                }
+            }
+        }
+        _ if has_remote => {}
+        _ => errors.push(ValidationError::new(
+            "recent_project_commits",
+            None,
+            "the report has no commit history or remote URL",
+        )),
+    }
+
+    // the same normaliser is used by stm_inbox_router, so placeholders like `root@localhost` pass here
+    // and are dropped by the router when the report is stored
+    for email in report.git_ids_included.iter() {
+        if normalize_email(email) == ReportEmail::Invalid {
+            warn!("Invalid git ID ignored: {}", email);
+        }
+    }
+
+    // an empty string means NO CONTACT - see https://github.com/stackmuncher/stm_server/issues/16
+    if let Some(email) = report.primary_email.as_ref() {
+        if !email.is_empty() && normalize_email(email) == ReportEmail::Invalid {
+            errors.push(ValidationError::new("primary_email", Some(email), "not a valid email address"));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(report)
+    } else {
+        warn!("Report validation failed with {} errors", errors.len());
+        Err(errors)
+    }
+}
+
+/// Returns a minimal valid report as JSON bytes with `fields` replacing the defaults.
+#[cfg(test)]
+fn test_report_json(fields: serde_json::Value) -> Vec<u8> {
+    let mut report = serde_json::json!({
+        "timestamp": "2021-08-13T02:33:06.671340840+00:00",
+        "primary_email": "max@onebro.me",
+        "report_id": "977e2161-f7ff-4365-8f3b-c7026cfb29f6",
+        "is_single_commit": false,
+        "last_contributor_commit_sha1": "8b2eea0f3ca55ad188af3957eb339df7dfe4766d",
+        "last_contributor_commit_date_epoch": 1628821732,
+        "tech": [],
+        "projects_included": [],
+        "git_ids_included": ["max@onebro.me"],
+        "recent_project_commits": ["8b2eea0f_1628821732", "7474684a_1595904770"]
+    });
+
+    for (field, value) in fields.as_object().expect("fields must be a JSON object") {
+        report[field] = value.clone();
+    }
+
+    serde_json::to_vec(&report).expect("Failed to serialize the test report")
+}
+
+#[test]
+fn validate_report_test() {
+    let short = Regex::new("[a-f0-9]{8}").unwrap();
+    let full = Regex::new("[a-f0-9]{40}").unwrap();
+    let invalid_fields = |fields: serde_json::Value| -> Vec<String> {
+        match validate_report(&test_report_json(fields), &short, &full) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.into_iter().map(|e| e.field).collect(),
+        }
+    };
+
+    // the defaults are valid
+    assert!(invalid_fields(serde_json::json!({})).is_empty());
+
+    // git IDs are baked into the history and are only logged
+    assert!(invalid_fields(
+        serde_json::json!({ "git_ids_included": ["max@onebro.me", "max@localhost", "not an email"] })
+    )
+    .is_empty());
+
+    // the primary email is set by the user and must be valid, unless it's empty for NO CONTACT
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "primary_email": "not an email" })),
+        vec!["primary_email"]
+    );
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "primary_email": "max@notlocal" })),
+        vec!["primary_email"]
+    );
+    assert!(invalid_fields(serde_json::json!({ "primary_email": "Max@OneBro.me" })).is_empty());
+    assert!(invalid_fields(serde_json::json!({ "primary_email": "max@localhost" })).is_empty());
+    assert!(invalid_fields(serde_json::json!({ "primary_email": "" })).is_empty());
+    assert!(invalid_fields(serde_json::json!({ "primary_email": null })).is_empty());
+
+    // commits
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "recent_project_commits": ["8b2eea0f_1628821732", "7474684A_1595904770"] })),
+        vec!["recent_project_commits"]
+    );
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "last_contributor_commit_sha1": "8b2eea0f" })),
+        vec!["last_contributor_commit_sha1"]
+    );
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "last_contributor_commit_sha1": null })),
+        vec!["last_contributor_commit_sha1"]
+    );
+
+    // no commits and no remote
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "recent_project_commits": [], "last_contributor_commit_sha1": null })),
+        vec!["recent_project_commits"]
+    );
+    // no commits, but there is a remote
+    assert!(invalid_fields(serde_json::json!({
+        "recent_project_commits": [],
+        "last_contributor_commit_sha1": null,
+        "github_user_name": "stackmuncher",
+        "github_repo_name": "stm_server"
+    }))
+    .is_empty());
+
+    // all problems are reported at once
+    assert_eq!(
+        invalid_fields(serde_json::json!({ "primary_email": "@", "last_contributor_commit_sha1": "x" })).len(),
+        2
+    );
+
+    // not a report
+    match validate_report(b"not json", &short, &full) {
+        Err(errors) => assert_eq!(errors[0].field, "body"),
+        Ok(_) => panic!("Not a report, but passed validation"),
+    }
+}
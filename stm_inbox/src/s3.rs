@@ -1,34 +1,88 @@
-use crate::config::Config;
+use crate::config::{Config, Storage};
 use chrono::Utc;
 use rusoto_s3::{PutObjectRequest, S3};
-use tracing::{error, info};
+use std::path::PathBuf;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
 
 /// This const must be in sync with the same constant in other crates.
 pub(crate) const REPORT_FILE_EXT_IN_S3: &str = ".gz";
+/// The number of attempts to upload a report before giving up. The lambda timeout should allow for all of them.
+const UPLOAD_ATTEMPTS: u32 = 3;
+/// The delay before the 2nd attempt. It doubles with every subsequent attempt.
+const UPLOAD_BACKOFF_MS: u64 = 250;
+
+/// Returns the S3 key for a new submission, e.g.
+/// `queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`
+pub(crate) fn build_s3_key(config: &Config, pub_key: &String, submission_id: &String) -> String {
+    // the public key is definitely a base58 string because it was decoded for signature validation,
+    // so it's safe to be used in the object name as-is, same as the base58 submission ID
+    let report_name = [
+        Utc::now().timestamp().to_string(),
+        submission_id.clone(),
+        pub_key.clone(),
+    ]
+    .join("_");
+
+    [&config.s3_prefix, "/", &report_name, REPORT_FILE_EXT_IN_S3].concat()
+}
 
 /// Reuses the existing S3 client and calls `put_object` for the provided payload and config.
-/// The reports are stored under `timestamp_pubkey.json`
+/// The reports are stored under `s3_key` generated by `build_s3_key()`.
 /// They are just dumped there as fast as possible for later processing.
-pub(crate) async fn upload_to_s3(config: &Config, report_bytes: Vec<u8>, pub_key: String) {
-    // the public key is definitely a base58 string because it was decoded for signature validation,
-    // so it's safe to be used in the object name as-is
-    let report_name = [Utc::now().timestamp().to_string(), pub_key].join("_");
-
-    // the resulting key looks like `queue/1621680890_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`
-    let s3_key: String = [&config.s3_prefix, "/", &report_name, REPORT_FILE_EXT_IN_S3].concat();
-
-    info!("Uploading to S3 {}", s3_key);
-    if let Err(e) = config
-        .s3_client
-        .put_object(PutObjectRequest {
-            bucket: config.s3_bucket.clone(),
-            key: s3_key.clone(),
-            body: Some(report_bytes.into()),
-            ..Default::default()
-        })
-        .await
-    {
-        error!("Uploading failed for {} with {}", s3_key, e);
-        return;
+/// Transient S3 errors are retried a few times with an exponential backoff. Returns an error if all attempts failed.
+pub(crate) async fn upload_to_s3(config: &Config, report_bytes: &Vec<u8>, s3_key: &String) -> Result<(), ()> {
+    // local storage is used for debugging and self-hosting instead of S3
+    if let Storage::LocalDir(dir) = &config.storage {
+        return save_to_local_dir(dir, report_bytes, s3_key).await;
+    }
+
+    let mut backoff_ms = UPLOAD_BACKOFF_MS;
+
+    for attempt in 1..=UPLOAD_ATTEMPTS {
+        info!("Uploading to S3 {}, attempt {}", s3_key, attempt);
+        match config
+            .s3_client
+            .put_object(PutObjectRequest {
+                bucket: config.s3_bucket.clone(),
+                key: s3_key.clone(),
+                body: Some(report_bytes.clone().into()),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                warn!("Uploading failed for {} with {}", s3_key, e);
+            }
+        }
+
+        if attempt < UPLOAD_ATTEMPTS {
+            sleep(Duration::from_millis(backoff_ms)).await;
+            backoff_ms *= 2;
+        }
     }
+
+    error!("Giving up on uploading {} after {} attempts", s3_key, UPLOAD_ATTEMPTS);
+    Err(())
+}
+
+/// Saves the report in a local directory under the same path as it would have in S3 bucket.
+async fn save_to_local_dir(dir: &PathBuf, report_bytes: &Vec<u8>, s3_key: &String) -> Result<(), ()> {
+    let path = dir.join(s3_key);
+    info!("Saving to {}", path.to_string_lossy());
+
+    if let Some(parent) = path.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            error!("Cannot create {}: {}", parent.to_string_lossy(), e);
+            return Err(());
+        }
+    }
+
+    if let Err(e) = tokio::fs::write(&path, report_bytes).await {
+        error!("Cannot write to {}: {}", path.to_string_lossy(), e);
+        return Err(());
+    }
+
+    Ok(())
 }
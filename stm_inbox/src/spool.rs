@@ -0,0 +1,41 @@
+use crate::config::Config;
+use stm_shared::sqs::{self, SpooledS3Object};
+use tracing::{error, info, warn};
+
+/// Sends the report to the SQS spool queue if one is configured. The report is saved in S3 later
+/// by `stm_inbox_flows -flow spool_drain`.
+/// Returns an error if there is no spool, the report is too large for an SQS message or SQS failed as well.
+pub(crate) async fn spool_report(config: &Config, report_bytes: &Vec<u8>, s3_key: &String) -> Result<(), ()> {
+    let spool_sqs_url = match config.spool_sqs_url.as_ref() {
+        Some(v) => v,
+        None => {
+            info!("No spool configured");
+            return Err(());
+        }
+    };
+
+    let msg = SpooledS3Object {
+        s3_bucket: config.s3_bucket.clone(),
+        s3_key: s3_key.clone(),
+        payload: base64::encode(report_bytes),
+    };
+
+    let msg = match serde_json::to_string(&msg) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to serialize the spool msg: {}", e);
+            return Err(());
+        }
+    };
+
+    if msg.len() > SpooledS3Object::MAX_SQS_MSG_SIZE {
+        warn!("The report is too large for the spool: {} bytes", msg.len());
+        return Err(());
+    }
+
+    sqs::send(&config.sqs_client, msg, spool_sqs_url).await?;
+
+    info!("Report spooled as {}", s3_key);
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use crate::auth::authenticate_query;
+use crate::config::Config;
+use crate::handler::{gw_json_response, gw_response, ApiGatewayRequestHeaders, ERROR_500_MSG};
+use crate::postgres::SubmissionStatus;
+use lambda_runtime::Error;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::{error, info};
+
+/// The URL path for submission status requests, lower case without the trailing `/`.
+/// E.g. `GET /status?id=Wgx98Rbi8nQuL9ddn3mTk1`
+pub(crate) const PATH: &str = "/status";
+
+/// The name of the query string parameter with the submission ID.
+const SUBMISSION_ID_PARAM: &str = "id";
+
+/// Returned to the app after a successful submission and in response to a status request.
+#[derive(Serialize, Debug)]
+pub(crate) struct SubmissionReceipt {
+    /// A base58 encoded UUID, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+    pub submission_id: String,
+    /// The latest known stage of processing, e.g. `stored`
+    pub stage: String,
+    /// All stages the submission went through, oldest first. Not included in the receipt for a new submission.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<SubmissionStatus>,
+}
+
+/// Generates a new submission ID as a base58 encoded UUID, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`.
+pub(crate) fn new_submission_id() -> String {
+    bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string()
+}
+
+/// Returns the processing history of a submission. The request must be signed by the same key as the submission
+/// or any other key linked to the same account. The signed content is the submission ID.
+pub(crate) async fn get_submission_status(
+    config: &Config,
+    headers: &ApiGatewayRequestHeaders,
+    query_string_parameters: &Option<HashMap<String, String>>,
+) -> Result<Value, Error> {
+    let submission_id = match query_string_parameters
+        .as_ref()
+        .and_then(|params| params.get(SUBMISSION_ID_PARAM))
+        .map(|v| v.trim().to_owned())
+    {
+        Some(v) if validate_submission_id(&v) => v,
+        v => {
+            error!("Invalid submission ID: {:?}", v);
+            return gw_response(
+                Some("stackmuncher.com rejected the request: `id` query string parameter must contain the submission ID from the receipt.".to_owned()),
+                400,
+            );
+        }
+    };
+
+    info!("Status request for {}", submission_id);
+
+    // there is no body in GET requests, so the signature covers the submission ID instead
+    // status polls have a rate limit of their own, so that they do not use up the quota for submissions
+    let pub_key_bs58 = match authenticate_query(config, headers, submission_id.as_bytes()).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let history = match SubmissionStatus::get_status(&config.pg_client, &submission_id, &pub_key_bs58).await {
+        Ok(v) => v,
+        Err(_) => return gw_response(Some(ERROR_500_MSG.to_owned()), 500),
+    };
+
+    // the submission may exist, but belong to someone else - there is no need to tell the difference
+    let stage = match history.last() {
+        Some(v) => v.stage.clone(),
+        None => {
+            return gw_response(
+                Some(format!("stackmuncher.com has no record of submission {} for this key.", submission_id)),
+                404,
+            )
+        }
+    };
+
+    gw_json_response(
+        &SubmissionReceipt {
+            submission_id,
+            stage,
+            history,
+        },
+        200,
+    )
+}
+
+/// Returns TRUE if the ID decodes from base58 into a 16-byte UUID.
+fn validate_submission_id(submission_id: &str) -> bool {
+    match bs58::decode(submission_id).into_vec() {
+        Ok(v) => v.len() == 16,
+        Err(_) => false,
+    }
+}
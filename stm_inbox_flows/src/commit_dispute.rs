@@ -0,0 +1,123 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::{Client, Row};
+use tracing::{debug, error, info};
+
+/// Corresponds to `t_commit_dispute` table. All SPs and the table creation reside in stm_inbox project for consistency.
+/// The disputes are flagged by stm_inbox_router.
+#[derive(Debug, Clone)]
+pub(crate) struct CommitDispute {
+    /// The canonical owner_id of the member who submitted the report
+    pub owner_id: String,
+    pub project_id: String,
+    /// `co_contributors` or `full_history_claim`
+    pub dispute_type: String,
+    /// Members or GitHub owners (prefixed with `gh:`) of the shared commits
+    pub other_owner_ids: Vec<String>,
+    pub shared_commits: i32,
+    pub report_commits: i32,
+    pub flagged_ts: DateTime<Utc>,
+    /// None = pending review, `accepted` or `rejected`
+    pub resolution: Option<String>,
+}
+
+impl From<&Row> for CommitDispute {
+    /// Creates a new structure from tokio_postgres::Row
+    fn from(row: &Row) -> Self {
+        Self {
+            owner_id: row.get("owner_id"),
+            project_id: row.get("project_id"),
+            dispute_type: row.get("dispute_type"),
+            other_owner_ids: row.get("other_owner_ids"),
+            shared_commits: row.get("shared_commits"),
+            report_commits: row.get("report_commits"),
+            flagged_ts: row.get("flagged_ts"),
+            resolution: row.get("resolution"),
+        }
+    }
+}
+
+impl CommitDispute {
+    /// The member is a genuine contributor and the project is included in the profile.
+    pub(crate) const RESOLUTION_ACCEPTED: &'static str = "accepted";
+    /// The claim is false and the project stays out of the profile.
+    pub(crate) const RESOLUTION_REJECTED: &'static str = "rejected";
+
+    /// Returns all disputes pending review, oldest first, or all disputes of the member if `owner_id` is set.
+    pub(crate) async fn get(pg_client: &Client, owner_id: Option<&String>) -> Result<Vec<CommitDispute>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_commit_disputes($1::varchar)", &[&owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_commit_disputes failed with {}", e);
+                return Err(());
+            }
+        };
+
+        Ok(rows.iter().map(|row| CommitDispute::from(row)).collect())
+    }
+
+    /// Returns the IDs of the projects of the member that must be left out of the profile: full history claims
+    /// pending review or rejected.
+    pub(crate) async fn get_excluded_projects(pg_client: &Client, owner_id: &String) -> Result<Vec<String>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_excluded_projects($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_excluded_projects for {} failed with {}", owner_id, e);
+                return Err(());
+            }
+        };
+
+        let mut project_ids: Vec<String> = Vec::new();
+        for row in rows {
+            match row.try_get::<_, String>(0) {
+                Ok(v) => project_ids.push(v),
+                Err(e) => {
+                    error!("Cannot convert excluded project_id to String for {}: {}", owner_id, e);
+                    return Err(());
+                }
+            }
+        }
+
+        debug!("Excluded projects for {}: {}", owner_id, project_ids.join(", "));
+
+        Ok(project_ids)
+    }
+
+    /// Sets the resolution of the dispute and queues up the dev for the profile to be regenerated.
+    /// Returns FALSE if there is no such dispute.
+    pub(crate) async fn resolve(
+        pg_client: &Client,
+        owner_id: &String,
+        project_id: &String,
+        resolution: &String,
+    ) -> Result<bool, ()> {
+        info!("Resolving dispute {}/{} as {}", owner_id, project_id, resolution);
+
+        let rows = match pg_client
+            .query(
+                "select stm_resolve_commit_dispute($1::varchar, $2::varchar, $3::varchar)",
+                &[owner_id, project_id, resolution],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_resolve_commit_dispute failed with {}", e);
+                return Err(());
+            }
+        };
+
+        match rows.get(0).map(|row| row.try_get::<_, i64>(0)) {
+            Some(Ok(v)) => Ok(v > 0),
+            _ => {
+                error!("stm_resolve_commit_dispute for {}/{} returned no count", owner_id, project_id);
+                Err(())
+            }
+        }
+    }
+}
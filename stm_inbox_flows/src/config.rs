@@ -2,6 +2,7 @@ use chrono::Utc;
 use regex::Regex;
 use rusoto_core::credential::{AwsCredentials, DefaultCredentialsProvider, ProvideAwsCredentials};
 use rusoto_s3::S3Client;
+use rusoto_sqs::SqsClient;
 use serde::Deserialize;
 pub use stackmuncher_lib::config::Config as CoreConfig;
 use std::fs;
@@ -35,6 +36,64 @@ pub(crate) struct JobQueues {
     pub con_str: String,
 }
 
+/// ### Params of the SQS spool used by stm_inbox for reports it could not save in S3
+#[derive(Debug, Deserialize)]
+pub(crate) struct Spool {
+    /// The URL of the spool queue, e.g. `https://sqs.us-east-1.amazonaws.com/028534811986/stm_inbox_spool`
+    pub sqs_url: String,
+}
+
+/// ### Location of the inbox queue processed by stm_inbox_router
+#[derive(Debug, Deserialize)]
+pub(crate) struct Inbox {
+    /// The inbox bucket, same as STM_INBOX_S3_BUCKET in stm_inbox_router, e.g. `stm-subs-j5awwhv9pb9np7d`
+    pub s3_bucket: String,
+    /// The queue prefix, same as STM_INBOX_S3_PREFIX in stm_inbox_router, e.g. `queue`
+    pub s3_prefix: String,
+    /// A prefix in the inbox bucket with a copy of all raw submissions, e.g. `archive`.
+    /// Only required for `replay` flow with `-archive`.
+    #[serde(default)]
+    pub archive_s3_prefix: Option<String>,
+}
+
+/// ### Params of the mailer used by `email_confirmation` flow
+/// Either `smtp` or `dir` must be present.
+#[derive(Debug, Deserialize)]
+pub(crate) struct MailerConfig {
+    /// The sender address, e.g. `StackMuncher <noreply@stackmuncher.com>`
+    pub from: String,
+    /// The confirmation ID is appended to this URL, e.g. `https://inbox.stackmuncher.com/confirm_email?id=`
+    pub confirmation_url: String,
+    /// An SMTP relay to send the emails through. Takes precedence over `dir`.
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+    /// A local folder to write the emails to as `.eml` files instead of sending them, e.g. for local runs.
+    #[serde(default)]
+    pub dir: Option<String>,
+}
+
+/// ### SMTP relay details
+#[derive(Debug, Deserialize)]
+pub(crate) struct SmtpConfig {
+    /// The relay host name, e.g. `email-smtp.us-east-1.amazonaws.com`. The connection is always over TLS.
+    pub host: String,
+    /// Defaults to 465 if not set.
+    #[serde(default)]
+    pub port: Option<u16>,
+    pub username: String,
+    pub password: String,
+}
+
+/// ### Retention policy for timestamped project reports used by `retention` flow
+#[derive(Debug, Deserialize)]
+pub(crate) struct Retention {
+    /// The number of the most recent reports to keep in every project folder. Must be at least 1.
+    pub keep_last: usize,
+    /// Also keep the most recent report of every calendar month if `true`. Defaults to `false`.
+    #[serde(default)]
+    pub keep_one_per_month: bool,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct Config {
     /// Defaults to INFO
@@ -53,12 +112,51 @@ pub(crate) struct Config {
     pub flow: Flow,
     /// DB connection string, timeouts and other properties required to interact with DB-based job queues.
     pub job_queues: JobQueues,
+    /// The SQS spool of stm_inbox. Only required for `spool_drain` flow.
+    #[serde(default)]
+    pub spool: Option<Spool>,
+    /// The inbox of stm_inbox_router. Only required for `failed`, `replay` and `deletion` flows.
+    #[serde(default)]
+    pub inbox: Option<Inbox>,
+    /// Email settings. Only required for `email_confirmation` flow.
+    #[serde(default)]
+    pub mailer: Option<MailerConfig>,
+    /// Retention policy for timestamped reports. Only required for `retention` flow.
+    #[serde(default)]
+    pub retention: Option<Retention>,
+    /// Set by `-redrive` CLI arg for `failed` flow to move failed submissions back into the inbox queue.
+    /// The flow only lists them otherwise.
+    #[serde(skip)]
+    pub redrive: bool,
+    /// Set by `-dry-run` CLI arg for `replay` flow to only list the reports it would replay,
+    /// for `identities` flow to only list the duplicate accounts and for `retention` flow to only list the reports
+    /// it would delete.
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Set by `-archive` CLI arg for `replay` flow to replay raw submissions from `inbox.archive_s3_prefix`
+    /// instead of the reports in the members' folders.
+    #[serde(skip)]
+    pub archive: bool,
+    /// Set by `-owner <owner_id>` CLI arg for `replay` flow to replay the reports of a single member
+    /// and for `disputes` flow to list or resolve the disputes of a single member and for `identities` flow
+    /// to list the merges of a single member and for `retention` flow to apply the policy to a single member.
+    #[serde(skip)]
+    pub owner_id: Option<String>,
+    /// Set by `-project <project_id>` CLI arg for `disputes` flow to select the dispute to resolve.
+    #[serde(skip)]
+    pub project_id: Option<String>,
+    /// Set by `-resolve <accepted|rejected>` CLI arg for `disputes` flow.
+    #[serde(skip)]
+    pub resolution: Option<String>,
     /// Contains `stackmuncher::config::Config`, when applicable. The upstream code should always init this member for the downstream code to use `unwrap`.
     #[serde(skip)]
     pub core_config: Option<CoreConfig>,
     /// Contains an initialized S3 Client for reuse. Doesn't need to be public. It is retrieved using a function call.
     #[serde(skip)]
     s3_client_inner: Option<S3Client>,
+    /// Contains an initialized SQS Client for reuse. Doesn't need to be public. It is retrieved using a function call.
+    #[serde(skip)]
+    sqs_client_inner: Option<SqsClient>,
     /// No-SQL field value validation regex - the value would be invalid if it's a match
     /// Doesn't need to be public. It is retrieved using a function call.
     #[serde(skip)]
@@ -77,6 +175,14 @@ pub(crate) struct Config {
 #[derive(Debug)]
 pub(crate) enum Flow {
     DevQueue,
+    SpoolDrain,
+    Deletion,
+    EmailConfirmation,
+    Failed,
+    Replay,
+    Disputes,
+    Identities,
+    Retention,
     Help,
 }
 
@@ -93,9 +199,25 @@ impl FromStr for Flow {
         // I could not use `Config::CLI_MODES[0]` directly in match arms
         // this is a hack to use the values from the arrat instead of literals
         const S0: &str = Config::CLI_MODES[0];
+        const S1: &str = Config::CLI_MODES[1];
+        const S2: &str = Config::CLI_MODES[2];
+        const S3: &str = Config::CLI_MODES[3];
+        const S4: &str = Config::CLI_MODES[4];
+        const S5: &str = Config::CLI_MODES[5];
+        const S6: &str = Config::CLI_MODES[6];
+        const S7: &str = Config::CLI_MODES[7];
+        const S8: &str = Config::CLI_MODES[8];
 
         match s {
             S0 => Ok(Flow::DevQueue),
+            S1 => Ok(Flow::SpoolDrain),
+            S2 => Ok(Flow::Deletion),
+            S3 => Ok(Flow::EmailConfirmation),
+            S4 => Ok(Flow::Failed),
+            S5 => Ok(Flow::Replay),
+            S6 => Ok(Flow::Disputes),
+            S7 => Ok(Flow::Identities),
+            S8 => Ok(Flow::Retention),
             _ => {
                 if !s.is_empty() {
                     println!("Invalid flow type: {}", s);
@@ -108,7 +230,17 @@ impl FromStr for Flow {
 
 impl Config {
     /// The order of items in this array must correspond to the order of `impl FromStr for Flow`
-    pub(crate) const CLI_MODES: [&'static str; 1] = ["dev_queue"];
+    pub(crate) const CLI_MODES: [&'static str; 9] = [
+        "dev_queue",
+        "spool_drain",
+        "deletion",
+        "email_confirmation",
+        "failed",
+        "replay",
+        "disputes",
+        "identities",
+        "retention",
+    ];
 
     /// Inits values from ENV vars and the command line arguments
     pub(crate) async fn new() -> Self {
@@ -148,6 +280,28 @@ impl Config {
                             }
                         }
                     }
+                    "-redrive" => {
+                        config.redrive = true;
+                    }
+                    "-dry-run" => {
+                        config.dry_run = true;
+                    }
+                    "-archive" => {
+                        config.archive = true;
+                    }
+                    "-owner" => {
+                        config.owner_id = Some(args.peek().expect("-owner arg is missing the owner_id").clone());
+                    }
+                    "-project" => {
+                        config.project_id = Some(args.peek().expect("-project arg is missing the project_id").clone());
+                    }
+                    "-resolve" => {
+                        config.resolution = Some(
+                            args.peek()
+                                .expect("-resolve arg is missing one of [accepted, rejected]")
+                                .to_lowercase(),
+                        );
+                    }
                     _ => { //do nothing
                     }
                 };
@@ -161,6 +315,7 @@ impl Config {
 
         // init a reusable S3 client
         config.s3_client_inner = Some(s3::generate_s3_client(&config.s3_region));
+        config.sqs_client_inner = Some(SqsClient::new(config.s3_region.0.clone()));
 
         // pre-compile NOSQL param validation regex
         // A regex formula to check for unsafe values to insert into another regex string.
@@ -211,6 +366,11 @@ impl Config {
         self.s3_client_inner.as_ref().unwrap()
     }
 
+    /// Unwraps `sqs_client_inner` member with an initialized SqsClient.
+    pub(crate) fn sqs_client(&self) -> &SqsClient {
+        self.sqs_client_inner.as_ref().unwrap()
+    }
+
     /// Returns the log level as struct. Defaults to INFO if none was provided. Panics if the value is invalid.
     pub(crate) fn string_to_log_level(s: &str) -> tracing::Level {
         if s.is_empty() {
@@ -0,0 +1,159 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+use tokio_postgres::{Client, Row};
+use tracing::{debug, error, info};
+
+/// Corresponds to `t_deletion_queue` table. All SPs and the table creation reside in stm_inbox project for consistency.
+#[derive(Debug, Clone)]
+pub(crate) struct DeletionJob {
+    /// A base58 encoded UUID generated by the inbox, e.g. `Wgx98Rbi8nQuL9ddn3mTk1`
+    pub deletion_id: String,
+    /// The canonical owner_id of the account to be deleted
+    pub owner_id: String,
+    /// None = delete the entire account, Some = delete only this project
+    pub project_id: Option<String>,
+    /// The GitHub login of the member from `t_dev`, if it was validated
+    pub gh_login: Option<String>,
+    /// The number of attempts to process this request, including the current one
+    pub attempts: i32,
+}
+
+/// A snapshot of the deletion queue for `stm_stats_deletion_queue_counts` ES index.
+/// The field names must match what `stats.html` template of stm_html_ui expects.
+#[derive(Debug, Serialize)]
+pub(crate) struct DeletionQueueCounts {
+    pub ts: i64,
+    pub iso: String,
+    pub queue_total: i64,
+    pub to_delete: i64,
+    pub failed: i64,
+    pub oldest: Option<String>,
+    pub newest: Option<String>,
+    pub added_10m: i64,
+    pub added_1hr: i64,
+    pub added_24hr: i64,
+}
+
+impl From<&Row> for DeletionJob {
+    /// Creates a new structure from tokio_postgres::Row
+    fn from(row: &Row) -> Self {
+        Self {
+            deletion_id: row.get("deletion_id"),
+            owner_id: row.get("owner_id"),
+            project_id: row.get("project_id"),
+            gh_login: row.get("gh_login"),
+            attempts: row.get("attempts"),
+        }
+    }
+}
+
+impl DeletionJob {
+    /// Returns a list of pending deletion requests and marks them as in-flight.
+    /// Requests that have been in flight for longer than `retry_after_secs` are returned again.
+    pub(crate) async fn get_new(
+        pg_client: &Client,
+        jobs_max: i32,
+        retry_after_secs: i64,
+        max_attempts: i32,
+    ) -> Result<Vec<DeletionJob>, ()> {
+        let rows = match pg_client
+            .query(
+                "select * from stm_get_deletion_jobs($1::integer, $2::bigint, $3::integer)",
+                &[&jobs_max, &retry_after_secs, &max_attempts],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_deletion_jobs failed with {}", e);
+                return Err(());
+            }
+        };
+
+        debug!("Deletion jobs: {}", rows.len());
+
+        Ok(rows.iter().map(|row| DeletionJob::from(row)).collect())
+    }
+
+    /// Purges all PG records of the account or the project and marks the request as completed.
+    /// `details` is what was deleted outside of PG and is stored for audit together with the PG row counts.
+    pub(crate) async fn mark_completed(pg_client: &Client, deletion_id: &String, details: &String) -> Result<(), ()> {
+        info!("Completing deletion {}: {}", deletion_id, details);
+
+        if let Err(e) = pg_client
+            .execute("select stm_complete_deletion_job($1::varchar, $2::varchar)", &[deletion_id, details])
+            .await
+        {
+            error!("stm_complete_deletion_job failed with {}", e);
+            return Err(());
+        };
+
+        Ok(())
+    }
+
+    /// Returns all keys of the accounts that were deleted. Their stored submissions must not be routed again.
+    pub(crate) async fn get_deleted_owners(pg_client: &Client) -> Result<HashSet<String>, ()> {
+        let rows = match pg_client.query("select * from stm_get_deleted_owners()", &[]).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_deleted_owners failed with {}", e);
+                return Err(());
+            }
+        };
+
+        Ok(rows.iter().map(|row| row.get::<_, String>(0)).collect())
+    }
+
+    /// Stores the reason of the failed attempt on the request. It is retried after the timeout as before.
+    /// A failure to record it is logged and ignored.
+    pub(crate) async fn mark_failed(pg_client: &Client, deletion_id: &String, error: &String) {
+        info!("Deletion {} failed: {}", deletion_id, error);
+
+        if let Err(e) = pg_client
+            .execute("select stm_fail_deletion_job($1::varchar, $2::varchar)", &[deletion_id, error])
+            .await
+        {
+            error!("stm_fail_deletion_job failed with {}", e);
+        };
+    }
+}
+
+impl DeletionQueueCounts {
+    /// Returns the current state of the deletion queue.
+    pub(crate) async fn get(pg_client: &Client) -> Result<Self, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_deletion_queue_counts()", &[])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_deletion_queue_counts failed with {}", e);
+                return Err(());
+            }
+        };
+
+        let row = match rows.get(0) {
+            Some(v) => v,
+            None => {
+                error!("stm_get_deletion_queue_counts returned no rows");
+                return Err(());
+            }
+        };
+
+        let now = Utc::now();
+
+        Ok(Self {
+            ts: now.timestamp(),
+            iso: now.to_rfc3339(),
+            queue_total: row.get("queue_total"),
+            to_delete: row.get("to_delete"),
+            failed: row.get("failed"),
+            oldest: row.get::<_, Option<DateTime<Utc>>>("oldest").map(|v| v.to_rfc3339()),
+            newest: row.get::<_, Option<DateTime<Utc>>>("newest").map(|v| v.to_rfc3339()),
+            added_10m: row.get("added_10m"),
+            added_1hr: row.get("added_1hr"),
+            added_24hr: row.get("added_24hr"),
+        })
+    }
+}
@@ -11,6 +11,9 @@ use stm_shared::s3;
 pub(crate) struct DevProfile {
     pub owner_id: String,
     pub updated_at: String,
+    /// Emails confirmed by the member via a link sent by `email_confirmation` flow.
+    /// Emails from the report itself are not verified and must not be used as contacts.
+    pub verified_emails: Vec<String>,
     #[serde(skip_deserializing)]
     pub report: Option<Report>,
 }
@@ -37,6 +40,9 @@ pub(crate) struct GitHubUser {
     pub following: i32,
     pub created_at: String,
     pub updated_at: String,
+    /// Same as `DevProfile::verified_emails`. It is not part of GitHub API.
+    #[serde(skip_deserializing)]
+    pub verified_emails: Vec<String>,
     #[serde(skip_deserializing)]
     pub report: Option<Report>,
 }
@@ -91,11 +97,12 @@ impl DevProfile {
     }
 
     /// Returns itself with the report embedded
-    pub(crate) fn new(combined_report: Option<Report>, owner_id: &String) -> Self {
+    pub(crate) fn new(combined_report: Option<Report>, owner_id: &String, verified_emails: Vec<String>) -> Self {
         DevProfile {
             updated_at: Utc::now().to_rfc3339(),
             report: combined_report,
             owner_id: owner_id.clone(),
+            verified_emails,
         }
     }
 
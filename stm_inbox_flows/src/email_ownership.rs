@@ -0,0 +1,102 @@
+use tokio_postgres::Client;
+use tracing::{debug, error, info};
+
+/// Corresponds to `t_email_ownership` table. All SPs and the table creation reside in stm_inbox project for consistency.
+pub(crate) struct EmailOwnership {}
+
+/// An unconfirmed primary email that needs a confirmation link.
+#[derive(Debug)]
+pub(crate) struct EmailConfirmationJob {
+    /// The key the email was reported with, not necessarily the canonical owner_id
+    pub owner_id: String,
+    pub email: String,
+}
+
+impl EmailOwnership {
+    /// Returns a list of unconfirmed primary emails that were never sent a confirmation link
+    /// or were sent one more than `resend_after_secs` ago.
+    pub(crate) async fn get_confirmation_jobs(
+        pg_client: &Client,
+        jobs_max: i32,
+        resend_after_secs: i64,
+    ) -> Result<Vec<EmailConfirmationJob>, ()> {
+        let rows = match pg_client
+            .query(
+                "select * from stm_get_email_confirmation_jobs($1::integer, $2::bigint)",
+                &[&jobs_max, &resend_after_secs],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_email_confirmation_jobs failed with {}", e);
+                return Err(());
+            }
+        };
+
+        debug!("Email confirmation jobs: {}", rows.len());
+
+        Ok(rows
+            .iter()
+            .map(|row| EmailConfirmationJob {
+                owner_id: row.get("owner_id"),
+                email: row.get("email"),
+            })
+            .collect())
+    }
+
+    /// Stores the ID sent in the confirmation link. It is matched against the ID in `GET /confirm_email` of stm_inbox.
+    /// `error` is the reason the email could not be delivered at all, the ID is stored to hold off retries until it expires.
+    pub(crate) async fn set_confirmation_id(
+        pg_client: &Client,
+        owner_id: &String,
+        email: &String,
+        confirmation_id: &String,
+        error: Option<&String>,
+    ) -> Result<(), ()> {
+        match error {
+            Some(e) => info!("Confirmation {} for {} failed: {}", confirmation_id, email, e),
+            None => info!("Confirmation {} sent to {}", confirmation_id, email),
+        }
+
+        if let Err(e) = pg_client
+            .execute(
+                "select stm_set_email_confirmation_id($1::varchar, $2::varchar, $3::varchar, $4::varchar)",
+                &[owner_id, email, confirmation_id, &error],
+            )
+            .await
+        {
+            error!("stm_set_email_confirmation_id failed with {}", e);
+            return Err(());
+        };
+
+        Ok(())
+    }
+
+    /// Returns all confirmed emails of the member account, including emails reported with any of the linked keys.
+    pub(crate) async fn get_confirmed_emails(pg_client: &Client, owner_id: &String) -> Result<Vec<String>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_confirmed_emails($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_confirmed_emails for {} failed with {}", owner_id, e);
+                return Err(());
+            }
+        };
+
+        let mut emails: Vec<String> = Vec::new();
+        for row in rows {
+            match row.try_get::<_, String>(0) {
+                Ok(v) => emails.push(v),
+                Err(e) => {
+                    error!("Cannot convert confirmed email to String for {}: {}", owner_id, e);
+                    return Err(());
+                }
+            }
+        }
+
+        Ok(emails)
+    }
+}
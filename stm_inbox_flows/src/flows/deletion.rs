@@ -0,0 +1,352 @@
+use crate::config::Config;
+use crate::deletion_job::{DeletionJob, DeletionQueueCounts};
+use crate::dev_profile::GitHubUser;
+use crate::jobs::wait_for_next_cycle;
+use crate::key_link::KeyLink;
+use chrono::Utc;
+use stm_shared::elastic;
+use stm_shared::pgsql::get_pg_client;
+use stm_shared::s3::{
+    self, S3_FAILED_SUBMISSION_SIDECAR_EXT, S3_FOLDER_FAILED_SUBMISSIONS, S3_FOLDER_REDRIVING_SUBMISSIONS,
+};
+use tokio::time::Instant;
+use tokio_postgres::Client as PgClient;
+use tracing::{error, info, warn};
+
+/// Deletion requests are rare, so there is no need to poll the DB often
+const MIN_CYCLE_DURATION_IN_MS: u64 = 60000;
+/// The max number of requests processed per cycle
+const MAX_NUMBER_OF_DELETION_JOBS: i32 = 10;
+/// A request that was not completed within this time is picked up again
+const DELETION_RETRY_AFTER_SECS: i64 = 3600;
+/// The request is left in the queue for investigation after this many attempts
+const MAX_DELETION_ATTEMPTS: i32 = 10;
+/// S3 DeleteObjects API accepts up to 1000 keys per call
+const S3_DELETE_BATCH_SIZE: usize = 1000;
+/// Submissions are always stored as `.gz`, same as `REPORT_FILE_EXT_IN_S3` in stm_inbox_router
+const SUBMISSION_EXT: &str = ".gz";
+/// ES index read by the stats page of stm_html_ui
+const ES_IDX_DELETION_QUEUE_COUNTS: &str = "stm_stats_deletion_queue_counts";
+/// How often the deletion queue counts are saved in ES
+const STATS_INTERVAL_IN_SECS: i64 = 600;
+
+/// Purges all data of members who requested their accounts to be deleted via stm_inbox:
+/// S3 reports of all linked keys, raw submissions in the inbox bucket, ES profiles and PG records.
+/// Project deletion requests only remove the project. The requests are kept in `t_deletion_queue` for audit.
+pub(crate) async fn purge_deleted_accounts(mut config: Config) {
+    info!("Purging accounts from the deletion queue.");
+
+    // raw submissions are full reports with emails and have to be purged as well
+    if config.inbox.is_none() {
+        error!("Missing `inbox` section in config.json. It is required for this flow.");
+        return;
+    }
+
+    // used to determine repeated errors and abort processing
+    let mut err_counter = 0usize;
+    const MAX_CONSECUTIVE_ERRORS: usize = 10;
+
+    // try to get the jobs DB client (postgres)
+    // this line panics if the connection fails
+    let pg_client = get_pg_client(&config.job_queues.con_str).await;
+
+    // track the time it takes for a single cycle to complete
+    let mut main_loop_start = Instant::now();
+    // set to false by no-jobs cycle and to true when there are jobs
+    let mut log_sleep_msg = true;
+    // the stats are saved on the first cycle and then every STATS_INTERVAL_IN_SECS
+    let mut last_stats_ts = 0i64;
+
+    loop {
+        // terminate the process if it keeps failing
+        if err_counter >= MAX_CONSECUTIVE_ERRORS {
+            error!("Too many errors. Exiting.");
+            std::process::exit(1);
+        }
+
+        // renew the creds if needed
+        config.renew_aws_credentials().await;
+
+        if Utc::now().timestamp() - last_stats_ts >= STATS_INTERVAL_IN_SECS {
+            save_queue_counts(&config, &pg_client).await;
+            last_stats_ts = Utc::now().timestamp();
+        }
+
+        let jobs = match DeletionJob::get_new(
+            &pg_client,
+            MAX_NUMBER_OF_DELETION_JOBS,
+            DELETION_RETRY_AFTER_SECS,
+            MAX_DELETION_ATTEMPTS,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(_) => {
+                err_counter += 1;
+                error!("Attempt {}", err_counter);
+                wait_for_next_cycle(&main_loop_start, true, MIN_CYCLE_DURATION_IN_MS).await;
+                main_loop_start = Instant::now();
+                continue;
+            }
+        };
+
+        if jobs.is_empty() {
+            wait_for_next_cycle(&main_loop_start, log_sleep_msg, MIN_CYCLE_DURATION_IN_MS).await;
+            log_sleep_msg = false;
+            main_loop_start = Instant::now();
+            continue;
+        }
+
+        // the jobs are processed one at a time to keep the load on S3 and ES low
+        for job in jobs {
+            info!(
+                "Deletion {} for {}/{:?}, attempt {}",
+                job.deletion_id, job.owner_id, job.project_id, job.attempts
+            );
+            let result = match job.project_id.as_ref() {
+                Some(project_id) => purge_project(&job, project_id, &config, &pg_client).await,
+                None => purge_account(&job, &config, &pg_client).await,
+            };
+            match result {
+                Ok(_) => {
+                    err_counter = 0;
+                }
+                Err(e) => {
+                    // the job stays in flight and is retried after DELETION_RETRY_AFTER_SECS
+                    warn!("Deletion {} failed. It will be retried later.", job.deletion_id);
+                    DeletionJob::mark_failed(&pg_client, &job.deletion_id, &e).await;
+                    err_counter += 1;
+                }
+            }
+        }
+
+        log_sleep_msg = true;
+        wait_for_next_cycle(&main_loop_start, log_sleep_msg, MIN_CYCLE_DURATION_IN_MS).await;
+        main_loop_start = Instant::now();
+    }
+}
+
+/// Deletes the data of a single member in S3 and ES first and then in PG.
+/// PG goes last because the list of linked keys and the GitHub login are needed to find the data elsewhere.
+/// Returns the reason of the failure to be stored on the request.
+async fn purge_account(job: &DeletionJob, config: &Config, pg_client: &PgClient) -> Result<(), String> {
+    let linked_keys = KeyLink::get_linked_keys(pg_client, &job.owner_id)
+        .await
+        .map_err(|_| "PG: failed to get linked keys".to_owned())?;
+
+    // all reports in the private folders of all the keys of the member
+    let mut s3_objects_deleted = 0usize;
+    for linked_key in &linked_keys {
+        let dev_s3_key =
+            s3::build_dev_s3_key_from_owner_id(linked_key).map_err(|_| format!("Invalid owner_id {}", linked_key))?;
+        s3_objects_deleted += delete_s3_folder(config, dev_s3_key).await?;
+    }
+
+    // raw submissions signed by any of the keys in the queue, in failed/ with their sidecars and in the archive
+    let inbox_objects_deleted = delete_inbox_submissions(config, &linked_keys).await?;
+
+    // private profiles are stored under owner_id
+    let mut es_docs_deleted = 0usize;
+    for linked_key in &linked_keys {
+        if elastic::delete_doc_by_id(&config.es_url, &config.es_idx.dev, linked_key)
+            .await
+            .map_err(|_| format!("ES: failed to delete {}", linked_key))?
+        {
+            es_docs_deleted += 1;
+        }
+    }
+
+    // profiles of members with a validated GitHub login are stored under the GitHub node_id
+    if let Some(gh_login) = job.gh_login.as_ref() {
+        let gh_user_s3_key = [
+            s3::build_dev_s3_key_from_gh_login(gh_login, config.gh_login_invalidation_regex())
+                .map_err(|_| format!("Invalid gh_login {}", gh_login))?,
+            s3::S3_OBJ_NAME_GH_USER.to_owned(),
+        ]
+        .concat();
+        match GitHubUser::from_s3(config, gh_user_s3_key).await {
+            Ok(gh_user) => {
+                if elastic::delete_doc_by_id(&config.es_url, &config.es_idx.dev, &gh_user.node_id)
+                    .await
+                    .map_err(|_| format!("ES: failed to delete {}", gh_user.node_id))?
+                {
+                    es_docs_deleted += 1;
+                }
+            }
+            Err(_) => {
+                warn!("No GitHub profile found for {}", gh_login);
+            }
+        }
+    }
+
+    let details = format!(
+        "keys: {}, s3 objects: {}, inbox objects: {}, es docs: {}",
+        linked_keys.len(),
+        s3_objects_deleted,
+        inbox_objects_deleted,
+        es_docs_deleted
+    );
+
+    DeletionJob::mark_completed(pg_client, &job.deletion_id, &details)
+        .await
+        .map_err(|_| "PG: stm_complete_deletion_job failed".to_owned())
+}
+
+/// Deletes the reports of a single project from S3 and its commits from PG, then queues up the dev
+/// for the profile to be regenerated without the project by `dev_queue` flow.
+/// Returns the reason of the failure to be stored on the request.
+async fn purge_project(
+    job: &DeletionJob,
+    project_id: &String,
+    config: &Config,
+    pg_client: &PgClient,
+) -> Result<(), String> {
+    let linked_keys = KeyLink::get_linked_keys(pg_client, &job.owner_id)
+        .await
+        .map_err(|_| "PG: failed to get linked keys".to_owned())?;
+
+    // the project folder normally exists only under the canonical key, but older reports may be under any of the keys
+    let mut s3_objects_deleted = 0usize;
+    for linked_key in &linked_keys {
+        let project_s3_key = [
+            s3::build_dev_s3_key_from_owner_id(linked_key).map_err(|_| format!("Invalid owner_id {}", linked_key))?,
+            project_id.clone(),
+            "/".to_owned(),
+        ]
+        .concat();
+        s3_objects_deleted += delete_s3_folder(config, project_s3_key).await?;
+    }
+
+    let details = format!("project: {}, s3 objects: {}", project_id, s3_objects_deleted);
+
+    DeletionJob::mark_completed(pg_client, &job.deletion_id, &details)
+        .await
+        .map_err(|_| "PG: stm_complete_deletion_job failed".to_owned())
+}
+
+/// Deletes all objects under the prefix in the private reports bucket and returns their number.
+/// The error names the S3 call and the prefix that failed.
+async fn delete_s3_folder(config: &Config, s3_prefix: String) -> Result<usize, String> {
+    let s3_keys =
+        s3::list_objects_from_s3(config.s3_client(), &config.s3_bucket_private_reports, s3_prefix.clone(), None)
+            .await
+            .map_err(|_| format!("S3: failed to list {}", s3_prefix))?
+            .into_iter()
+            .map(|s3_object| s3_object.key)
+            .collect::<Vec<String>>();
+
+    for chunk in s3_keys.chunks(S3_DELETE_BATCH_SIZE) {
+        s3::delete_from_s3(config.s3_client(), &config.s3_bucket_private_reports, chunk.to_vec())
+            .await
+            .map_err(|_| format!("S3: failed to delete {}", s3_prefix))?;
+    }
+
+    Ok(s3_keys.len())
+}
+
+/// Deletes the submissions signed by any of `linked_keys` from the queue, `failed/`, `redriving/` and the archive prefixes
+/// of the inbox bucket and returns their number. Sidecars of failed submissions are deleted with them.
+/// The inbox is not organized by owner, so every prefix is listed in full. Deletion requests are rare enough for that.
+async fn delete_inbox_submissions(config: &Config, linked_keys: &Vec<String>) -> Result<usize, String> {
+    let inbox = config
+        .inbox
+        .as_ref()
+        .ok_or_else(|| "Missing `inbox` section in config.json".to_owned())?;
+
+    let mut s3_prefixes = vec![
+        inbox.s3_prefix.clone(),
+        S3_FOLDER_FAILED_SUBMISSIONS.to_owned(),
+        S3_FOLDER_REDRIVING_SUBMISSIONS.to_owned(),
+    ];
+    if let Some(archive_s3_prefix) = inbox.archive_s3_prefix.as_ref() {
+        s3_prefixes.push(archive_s3_prefix.clone());
+    }
+
+    let mut s3_objects_deleted = 0usize;
+    for s3_prefix in s3_prefixes {
+        let s3_prefix = [s3_prefix.as_str(), "/"].concat();
+        let s3_keys = s3::list_objects_from_s3(config.s3_client(), &inbox.s3_bucket, s3_prefix.clone(), None)
+            .await
+            .map_err(|_| format!("S3: failed to list {}", s3_prefix))?
+            .into_iter()
+            .map(|s3_object| s3_object.key)
+            .filter(|s3_key| is_submission_of(s3_key, linked_keys))
+            .collect::<Vec<String>>();
+
+        for chunk in s3_keys.chunks(S3_DELETE_BATCH_SIZE) {
+            s3::delete_from_s3(config.s3_client(), &inbox.s3_bucket, chunk.to_vec())
+                .await
+                .map_err(|_| format!("S3: failed to delete {}", s3_prefix))?;
+        }
+
+        s3_objects_deleted += s3_keys.len();
+    }
+
+    Ok(s3_objects_deleted)
+}
+
+/// Returns true if the inbox object is a submission signed by one of `keys` or the sidecar of one, e.g.
+/// `queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_9PdH...LeFK.gz` or `failed/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_9PdH...LeFK.json`.
+fn is_submission_of(s3_key: &str, keys: &Vec<String>) -> bool {
+    let file_name = s3_key.rsplit("/").next().unwrap_or(s3_key);
+    let name = file_name
+        .strip_suffix(S3_FAILED_SUBMISSION_SIDECAR_EXT)
+        .unwrap_or(file_name);
+    let name = name.strip_suffix(SUBMISSION_EXT).unwrap_or(name);
+
+    // the signing key is the last part of the name
+    match name.rsplit("_").next() {
+        Some(key) => keys.iter().any(|v| v == key),
+        None => false,
+    }
+}
+
+/// Saves the current state of the deletion queue in ES for the stats page. Errors are logged and ignored.
+async fn save_queue_counts(config: &Config, pg_client: &PgClient) {
+    let counts = match DeletionQueueCounts::get(pg_client).await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let doc_id = counts.ts.to_string();
+    let _ = elastic::upload_object_to_es(
+        config.es_url.clone(),
+        serde_json::json!({ ES_IDX_DELETION_QUEUE_COUNTS: counts }),
+        doc_id,
+        ES_IDX_DELETION_QUEUE_COUNTS.to_owned(),
+    )
+    .await;
+}
+
+#[test]
+fn is_submission_of_test() {
+    let keys = vec![
+        "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7".to_owned(),
+    ];
+
+    assert!(is_submission_of(
+        "queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz",
+        &keys
+    ));
+    assert!(is_submission_of(
+        "failed/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.json",
+        &keys
+    ));
+    // older submissions have no ID
+    assert!(is_submission_of("archive/1621680890_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz", &keys));
+    // another member
+    assert!(!is_submission_of(
+        "queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB.gz",
+        &keys
+    ));
+    // the key is a part of a longer name
+    assert!(!is_submission_of(
+        "queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFKx.gz",
+        &keys
+    ));
+    // not a submission
+    assert!(!is_submission_of("failed/1627801781.txt", &keys));
+    assert!(!is_submission_of("failed/1627801781.txt.json", &keys));
+    assert!(!is_submission_of("queue/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.txt", &keys));
+}
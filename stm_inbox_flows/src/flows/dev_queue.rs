@@ -1,8 +1,12 @@
+use crate::commit_dispute::CommitDispute;
 use crate::config::Config;
 use crate::dev_profile::{DevProfile, GitHubUser};
+use crate::email_ownership::EmailOwnership;
 use crate::jobs::{wait_for_next_cycle, DevJob, FailureType};
+use crate::key_link::KeyLink;
 use chrono::{Duration, Utc};
 use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::BTreeMap;
 use stm_shared;
 use stm_shared::pgsql::get_pg_client;
 use stm_shared::s3;
@@ -104,7 +108,7 @@ async fn process_devs(
     let mut dev_jobs_futures: FuturesUnordered<_> = dev_jobs
         .drain(..MAX_NUMBER_OF_ACTIVE_DEV_JOBS.min(dev_jobs.len()))
         .enumerate()
-        .map(|(idx, dev_job)| process_dev(dev_job, config, idx))
+        .map(|(idx, dev_job)| process_dev(dev_job, config, pg_client, idx))
         .collect();
 
     // a job counter to identify the job in the log
@@ -145,7 +149,7 @@ async fn process_devs(
 
                 // top up the futures queue with either a user or an org until they run out
                 if let Some(dev_job) = dev_jobs.pop() {
-                    let dev_job = process_dev(dev_job, config, idx);
+                    let dev_job = process_dev(dev_job, config, pg_client, idx);
                     dev_jobs_futures.push(dev_job);
                     info!("Added job {}", idx);
                     idx += 1;
@@ -164,32 +168,47 @@ async fn process_devs(
 
 /// Merge all existing dev reports for the specified owner_id. Param `idx` is only used to identify the job #
 /// in async execution for logging. Returns an updated `DevJob` in Ok or Err.
-#[instrument(skip(dev_job, config), name = "pd")]
-pub(crate) async fn process_dev(dev_job: DevJob, config: &Config, idx: usize) -> Result<DevJob, FailureType<DevJob>> {
-    // check if gh_login needs to be discovered or re-validated
-    // this could be an async task, but it is not expected to be called often enough to warrant that
-    let dev_job = add_gh_login(dev_job, config).await;
-
-    // get a key for dev's private reports folder
-    let dev_s3_key = match s3::build_dev_s3_key_from_owner_id(&dev_job.owner_id) {
-        Err(()) => {
-            // there is something wrong with the key - def no point retrying with the same input
-            return Err(FailureType::DoNotRetry(dev_job));
-        }
+#[instrument(skip(dev_job, config, pg_client), name = "pd")]
+pub(crate) async fn process_dev(
+    dev_job: DevJob,
+    config: &Config,
+    pg_client: &PgClient,
+    idx: usize,
+) -> Result<DevJob, FailureType<DevJob>> {
+    // the member may have more than one key after key rotation
+    // reports submitted before the rotation stay in the folder of the key they were signed with
+    let linked_keys = match KeyLink::get_linked_keys(pg_client, &dev_job.owner_id).await {
         Ok(v) => v,
+        Err(_) => return Err(FailureType::Retry(dev_job)),
     };
 
-    info!("Processing private s3 key {}", dev_s3_key);
+    // check if gh_login needs to be discovered or re-validated
+    // this could be an async task, but it is not expected to be called often enough to warrant that
+    let dev_job = add_gh_login(dev_job, &linked_keys, config).await;
+
+    // collect the list of all objects in the private folders of all dev's keys
+    let mut dev_s3_objects: Vec<(s3::S3ObjectProps, String)> = Vec::new();
+    for linked_key in &linked_keys {
+        // get a key for dev's private reports folder
+        let dev_s3_key = match s3::build_dev_s3_key_from_owner_id(linked_key) {
+            Err(()) => {
+                // there is something wrong with the key - def no point retrying with the same input
+                return Err(FailureType::DoNotRetry(dev_job));
+            }
+            Ok(v) => v,
+        };
+
+        info!("Processing private s3 key {}", dev_s3_key);
 
-    // get the list of all objects in the dev's folder in S3
-    // the trailing "/" is needed to make it the exact path match, e.g. "repos/ddd" matches "repos/ddd-retail", but "repos/ddd/" will be the exact match
-    let dev_s3_objects =
+        // get the list of all objects in the dev's folder in S3
+        // the trailing "/" is needed to make it the exact path match, e.g. "repos/ddd" matches "repos/ddd-retail", but "repos/ddd/" will be the exact match
         match s3::list_objects_from_s3(config.s3_client(), &config.s3_bucket_private_reports, dev_s3_key.clone(), None)
             .await
         {
-            Ok(v) => v,
+            Ok(v) => dev_s3_objects.extend(v.into_iter().map(|s3_object| (s3_object, linked_key.clone()))),
             Err(_) => return Err(FailureType::Retry(dev_job)),
         };
+    }
 
     // get the list of objects for GH repos/reports for dev'g GH login, if any
     let dev_gh_s3_objects = match &dev_job.gh_login {
@@ -223,18 +242,34 @@ pub(crate) async fn process_dev(dev_job: DevJob, config: &Config, idx: usize) ->
         None => Vec::new(),
     };
 
+    // full history claims on projects of other members stay out of the profile until an admin resolves them
+    // and deleted projects stay out for good
+    let excluded_project_ids = match CommitDispute::get_excluded_projects(pg_client, &dev_job.owner_id).await {
+        Ok(v) => v,
+        Err(_) => return Err(FailureType::Retry(dev_job)),
+    };
+
     // collect all combined project reports in the dev's private folder
-    let mut private_report_s3_keys: Vec<String> = Vec::new();
-    for s3_object in dev_s3_objects {
+    let mut private_reports: Vec<(s3::S3ObjectProps, String)> = Vec::new();
+    for (s3_object, linked_key) in dev_s3_objects {
         debug!("Considering private: {}", s3_object.key);
         // is this a combined project report?
-        if s3::is_combined_project_report(&s3_object.key, &dev_job.owner_id) {
-            info!("{} privae report for merging", s3_object.key);
-            private_report_s3_keys.push(s3_object.key);
+        if s3::is_combined_project_report(&s3_object.key, &linked_key) {
+            if excluded_project_ids.contains(&s3::split_key_into_parts(&s3_object.key).1) {
+                info!("{} excluded by a commit dispute or deletion", s3_object.key);
+                continue;
+            }
+            private_reports.push((s3_object, linked_key));
             continue;
         }
     }
 
+    // the canonical key goes first in linked_keys
+    let private_report_s3_keys = select_project_reports(private_reports, &linked_keys[0]);
+    for s3_key in &private_report_s3_keys {
+        info!("{} privae report for merging", s3_key);
+    }
+
     // collect all combined project reports in the dev's GH folder
     let mut gh_reports: Vec<s3::S3ObjectProps> = Vec::new();
     let mut gh_user_profile_s3_key: Option<String> = None;
@@ -269,6 +304,12 @@ pub(crate) async fn process_dev(dev_job: DevJob, config: &Config, idx: usize) ->
         }
     };
 
+    // only emails confirmed by the member are shown as contacts
+    let verified_emails = match EmailOwnership::get_confirmed_emails(pg_client, &dev_job.owner_id).await {
+        Ok(v) => v,
+        Err(_) => return Err(FailureType::Retry(dev_job)),
+    };
+
     // load either GH User Profile or a trimmed down private profile, add the combined report to it and convert into Vec<u8>
     let (serialized_profile, es_object_id) = match gh_user_profile_s3_key {
         Some(gh_user_profile_s3_key) => {
@@ -280,9 +321,13 @@ pub(crate) async fn process_dev(dev_job: DevJob, config: &Config, idx: usize) ->
                 }
             };
             profile.report = combined_report;
+            profile.verified_emails = verified_emails;
             (profile.to_vec(), profile.node_id.clone())
         }
-        None => (DevProfile::new(combined_report, &dev_job.owner_id).to_vec(), dev_job.owner_id.clone()),
+        None => (
+            DevProfile::new(combined_report, &dev_job.owner_id, verified_emails).to_vec(),
+            dev_job.owner_id.clone(),
+        ),
     };
 
     // check if we have a profile to save
@@ -306,12 +351,51 @@ pub(crate) async fn process_dev(dev_job: DevJob, config: &Config, idx: usize) ->
         return Err(FailureType::Retry(dev_job));
     }
 
+    // keys linked by a rotation or an identity merge may have had profiles of their own before they were linked
+    // stm_link_keys removes their t_dev records, but the profiles stay in ES until they are deleted here once per link
+    let linked_profiles = match KeyLink::get_linked_profiles(pg_client, &dev_job.owner_id).await {
+        Ok(v) => v,
+        Err(_) => return Err(FailureType::Retry(dev_job)),
+    };
+    for linked_key in linked_profiles {
+        if stm_shared::elastic::delete_doc_by_id(&config.es_url, &config.es_idx.dev, &linked_key)
+            .await
+            .is_err()
+            || KeyLink::set_profile_removed(pg_client, &linked_key).await.is_err()
+        {
+            return Err(FailureType::Retry(dev_job));
+        }
+    }
+
     Ok(dev_job)
 }
 
+/// Returns one combined report per project ID out of the reports found in the folders of all linked keys.
+/// The router writes the reports of linked keys into the canonical folder, but the folders of the linked keys keep
+/// the reports submitted before the keys were linked under the same project IDs. The report from the canonical folder
+/// is preferred, otherwise the most recently modified one.
+fn select_project_reports(reports: Vec<(s3::S3ObjectProps, String)>, canonical_owner_id: &String) -> Vec<String> {
+    let mut selected: BTreeMap<String, (s3::S3ObjectProps, bool)> = BTreeMap::new();
+    for (s3_object, owner_id) in reports {
+        let project_id = s3::split_key_into_parts(&s3_object.key).1;
+        let is_canonical = &owner_id == canonical_owner_id;
+        if let Some((selected_object, selected_is_canonical)) = selected.get(&project_id) {
+            if *selected_is_canonical || (!is_canonical && selected_object.last_modified >= s3_object.last_modified) {
+                info!("{} skipped in favour of {}", s3_object.key, selected_object.key);
+                continue;
+            }
+            info!("{} skipped in favour of {}", selected_object.key, s3_object.key);
+        }
+        selected.insert(project_id, (s3_object, is_canonical));
+    }
+
+    selected.into_iter().map(|(_, (s3_object, _))| s3_object.key).collect()
+}
+
 /// Checks if there is new GitHub login validation ID or the previous ID is due for revalidation.
 /// Returns the original DevJob is no changes were made or adds new GitHub login details.
-async fn add_gh_login(dev_job: DevJob, config: &Config) -> DevJob {
+/// The gist can be signed by any of the `linked_keys` of the dev.
+async fn add_gh_login(dev_job: DevJob, linked_keys: &Vec<String>, config: &Config) -> DevJob {
     if dev_job.gh_login_gist_latest != dev_job.gh_login_gist_validation
         || dev_job.gh_login_validation_ts.is_none()
         || Utc::now()
@@ -322,7 +406,7 @@ async fn add_gh_login(dev_job: DevJob, config: &Config) -> DevJob {
     {
         let gh_login = crate::gh_login::get_validated_gist(
             &dev_job.gh_login_gist_latest,
-            &dev_job.owner_id,
+            linked_keys,
             config.gh_login_invalidation_regex(),
         )
         .await;
@@ -339,3 +423,44 @@ async fn add_gh_login(dev_job: DevJob, config: &Config) -> DevJob {
         dev_job
     }
 }
+
+#[test]
+fn select_project_reports_test() {
+    let canonical = "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned();
+    let old_key = "EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB".to_owned();
+    let older_key = "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7".to_owned();
+    let report = |owner_id: &String, project_id: &str, last_modified: &str| {
+        (
+            s3::S3ObjectProps {
+                key: ["reports/", owner_id, "/", project_id, "/report.gz"].concat(),
+                last_modified: last_modified.to_owned(),
+                size: 100,
+            },
+            owner_id.clone(),
+        )
+    };
+
+    let selected = select_project_reports(
+        vec![
+            // p1 was submitted with both keys before and after the link, the canonical copy wins even if it is older
+            report(&old_key, "p1", "2021-09-01T00:00:00.000Z"),
+            report(&canonical, "p1", "2021-08-01T00:00:00.000Z"),
+            // p2 exists only in the folders of the linked keys, the newest wins
+            report(&older_key, "p2", "2021-07-01T00:00:00.000Z"),
+            report(&old_key, "p2", "2021-08-01T00:00:00.000Z"),
+            // p3 has a single copy
+            report(&older_key, "p3", "2021-07-01T00:00:00.000Z"),
+        ],
+        &canonical,
+    );
+
+    assert_eq!(
+        selected,
+        vec![
+            report(&canonical, "p1", "").0.key,
+            report(&old_key, "p2", "").0.key,
+            report(&older_key, "p3", "").0.key,
+        ]
+    );
+    assert!(select_project_reports(Vec::new(), &canonical).is_empty());
+}
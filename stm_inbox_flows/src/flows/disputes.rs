@@ -0,0 +1,69 @@
+use crate::commit_dispute::CommitDispute;
+use crate::config::Config;
+use stm_shared::pgsql::get_pg_client;
+use stm_shared::validate_owner_id;
+use tracing::{error, info, warn};
+
+/// Lists commit ownership disputes flagged by stm_inbox_router for review: all pending disputes, oldest first,
+/// or all disputes of a single member with `-owner`. `-owner <owner_id> -project <project_id> -resolve <accepted|rejected>`
+/// resolves a dispute and queues up the dev for the profile to be regenerated with or without the project.
+/// The flow runs once and exits.
+pub(crate) async fn review_disputes(config: Config) {
+    if let Some(owner_id) = config.owner_id.as_ref() {
+        if !validate_owner_id(owner_id) {
+            error!("Invalid -owner: {}", owner_id);
+            return;
+        }
+    }
+
+    // this line panics if the connection fails
+    let pg_client = get_pg_client(&config.job_queues.con_str).await;
+
+    if let Some(resolution) = config.resolution.as_ref() {
+        let (owner_id, project_id) = match (config.owner_id.as_ref(), config.project_id.as_ref()) {
+            (Some(owner_id), Some(project_id)) => (owner_id, project_id),
+            _ => {
+                error!("-resolve requires -owner and -project");
+                return;
+            }
+        };
+
+        if resolution != CommitDispute::RESOLUTION_ACCEPTED && resolution != CommitDispute::RESOLUTION_REJECTED {
+            error!(
+                "Invalid -resolve: {}. Expected {} or {}",
+                resolution,
+                CommitDispute::RESOLUTION_ACCEPTED,
+                CommitDispute::RESOLUTION_REJECTED
+            );
+            return;
+        }
+
+        match CommitDispute::resolve(&pg_client, owner_id, project_id, resolution).await {
+            Ok(true) => info!("Dispute {}/{} resolved as {}", owner_id, project_id, resolution),
+            Ok(false) => warn!("No dispute for {}/{}", owner_id, project_id),
+            Err(_) => {}
+        }
+        return;
+    }
+
+    let disputes = match CommitDispute::get(&pg_client, config.owner_id.as_ref()).await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    for dispute in &disputes {
+        info!(
+            "{} | {} | {} | {}/{} | {} | {} | {}",
+            dispute.owner_id,
+            dispute.project_id,
+            dispute.dispute_type,
+            dispute.shared_commits,
+            dispute.report_commits,
+            dispute.other_owner_ids.join(","),
+            dispute.flagged_ts.to_rfc3339(),
+            dispute.resolution.as_deref().unwrap_or("pending")
+        );
+    }
+
+    info!("Disputes: {}", disputes.len());
+}
@@ -0,0 +1,163 @@
+use crate::config::Config;
+use crate::email_ownership::EmailOwnership;
+use crate::jobs::wait_for_next_cycle;
+use crate::mailer::{Mailer, SendError};
+use chrono::Utc;
+use stm_shared::pgsql::get_pg_client;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// New primary emails appear only with new reports, so there is no need to poll the DB often
+const MIN_CYCLE_DURATION_IN_MS: u64 = 60000;
+/// The max number of emails sent per cycle
+const MAX_NUMBER_OF_CONFIRMATIONS: i32 = 50;
+/// An unconfirmed email gets a new link after this period.
+/// Must be in sync with `CONFIRMATION_VALID_FOR_SECS` in stm_inbox, which rejects older links.
+const CONFIRMATION_RESEND_AFTER_SECS: i64 = 30 * 24 * 3600;
+const CONFIRMATION_SUBJECT: &str = "Please confirm your email address for stackmuncher.com";
+
+/// Sends confirmation links to new primary emails of the members. The emails are confirmed via `GET /confirm_email`
+/// of stm_inbox and only confirmed emails are included in dev profiles as verified contacts.
+pub(crate) async fn send_email_confirmations(config: Config) {
+    info!("Sending email confirmations.");
+
+    let mailer_config = match config.mailer.as_ref() {
+        Some(v) => v,
+        None => {
+            error!("Missing `mailer` section in config.json. It is required for this flow.");
+            return;
+        }
+    };
+
+    let mailer = match Mailer::new(mailer_config) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    // used to determine repeated errors and abort processing
+    let mut err_counter = 0usize;
+    const MAX_CONSECUTIVE_ERRORS: usize = 10;
+
+    // try to get the jobs DB client (postgres)
+    // this line panics if the connection fails
+    let pg_client = get_pg_client(&config.job_queues.con_str).await;
+
+    // track the time it takes for a single cycle to complete
+    let mut main_loop_start = Instant::now();
+    // set to false by no-jobs cycle and to true when there are jobs
+    let mut log_sleep_msg = true;
+
+    loop {
+        // terminate the process if it keeps failing
+        if err_counter >= MAX_CONSECUTIVE_ERRORS {
+            error!("Too many errors. Exiting.");
+            std::process::exit(1);
+        }
+
+        let jobs = match EmailOwnership::get_confirmation_jobs(
+            &pg_client,
+            MAX_NUMBER_OF_CONFIRMATIONS,
+            CONFIRMATION_RESEND_AFTER_SECS,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(_) => {
+                err_counter += 1;
+                error!("Attempt {}", err_counter);
+                wait_for_next_cycle(&main_loop_start, true, MIN_CYCLE_DURATION_IN_MS).await;
+                main_loop_start = Instant::now();
+                continue;
+            }
+        };
+
+        if jobs.is_empty() {
+            wait_for_next_cycle(&main_loop_start, log_sleep_msg, MIN_CYCLE_DURATION_IN_MS).await;
+            log_sleep_msg = false;
+            main_loop_start = Instant::now();
+            continue;
+        }
+
+        for job in jobs {
+            // the ID is stored only after the email was sent, so a transient failure is retried on the next cycle
+            let confirmation_id = new_confirmation_id();
+            let body = confirmation_body(&mailer_config.confirmation_url, &confirmation_id);
+
+            // an email that can never be delivered is recorded with the ID, so that it does not block the queue
+            // and is not mistaken for a problem with the mailer
+            let send_error = match mailer
+                .send(&mailer_config.from, &job.email, CONFIRMATION_SUBJECT, body)
+                .await
+            {
+                Ok(_) => None,
+                Err(SendError::Permanent(e)) => {
+                    warn!("Confirmation for {} cannot be delivered: {}", job.email, e);
+                    Some(e)
+                }
+                Err(SendError::Transient) => {
+                    warn!("Confirmation for {} failed. It will be retried later.", job.email);
+                    err_counter += 1;
+                    continue;
+                }
+            };
+
+            match EmailOwnership::set_confirmation_id(
+                &pg_client,
+                &job.owner_id,
+                &job.email,
+                &confirmation_id,
+                send_error.as_ref(),
+            )
+            .await
+            {
+                Ok(_) => {
+                    if send_error.is_none() {
+                        err_counter = 0;
+                    }
+                }
+                Err(_) => {
+                    err_counter += 1;
+                }
+            }
+        }
+
+        log_sleep_msg = true;
+        wait_for_next_cycle(&main_loop_start, log_sleep_msg, MIN_CYCLE_DURATION_IN_MS).await;
+        main_loop_start = Instant::now();
+    }
+}
+
+/// Returns a new ID for the confirmation link, e.g. `1627380297_Wgx98Rbi8nQuL9ddn3mTk1`.
+/// The timestamp is used by PG to expire the links.
+fn new_confirmation_id() -> String {
+    [
+        Utc::now().timestamp().to_string(),
+        bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string(),
+    ]
+    .join("_")
+}
+
+/// Returns the plain text body of the confirmation email.
+fn confirmation_body(confirmation_url: &str, confirmation_id: &str) -> String {
+    format!(
+        "Hi,\n\nThis email address was listed as the primary contact in a report submitted to stackmuncher.com.\n\nPlease open this link to confirm it is yours:\n{}{}\n\nIgnore this email if you did not use StackMuncher. The address will not be listed as a verified contact until it is confirmed.\n",
+        confirmation_url, confirmation_id
+    )
+}
+
+#[test]
+fn new_confirmation_id_test() {
+    let before = Utc::now().timestamp();
+    let confirmation_id = new_confirmation_id();
+    let after = Utc::now().timestamp();
+
+    // `1627380297_Wgx98Rbi8nQuL9ddn3mTk1`: the timestamp PG expires the link by and a base58 encoded UUID
+    let (ts, uuid) = confirmation_id.split_once("_").expect("No `_` in the confirmation ID");
+    let ts = ts.parse::<i64>().expect("The timestamp is not a number");
+    assert!(ts >= before && ts <= after);
+    assert_eq!(bs58::decode(uuid).into_vec().expect("Invalid base58").len(), 16);
+    assert!(!uuid.contains("_"));
+
+    // every link is different
+    assert_ne!(new_confirmation_id(), new_confirmation_id());
+}
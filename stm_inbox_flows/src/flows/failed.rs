@@ -0,0 +1,294 @@
+use crate::config::{Config, Inbox};
+use crate::deletion_job::DeletionJob;
+use chrono::{TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use stm_shared::pgsql::get_pg_client;
+use stm_shared::s3::{
+    self, FailedSubmission, S3_FAILED_SUBMISSION_SIDECAR_EXT, S3_FOLDER_FAILED_SUBMISSIONS,
+    S3_FOLDER_REDRIVING_SUBMISSIONS,
+};
+use tracing::{error, info, warn};
+
+/// Submissions are always stored as `.gz`, same as `REPORT_FILE_EXT_IN_S3` in stm_inbox_router
+const SUBMISSION_EXT: &str = ".gz";
+
+/// Lists submissions stm_inbox_router moved to `failed/` prefix of the inbox bucket with the reasons from their sidecars.
+/// With `-redrive` the submissions are moved back into the inbox queue under the same name for the router
+/// to process them again, e.g. after a fix was deployed. Submissions that fail again end up in `failed/` with a new sidecar.
+/// Each submission is moved out of `failed/` into `redriving/` before it is copied into the queue, so that a repeated
+/// failure is not deleted with the old one. Anything left in `redriving/` by an interrupted run is redriven first.
+/// Submissions of deleted accounts are not redriven, otherwise the router would recreate their records.
+/// The flow runs once and exits.
+pub(crate) async fn process_failed_submissions(config: Config) {
+    let inbox = match config.inbox.as_ref() {
+        Some(v) => v,
+        None => {
+            error!("Missing `inbox` section in config.json. It is required for this flow.");
+            return;
+        }
+    };
+
+    info!("Listing failed submissions in {}. Redrive: {}", inbox.s3_bucket, config.redrive);
+
+    // only needed for redrive
+    let deleted_owners = if config.redrive {
+        // this line panics if the connection fails
+        let pg_client = get_pg_client(&config.job_queues.con_str).await;
+        match DeletionJob::get_deleted_owners(&pg_client).await {
+            Ok(v) => v,
+            Err(_) => return,
+        }
+    } else {
+        HashSet::new()
+    };
+
+    // submissions moved out of `failed/` by an interrupted run would be lost otherwise
+    let (mut redriven, mut redrive_errors) = if config.redrive {
+        redrive_leftovers(&config, inbox, &deleted_owners).await
+    } else {
+        (0, 0)
+    };
+
+    let failed_s3_objects = match s3::list_objects_from_s3(
+        config.s3_client(),
+        &inbox.s3_bucket,
+        [S3_FOLDER_FAILED_SUBMISSIONS, "/"].concat(),
+        None,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let submissions = pair_failed_submissions(failed_s3_objects.into_iter().map(|s3_object| s3_object.key));
+
+    let mut reason_counts: HashMap<String, usize> = HashMap::new();
+    let mut deleted_skipped = 0usize;
+
+    for (name, (submission_s3_key, sidecar_s3_key)) in submissions {
+        let failed = match sidecar_s3_key.as_ref() {
+            Some(sidecar_s3_key) => load_sidecar(&config, &inbox.s3_bucket, sidecar_s3_key.clone()).await,
+            None => None,
+        };
+
+        match failed.as_ref() {
+            Some(failed) => {
+                info!(
+                    "{} | {} | {} | {}",
+                    name,
+                    failed.owner_id,
+                    Utc.timestamp(failed.failed_ts, 0).to_rfc3339(),
+                    failed.reason
+                );
+                *reason_counts.entry(failed.reason.clone()).or_default() += 1;
+            }
+            None => {
+                warn!("{} | no valid sidecar", name);
+                *reason_counts.entry("unknown".to_owned()).or_default() += 1;
+            }
+        }
+
+        let submission_s3_key = match submission_s3_key {
+            Some(v) => v,
+            None => {
+                // the submission itself was removed manually or the router failed half-way
+                warn!("{} | sidecar without a submission", name);
+                continue;
+            }
+        };
+
+        if !config.redrive {
+            continue;
+        }
+
+        // the sidecar and the name normally have the same key, but either may be missing or invalid
+        let signing_key = name.rsplit("_").next().unwrap_or_default();
+        if deleted_owners.contains(signing_key)
+            || failed
+                .as_ref()
+                .map(|failed| deleted_owners.contains(&failed.owner_id))
+                .unwrap_or_default()
+        {
+            warn!("{} | the account was deleted, not redriven", name);
+            deleted_skipped += 1;
+            continue;
+        }
+
+        // the router writes a repeated failure under the same name as this one, so the submission and its sidecar
+        // must be out of `failed/` before the router is triggered
+        let file_name = submission_s3_key
+            .rsplit("/")
+            .next()
+            .unwrap_or(submission_s3_key.as_str())
+            .to_owned();
+        let redriving_s3_key = [S3_FOLDER_REDRIVING_SUBMISSIONS, "/", file_name.as_str()].concat();
+        if s3::copy_within_s3(config.s3_client(), &inbox.s3_bucket, submission_s3_key.clone(), redriving_s3_key.clone())
+            .await
+            .is_err()
+        {
+            redrive_errors += 1;
+            continue;
+        }
+
+        let mut s3_keys_to_delete = vec![submission_s3_key];
+        if let Some(sidecar_s3_key) = sidecar_s3_key {
+            s3_keys_to_delete.push(sidecar_s3_key);
+        }
+        if s3::delete_from_s3(config.s3_client(), &inbox.s3_bucket, s3_keys_to_delete)
+            .await
+            .is_err()
+        {
+            redrive_errors += 1;
+            continue;
+        }
+
+        if move_to_queue(&config, inbox, redriving_s3_key, &file_name)
+            .await
+            .is_err()
+        {
+            redrive_errors += 1;
+            continue;
+        }
+
+        redriven += 1;
+    }
+
+    for (reason, count) in reason_counts {
+        info!("{}: {}", reason, count);
+    }
+
+    if config.redrive {
+        info!(
+            "Redriven: {}, errors: {}, deleted accounts skipped: {}",
+            redriven, redrive_errors, deleted_skipped
+        );
+    }
+}
+
+/// Moves submissions left in `redriving/` by an interrupted run into the inbox queue and returns the number of
+/// redriven submissions and errors. Submissions of deleted accounts are left where they are for `deletion` flow.
+async fn redrive_leftovers(config: &Config, inbox: &Inbox, deleted_owners: &HashSet<String>) -> (usize, usize) {
+    let redriving_s3_objects = match s3::list_objects_from_s3(
+        config.s3_client(),
+        &inbox.s3_bucket,
+        [S3_FOLDER_REDRIVING_SUBMISSIONS, "/"].concat(),
+        None,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(_) => return (0, 1),
+    };
+
+    let mut redriven = 0usize;
+    let mut redrive_errors = 0usize;
+    for s3_object in redriving_s3_objects {
+        let file_name = s3_object.key.rsplit("/").next().unwrap_or_default().to_owned();
+        let name = file_name.strip_suffix(SUBMISSION_EXT).unwrap_or(file_name.as_str());
+        if deleted_owners.contains(name.rsplit("_").next().unwrap_or_default()) {
+            warn!("{} | the account was deleted, not redriven", s3_object.key);
+            continue;
+        }
+
+        warn!("{} | left by an interrupted redrive", s3_object.key);
+        match move_to_queue(config, inbox, s3_object.key, &file_name).await {
+            Ok(_) => redriven += 1,
+            Err(_) => redrive_errors += 1,
+        }
+    }
+
+    (redriven, redrive_errors)
+}
+
+/// Copies a submission from `redriving/` into the inbox queue under the same name and deletes it from `redriving/`.
+/// The router is triggered by the new object in the queue.
+async fn move_to_queue(config: &Config, inbox: &Inbox, redriving_s3_key: String, file_name: &str) -> Result<(), ()> {
+    let queue_s3_key = [inbox.s3_prefix.as_str(), "/", file_name].concat();
+    s3::copy_within_s3(config.s3_client(), &inbox.s3_bucket, redriving_s3_key.clone(), queue_s3_key).await?;
+    s3::delete_from_s3(config.s3_client(), &inbox.s3_bucket, vec![redriving_s3_key]).await?;
+
+    Ok(())
+}
+
+/// Pairs up the failed submissions with their sidecars by the name without the extension, e.g.
+/// `failed/1627801778_9PdH...LeFK.gz` + `failed/1627801778_9PdH...LeFK.json` -> `failed/1627801778_9PdH...LeFK`.
+/// Any object other than a sidecar is a submission, even without `.gz`, because the router moves objects with
+/// unexpected names to `failed/` as well and names their sidecars `<full name>.json`.
+/// Returns (submission, sidecar) keys by name, either of them may be missing.
+fn pair_failed_submissions<I: Iterator<Item = String>>(
+    s3_keys: I,
+) -> BTreeMap<String, (Option<String>, Option<String>)> {
+    let mut submissions: BTreeMap<String, (Option<String>, Option<String>)> = BTreeMap::new();
+    for s3_key in s3_keys {
+        if let Some(name) = s3_key.strip_suffix(S3_FAILED_SUBMISSION_SIDECAR_EXT) {
+            submissions.entry(name.to_owned()).or_default().1 = Some(s3_key.clone());
+        } else {
+            if !s3_key.ends_with(SUBMISSION_EXT) {
+                warn!("Unexpected submission name: {}", s3_key);
+            }
+            let name = s3_key
+                .strip_suffix(SUBMISSION_EXT)
+                .unwrap_or(s3_key.as_str())
+                .to_owned();
+            submissions.entry(name).or_default().0 = Some(s3_key);
+        }
+    }
+
+    submissions
+}
+
+/// Returns the contents of the sidecar or None if it cannot be read or parsed.
+async fn load_sidecar(config: &Config, s3_bucket: &String, s3_key: String) -> Option<FailedSubmission> {
+    let (contents, s3_key) = s3::get_text_from_s3(config.s3_client(), s3_bucket, s3_key, true)
+        .await
+        .ok()?;
+
+    match serde_json::from_slice::<FailedSubmission>(&contents) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            error!("Invalid sidecar {}: {}", s3_key, e);
+            None
+        }
+    }
+}
+
+#[test]
+fn pair_failed_submissions_test() {
+    let s3_keys = vec![
+        "failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.json",
+        "failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz",
+        // the sidecar was deleted manually
+        "failed/1627801779_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz",
+        // the router failed after saving the sidecar
+        "failed/1627801780_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.json",
+        // an object with an unexpected name keeps its full name in the sidecar
+        "failed/1627801781.txt",
+        "failed/1627801781.txt.json",
+        // a double extension loses only one `.gz`
+        "failed/1627801782.gz.gz",
+        "failed/1627801782.gz.json",
+    ];
+    let pairs = pair_failed_submissions(s3_keys.iter().map(|v| v.to_string()));
+
+    let pair = |submission: Option<&str>, sidecar: Option<&str>| {
+        (submission.map(|v| v.to_owned()), sidecar.map(|v| v.to_owned()))
+    };
+    let mut expected = BTreeMap::new();
+    expected.insert(
+        "failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        pair(Some(s3_keys[1]), Some(s3_keys[0])),
+    );
+    expected.insert(
+        "failed/1627801779_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        pair(Some(s3_keys[2]), None),
+    );
+    expected.insert(
+        "failed/1627801780_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        pair(None, Some(s3_keys[3])),
+    );
+    expected.insert("failed/1627801781.txt".to_owned(), pair(Some(s3_keys[4]), Some(s3_keys[5])));
+    expected.insert("failed/1627801782.gz".to_owned(), pair(Some(s3_keys[6]), Some(s3_keys[7])));
+
+    assert_eq!(pairs, expected);
+}
@@ -7,6 +7,11 @@ pub(crate) fn print_help_msg() {
         "Required param: -flow with one of {}",
         Config::CLI_MODES.join(", ")
     );
+    info!("Optional param: -redrive for `failed` flow to move failed submissions back into the inbox queue.");
+    info!("Optional params: -dry-run, -owner <owner_id> and -archive for `replay` flow.");
+    info!("Optional params: -owner <owner_id>, -project <project_id> and -resolve <accepted|rejected> for `disputes` flow.");
+    info!("Optional params: -dry-run and -owner <owner_id> for `identities` flow.");
+    info!("Optional params: -dry-run and -owner <owner_id> for `retention` flow.");
     info!("Optional param: -l for logging with one of [trace, debug, info, error]. Defaults to [info].");
     info!(
         "Requires config.json in the same folder as the app. See config-schema.json for details."
@@ -0,0 +1,115 @@
+use crate::config::Config;
+use crate::identity_merge::IdentityMerge;
+use stm_shared::elastic;
+use stm_shared::pgsql::get_pg_client;
+use stm_shared::validate_owner_id;
+use tracing::{error, info};
+
+/// Finds member accounts that share confirmed emails or a validated GitHub login, e.g. the same person running the app
+/// on several machines with different keys, and proposes to merge them. The members list the proposals and approve them
+/// with a signed request from a key of each account via stm_inbox. Once both approved, the accounts are linked the same way
+/// as after a key rotation and `dev_queue` flow builds a single profile from the reports of all their keys.
+/// This flow also removes the ES profiles left behind by merged accounts.
+/// `-dry-run` only lists the duplicates and `-owner` lists the merges of a single member. The flow runs once and exits.
+pub(crate) async fn merge_identities(config: Config) {
+    if let Some(owner_id) = config.owner_id.as_ref() {
+        if !validate_owner_id(owner_id) {
+            error!("Invalid -owner: {}", owner_id);
+            return;
+        }
+    }
+
+    // this line panics if the connection fails
+    let pg_client = get_pg_client(&config.job_queues.con_str).await;
+
+    if config.owner_id.is_some() {
+        let merges = match IdentityMerge::get(&pg_client, config.owner_id.as_ref()).await {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        for merge in &merges {
+            log_merge(merge);
+        }
+        info!("Merges: {}", merges.len());
+        return;
+    }
+
+    let duplicates = match IdentityMerge::find_duplicates(&pg_client).await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let mut proposed = 0usize;
+    for duplicate in &duplicates {
+        info!(
+            "{} | {} | {} | {}",
+            duplicate.owner_id,
+            duplicate.other_owner_id,
+            duplicate.shared_emails.join(","),
+            duplicate.shared_gh_login.as_deref().unwrap_or_default()
+        );
+        if config.dry_run {
+            continue;
+        }
+        match duplicate.propose(&pg_client).await {
+            Ok(true) => proposed += 1,
+            Ok(false) => {}
+            Err(_) => return,
+        }
+    }
+
+    info!("Duplicates: {}, new proposals: {}", duplicates.len(), proposed);
+
+    if config.dry_run {
+        return;
+    }
+
+    // the merged accounts have no t_dev record any more, so their private profiles would stay in ES for good
+    // profiles stored under a GitHub node_id are overwritten by the profile of the kept account with the same login
+    let merges = match IdentityMerge::get(&pg_client, None).await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let mut profiles_removed = 0usize;
+    for merge in merges {
+        let merged_owner_id = match merge.merged_owner_id.as_ref() {
+            Some(v) => v,
+            None => continue,
+        };
+
+        if elastic::delete_doc_by_id(&config.es_url, &config.es_idx.dev, merged_owner_id)
+            .await
+            .is_err()
+        {
+            continue;
+        }
+
+        if merge.set_profile_removed(&pg_client).await.is_ok() {
+            profiles_removed += 1;
+        }
+    }
+
+    info!("Profiles of merged accounts removed: {}", profiles_removed);
+}
+
+/// Logs a single merge as a line of `|`-separated values.
+fn log_merge(merge: &IdentityMerge) {
+    let status = if merge.merged_ts.is_some() {
+        "merged"
+    } else if merge.owner_approved_ts.is_some() || merge.other_owner_approved_ts.is_some() {
+        "approved by one"
+    } else {
+        "pending"
+    };
+
+    info!(
+        "{} | {} | {} | {} | {} | {}",
+        merge.owner_id,
+        merge.other_owner_id,
+        merge.shared_emails.join(","),
+        merge.shared_gh_login.as_deref().unwrap_or_default(),
+        merge.proposed_ts.map(|ts| ts.to_rfc3339()).unwrap_or_default(),
+        status
+    );
+}
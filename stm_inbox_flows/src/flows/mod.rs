@@ -1,3 +1,11 @@
 //pub(crate) mod from_s3;
+pub(crate) mod deletion;
 pub(crate) mod dev_queue;
+pub(crate) mod disputes;
+pub(crate) mod email_confirmation;
+pub(crate) mod failed;
 pub(crate) mod help;
+pub(crate) mod identities;
+pub(crate) mod replay;
+pub(crate) mod retention;
+pub(crate) mod spool_drain;
@@ -0,0 +1,252 @@
+use crate::config::Config;
+use crate::deletion_job::DeletionJob;
+use crate::jobs::wait_for_next_cycle;
+use crate::report_hash::ReportHash;
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use stm_shared::pgsql::get_pg_client;
+use stm_shared::s3::{self, S3_COMBINED_DEV_REPORT_FILE_NAME, S3_FOLDER_DEV_REPORTS};
+use stm_shared::validate_owner_id;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// The number of reports copied into the inbox queue per cycle
+const REPLAY_BATCH_SIZE: usize = 10;
+/// Limits the load on stm_inbox_router and PG to `REPLAY_BATCH_SIZE` reports per cycle of this duration
+const MIN_CYCLE_DURATION_IN_MS: u64 = 10000;
+/// Reports are always stored as `.gz`, same as `REPORT_FILE_EXT_IN_S3` in stm_inbox_router
+const REPORT_EXT: &str = ".gz";
+
+/// A stored report to be copied into the inbox queue.
+struct StoredReport {
+    /// The full S3 key of the report in its bucket
+    s3_key: String,
+    /// The owner_id from the S3 key, which is the canonical owner for `reports/` and the signing key for the archive
+    owner_id: String,
+    /// The name of the raw submission if the report came from the archive
+    submission_file_name: Option<String>,
+}
+
+/// Re-applies the routing logic of stm_inbox_router to stored reports by copying them back into the inbox queue.
+/// The router then adds the commits, re-assigns the projects and queues up the devs as for a new submission.
+/// The reports are taken from `reports/` folders of the private reports bucket or from the archive of raw submissions
+/// with `-archive`, oldest first. `-owner` limits the replay to a single member and `-dry-run` only lists the reports.
+/// Reports of deleted accounts are skipped, otherwise the router would recreate their records.
+/// The flow runs once and exits.
+pub(crate) async fn replay_reports(mut config: Config) {
+    let (inbox_s3_bucket, inbox_s3_prefix, archive_s3_prefix) = match config.inbox.as_ref() {
+        Some(v) => (v.s3_bucket.clone(), v.s3_prefix.clone(), v.archive_s3_prefix.clone()),
+        None => {
+            error!("Missing `inbox` section in config.json. It is required for this flow.");
+            return;
+        }
+    };
+
+    if let Some(owner_id) = config.owner_id.as_ref() {
+        if !validate_owner_id(owner_id) {
+            error!("Invalid -owner: {}", owner_id);
+            return;
+        }
+    }
+
+    // this line panics if the connection fails
+    let pg_client = get_pg_client(&config.job_queues.con_str).await;
+
+    let deleted_owners = match DeletionJob::get_deleted_owners(&pg_client).await {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    // the archive is in the inbox bucket, the reports are in the members' folders
+    let (source_s3_bucket, stored_reports) = if config.archive {
+        let archive_s3_prefix = match archive_s3_prefix {
+            Some(v) => v,
+            None => {
+                error!("Missing `inbox.archive_s3_prefix` in config.json. It is required for -archive.");
+                return;
+            }
+        };
+        match list_archived_submissions(&config, &inbox_s3_bucket, &archive_s3_prefix).await {
+            Ok(v) => (inbox_s3_bucket.clone(), v),
+            Err(_) => return,
+        }
+    } else {
+        match list_member_reports(&config).await {
+            Ok(v) => (config.s3_bucket_private_reports.clone(), v),
+            Err(_) => return,
+        }
+    };
+
+    let (stored_reports, deleted_reports): (Vec<StoredReport>, Vec<StoredReport>) = stored_reports
+        .into_iter()
+        .partition(|stored_report| !deleted_owners.contains(&stored_report.owner_id));
+    if !deleted_reports.is_empty() {
+        warn!("Skipping {} reports of deleted accounts", deleted_reports.len());
+    }
+
+    info!(
+        "Replaying {} reports from {}. Owner: {:?}, dry run: {}",
+        stored_reports.len(),
+        source_s3_bucket,
+        config.owner_id,
+        config.dry_run
+    );
+
+    if config.dry_run {
+        for stored_report in &stored_reports {
+            info!("{} | {}", stored_report.owner_id, stored_report.s3_key);
+        }
+        // the routing happens in stm_inbox_router, so there is nothing to preview here
+        info!("Dry run: only the reports to replay are listed. Changes to project assignments, commit ownership and emails made by the router are not shown.");
+        return;
+    }
+
+    // the hashes are reset once per owner before the first of their reports is replayed and are put back
+    // if that copy fails, so that only owners with replayed reports lose them
+    let mut reset_hashes: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    let mut replayed_owners: HashSet<String> = HashSet::new();
+    let mut replayed = 0usize;
+    let mut replay_errors = 0usize;
+
+    for batch in stored_reports.chunks(REPLAY_BATCH_SIZE) {
+        let cycle_start = Instant::now();
+        config.renew_aws_credentials().await;
+
+        for stored_report in batch {
+            if !replayed_owners.contains(&stored_report.owner_id) && !reset_hashes.contains_key(&stored_report.owner_id)
+            {
+                match ReportHash::reset(&pg_client, &stored_report.owner_id).await {
+                    Ok(v) => {
+                        reset_hashes.insert(stored_report.owner_id.clone(), v);
+                    }
+                    Err(_) => {
+                        replay_errors += 1;
+                        continue;
+                    }
+                }
+            }
+
+            // archived submissions keep their names to add the statuses to the same submission
+            // reports from the members' folders get a new submission ID
+            let queue_file_name = match stored_report.submission_file_name.as_ref() {
+                Some(v) => v.clone(),
+                None => [
+                    Utc::now().timestamp().to_string().as_str(),
+                    "_",
+                    bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string().as_str(),
+                    "_",
+                    stored_report.owner_id.as_str(),
+                    REPORT_EXT,
+                ]
+                .concat(),
+            };
+            let queue_s3_key = [inbox_s3_prefix.as_str(), "/", queue_file_name.as_str()].concat();
+
+            // the router is triggered by the new object in the queue
+            match s3::copy_to_other_bucket(
+                config.s3_client(),
+                &source_s3_bucket,
+                stored_report.s3_key.clone(),
+                &inbox_s3_bucket,
+                queue_s3_key,
+            )
+            .await
+            {
+                Ok(_) => {
+                    replayed += 1;
+                    reset_hashes.remove(&stored_report.owner_id);
+                    replayed_owners.insert(stored_report.owner_id.clone());
+                }
+                Err(_) => {
+                    replay_errors += 1;
+                    // nothing of this owner is in the queue yet, so the old hashes are still valid
+                    // the next report of the owner resets them again
+                    if let Some(hashes) = reset_hashes.remove(&stored_report.owner_id) {
+                        let _ = ReportHash::restore(&pg_client, &stored_report.owner_id, &hashes).await;
+                    }
+                }
+            }
+        }
+
+        info!("Replayed: {}, errors: {}", replayed, replay_errors);
+        wait_for_next_cycle(&cycle_start, false, MIN_CYCLE_DURATION_IN_MS).await;
+    }
+
+    info!("Replay completed. Replayed: {}, errors: {}", replayed, replay_errors);
+}
+
+/// Returns the reports of individual submissions from `reports/<owner_id>/<project_id>/` folders, oldest first
+/// within every project. The combined `report.gz` of a project is a copy of the latest submission and is skipped.
+async fn list_member_reports(config: &Config) -> Result<Vec<StoredReport>, ()> {
+    let s3_prefix = match config.owner_id.as_ref() {
+        Some(owner_id) => s3::build_dev_s3_key_from_owner_id(owner_id)?,
+        None => [S3_FOLDER_DEV_REPORTS, "/"].concat(),
+    };
+
+    let s3_objects =
+        s3::list_objects_from_s3(config.s3_client(), &config.s3_bucket_private_reports, s3_prefix, None).await?;
+
+    // the names start with the timestamp of the last commit, so the order of the keys is chronological per project
+    let mut stored_reports: Vec<StoredReport> = Vec::new();
+    for s3_object in s3_objects {
+        let parts = s3_object.key.split("/").collect::<Vec<&str>>();
+        if parts.len() != 4 || !parts[3].ends_with(REPORT_EXT) || parts[3] == S3_COMBINED_DEV_REPORT_FILE_NAME {
+            continue;
+        }
+
+        stored_reports.push(StoredReport {
+            owner_id: parts[1].to_owned(),
+            s3_key: s3_object.key,
+            submission_file_name: None,
+        });
+    }
+
+    Ok(stored_reports)
+}
+
+/// Returns the raw submissions from the archive prefix of the inbox bucket, oldest first.
+/// The names are the same as in the inbox queue, e.g. `1621680890_Wgx98Rbi8nQuL9ddn3mTk1_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`.
+async fn list_archived_submissions(
+    config: &Config,
+    inbox_s3_bucket: &String,
+    archive_s3_prefix: &String,
+) -> Result<Vec<StoredReport>, ()> {
+    let s3_objects =
+        s3::list_objects_from_s3(config.s3_client(), inbox_s3_bucket, [archive_s3_prefix.as_str(), "/"].concat(), None)
+            .await?;
+
+    let mut stored_reports: Vec<StoredReport> = Vec::new();
+    for s3_object in s3_objects {
+        let file_name = match s3_object.key.rsplit("/").next() {
+            Some(v) if v.ends_with(REPORT_EXT) => v.to_owned(),
+            _ => {
+                warn!("Unexpected object in the archive: {}", s3_object.key);
+                continue;
+            }
+        };
+
+        // the signing key is the last part of the name
+        let owner_id = match file_name.trim_end_matches(REPORT_EXT).rsplit("_").next() {
+            Some(v) if validate_owner_id(&v.to_owned()) => v.to_owned(),
+            _ => {
+                warn!("No owner_id in archived submission {}", s3_object.key);
+                continue;
+            }
+        };
+
+        // the filter is applied to the signing key because the archive is not aware of linked keys
+        if let Some(owner_id_filter) = config.owner_id.as_ref() {
+            if owner_id_filter != &owner_id {
+                continue;
+            }
+        }
+
+        stored_reports.push(StoredReport {
+            owner_id,
+            s3_key: s3_object.key,
+            submission_file_name: Some(file_name),
+        });
+    }
+
+    Ok(stored_reports)
+}
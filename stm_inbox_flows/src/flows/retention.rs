@@ -0,0 +1,370 @@
+use crate::config::Config;
+use chrono::{Datelike, NaiveDateTime};
+use std::collections::{BTreeMap, HashSet};
+use stm_shared::s3::{self, S3_FOLDER_DEV_REPORTS};
+use stm_shared::validate_owner_id;
+use tracing::{error, info, warn};
+
+/// S3 accepts up to 1000 keys per DeleteObjects request
+const S3_DELETE_BATCH_SIZE: usize = 1000;
+/// Reports are always stored as `.gz`, same as `REPORT_FILE_EXT_IN_S3` in stm_inbox_router
+const REPORT_EXT: &str = ".gz";
+
+/// A report of a single submission stored by stm_inbox_router as `<commit_ts>_<suffix>.gz` in a project folder.
+struct TimestampedReport {
+    /// The full S3 key of the report
+    s3_key: String,
+    /// The timestamp of the last commit in the report from the start of the name
+    commit_ts: i64,
+    /// S3 `LastModified` of the report in the ISO format S3 lists it in, e.g. `2021-08-12T01:02:44.000Z`
+    last_modified: String,
+}
+
+/// Deletes timestamped reports from `reports/<owner_id>/<project_id>/` folders of the private reports bucket
+/// as per `retention` section of config.json. The most recent `keep_last` reports of every project are kept
+/// as well as the most recent report of every calendar month with `keep_one_per_month`. The combined `report.gz`
+/// of the project is never deleted. `-owner` limits the flow to a single member and `-dry-run` only lists the reports
+/// that would be deleted. The flow runs once and exits.
+pub(crate) async fn apply_retention_policy(mut config: Config) {
+    let (keep_last, keep_one_per_month) = match config.retention.as_ref() {
+        Some(v) => (v.keep_last, v.keep_one_per_month),
+        None => {
+            error!("Missing `retention` section in config.json. It is required for this flow.");
+            return;
+        }
+    };
+
+    // the latest timestamped report is the source of the combined report and must stay
+    if keep_last == 0 {
+        error!("Invalid `retention.keep_last`: it must be at least 1.");
+        return;
+    }
+
+    let s3_prefix = match config.owner_id.as_ref() {
+        Some(owner_id) => {
+            if !validate_owner_id(owner_id) {
+                error!("Invalid -owner: {}", owner_id);
+                return;
+            }
+            match s3::build_dev_s3_key_from_owner_id(owner_id) {
+                Ok(v) => v,
+                Err(_) => return,
+            }
+        }
+        None => [S3_FOLDER_DEV_REPORTS, "/"].concat(),
+    };
+
+    let s3_objects =
+        match s3::list_objects_from_s3(config.s3_client(), &config.s3_bucket_private_reports, s3_prefix, None).await {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+
+    // group the reports by project folder
+    let mut project_reports: BTreeMap<String, Vec<TimestampedReport>> = BTreeMap::new();
+    for s3_object in s3_objects {
+        if let Some((project_s3_key, report)) = parse_timestamped_report(s3_object.key, s3_object.last_modified) {
+            project_reports.entry(project_s3_key).or_default().push(report);
+        }
+    }
+
+    info!(
+        "Applying retention to {} projects. Keep last: {}, one per month: {}, owner: {:?}, dry run: {}",
+        project_reports.len(),
+        keep_last,
+        keep_one_per_month,
+        config.owner_id,
+        config.dry_run
+    );
+
+    let mut s3_keys_to_delete: Vec<String> = Vec::new();
+    let mut kept = 0usize;
+    for (project_s3_key, reports) in project_reports {
+        let (keep, delete) = select_expired_reports(reports, keep_last, keep_one_per_month);
+        kept += keep;
+        if delete.is_empty() {
+            continue;
+        }
+
+        info!("{} | keep: {} | delete: {}", project_s3_key, keep, delete.len());
+        if config.dry_run {
+            for s3_key in &delete {
+                info!("{}", s3_key);
+            }
+        }
+        s3_keys_to_delete.extend(delete);
+    }
+
+    info!("Reports to keep: {}, to delete: {}", kept, s3_keys_to_delete.len());
+
+    if config.dry_run || s3_keys_to_delete.is_empty() {
+        return;
+    }
+
+    let mut deleted = 0usize;
+    for chunk in s3_keys_to_delete.chunks(S3_DELETE_BATCH_SIZE) {
+        // a long list may take longer than the life of the token
+        config.renew_aws_credentials().await;
+        if s3::delete_from_s3(config.s3_client(), &config.s3_bucket_private_reports, chunk.to_vec())
+            .await
+            .is_err()
+        {
+            error!("Retention stopped after deleting {} reports", deleted);
+            return;
+        }
+        deleted += chunk.len();
+    }
+
+    info!("Retention completed. Deleted: {}", deleted);
+}
+
+/// Returns the project folder with the trailing `/` and the report if the key is a timestamped report,
+/// e.g. `reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/DhL8S4tQ1pMCuCAFNG5qZg/1621680890_Wgx98Rbi8nQuL9ddn3mTk1.gz`.
+/// Combined `report.gz`, backups and anything else without a timestamp in the name are ignored.
+fn parse_timestamped_report(s3_key: String, last_modified: String) -> Option<(String, TimestampedReport)> {
+    let parts = s3_key.split("/").collect::<Vec<&str>>();
+    if parts.len() != 4 || !parts[3].ends_with(REPORT_EXT) {
+        return None;
+    }
+
+    let commit_ts = match parts[3].split("_").next().and_then(|v| v.parse::<i64>().ok()) {
+        Some(v) => v,
+        _ => {
+            if parts[3] != s3::S3_COMBINED_DEV_REPORT_FILE_NAME {
+                warn!("Unexpected report name: {}", s3_key);
+            }
+            return None;
+        }
+    };
+
+    let project_s3_key = [parts[0], "/", parts[1], "/", parts[2], "/"].concat();
+
+    Some((
+        project_s3_key,
+        TimestampedReport {
+            s3_key,
+            commit_ts,
+            last_modified,
+        },
+    ))
+}
+
+/// Splits the reports of a single project into the number of reports to keep and the keys of the ones to delete.
+/// The most recent `keep_last` reports are kept and, with `keep_one_per_month`, the most recent report of every
+/// calendar month of the commit timestamps. Reports for the same commit, including all reports without commit history
+/// that the router names `0_<report hash>.gz`, are ordered by the time they were stored.
+fn select_expired_reports(
+    mut reports: Vec<TimestampedReport>,
+    keep_last: usize,
+    keep_one_per_month: bool,
+) -> (usize, Vec<String>) {
+    // most recent first, the time of storing breaks ties between reports for the same commit and the key breaks
+    // ties between reports stored within the same second
+    reports.sort_by(|a, b| {
+        b.commit_ts
+            .cmp(&a.commit_ts)
+            .then_with(|| b.last_modified.cmp(&a.last_modified))
+            .then_with(|| b.s3_key.cmp(&a.s3_key))
+    });
+
+    let mut kept_months: HashSet<(i32, u32)> = HashSet::new();
+    let mut keep = 0usize;
+    let mut delete: Vec<String> = Vec::new();
+
+    for (idx, report) in reports.into_iter().enumerate() {
+        // a timestamp out of chrono's range cannot be placed in a month and is kept to be safe
+        let is_new_month = match NaiveDateTime::from_timestamp_opt(report.commit_ts, 0) {
+            Some(commit_date) => kept_months.insert((commit_date.year(), commit_date.month())),
+            None => true,
+        };
+
+        if idx < keep_last || (keep_one_per_month && is_new_month) {
+            keep += 1;
+        } else {
+            delete.push(report.s3_key);
+        }
+    }
+
+    (keep, delete)
+}
+
+/// Builds reports of a single project from `(commit_ts, suffix)` pairs, e.g. `(1621680890, "a")`
+/// becomes `reports/owner/project/1621680890_a.gz`. All of them are stored at the same time.
+#[cfg(test)]
+fn test_reports(reports: &[(i64, &str)]) -> Vec<TimestampedReport> {
+    reports
+        .iter()
+        .map(|(commit_ts, suffix)| TimestampedReport {
+            s3_key: format!("reports/owner/project/{}_{}.gz", commit_ts, suffix),
+            commit_ts: *commit_ts,
+            last_modified: "2021-08-12T01:02:44.000Z".to_owned(),
+        })
+        .collect()
+}
+
+#[test]
+fn parse_timestamped_report_test() {
+    let project = "reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/DhL8S4tQ1pMCuCAFNG5qZg/";
+    let parse = |name: &str| {
+        parse_timestamped_report([project, name].concat(), String::new()).map(|(project_s3_key, report)| {
+            assert_eq!(project_s3_key, project);
+            assert_eq!(report.s3_key, [project, name].concat());
+            report.commit_ts
+        })
+    };
+
+    assert_eq!(parse("1621680890_Wgx98Rbi8nQuL9ddn3mTk1.gz"), Some(1621680890));
+    // the combined report and backups are never deleted
+    assert_eq!(parse("report.gz"), None);
+    assert_eq!(parse("1621680890_Wgx98Rbi8nQuL9ddn3mTk1.gz.prev"), None);
+    assert_eq!(parse("report.gz.prev"), None);
+    // no timestamp
+    assert_eq!(parse("Wgx98Rbi8nQuL9ddn3mTk1.gz"), None);
+    assert_eq!(parse("_1621680890.gz"), None);
+
+    // anything outside of project folders
+    assert!(parse_timestamped_report(
+        "reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/1621680890_a.gz".to_owned(),
+        String::new()
+    )
+    .is_none());
+    assert!(parse_timestamped_report([project, "sub/1621680890_a.gz"].concat(), String::new()).is_none());
+}
+
+#[test]
+fn select_expired_reports_test() {
+    // May 22, May 3, May 1 00:00:00, Apr 30 23:59:59, Apr 21 and Mar 6 2021 UTC
+    let reports: [(i64, &str); 6] = [
+        (1621680890, "a"),
+        (1620000000, "b"),
+        (1619827200, "c"),
+        (1619827199, "d"),
+        (1619000000, "e"),
+        (1615000000, "f"),
+    ];
+
+    // (keep_last, keep_one_per_month, number of reports kept, suffixes of deleted reports, most recent first)
+    let cases: Vec<(usize, bool, usize, Vec<&str>)> = vec![
+        // keep_last boundary
+        (6, false, 6, vec![]),
+        (7, false, 6, vec![]),
+        (5, false, 5, vec!["f"]),
+        (1, false, 1, vec!["b", "c", "d", "e", "f"]),
+        // the most recent report of every month, the rest of May and April go
+        (1, true, 3, vec!["b", "c", "e"]),
+        // May 3 is kept by keep_last, May 1 is not the most recent one in May
+        (2, true, 4, vec!["c", "e"]),
+        (3, true, 5, vec!["e"]),
+        // Apr 30 is kept by keep_last and is also the most recent one in April
+        (4, true, 5, vec!["e"]),
+        (6, true, 6, vec![]),
+    ];
+
+    for (keep_last, keep_one_per_month, expected_keep, expected_delete) in cases {
+        let (keep, delete) = select_expired_reports(test_reports(&reports), keep_last, keep_one_per_month);
+        let expected_delete = reports
+            .iter()
+            .filter(|(_, suffix)| expected_delete.contains(suffix))
+            .map(|(commit_ts, suffix)| format!("reports/owner/project/{}_{}.gz", commit_ts, suffix))
+            .collect::<Vec<String>>();
+        assert_eq!(keep, expected_keep, "keep_last: {}, per month: {}", keep_last, keep_one_per_month);
+        assert_eq!(delete, expected_delete, "keep_last: {}, per month: {}", keep_last, keep_one_per_month);
+    }
+
+    // the order of the listing does not matter
+    let mut reversed = test_reports(&reports);
+    reversed.reverse();
+    assert_eq!(
+        select_expired_reports(reversed, 1, true).1,
+        select_expired_reports(test_reports(&reports), 1, true).1
+    );
+}
+
+#[test]
+fn select_expired_reports_ties_test() {
+    // several reports for the same commit, e.g. re-submitted from another machine, stored within the same second
+    // are ordered by the key, so the same one is kept no matter the order of the listing
+    let same_commit = [(1621680890, "a"), (1621680890, "c"), (1621680890, "b")];
+    for (keep_last, keep_one_per_month, expected_delete) in vec![
+        (
+            1,
+            false,
+            vec![
+                "reports/owner/project/1621680890_b.gz",
+                "reports/owner/project/1621680890_a.gz",
+            ],
+        ),
+        (
+            1,
+            true,
+            vec![
+                "reports/owner/project/1621680890_b.gz",
+                "reports/owner/project/1621680890_a.gz",
+            ],
+        ),
+        (2, true, vec!["reports/owner/project/1621680890_a.gz"]),
+    ] {
+        for reports in vec![same_commit.to_vec(), same_commit.iter().rev().cloned().collect()] {
+            let (keep, delete) = select_expired_reports(test_reports(&reports), keep_last, keep_one_per_month);
+            assert_eq!(keep, 3 - expected_delete.len());
+            assert_eq!(delete, expected_delete);
+        }
+    }
+
+    // a timestamp out of chrono's range cannot be placed in a month and is always kept with keep_one_per_month
+    let out_of_range = [(i64::MAX, "a"), (i64::MAX - 1, "b"), (1621680890, "c")];
+    assert_eq!(select_expired_reports(test_reports(&out_of_range), 1, true), (3, Vec::new()));
+    assert_eq!(
+        select_expired_reports(test_reports(&out_of_range), 1, false),
+        (
+            1,
+            vec![
+                format!("reports/owner/project/{}_b.gz", i64::MAX - 1),
+                "reports/owner/project/1621680890_c.gz".to_owned()
+            ]
+        )
+    );
+}
+
+#[test]
+fn select_expired_reports_no_commits_test() {
+    // reports without commit history are all named `0_<report hash>.gz`, so the time they were stored is the only order
+    // the hashes are random: `c` was stored first, then `a`, then `b`
+    let stored = [
+        ("a", "2021-08-02T00:00:00.000Z"),
+        ("b", "2021-09-01T00:00:00.000Z"),
+        ("c", "2021-08-01T00:00:00.000Z"),
+    ];
+    let reports = |reversed: bool| {
+        let mut reports = stored
+            .iter()
+            .map(|(suffix, last_modified)| TimestampedReport {
+                s3_key: format!("reports/owner/project/0_{}.gz", suffix),
+                commit_ts: 0,
+                last_modified: last_modified.to_string(),
+            })
+            .collect::<Vec<TimestampedReport>>();
+        if reversed {
+            reports.reverse();
+        }
+        reports
+    };
+
+    // (keep_last, keep_one_per_month, number of reports kept, deleted reports, most recent first)
+    let cases: Vec<(usize, bool, usize, Vec<&str>)> = vec![
+        (1, false, 1, vec!["reports/owner/project/0_a.gz", "reports/owner/project/0_c.gz"]),
+        (2, false, 2, vec!["reports/owner/project/0_c.gz"]),
+        (3, false, 3, vec![]),
+        // all of them fall into Jan 1970, so the month adds nothing to keep_last
+        (1, true, 1, vec!["reports/owner/project/0_a.gz", "reports/owner/project/0_c.gz"]),
+        (2, true, 2, vec!["reports/owner/project/0_c.gz"]),
+    ];
+
+    for (keep_last, keep_one_per_month, expected_keep, expected_delete) in cases {
+        for reversed in vec![false, true] {
+            let (keep, delete) = select_expired_reports(reports(reversed), keep_last, keep_one_per_month);
+            assert_eq!(keep, expected_keep, "keep_last: {}, per month: {}", keep_last, keep_one_per_month);
+            assert_eq!(delete, expected_delete, "keep_last: {}, per month: {}", keep_last, keep_one_per_month);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::config::Config;
+use stm_shared::s3;
+use stm_shared::sqs::{self, SpooledS3Object, SqsMessages};
+use tracing::{error, info, warn};
+
+/// Moves reports spooled by stm_inbox to SQS when S3 was unavailable into the inbox bucket for the router to pick up.
+/// Messages that could not be saved in S3 stay in the queue and are retried after their visibility timeout expires.
+pub(crate) async fn drain_spool(mut config: Config) {
+    info!("Draining the inbox spool into S3.");
+
+    let spool_sqs_url = match config.spool.as_ref() {
+        Some(v) => v.sqs_url.clone(),
+        None => {
+            error!("Missing `spool` section in config.json. It is required for this flow.");
+            return;
+        }
+    };
+
+    // used to determine repeated errors and abort processing
+    let mut err_counter = 0usize;
+    const MAX_CONSECUTIVE_ERRORS: usize = 10;
+
+    loop {
+        // terminate the process if it keeps failing
+        if err_counter >= MAX_CONSECUTIVE_ERRORS {
+            error!("Too many errors. Exiting.");
+            std::process::exit(1);
+        }
+
+        // renew the creds if needed
+        config.renew_aws_credentials().await;
+
+        // this call blocks until there is at least one message in the queue
+        let msgs = match SqsMessages::<SpooledS3Object>::get(config.sqs_client(), &spool_sqs_url, 10, true).await {
+            Ok(v) => v,
+            Err(_) => {
+                err_counter += 1;
+                error!("Attempt {}", err_counter);
+                continue;
+            }
+        };
+
+        // only the messages that were saved in S3 are deleted from the queue
+        let mut receipt_handles: Vec<String> = Vec::new();
+        let mut upload_failed = false;
+        for msg in msgs.messages {
+            let spooled = msg.message;
+            let payload = match base64::decode(&spooled.payload) {
+                Ok(v) => v,
+                Err(e) => {
+                    // it will never succeed - drop it
+                    error!("Invalid payload in spooled {}: {}", spooled.s3_key, e);
+                    receipt_handles.push(msg.receipt_handle);
+                    continue;
+                }
+            };
+
+            match s3::upload_to_s3(config.s3_client(), &spooled.s3_bucket, spooled.s3_key.clone(), payload).await {
+                Ok(_) => {
+                    info!("Unspooled {}", spooled.s3_key);
+                    receipt_handles.push(msg.receipt_handle);
+                }
+                Err(_) => {
+                    warn!("Failed to unspool {}. It will be retried later.", spooled.s3_key);
+                    upload_failed = true;
+                }
+            }
+        }
+
+        let delete_failed = sqs::delete_messages(config.sqs_client(), &spool_sqs_url, receipt_handles)
+            .await
+            .is_err();
+
+        if upload_failed || delete_failed {
+            err_counter += 1;
+        } else {
+            err_counter = 0;
+        }
+    }
+}
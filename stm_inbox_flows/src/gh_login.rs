@@ -39,16 +39,17 @@ pub(crate) struct RawGist {
 ///  -H "Accept: application/vnd.github.v3+json" \
 ///  https://api.github.com/gists/GIST_ID
 /// ```
+/// The gist is accepted if it was signed by any of `pub_keys`, which are all the keys linked to the same member account.
 pub(crate) async fn get_validated_gist(
     gist_id: &Option<String>,
-    pub_key: &String,
+    pub_keys: &Vec<String>,
     gh_login_invalidation_regex: &Regex,
 ) -> Option<String> {
     // remove GH login info if gist_is is empty - that's because the user reset it to empty and wants GH unlinked
     let gist_id = match gist_id {
         Some(v) => v,
         None => {
-            info!("Removing gh_login for {}", pub_key);
+            info!("Removing gh_login for {}", pub_keys.join(", "));
             return None;
         }
     };
@@ -216,28 +217,26 @@ pub(crate) async fn get_validated_gist(
         }
     };
 
-    // convert pub_key from base58 into bytes
-    let pub_key = match bs58::decode(pub_key.clone()).into_vec() {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Failed to decode pub_key(owner_id) from based58 due to: {}", e);
-            return None;
-        }
-    };
+    // check if the signature in the gist is valid for any of the member's keys
+    for pub_key_bs58 in pub_keys {
+        // convert pub_key from base58 into bytes
+        let pub_key = match bs58::decode(pub_key_bs58.clone()).into_vec() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to decode pub_key(owner_id) {} from based58 due to: {}", pub_key_bs58, e);
+                continue;
+            }
+        };
 
-    // check if the signature in the gist is valid
-    let pub_key = signature::UnparsedPublicKey::new(&signature::ED25519, pub_key);
-    match pub_key.verify(GH_VERIFICATION_STRING_TO_SIGN.as_bytes(), &signature) {
-        Ok(_) => {
-            info!("Signature OK");
-        }
-        Err(_) => {
-            error!("Invalid signature in Gist: {}", gist_contents);
-            return None;
+        let pub_key = signature::UnparsedPublicKey::new(&signature::ED25519, pub_key);
+        if pub_key.verify(GH_VERIFICATION_STRING_TO_SIGN.as_bytes(), &signature).is_ok() {
+            info!("Signature OK for {}", pub_key_bs58);
+            return Some(github_login);
         }
-    };
+    }
 
-    Some(github_login)
+    error!("Invalid signature in Gist: {}", gist_contents);
+    None
 }
 
 /// Logs and error and returns false if `gh_login` is empty or has any characters outside of the allowed range.
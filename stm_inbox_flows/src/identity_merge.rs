@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::{Client, Row};
+use tracing::{error, info};
+
+/// Corresponds to `t_identity_merge` table. All SPs and the table creation reside in stm_inbox project for consistency.
+/// The merges are proposed by `identities` flow and approved by the members via stm_inbox.
+#[derive(Debug, Clone)]
+pub(crate) struct IdentityMerge {
+    /// The lower of the two canonical owner_ids in the sort order
+    pub owner_id: String,
+    pub other_owner_id: String,
+    /// Confirmed emails found in both accounts
+    pub shared_emails: Vec<String>,
+    /// The validated GitHub login of both accounts, if any
+    pub shared_gh_login: Option<String>,
+    pub proposed_ts: Option<DateTime<Utc>>,
+    pub owner_approved_ts: Option<DateTime<Utc>>,
+    pub other_owner_approved_ts: Option<DateTime<Utc>>,
+    /// The account that was linked to the other one, None until both approved
+    pub merged_owner_id: Option<String>,
+    pub merged_ts: Option<DateTime<Utc>>,
+}
+
+impl From<&Row> for IdentityMerge {
+    /// Creates a new structure from tokio_postgres::Row
+    fn from(row: &Row) -> Self {
+        Self {
+            owner_id: row.get("owner_id"),
+            other_owner_id: row.get("other_owner_id"),
+            shared_emails: row.get("shared_emails"),
+            shared_gh_login: row.get("shared_gh_login"),
+            proposed_ts: row.try_get("proposed_ts").unwrap_or_default(),
+            owner_approved_ts: row.try_get("owner_approved_ts").unwrap_or_default(),
+            other_owner_approved_ts: row.try_get("other_owner_approved_ts").unwrap_or_default(),
+            merged_owner_id: row.try_get("merged_owner_id").unwrap_or_default(),
+            merged_ts: row.try_get("merged_ts").unwrap_or_default(),
+        }
+    }
+}
+
+impl IdentityMerge {
+    /// Returns pairs of accounts sharing confirmed emails or a validated GitHub login that are not linked yet.
+    /// Only the owner_ids, the emails and the login are set.
+    pub(crate) async fn find_duplicates(pg_client: &Client) -> Result<Vec<IdentityMerge>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_find_duplicate_identities()", &[])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_find_duplicate_identities failed with {}", e);
+                return Err(());
+            }
+        };
+
+        Ok(rows.iter().map(|row| IdentityMerge::from(row)).collect())
+    }
+
+    /// Records the proposal or refreshes the shared emails and the login of an existing one.
+    /// Returns TRUE if the proposal is new.
+    pub(crate) async fn propose(&self, pg_client: &Client) -> Result<bool, ()> {
+        let rows = match pg_client
+            .query(
+                "select stm_propose_identity_merge($1::varchar, $2::varchar, $3::varchar[], $4::varchar)",
+                &[
+                    &self.owner_id,
+                    &self.other_owner_id,
+                    &self.shared_emails,
+                    &self.shared_gh_login,
+                ],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_propose_identity_merge failed with {}", e);
+                return Err(());
+            }
+        };
+
+        match rows.get(0).map(|row| row.try_get::<_, bool>(0)) {
+            Some(Ok(v)) => Ok(v),
+            _ => {
+                error!(
+                    "stm_propose_identity_merge for {}/{} returned no result",
+                    self.owner_id, self.other_owner_id
+                );
+                Err(())
+            }
+        }
+    }
+
+    /// Returns all pending merges and completed merges with the ES profile of the merged account not removed yet,
+    /// oldest first, or all merges of the member if `owner_id` is set.
+    pub(crate) async fn get(pg_client: &Client, owner_id: Option<&String>) -> Result<Vec<IdentityMerge>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_identity_merges($1::varchar)", &[&owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_identity_merges failed with {}", e);
+                return Err(());
+            }
+        };
+
+        Ok(rows.iter().map(|row| IdentityMerge::from(row)).collect())
+    }
+
+    /// Records that the ES profile of the merged account was removed.
+    pub(crate) async fn set_profile_removed(&self, pg_client: &Client) -> Result<(), ()> {
+        info!("Profile removed for merge {}/{}", self.owner_id, self.other_owner_id);
+
+        if let Err(e) = pg_client
+            .execute(
+                "select stm_set_identity_profile_removed($1::varchar, $2::varchar)",
+                &[&self.owner_id, &self.other_owner_id],
+            )
+            .await
+        {
+            error!("stm_set_identity_profile_removed failed with {}", e);
+            return Err(());
+        }
+
+        Ok(())
+    }
+}
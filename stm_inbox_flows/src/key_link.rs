@@ -0,0 +1,80 @@
+use tokio_postgres::Client;
+use tracing::{debug, error, info};
+
+/// Corresponds to `t_key_link` table. All SPs and the table creation reside in stm_inbox project for consistency.
+pub(crate) struct KeyLink {}
+
+impl KeyLink {
+    /// Returns the canonical owner_id of the member account followed by all other keys linked to it.
+    /// Returns a list with just `owner_id` if the key is not linked to anything.
+    pub(crate) async fn get_linked_keys(pg_client: &Client, owner_id: &String) -> Result<Vec<String>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_linked_keys($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_linked_keys for {} failed with {}", owner_id, e);
+                return Err(());
+            }
+        };
+
+        let mut linked_keys: Vec<String> = Vec::new();
+        for row in rows {
+            match row.try_get::<_, String>(0) {
+                Ok(v) => linked_keys.push(v),
+                Err(e) => {
+                    error!("Cannot convert linked key to String for {}: {}", owner_id, e);
+                    return Err(());
+                }
+            }
+        }
+
+        debug!("Linked keys for {}: {}", owner_id, linked_keys.join(", "));
+
+        Ok(linked_keys)
+    }
+
+    /// Returns the keys linked to the canonical account of `owner_id` that may still have ES profiles of their own
+    /// from before they were linked. The canonical owner_id is never on the list.
+    pub(crate) async fn get_linked_profiles(pg_client: &Client, owner_id: &String) -> Result<Vec<String>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_get_linked_profiles($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_linked_profiles for {} failed with {}", owner_id, e);
+                return Err(());
+            }
+        };
+
+        let mut linked_profiles: Vec<String> = Vec::new();
+        for row in rows {
+            match row.try_get::<_, String>(0) {
+                Ok(v) => linked_profiles.push(v),
+                Err(e) => {
+                    error!("Cannot convert linked profile to String for {}: {}", owner_id, e);
+                    return Err(());
+                }
+            }
+        }
+
+        Ok(linked_profiles)
+    }
+
+    /// Records that the ES profile of the linked key was removed.
+    pub(crate) async fn set_profile_removed(pg_client: &Client, owner_id: &String) -> Result<(), ()> {
+        info!("Profile removed for linked key {}", owner_id);
+
+        if let Err(e) = pg_client
+            .execute("select stm_set_key_link_profile_removed($1::varchar)", &[owner_id])
+            .await
+        {
+            error!("stm_set_key_link_profile_removed for {} failed with {}", owner_id, e);
+            return Err(());
+        }
+
+        Ok(())
+    }
+}
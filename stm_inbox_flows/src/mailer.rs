@@ -0,0 +1,105 @@
+use crate::config::MailerConfig;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::{error, info};
+
+/// The reason an email was not sent.
+#[derive(Debug, PartialEq)]
+pub(crate) enum SendError {
+    /// The email will never be delivered as is, e.g. an invalid recipient address or an SMTP 5xx reply.
+    Permanent(String),
+    /// The email may be delivered if sent again later, e.g. the SMTP server is unavailable.
+    Transient,
+}
+
+/// Sends emails via SMTP or writes them into a local folder as `.eml` files, depending on the config.
+/// The folder option is a stand-in for local runs where no SMTP server is available.
+pub(crate) enum Mailer {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Dir(AsyncFileTransport<Tokio1Executor>),
+}
+
+impl Mailer {
+    /// Initializes the transport from `mailer` section of config.json. SMTP takes precedence if both are present.
+    pub(crate) fn new(config: &MailerConfig) -> Result<Self, ()> {
+        if let Some(smtp) = config.smtp.as_ref() {
+            let mut builder = match AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Invalid SMTP relay {}: {}", smtp.host, e);
+                    return Err(());
+                }
+            };
+            if let Some(port) = smtp.port {
+                builder = builder.port(port);
+            }
+            let transport = builder
+                .credentials(Credentials::new(smtp.username.clone(), smtp.password.clone()))
+                .build();
+            info!("Mailer: SMTP via {}", smtp.host);
+            return Ok(Mailer::Smtp(transport));
+        }
+
+        if let Some(dir) = config.dir.as_ref() {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                error!("Cannot create mailer dir {}: {}", dir, e);
+                return Err(());
+            }
+            info!("Mailer: writing emails to {}", dir);
+            return Ok(Mailer::Dir(AsyncFileTransport::<Tokio1Executor>::new(dir)));
+        }
+
+        error!("The `mailer` section of config.json must have either `smtp` or `dir`.");
+        Err(())
+    }
+
+    /// Sends a plain text email. All errors are logged.
+    /// An invalid sender is a config problem and is reported as `Transient` because it affects all emails.
+    pub(crate) async fn send(&self, from: &str, to: &str, subject: &str, body: String) -> Result<(), SendError> {
+        let from = match from.parse::<Mailbox>() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid sender address {}: {}", from, e);
+                return Err(SendError::Transient);
+            }
+        };
+        let to = match to.parse::<Mailbox>() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Invalid recipient address {}: {}", to, e);
+                return Err(SendError::Permanent(format!("Invalid recipient address: {}", e)));
+            }
+        };
+
+        let msg = match Message::builder().from(from).to(to).subject(subject).body(body) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Cannot build email: {}", e);
+                return Err(SendError::Permanent(format!("Cannot build email: {}", e)));
+            }
+        };
+
+        match self {
+            Mailer::Smtp(transport) => match transport.send(msg).await {
+                Ok(_) => Ok(()),
+                // 5xx replies, e.g. a mailbox that does not exist, will not change on a retry
+                Err(e) if e.is_permanent() => {
+                    error!("Failed to send email: {}", e);
+                    Err(SendError::Permanent(format!("SMTP: {}", e)))
+                }
+                Err(e) => {
+                    error!("Failed to send email: {}", e);
+                    Err(SendError::Transient)
+                }
+            },
+            Mailer::Dir(transport) => match transport.send(msg).await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    error!("Failed to write email: {}", e);
+                    Err(SendError::Transient)
+                }
+            },
+        }
+    }
+}
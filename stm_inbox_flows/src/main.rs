@@ -1,10 +1,17 @@
 use tracing::info;
 
+mod commit_dispute;
 mod config;
+mod deletion_job;
 mod dev_profile;
+mod email_ownership;
 mod flows;
 mod gh_login;
+mod identity_merge;
 mod jobs;
+mod key_link;
+mod mailer;
+mod report_hash;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
@@ -24,6 +31,38 @@ async fn main() -> Result<(), std::io::Error> {
             flows::dev_queue::merge_devs_reports(config).await;
         }
 
+        config::Flow::SpoolDrain => {
+            flows::spool_drain::drain_spool(config).await;
+        }
+
+        config::Flow::Deletion => {
+            flows::deletion::purge_deleted_accounts(config).await;
+        }
+
+        config::Flow::EmailConfirmation => {
+            flows::email_confirmation::send_email_confirmations(config).await;
+        }
+
+        config::Flow::Failed => {
+            flows::failed::process_failed_submissions(config).await;
+        }
+
+        config::Flow::Replay => {
+            flows::replay::replay_reports(config).await;
+        }
+
+        config::Flow::Disputes => {
+            flows::disputes::review_disputes(config).await;
+        }
+
+        config::Flow::Identities => {
+            flows::identities::merge_identities(config).await;
+        }
+
+        config::Flow::Retention => {
+            flows::retention::apply_retention_policy(config).await;
+        }
+
         config::Flow::Help => {
             flows::help::print_help_msg();
         }
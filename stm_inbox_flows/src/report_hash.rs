@@ -0,0 +1,55 @@
+use tokio_postgres::Client;
+use tracing::{debug, error};
+
+/// Corresponds to `t_report_hash` table. All SPs and the table creation reside in stm_inbox project for consistency.
+pub(crate) struct ReportHash {}
+
+impl ReportHash {
+    /// Deletes the hashes of the latest processed reports of the account `owner_id` belongs to,
+    /// so that the router does not skip replayed reports as unchanged. Returns the deleted (project_id, report_hash) pairs.
+    pub(crate) async fn reset(pg_client: &Client, owner_id: &String) -> Result<Vec<(String, String)>, ()> {
+        let rows = match pg_client
+            .query("select * from stm_reset_report_hashes($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_reset_report_hashes for {} failed with {}", owner_id, e);
+                return Err(());
+            }
+        };
+
+        let deleted = rows
+            .iter()
+            .map(|row| (row.get("project_id"), row.get("report_hash")))
+            .collect::<Vec<(String, String)>>();
+
+        debug!("Report hashes reset for {}: {}", owner_id, deleted.len());
+
+        Ok(deleted)
+    }
+
+    /// Puts back the hashes returned by `reset` unless the router stored newer ones in the meantime.
+    pub(crate) async fn restore(
+        pg_client: &Client,
+        owner_id: &String,
+        hashes: &Vec<(String, String)>,
+    ) -> Result<(), ()> {
+        let (project_ids, report_hashes): (Vec<String>, Vec<String>) = hashes.iter().cloned().unzip();
+
+        if let Err(e) = pg_client
+            .execute(
+                "select stm_restore_report_hashes($1::varchar, $2::varchar[], $3::varchar[])",
+                &[owner_id, &project_ids, &report_hashes],
+            )
+            .await
+        {
+            error!("stm_restore_report_hashes for {} failed with {}", owner_id, e);
+            return Err(());
+        };
+
+        debug!("Report hashes restored for {}: {}", owner_id, hashes.len());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::Config;
+use crate::postgres::{CommitDispute, CommitOwnership, KeyLink};
+use lambda_runtime::Error;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+
+/// The share of the checked report commits owned by others for the report to be treated as a claim
+/// on someone else's project rather than a contribution to a shared one
+const FULL_HISTORY_CLAIM_RATIO: f64 = 0.9;
+/// Reports with fewer checked commits are too short to tell a claim from a contribution
+const MIN_COMMITS_FOR_FULL_HISTORY_CLAIM: usize = 10;
+
+/// A project with commits already owned by other owners.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Dispute {
+    /// One of `CommitDispute::TYPE_*` constants
+    pub dispute_type: &'static str,
+    /// Sorted owners of the shared commits, excluding the reporting owner
+    pub other_owner_ids: Vec<String>,
+    /// The number of distinct report commits owned by others
+    pub shared_commits: usize,
+    /// The number of report commits checked for ownership
+    pub report_commits: usize,
+}
+
+/// Classifies the overlap between the report commits and the commits of other owners. Returns None if there is no overlap.
+/// A report that consists almost entirely of commits owned by others and adds nothing to a project the owner had
+/// no commits in before is a full history claim. Any other overlap is treated as co-contributors.
+/// * `owner_id`: the canonical owner of the report
+/// * `report_commits`: the number of report commits that were looked up in PG
+/// * `matching_commits`: commits from PG that match the report, with the owners of linked keys already replaced
+///   with `owner_id`
+fn classify_dispute(
+    owner_id: &String,
+    report_commits: usize,
+    matching_commits: &Vec<CommitOwnership>,
+) -> Option<Dispute> {
+    // the same commit may be owned by several other owners, so only distinct hashes are counted
+    let mut shared_hashes: HashSet<&String> = HashSet::new();
+    let mut other_owner_ids: HashSet<&String> = HashSet::new();
+    let mut has_own_commits = false;
+    for commit in matching_commits {
+        if &commit.owner_id == owner_id {
+            has_own_commits = true;
+        } else {
+            shared_hashes.insert(&commit.commit_hash);
+            other_owner_ids.insert(&commit.owner_id);
+        }
+    }
+
+    if shared_hashes.is_empty() {
+        return None;
+    }
+
+    let mut other_owner_ids = other_owner_ids.into_iter().cloned().collect::<Vec<String>>();
+    other_owner_ids.sort();
+
+    let is_full_history_claim = !has_own_commits
+        && report_commits >= MIN_COMMITS_FOR_FULL_HISTORY_CLAIM
+        && shared_hashes.len() as f64 >= report_commits as f64 * FULL_HISTORY_CLAIM_RATIO;
+
+    Some(Dispute {
+        dispute_type: if is_full_history_claim {
+            CommitDispute::TYPE_FULL_HISTORY_CLAIM
+        } else {
+            CommitDispute::TYPE_CO_CONTRIBUTORS
+        },
+        other_owner_ids,
+        shared_commits: shared_hashes.len(),
+        report_commits,
+    })
+}
+
+/// Checks if the report commits are already owned by other owners and returns the dispute if they are.
+/// Commits owned by the keys linked to `owner_id` are not disputes. The dispute is recorded for review
+/// by `stm_commit_report` in the same transaction as the rest of the report.
+pub(crate) async fn find_commit_dispute(
+    config: &Config,
+    owner_id: &String,
+    project_id: &String,
+    report_commits: usize,
+    matching_commits: &Vec<CommitOwnership>,
+) -> Result<Option<Dispute>, Error> {
+    // commits submitted before a key rotation are owned by the old key
+    let mut canonical_owner_ids: HashMap<String, String> = HashMap::new();
+    let mut owned_commits: Vec<CommitOwnership> = Vec::with_capacity(matching_commits.len());
+    for commit in matching_commits {
+        let mut commit = commit.clone();
+        if !canonical_owner_ids.contains_key(&commit.owner_id) {
+            let canonical_owner_id = KeyLink::get_canonical_owner_id(&config.pg_client, &commit.owner_id).await?;
+            canonical_owner_ids.insert(commit.owner_id.clone(), canonical_owner_id);
+        }
+        if let Some(canonical_owner_id) = canonical_owner_ids.get(&commit.owner_id) {
+            commit.owner_id = canonical_owner_id.clone();
+        }
+        owned_commits.push(commit);
+    }
+
+    let dispute = match classify_dispute(owner_id, report_commits, &owned_commits) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    if dispute.dispute_type == CommitDispute::TYPE_FULL_HISTORY_CLAIM {
+        warn!(
+            "Full history claim on {}: {}/{} commits owned by {}",
+            project_id,
+            dispute.shared_commits,
+            dispute.report_commits,
+            dispute.other_owner_ids.join(",")
+        );
+    } else {
+        info!(
+            "Co-contributors on {}: {}/{} commits owned by {}",
+            project_id,
+            dispute.shared_commits,
+            dispute.report_commits,
+            dispute.other_owner_ids.join(",")
+        );
+    }
+
+    Ok(Some(dispute))
+}
+
+#[cfg(test)]
+fn commit(owner_id: &str, commit_hash: &str) -> CommitOwnership {
+    CommitOwnership {
+        owner_id: owner_id.to_owned(),
+        project_id: "Wgx98Rbi8nQuL9ddn3mTk1".to_owned(),
+        commit_hash: commit_hash.to_owned(),
+        commit_ts: 1595904770,
+        commit_sha1: None,
+    }
+}
+
+#[test]
+fn classify_dispute_test() {
+    let owner_id = "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned();
+    let other_owner_id = "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7";
+
+    // no commits owned by others
+    assert_eq!(classify_dispute(&owner_id, 20, &Vec::new()), None);
+    assert_eq!(classify_dispute(&owner_id, 20, &vec![commit(&owner_id, "c1")]), None);
+
+    // all commits are owned by a GitHub project and a member and the owner never reported the project before
+    let mut commits: Vec<CommitOwnership> = Vec::new();
+    for idx in 0..20 {
+        commits.push(commit("gh:stackmuncher", &format!("c{}", idx)));
+        commits.push(commit(other_owner_id, &format!("c{}", idx)));
+    }
+    assert_eq!(
+        classify_dispute(&owner_id, 20, &commits),
+        Some(Dispute {
+            dispute_type: CommitDispute::TYPE_FULL_HISTORY_CLAIM,
+            other_owner_ids: vec![other_owner_id.to_owned(), "gh:stackmuncher".to_owned()],
+            shared_commits: 20,
+            report_commits: 20,
+        })
+    );
+
+    // the owner already had commits in the project, so the overlap is from working on it together
+    let mut shared_commits = commits.clone();
+    shared_commits.push(commit(&owner_id, "c0"));
+    assert_eq!(
+        classify_dispute(&owner_id, 20, &shared_commits).map(|v| v.dispute_type),
+        Some(CommitDispute::TYPE_CO_CONTRIBUTORS)
+    );
+
+    // the report brings enough commits nobody else has
+    assert_eq!(
+        classify_dispute(&owner_id, 30, &commits).map(|v| v.dispute_type),
+        Some(CommitDispute::TYPE_CO_CONTRIBUTORS)
+    );
+
+    // too few commits to tell
+    let commits = vec![commit(other_owner_id, "c1"), commit(other_owner_id, "c2")];
+    assert_eq!(
+        classify_dispute(&owner_id, 2, &commits),
+        Some(Dispute {
+            dispute_type: CommitDispute::TYPE_CO_CONTRIBUTORS,
+            other_owner_ids: vec![other_owner_id.to_owned()],
+            shared_commits: 2,
+            report_commits: 2,
+        })
+    );
+}
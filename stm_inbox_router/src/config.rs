@@ -6,6 +6,7 @@ use rusoto_core::HttpClient;
 use rusoto_core::Region;
 use rusoto_s3::S3Client;
 use std::str::FromStr;
+use stm_shared::gzip::{MAX_COMPRESSED_REPORT_SIZE_DEFAULT, MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT};
 use std::time::Duration;
 
 /// All buckets are expected to be in the same region (STM_INBOX_S3_REGION)
@@ -28,6 +29,14 @@ pub const S3_MEMBER_REPORTS_BUCKET_ENV: &str = "STM_MEMBER_REPORTS_S3_BUCKET";
 /// The storage tree with any additional folders is placed under this prefix.
 /// E.g. `reports`, leading/trailing `/` are removed
 pub const S3_MEMBER_REPORTS_PREFIX_ENV: &str = "STM_MEMBER_REPORTS_S3_PREFIX";
+/// Optional max size of the gzipped report in bytes, must be the same as in stm_inbox (STM_INBOX_MAX_COMPRESSED_SIZE)
+/// Defaults to `stm_shared::gzip::MAX_COMPRESSED_REPORT_SIZE_DEFAULT`.
+/// E.g. `5242880`
+pub const MAX_COMPRESSED_SIZE_ENV: &str = "STM_INBOX_MAX_COMPRESSED_SIZE";
+/// Optional max size of the unzipped report in bytes, must be the same as in stm_inbox (STM_INBOX_MAX_DECOMPRESSED_SIZE)
+/// Defaults to `stm_shared::gzip::MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT`.
+/// E.g. `52428800`
+pub const MAX_DECOMPRESSED_SIZE_ENV: &str = "STM_INBOX_MAX_DECOMPRESSED_SIZE";
 
 /// A struct with all the config info passed around as a single param
 pub struct Config {
@@ -53,6 +62,10 @@ pub struct Config {
     pub commit_hash_regex_short: Regex,
     /// A compiled regex for validating full-length commit hashes
     pub commit_hash_regex_full: Regex,
+    /// Reports larger than this number of bytes are moved to `failed/` without unzipping.
+    pub max_compressed_size: usize,
+    /// Reports that unzip into more than this number of bytes are moved to `failed/`.
+    pub max_decompressed_size: usize,
 }
 
 impl Config {
@@ -108,10 +121,24 @@ impl Config {
             pg_client: get_pg_client(&pg_connection_string).await,
             commit_hash_regex_short: Regex::new("[a-f0-9]{8}").expect("Invalid commit_hash_regex. It's a bug."),
             commit_hash_regex_full: Regex::new("[a-f0-9]{40}").expect("Invalid commit_hash_regex. It's a bug."),
+            max_compressed_size: get_optional_size(MAX_COMPRESSED_SIZE_ENV, MAX_COMPRESSED_REPORT_SIZE_DEFAULT),
+            max_decompressed_size: get_optional_size(MAX_DECOMPRESSED_SIZE_ENV, MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT),
         }
     }
 }
 
+/// Returns the value of an optional env var with a size in bytes or the default if the var is not set.
+/// Panics if the value is not a positive number.
+fn get_optional_size(env_var: &str, default: usize) -> usize {
+    match std::env::var(env_var) {
+        Ok(v) => match v.trim().parse::<usize>() {
+            Ok(v) if v > 0 => v,
+            _ => panic!("Invalid {} value. Must be a positive number of bytes, e.g. {}", env_var, default),
+        },
+        Err(_) => default,
+    }
+}
+
 /// Generates an S3Client with custom settings to match AWS server defaults.
 fn generate_s3_client(s3_region: Region) -> S3Client {
     let https_connector = HttpsConnector::with_native_roots();
@@ -1,362 +1,1043 @@
+use crate::commit_dispute::find_commit_dispute;
 use crate::config::Config;
-use crate::postgres::{CommitOwnership, Dev, EmailOwnership};
-use crate::s3::{copy_within_s3, delete_s3_object, get_bytes_from_s3, S3Event, REPORT_FILE_EXT_IN_S3};
-use bs58;
-use flate2::read::GzDecoder;
-use futures::stream::{FuturesUnordered, StreamExt};
+use crate::postgres::{CommitOwnership, KeyLink, ProjectFingerprint, ReportHash, ReportWrites, SubmissionStatus};
+use crate::project_merge::{delete_merged_reports, plan_conflicting_merges, plan_merges, ProjectMerges};
+use crate::report_store::{store_report, ReportS3Keys};
+use crate::s3::{delete_s3_object, get_bytes_from_s3, move_to_failed, S3Event, REPORT_FILE_EXT_IN_S3};
+use crate::sqs::{BatchItemFailure, SqsBatchResponse, SqsEvent, SQS_EVENT_SOURCE};
+use chrono::Utc;
+use futures::stream::StreamExt;
 use lambda_runtime::{Context, Error};
 use log::info;
 use serde_json::Value;
 use stackmuncher_lib::report::Report;
-use std::collections::{HashMap, HashSet};
-use std::io::Read;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use stm_shared::email::{normalize_email, ReportEmail};
+use stm_shared::gzip::{decompress_with_limit, GzipError};
+use stm_shared::{
+    is_plausible_commit_ts, validate_commit_hash_with_ts, validate_full_commit_hash, validate_owner_id, ReportCommit,
+    MAX_FUTURE_COMMIT_TS_DRIFT_SECS, MIN_PLAUSIBLE_COMMIT_TS,
+};
 use tracing::{debug, error, warn};
-use unicode_segmentation::UnicodeSegmentation;
 
-pub(crate) async fn my_handler(event: Value, ctx: Context, config: &Config) -> Result<(), Error> {
+/// The max number of owners whose inbox objects are processed concurrently within a single event
+const MAX_CONCURRENT_OWNERS: usize = 10;
+
+/// A single inbox object from an S3 event.
+#[derive(Debug, PartialEq)]
+struct InboxRecord {
+    /// `messageId` of the SQS message the S3 event arrived in or None if S3 invoked the lambda directly
+    message_id: Option<String>,
+    /// S3 key, ex bucket name, e.g. `queue/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz`
+    s3_key: String,
+    /// The object size in bytes, e.g. 7172
+    object_size: i64,
+}
+
+/// All inbox objects from a Lambda event, which may be an S3 event or a batch of SQS messages with S3 events.
+#[derive(Debug, Default)]
+struct InboxEvent {
+    /// TRUE if the event came from an SQS trigger and expects `SqsBatchResponse`
+    is_sqs: bool,
+    records: Vec<InboxRecord>,
+    /// SQS messages that could not be turned into records, e.g. with an invalid body
+    failed_message_ids: HashSet<String>,
+}
+
+/// Processes S3 events arriving directly from S3 or via an SQS queue. Every S3 record is processed independently,
+/// but the records of the same owner are processed one after another.
+/// * SQS: returns the IDs of the messages with failed records in `batchItemFailures` for only them to be retried
+/// * S3: returns an error listing the failed keys for Lambda to retry the event. The records that succeeded
+/// are no longer in the inbox and are skipped on the retry.
+pub(crate) async fn my_handler(event: Value, ctx: Context, config: &Config) -> Result<Value, Error> {
     // these 2 lines are for debugging only to see the raw request
     debug!("Event: {}", event);
     debug!("Context: {:?}", ctx);
 
-    // convert the event into a struct
-    let event = match serde_json::from_value::<S3Event>(event) {
+    let InboxEvent {
+        is_sqs,
+        records,
+        mut failed_message_ids,
+    } = parse_event(event)?;
+    info!("S3 records: {}", records.len());
+
+    // records of the same owner share the backup of the combined project report and the check for the latest report,
+    // so only records of different owners can be processed concurrently
+    let owner_groups = group_records_by_owner(config, records).await;
+
+    let results = futures::stream::iter(owner_groups.into_iter().map(|owner_records| async move {
+        let mut results: Vec<(InboxRecord, Result<(), Error>)> = Vec::with_capacity(owner_records.len());
+        for record in owner_records {
+            let result = process_record(config, record.s3_key.clone(), record.object_size).await;
+            results.push((record, result));
+        }
+        results
+    }))
+    .buffer_unordered(MAX_CONCURRENT_OWNERS)
+    .collect::<Vec<Vec<(InboxRecord, Result<(), Error>)>>>()
+    .await
+    .into_iter()
+    .flatten();
+
+    let mut failed_s3_keys: Vec<String> = Vec::new();
+    for (record, result) in results {
+        if let Err(e) = result {
+            error!("Failed to process {}: {}", record.s3_key, e);
+            if let Some(message_id) = record.message_id {
+                failed_message_ids.insert(message_id);
+            }
+            failed_s3_keys.push(record.s3_key);
+        }
+    }
+
+    if is_sqs {
+        if !failed_message_ids.is_empty() {
+            warn!("Failed SQS messages: {}", failed_message_ids.len());
+        }
+        let response = SqsBatchResponse {
+            batch_item_failures: failed_message_ids
+                .into_iter()
+                .map(|message_id| BatchItemFailure {
+                    item_identifier: message_id,
+                })
+                .collect(),
+        };
+        return Ok(serde_json::to_value(response)?);
+    }
+
+    if !failed_s3_keys.is_empty() {
+        return Err(Error::from(format!("Failed S3 keys: {}", failed_s3_keys.join(", "))));
+    }
+
+    Ok(Value::Null)
+}
+
+/// Extracts all inbox objects from an S3 event or from S3 events inside a batch of SQS messages.
+/// An invalid SQS message is marked as failed without affecting the rest of the batch.
+fn parse_event(event: Value) -> Result<InboxEvent, Error> {
+    let is_sqs = event
+        .get("Records")
+        .and_then(|records| records.get(0))
+        .and_then(|record| record.get("eventSource"))
+        .and_then(|event_source| event_source.as_str())
+        == Some(SQS_EVENT_SOURCE);
+
+    if !is_sqs {
+        let event = match serde_json::from_value::<S3Event>(event) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Cannot deser S3 event with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        return Ok(InboxEvent {
+            records: s3_event_into_records(event, None),
+            ..Default::default()
+        });
+    }
+
+    let event = match serde_json::from_value::<SqsEvent>(event) {
         Ok(v) => v,
         Err(e) => {
-            error!("Cannot deser S3 event with {}", e);
+            error!("Cannot deser SQS event with {}", e);
             return Err(Error::from(e));
         }
     };
 
-    // the number of records should always be 1
-    if event.records.len() != 1 {
-        return Err(Error::from(format!("Wrong number of S3 records: {}", event.records.len())));
+    let mut inbox_event = InboxEvent {
+        is_sqs: true,
+        ..Default::default()
+    };
+    for message in event.records {
+        match serde_json::from_str::<S3Event>(message.body.as_deref().unwrap_or_default()) {
+            Ok(s3_event) => inbox_event
+                .records
+                .extend(s3_event_into_records(s3_event, Some(&message.message_id))),
+            Err(e) => {
+                error!("Cannot deser S3 event in SQS message {} with {}", message.message_id, e);
+                inbox_event.failed_message_ids.insert(message.message_id);
+            }
+        }
     }
 
-    // the object key should always be present for this type of call
-    let s3_key = match event.records[0].s3.object.key.as_ref() {
-        Some(v) => v.clone(),
-        None => {
-            return Err(Error::from("Empty object key in the event details"));
+    Ok(inbox_event)
+}
+
+/// Groups the records by the canonical owner of the key in the inbox key name, so that keys linked to the same
+/// account end up in the same group. If the canonical owner cannot be retrieved the signing key is used.
+/// Records without a key in the name are not routed and get a group of their own.
+async fn group_records_by_owner(config: &Config, records: Vec<InboxRecord>) -> Vec<Vec<InboxRecord>> {
+    let signer_ids = records
+        .iter()
+        .filter_map(|record| parse_inbox_key(&record.s3_key))
+        .map(|(owner_id, _)| owner_id)
+        .filter(|owner_id| validate_owner_id(owner_id))
+        .collect::<BTreeSet<String>>();
+
+    let mut canonical_owner_ids: HashMap<String, String> = HashMap::new();
+    for signer_id in signer_ids {
+        match KeyLink::get_canonical_owner_id(&config.pg_client, &signer_id).await {
+            Ok(owner_id) => {
+                canonical_owner_ids.insert(signer_id, owner_id);
+            }
+            Err(e) => warn!("Grouping {} by the signing key: {}", signer_id, e),
         }
-    };
+    }
+
+    group_records(records, |record| match parse_inbox_key(&record.s3_key) {
+        Some((signer_id, _)) => canonical_owner_ids.get(&signer_id).cloned().unwrap_or(signer_id),
+        None => record.s3_key.clone(),
+    })
+}
 
+/// Groups the records by `owner_of` with the oldest submission first within every group.
+/// The names start with the timestamp of the submission, so sorting them by the key is enough.
+fn group_records<F: Fn(&InboxRecord) -> String>(records: Vec<InboxRecord>, owner_of: F) -> Vec<Vec<InboxRecord>> {
+    let mut groups: HashMap<String, Vec<InboxRecord>> = HashMap::new();
+    for record in records {
+        groups.entry(owner_of(&record)).or_default().push(record);
+    }
+
+    groups
+        .into_iter()
+        .map(|(_, mut owner_records)| {
+            owner_records.sort_by(|a, b| a.s3_key.cmp(&b.s3_key));
+            owner_records
+        })
+        .collect()
+}
+
+/// Converts the records of an S3 event into inbox records. Records without a key are logged and skipped.
+fn s3_event_into_records(event: S3Event, message_id: Option<&String>) -> Vec<InboxRecord> {
+    event
+        .records
+        .into_iter()
+        .filter_map(|record| match record.s3.object.key {
+            Some(s3_key) => Some(InboxRecord {
+                message_id: message_id.cloned(),
+                s3_key,
+                object_size: record.s3.object.size.unwrap_or_default(),
+            }),
+            None => {
+                warn!("Empty object key in the event details");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Routes a single inbox object: validates the report, assigns it to a project, moves it to the member's folder
+/// and queues up the dev for the profile to be regenerated.
+async fn process_record(config: &Config, s3_key: String, object_size: i64) -> Result<(), Error> {
     // required to ID the transaction in the log, otherwise it's not known which report failed
     info!("S3 key: {}", s3_key);
 
-    // extract the owner id from a key like this `queue/1621680890_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`
-    let owner_id = match s3_key.split("_").last() {
+    // extract the owner id and the submission id from the key
+    // retrying will not help with any of the checks of the key and the size - move it out of the way
+    let (owner_id, submission_id) = match parse_inbox_key(&s3_key) {
         Some(v) => v,
         None => {
-            return Err(Error::from("Failed to split the key at _ as pub_key.ext"));
-        }
-    };
-    let owner_id = match owner_id.split(".").next() {
-        Some(v) => v.to_owned(),
-        None => {
-            return Err(Error::from("Failed to split the key at . as pub_key.ext"));
+            let details = "Failed to split the key into ts_submissionid_pubkey.ext";
+            return fail_submission(config, s3_key, None, &None, details).await;
         }
     };
+    // the submission status is recorded for the key that signed it
+    let signer_id = owner_id.clone();
+    info!("Submission ID: {:?}", submission_id);
 
     // check if the object has any contents
-    if event.records[0].s3.object.size.unwrap_or_default() == 0 {
-        return Err(Error::from(format!("Zero-sized object: {}", s3_key)));
+    if object_size == 0 {
+        return fail_submission(config, s3_key, Some(&signer_id), &submission_id, "Zero-sized object").await;
+    }
+
+    // do not even download objects that are too large
+    // the inbox should have rejected them, but the limits may have changed or the object was placed there by other means
+    if object_size as usize > config.max_compressed_size {
+        let e = GzipError::CompressedTooLarge(config.max_compressed_size).to_string();
+        return fail_submission(config, s3_key, Some(&signer_id), &submission_id, &e).await;
     }
 
     info!("OwnerID: {}", owner_id);
 
     // this should already be validated, but check just in case
+    // there is no point recording the status for an invalid key because nobody can sign a request to read it
     if !validate_owner_id(&owner_id) {
-        return Err(Error::from(format!("Invalid owner_id length: {}", owner_id)));
+        let details = format!("Invalid owner_id: {}", owner_id);
+        return fail_submission(config, s3_key, None, &submission_id, &details).await;
     }
 
+    // the report may be signed by a key linked to an existing account after a key rotation
+    // all the ownership records, S3 folders and the dev job are kept under the canonical owner_id
+    let owner_id = KeyLink::get_canonical_owner_id(&config.pg_client, &owner_id).await?;
+    info!("Canonical OwnerID: {}", owner_id);
+
     // read and unzip the report from S3
-    let report = get_bytes_from_s3(config, s3_key.clone()).await?;
-    let mut decoder = GzDecoder::new(report.as_slice());
-    let mut buffer: Vec<u8> = Vec::new();
-    let len = decoder.read_to_end(&mut buffer)?;
+    let report = match get_bytes_from_s3(config, s3_key.clone()).await? {
+        Some(v) => v,
+        None => {
+            // S3 may deliver the same event more than once and failed batches are retried in full
+            info!("{} is no longer in the inbox. It was already processed.", s3_key);
+            return Ok(());
+        }
+    };
+    // the decoding stops as soon as the limit is reached to protect the lambda from decompression bombs
+    let buffer = match decompress_with_limit(&report, config.max_compressed_size, config.max_decompressed_size) {
+        Ok(v) => v,
+        Err(e) => {
+            // retrying will not help - move it out of the way
+            return fail_submission(config, s3_key, Some(&signer_id), &submission_id, &e.to_string()).await;
+        }
+    };
 
-    info!("Decoded {} bytes", len);
+    SubmissionStatus::add_status(
+        &config.pg_client,
+        &submission_id,
+        &signer_id,
+        SubmissionStatus::STAGE_ROUTED,
+        None,
+        None,
+    )
+    .await;
+
+    // identical reports are skipped further down the line once the project is known
+    let report_hash = hash_report(&buffer);
+    debug!("Report hash: {}", report_hash);
 
     // load the file into a report struct
-    let report = serde_json::from_slice::<Report>(buffer.as_slice())?;
+    let report = match serde_json::from_slice::<Report>(buffer.as_slice()) {
+        Ok(v) => v,
+        Err(e) => {
+            let details = format!("Invalid report: {}", e);
+            return fail_submission(config, s3_key, Some(&signer_id), &submission_id, &details).await;
+        }
+    };
 
     // compile the full list of user emails and mark the primary email as such
     // the primary email may or may not be in the list of git IDs
-    let mut user_emails = report
-        .git_ids_included
-        .iter()
-        .filter_map(|email| {
-            if let Some(email) = validate_email_address(email) {
-                Some((email, false))
-            } else {
-                warn!("Invalid email: {}", email);
-                None
+    // GitHub noreply addresses cannot be contacted, but the logins they encode are kept as a hint for linking
+    let mut user_emails: HashMap<String, bool> = HashMap::new();
+    let mut gh_noreply_logins: BTreeSet<String> = BTreeSet::new();
+    for email in report.git_ids_included.iter() {
+        match normalize_email(email) {
+            ReportEmail::Mailbox(v) => {
+                user_emails.insert(v, false);
             }
-        })
-        .collect::<HashMap<String, bool>>();
+            ReportEmail::GitHubNoReply(v) => {
+                gh_noreply_logins.insert(v);
+            }
+            ReportEmail::Placeholder(v) => info!("Placeholder email: {}", v),
+            ReportEmail::Invalid => warn!("Invalid email: {}", email),
+        }
+    }
     // add the primary email, if any
     // An empty string means NO CONTACT - see https://github.com/stackmuncher/stm_server/issues/16
     if let Some(email) = &report.primary_email {
-        if let Some(email) = validate_email_address(email) {
-            user_emails.insert(email, true);
-        } else {
-            warn!("Invalid primary email: {}", email);
+        match normalize_email(email) {
+            ReportEmail::Mailbox(v) => {
+                user_emails.insert(v, true);
+            }
+            ReportEmail::GitHubNoReply(v) => {
+                gh_noreply_logins.insert(v);
+            }
+            ReportEmail::Placeholder(v) => info!("Placeholder primary email: {}", v),
+            ReportEmail::Invalid if email.is_empty() => {}
+            ReportEmail::Invalid => warn!("Invalid primary email: {}", email),
         }
     }
 
-    // make a list of email jobs to update the DB
-    let mut email_jobs: FuturesUnordered<_> = user_emails
-        .iter()
-        .map(|email| EmailOwnership::add_email(&config.pg_client, &owner_id, &email.0, email.1))
-        .collect();
+    // fresh repos and shallow clones may have no commit history
+    // such reports are assigned to a project by the fingerprint of their remote URL instead
+    let has_commit_history = match report.recent_project_commits.as_ref() {
+        Some(v) => !v.is_empty(),
+        None => false,
+    };
+    let fingerprint = project_fingerprint(report.github_user_name.as_ref(), report.github_repo_name.as_ref());
 
-    // validate the latest commit SHA1
+    // validate the latest commit SHA1, which is only allowed to be missing if there is no commit history
     let last_contributor_commit_sha1 = report.last_contributor_commit_sha1.unwrap_or_default();
-    if last_contributor_commit_sha1.len() != 40
-        || !config.commit_hash_regex_full.is_match(&last_contributor_commit_sha1)
+    if (has_commit_history || !last_contributor_commit_sha1.is_empty())
+        && !validate_full_commit_hash(&last_contributor_commit_sha1, &config.commit_hash_regex_full)
     {
         // something's off here - no point proceeding
         error!("Invalid latest report commit: {}", last_contributor_commit_sha1);
-        return Ok(());
+        let details = format!("Invalid last_contributor_commit_sha1: {}", last_contributor_commit_sha1);
+        return fail_submission(config, s3_key, Some(&signer_id), &submission_id, &details).await;
     }
 
     // get the list of recent project commits
     let commit_list = match report.recent_project_commits.as_ref() {
-        Some(v) => v,
-        None => {
+        Some(v) if has_commit_history => v.as_slice(),
+        _ => {
             info!("No commit details found.");
-            return Ok(());
+            if fingerprint.is_none() {
+                let details = "The report has no commit history or remote URL";
+                return fail_submission(config, s3_key, Some(&signer_id), &submission_id, details).await;
+            }
+            &[]
         }
     };
 
     debug!("{}", commit_list.join(", "));
 
-    // split the commits into hash and timestamp parts
-    let mut valid_commits: HashMap<String, i64> = HashMap::new();
-    // a valid commit looks like this: 7474684a_1595904770
+    // split the commits into hash and timestamp parts, keyed by the full SHA1 or the 8-char hash prefix if there is none,
+    // so that different commits with the same prefix are all kept
+    let mut valid_commits: HashMap<String, ReportCommit> = HashMap::new();
+    // commits dated before git existed or in the future are still used for project matching,
+    // but are ignored when deciding which report is the latest
+    let mut implausible_commits = 0usize;
+    // a valid commit looks like this: 7474684a_1595904770 or has the full SHA1 in newer reports
     // anything else is either a bug or some other kind of data corruption
     for commit in commit_list {
-        if let Some(commit) = validate_short_commit_hash(commit, config) {
-            valid_commits.insert(commit.0, commit.1);
+        if let Some(report_commit) =
+            validate_commit_hash_with_ts(commit, &config.commit_hash_regex_short, &config.commit_hash_regex_full)
+        {
+            if !report_commit.is_plausible {
+                implausible_commits += 1;
+            }
+            let commit_key = report_commit.sha1.as_ref().unwrap_or(&report_commit.hash).clone();
+            valid_commits.insert(commit_key, report_commit);
         } else {
             // something's off here - no point processing this report any further
             error!("Invalid commit: {}", commit);
-            return Ok(());
+            let details = format!("Invalid commit: {}", commit);
+            return fail_submission(config, s3_key, Some(&signer_id), &submission_id, &details).await;
         }
     }
 
     // get a list of hashes to search for existing projects
     // hashmap should give us a randomized list, but we may need to adjust its size
-    let commit_hashes_for_search = valid_commits
-        .keys()
-        .take(valid_commits.keys().len().min(50))
+    // PG is searched by the prefix because older commits have no SHA1
+    let commits_for_search = valid_commits
+        .values()
+        .take(valid_commits.len().min(50))
+        .collect::<Vec<&ReportCommit>>();
+    let searched_commits = commits_for_search.len();
+    let commit_hashes_for_search = commits_for_search
+        .iter()
+        .map(|commit| &commit.hash)
+        .collect::<HashSet<&String>>()
+        .into_iter()
         .collect::<Vec<&String>>();
 
     // search for project matches by commit
-    let commit_ownerships = CommitOwnership::find_matching_commits(&config.pg_client, commit_hashes_for_search).await?;
+    let commit_ownerships = if commit_hashes_for_search.is_empty() {
+        Vec::new()
+    } else {
+        CommitOwnership::find_matching_commits(&config.pg_client, commit_hashes_for_search).await?
+    };
 
     info!("Found {} matching commits in PG", commit_ownerships.len());
 
-    // collect matching project IDs
-    let mut project_ids: HashSet<String> = HashSet::new();
-    for ownership in commit_ownerships {
-        // check if the commits match on the date as well
-        if let Some(commit_ts) = valid_commits.get(&ownership.commit_hash) {
-            if commit_ts == &ownership.commit_ts {
-                project_ids.insert(ownership.project_id);
-            }
-        }
+    // only commits that match on the date and the full SHA1, if known on both sides, are considered
+    let (matching_commits, colliding_commits) = match_commits(&commits_for_search, commit_ownerships);
+    if colliding_commits > 0 {
+        warn!("Ignored {} commits with the same hash prefix and a different SHA1", colliding_commits);
     }
 
-    // a Vec is easier to work with
-    let mut project_ids = project_ids.into_iter().collect::<Vec<String>>();
+    // collect matching project IDs
+    let mut project_ids = matching_commits
+        .iter()
+        .map(|ownership| ownership.project_id.clone())
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
     info!("Found matching projects: {}", project_ids.join(","));
 
+    // a project previously assigned to the same remote URL of this owner
+    let fingerprint_project = match fingerprint.as_ref() {
+        Some(v) => ProjectFingerprint::get_project(&config.pg_client, &owner_id, v).await?,
+        None => None,
+    };
+
     // get or generate the project ID
+    // nothing is written to PG or S3 until the report is stored, so that a failure on the way leaves no partial writes
+    let mut project_merges = ProjectMerges::default();
     let project_id = match project_ids.len() {
-        0 => {
-            // generate a new one
-            bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string()
-        }
+        0 => match fingerprint_project.as_ref() {
+            // a report without commits goes to the project of its remote URL, with or without commits
+            // a report with commits can only continue a project that had no commits to match on
+            Some((project_id, has_commits)) if valid_commits.is_empty() || !has_commits => {
+                info!("Matched project by fingerprint");
+                project_id.clone()
+            }
+            _ => {
+                // generate a new one
+                bs58::encode(uuid::Uuid::new_v4().as_bytes()).into_string()
+            }
+        },
         1 => {
             // use existing
             project_ids.pop().expect("Failed to unwrap project_id")
         }
         _ => {
-            // resolve conflicts, but just log an error for now
-            error!("Project ID conflict resolution is not implemented.");
-            return Ok(());
+            // the same repo was reported as different projects before, e.g. with unrelated commit ranges
+            // that are now bridged by this report
+            let (project_id, merges) = plan_conflicting_merges(config, &owner_id, &matching_commits).await?;
+            project_merges = merges;
+            project_id
         }
     };
 
+    // a project created from earlier reports without commit history is re-attached to the project
+    // the commits matched, so that its reports are not listed twice in the profile
+    if let Some((fingerprint_project_id, false)) = fingerprint_project {
+        if fingerprint_project_id != project_id && !project_merges.contains(&fingerprint_project_id) {
+            let fingerprint_project_ids = vec![fingerprint_project_id];
+            project_merges.extend(plan_merges(config, &owner_id, &project_id, &fingerprint_project_ids).await?);
+        }
+    }
+
+    // commits already owned by other members are flagged for review
+    let dispute = if matching_commits.is_empty() {
+        None
+    } else {
+        find_commit_dispute(config, &owner_id, &project_id, searched_commits, &matching_commits).await?
+    };
+
+    let mut project_details: Vec<String> = Vec::new();
+    if !project_merges.merged_project_ids.is_empty() {
+        project_details.push(format!("Merged projects: {}", project_merges.merged_project_ids.join(", ")));
+    }
+    if !project_merges.reviews.is_empty() {
+        project_details.push(format!(
+            "Projects shared with other members sent for review before merging: {}",
+            project_merges.review_project_ids().join(", ")
+        ));
+    }
+    if let Some(dispute) = dispute.as_ref() {
+        project_details.push(format!(
+            "Commit dispute: {}, {}/{} commits owned by others",
+            dispute.dispute_type, dispute.shared_commits, dispute.report_commits
+        ));
+    }
+    let project_details = if project_details.is_empty() {
+        None
+    } else {
+        Some(project_details.join(". "))
+    };
+
     info!("ProjectID: {}", project_id);
+    SubmissionStatus::add_status(
+        &config.pg_client,
+        &submission_id,
+        &signer_id,
+        SubmissionStatus::STAGE_PROJECT_ASSIGNED,
+        Some(&project_id),
+        project_details.as_deref(),
+    )
+    .await;
+
+    // re-running the app on an unchanged repo produces an identical report
+    // there is nothing new in it to justify copying it and regenerating the profile
+    // unless it completes a merge of projects
+    // the reviews, the fingerprint and the dispute of an unchanged report were recorded when it was stored the first time
+    if project_merges.merged_project_ids.is_empty()
+        && ReportHash::is_unchanged(&config.pg_client, &owner_id, &project_id, &report_hash).await?
+    {
+        info!("Unchanged report for {}/{}", owner_id, project_id);
+        delete_s3_object(config, s3_key.clone()).await?;
+        SubmissionStatus::add_status(
+            &config.pg_client,
+            &submission_id,
+            &signer_id,
+            SubmissionStatus::STAGE_UNCHANGED,
+            Some(&project_id),
+            Some("No changes since the previous report for this project"),
+        )
+        .await;
+        return Ok(());
+    }
 
-    // split all all known commits into commit/timestamp and add them all to the DB
+    // split all all known commits into commit/timestamp for the DB
     let mut commit_hashes: Vec<String> = Vec::new();
     let mut commit_timestamps: Vec<i64> = Vec::new();
-    for commit in valid_commits {
-        commit_hashes.push(commit.0);
-        commit_timestamps.push(commit.1);
+    let mut commit_sha1s: Vec<Option<String>> = Vec::new();
+    for commit in valid_commits.values() {
+        commit_hashes.push(commit.hash.clone());
+        commit_timestamps.push(commit.ts);
+        commit_sha1s.push(commit.sha1.clone());
     }
-    CommitOwnership::add_commits(&config.pg_client, &owner_id, &project_id, &commit_hashes, &commit_timestamps).await?;
 
     // check if this report is the latest known for this project
-    let latest_report_commit_ts = report.last_contributor_commit_date_epoch.unwrap_or_default();
-    let latest_project_commit_ts =
-        CommitOwnership::get_latest_project_commit(&config.pg_client, &owner_id, &project_id).await?;
+    // implausible timestamps are ignored on both sides, otherwise a single commit dated 100 years ahead
+    // would make every later report for the project look out of order
+    let now = Utc::now().timestamp();
+    let mut ts_anomalies: Vec<String> = Vec::new();
+    if implausible_commits > 0 {
+        ts_anomalies.push(format!("Implausible commit timestamps: {}", implausible_commits));
+    }
+    let latest_plausible_report_commit_ts = valid_commits
+        .values()
+        .filter(|commit| commit.is_plausible)
+        .map(|commit| commit.ts)
+        .max()
+        .unwrap_or_default();
+    let latest_report_commit_ts = match report.last_contributor_commit_date_epoch {
+        Some(v) if is_plausible_commit_ts(v, now) => v,
+        last_contributor_commit_date_epoch => {
+            // fall back on the latest plausible commit in the report
+            if let Some(v) = last_contributor_commit_date_epoch {
+                ts_anomalies.push(format!("Implausible last_contributor_commit_date_epoch: {}", v));
+            }
+            latest_plausible_report_commit_ts
+        }
+    };
+    // the commits of this report and of the projects merged into this one are not in the project yet,
+    // so they are added to the comparison to make it the same as if they were already stored
+    let mut latest_project_commit_ts = latest_plausible_report_commit_ts;
+    for latest_project_id in std::iter::once(&project_id).chain(project_merges.merged_project_ids.iter()) {
+        latest_project_commit_ts = CommitOwnership::get_latest_project_commit(
+            &config.pg_client,
+            &owner_id,
+            latest_project_id,
+            MIN_PLAUSIBLE_COMMIT_TS,
+            now + MAX_FUTURE_COMMIT_TS_DRIFT_SECS,
+        )
+        .await?
+        .max(latest_project_commit_ts);
+    }
+    let is_latest = latest_report_commit_ts >= latest_project_commit_ts;
+
+    // the anomaly is recorded against the submission for the member to see why the dates were not trusted
+    let ts_anomaly_details = if ts_anomalies.is_empty() {
+        None
+    } else {
+        let details = ts_anomalies.join(", ");
+        warn!("Commit ts anomaly in {}: {}", s3_key, details);
+        Some(details)
+    };
 
-    // move it to the member's folder
     // the source has the timestamp of the submission in the name, but the dest should have the timestamp of the last commit
-    let copy_with_ts = copy_within_s3(
-        config,
-        s3_key.clone(),
-        [
-            config.s3_report_prefix.as_str(),
-            "/",
-            owner_id.as_str(),
-            "/",
-            project_id.as_str(),
-            "/",
+    // reports without commits have no commit SHA1, so the report hash is used to make the name unique
+    let report_s3_name_suffix = if last_contributor_commit_sha1.is_empty() {
+        report_hash.as_str()
+    } else {
+        last_contributor_commit_sha1.as_str()
+    };
+    let project_s3_key = [
+        config.s3_report_prefix.as_str(),
+        "/",
+        owner_id.as_str(),
+        "/",
+        project_id.as_str(),
+        "/",
+    ]
+    .concat();
+    let s3_keys = ReportS3Keys {
+        inbox: s3_key.clone(),
+        with_ts: [
+            project_s3_key.as_str(),
             latest_report_commit_ts.to_string().as_str(),
             "_",
-            last_contributor_commit_sha1.as_str(),
+            report_s3_name_suffix,
             REPORT_FILE_EXT_IN_S3,
         ]
         .concat(),
-    );
+        // an out of order report does not replace the latest report for the project
+        latest: if is_latest {
+            Some([project_s3_key.as_str(), "report", REPORT_FILE_EXT_IN_S3].concat())
+        } else {
+            None
+        },
+        merge_copies: project_merges.copies,
+    };
+
+    // all PG writes for the report are applied in a single transaction
+    let (emails, is_primary): (Vec<String>, Vec<bool>) = user_emails.into_iter().unzip();
+    let report_writes = ReportWrites {
+        owner_id: owner_id.clone(),
+        project_id: project_id.clone(),
+        merged_project_ids: project_merges.merged_project_ids.clone(),
+        merge_reviews: project_merges.reviews,
+        // the latest project for the remote URL is used for its future reports without commits
+        fingerprint,
+        dispute,
+        commit_hashes,
+        commit_timestamps,
+        commit_sha1s,
+        emails,
+        is_primary,
+        gh_login_gist_latest: report.gh_validation_id.clone(),
+        // the first in alphabetical order if there are several, which is rare
+        gh_login_noreply: gh_noreply_logins.into_iter().next(),
+        report_hash: report_hash.clone(),
+        is_latest,
+    };
+
+    // copy it and the reports of the merged projects to the member's folder, update PG and remove it from the inbox
+    // partial copies are undone on failure, so the retry of the S3 event starts from the same state
+    store_report(config, &s3_keys, &report_writes).await?;
 
-    // short-circuit the processing here if it's an out of order report
-    // it may be possible that someone makes a commit with a date 100 years ahead in the future
-    // then that commit will always be the latest and the project will never update according to this logic
-    if latest_report_commit_ts < latest_project_commit_ts {
+    // PG points at the copies of the merged reports now
+    delete_merged_reports(config, &project_merges.merged_s3_keys).await;
+
+    if !is_latest {
         warn!(
             "Out of order report for {}/{}. Latest commit ts in PG: {}, report: {}",
             owner_id, project_id, latest_project_commit_ts, latest_report_commit_ts
         );
-
-        // copy the report with the timestamp, but do not update the latest report for the project
-        // because this one arrived out of order
-        copy_with_ts.await?;
-        delete_s3_object(config, s3_key.clone()).await?;
+        // the report was processed successfully, it's just not the latest one
+        let details = match ts_anomaly_details.as_ref() {
+            Some(v) => format!("A newer report for this project was already received. {}", v),
+            None => "A newer report for this project was already received".to_owned(),
+        };
+        SubmissionStatus::add_status(
+            &config.pg_client,
+            &submission_id,
+            &signer_id,
+            SubmissionStatus::STAGE_SUPERSEDED,
+            Some(&project_id),
+            Some(&details),
+        )
+        .await;
         return Ok(());
     }
 
-    // copy it again as the latest report with a predefined file name
-    let copy_latest = copy_within_s3(
-        config,
-        s3_key.clone(),
-        [
-            config.s3_report_prefix.as_str(),
-            "/",
-            owner_id.as_str(),
-            "/",
-            project_id.as_str(),
-            "/report",
-            REPORT_FILE_EXT_IN_S3,
-        ]
-        .concat(),
-    );
+    SubmissionStatus::add_status(
+        &config.pg_client,
+        &submission_id,
+        &signer_id,
+        SubmissionStatus::STAGE_QUEUED,
+        Some(&project_id),
+        ts_anomaly_details.as_deref(),
+    )
+    .await;
 
-    // copy both concurrently
-    let copy_results = futures::join!(copy_with_ts, copy_latest);
-    if copy_results.0.is_err() || copy_results.1.is_err() {
-        return Err(Error::from("Failed to copy reports."));
+    Ok(())
+}
+
+/// Moves a submission that cannot be processed to `failed/` and records the failure in its status.
+/// Returns Ok because retrying will not help, unless the move itself failed.
+/// * `signer_id`: the key that signed the submission, not the canonical owner_id, or None if the inbox key
+/// has no valid key in it, in which case there is no status to record
+async fn fail_submission(
+    config: &Config,
+    s3_key: String,
+    signer_id: Option<&String>,
+    submission_id: &Option<String>,
+    details: &str,
+) -> Result<(), Error> {
+    move_to_failed(config, s3_key, signer_id.map(|v| v.as_str()).unwrap_or_default(), submission_id, details).await?;
+    if let Some(signer_id) = signer_id {
+        SubmissionStatus::add_failure(&config.pg_client, submission_id, signer_id, None, details).await;
     }
+    Ok(())
+}
 
-    // mark the developer record for re-processing
-    Dev::queue_up_for_update(&config.pg_client, &owner_id, &report.gh_validation_id).await?;
-
-    // drive email insertion jobs to completion
-    let mut email_addition_failed = false;
-    loop {
-        match email_jobs.next().await {
-            Some(job_result) => {
-                // a job was completed
-                if job_result.is_err() {
-                    email_addition_failed = true;
-                }
-            }
-            None => {
-                // no more jobs left in the futures queue
-                info!("All repo jobs processed");
-                break;
-            }
-        }
+/// Returns a base58 encoded SHA256 of the unzipped report.
+/// The unzipped contents are hashed because gzip headers may differ for identical reports.
+fn hash_report(report: &[u8]) -> String {
+    bs58::encode(ring::digest::digest(&ring::digest::SHA256, report).as_ref()).into_string()
+}
+
+/// Returns a base58 encoded SHA256 of the normalised remote URL of the project, e.g. `github.com/stackmuncher/stm_server`,
+/// or None if the report has no remote. Used as the project identity for reports without commit history.
+fn project_fingerprint(github_user_name: Option<&String>, github_repo_name: Option<&String>) -> Option<String> {
+    let github_user_name = github_user_name?.trim().to_lowercase();
+    let github_repo_name = github_repo_name?.trim().to_lowercase();
+    let github_repo_name = github_repo_name.strip_suffix(".git").unwrap_or(&github_repo_name);
+    if github_user_name.is_empty() || github_repo_name.is_empty() {
+        return None;
     }
 
-    if email_addition_failed {
-        return Err(Error::from("Failed to add one or more email addresses to t_email_ownership"));
+    let remote_url = ["github.com/", github_user_name.as_str(), "/", github_repo_name].concat();
+    Some(bs58::encode(ring::digest::digest(&ring::digest::SHA256, remote_url.as_bytes()).as_ref()).into_string())
+}
+
+/// Returns TRUE if the report commit and the commit from PG share the 8-char hash prefix, but are different commits.
+/// It can only be detected if both have the full SHA1. Commits from older reports have no SHA1 and are matched
+/// by the prefix and the timestamp only. `migrate_commit_sha1.sql` does not backfill them, so this check never
+/// applies to them until a newer report with the same commit adds the SHA1.
+fn is_hash_collision(commit: &ReportCommit, ownership: &CommitOwnership) -> bool {
+    match (commit.sha1.as_ref(), ownership.commit_sha1.as_ref()) {
+        (Some(report_sha1), Some(pg_sha1)) => report_sha1 != pg_sha1,
+        _ => false,
     }
+}
 
-    // delete the submission from inbox queue
-    delete_s3_object(config, s3_key.clone()).await?;
+/// Returns the commits from PG that are the same as any of the report commits with the same 8-char prefix,
+/// i.e. have the same timestamp and no SHA1 collision, and the number of PG commits rejected as SHA1 collisions.
+/// A report may have several commits with the same prefix, so every one of them is checked.
+fn match_commits(
+    report_commits: &Vec<&ReportCommit>,
+    commit_ownerships: Vec<CommitOwnership>,
+) -> (Vec<CommitOwnership>, usize) {
+    let mut colliding_commits = 0usize;
+    let matching_commits = commit_ownerships
+        .into_iter()
+        .filter(|ownership| {
+            let same_prefix = report_commits
+                .iter()
+                .filter(|commit| commit.hash == ownership.commit_hash)
+                .collect::<Vec<&&ReportCommit>>();
+            if same_prefix
+                .iter()
+                .any(|commit| commit.ts == ownership.commit_ts && !is_hash_collision(commit, ownership))
+            {
+                true
+            } else {
+                if same_prefix.iter().any(|commit| is_hash_collision(commit, ownership)) {
+                    colliding_commits += 1;
+                }
+                false
+            }
+        })
+        .collect::<Vec<CommitOwnership>>();
 
-    Ok(())
+    (matching_commits, colliding_commits)
 }
 
-/// Returns a cleaned up and normalized email address or None if the address doesn't seem to be deliverable.
-/// The length must be between 4 and 150 unicode chars. This validation is specific for the purpose of this module and the DB constraints.
-fn validate_email_address(email: &String) -> Option<String> {
-    let email = email.trim();
-    let email = email.trim().to_lowercase();
-    if email.len() < 4
-        || email.split("@").count() != 2
-        || email.contains(" ")
-        || email.contains("\n")
-        || email.contains("\r")
-        || email.contains("\t")
-        || email.contains("\\")
-        || email.contains("\0")
-    {
-        return None;
-    }
+/// Extracts the owner_id and the submission_id from an inbox key, e.g.
+/// `queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`.
+/// Older submissions have no submission_id, e.g. `queue/1621680890_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`.
+/// Base58 values never contain `_`, so it is safe to split at it.
+fn parse_inbox_key(s3_key: &str) -> Option<(String, Option<String>)> {
+    let file_name = s3_key.rsplit("/").next()?;
+    let file_name = file_name.split(".").next()?;
 
-    // Postgres DB does not allow more than 150 unicode chars per email.
-    // A longer than that email address is probably meaningless and would get stuck in the pipes.
-    let unicode_char_count = email.graphemes(true).count();
-    if unicode_char_count > 150 {
-        return None;
+    let parts = file_name.split("_").collect::<Vec<&str>>();
+    match parts.len() {
+        2 if !parts[1].is_empty() => Some((parts[1].to_owned(), None)),
+        3 if !parts[1].is_empty() && !parts[2].is_empty() => Some((parts[2].to_owned(), Some(parts[1].to_owned()))),
+        _ => None,
     }
+}
 
-    Some(email.to_lowercase())
+#[test]
+fn parse_inbox_key_test() {
+    assert_eq!(
+        parse_inbox_key("queue/1621680890_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz"),
+        Some(("7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7".to_owned(), None))
+    );
+    assert_eq!(
+        parse_inbox_key("queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz"),
+        Some((
+            "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7".to_owned(),
+            Some("Wgx98Rbi8nQuL9ddn3mTk1".to_owned())
+        ))
+    );
+    assert_eq!(parse_inbox_key("queue/1621680890.gz"), None);
+    assert_eq!(parse_inbox_key("queue/1621680890_a_b_c.gz"), None);
 }
 
-/// Returns a tuple with a valid commit hash and a timestamp if they seem to be valid.
-/// The timestamp can actually be any valid i64 number and not a realistic date.
-fn validate_short_commit_hash(commit_hash_with_ts: &String, config: &Config) -> Option<(String, i64)> {
-    let split = commit_hash_with_ts.split("_").collect::<Vec<&str>>();
-    if split.len() != 2 {
-        return None;
-    }
+#[test]
+fn project_fingerprint_test() {
+    let user = "stackmuncher".to_owned();
+    let repo = "stm_server".to_owned();
+    let fingerprint = project_fingerprint(Some(&user), Some(&repo));
+    assert!(fingerprint.is_some());
 
-    if split[0].len() != 8 || !config.commit_hash_regex_short.is_match(split[0]) {
-        return None;
-    }
+    // the same remote written differently
+    assert_eq!(
+        project_fingerprint(Some(&"StackMuncher".to_owned()), Some(&"stm_server.git".to_owned())),
+        fingerprint
+    );
+    assert_eq!(
+        project_fingerprint(Some(&" stackmuncher ".to_owned()), Some(&"STM_Server".to_owned())),
+        fingerprint
+    );
 
-    // there should be no commits with no dates
-    if let Ok(ts) = i64::from_str_radix(split[1], 10) {
-        return Some((split[0].to_string(), ts));
-    }
+    // a different repo
+    assert_ne!(project_fingerprint(Some(&user), Some(&"stm_app".to_owned())), fingerprint);
 
-    None
+    // no remote
+    assert_eq!(project_fingerprint(None, Some(&repo)), None);
+    assert_eq!(project_fingerprint(Some(&user), None), None);
+    assert_eq!(project_fingerprint(Some(&user), Some(&"".to_owned())), None);
+    assert_eq!(project_fingerprint(Some(&user), Some(&".git".to_owned())), None);
 }
 
-/// Returns TRUE if the owner_id decodes from base58 into exactly 256 bytes.
-/// Logs a warning and returns FALSE otherwise.
-/// TODO: this should be a shared utility function!!!
-fn validate_owner_id(owner_id: &str) -> bool {
-    match bs58::decode(owner_id).into_vec() {
-        Err(e) => {
-            warn!("Invalid owner_id: {}. Cannot decode from bs58: {}", owner_id, e);
-            false
-        }
-        Ok(v) => {
-            if v.len() == 32 {
-                true
-            } else {
-                warn!("Invalid owner_id: {}. Decoded to {} bytes", owner_id, v.len());
-                false
-            }
+#[test]
+fn hash_report_test() {
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+
+    let report = br#"{"timestamp":"2021-08-13T02:33:06.671340840+00:00","git_ids_included":["max@onebro.me"]}"#;
+
+    // the same report zipped at different times, under different names and with different compression levels
+    let mut encoder = GzBuilder::new()
+        .filename("report.json")
+        .mtime(1628821732)
+        .write(Vec::new(), Compression::best());
+    encoder.write_all(report).unwrap();
+    let gz_1 = encoder.finish().unwrap();
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(report).unwrap();
+    let gz_2 = encoder.finish().unwrap();
+    assert_ne!(gz_1, gz_2);
+
+    let hash_gz = |gz: &[u8]| hash_report(&decompress_with_limit(gz, 1024, 1024).expect("Failed to unzip"));
+    assert_eq!(hash_gz(&gz_1), hash_gz(&gz_2));
+    assert_eq!(hash_gz(&gz_1), hash_report(report));
+
+    // any change in the contents changes the hash
+    assert_ne!(hash_report(report), hash_report(&report[..report.len() - 1]));
+}
+
+#[test]
+fn is_hash_collision_test() {
+    let sha1 = "7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4".to_owned();
+    let colliding_sha1 = "7474684a00000000000000000000000000000000".to_owned();
+    let report_commit = |sha1: Option<&String>| ReportCommit {
+        hash: "7474684a".to_owned(),
+        sha1: sha1.cloned(),
+        ts: 1595904770,
+        is_plausible: true,
+    };
+    let ownership = |sha1: Option<&String>| CommitOwnership {
+        owner_id: "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        project_id: "Wgx98Rbi8nQuL9ddn3mTk1".to_owned(),
+        commit_hash: "7474684a".to_owned(),
+        commit_ts: 1595904770,
+        commit_sha1: sha1.cloned(),
+    };
+
+    // the same commit with the full SHA1 on both sides
+    assert!(!is_hash_collision(&report_commit(Some(&sha1)), &ownership(Some(&sha1))));
+
+    // the same prefix and timestamp, but a different commit
+    assert!(is_hash_collision(&report_commit(Some(&sha1)), &ownership(Some(&colliding_sha1))));
+
+    // old 8-char data on either side can only be matched by the prefix and the timestamp
+    assert!(!is_hash_collision(&report_commit(Some(&sha1)), &ownership(None)));
+    assert!(!is_hash_collision(&report_commit(None), &ownership(Some(&colliding_sha1))));
+    assert!(!is_hash_collision(&report_commit(None), &ownership(None)));
+}
+
+#[test]
+fn match_commits_test() {
+    let sha1 = "7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4".to_owned();
+    let colliding_sha1 = "7474684a00000000000000000000000000000000".to_owned();
+    let report_commit = |sha1: Option<&String>, ts: i64| ReportCommit {
+        hash: "7474684a".to_owned(),
+        sha1: sha1.cloned(),
+        ts,
+        is_plausible: true,
+    };
+    let ownership = |project_id: &str, sha1: Option<&String>, ts: i64| CommitOwnership {
+        owner_id: "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        project_id: project_id.to_owned(),
+        commit_hash: "7474684a".to_owned(),
+        commit_ts: ts,
+        commit_sha1: sha1.cloned(),
+    };
+
+    // PG has two different commits with the same prefix and timestamp, only the one with the same SHA1 matches
+    let commit = report_commit(Some(&sha1), 1595904770);
+    let ownerships = vec![
+        ownership("A", Some(&sha1), 1595904770),
+        ownership("B", Some(&colliding_sha1), 1595904770),
+    ];
+    assert_eq!(match_commits(&vec![&commit], ownerships.clone()), (vec![ownerships[0].clone()], 1));
+
+    // the report has both commits with the same prefix, so both match
+    let colliding_commit = report_commit(Some(&colliding_sha1), 1595904770);
+    assert_eq!(
+        match_commits(&vec![&commit, &colliding_commit], ownerships.clone()),
+        (ownerships.clone(), 0)
+    );
+
+    // a commit stored without the SHA1 matches by the prefix and the timestamp only
+    let ownerships = vec![ownership("A", None, 1595904770), ownership("B", None, 1595904771)];
+    assert_eq!(match_commits(&vec![&commit], ownerships.clone()), (vec![ownerships[0].clone()], 0));
+
+    // a report commit without the SHA1 matches any PG commit with the same prefix and timestamp
+    let old_commit = report_commit(None, 1595904770);
+    let ownerships = vec![
+        ownership("A", Some(&sha1), 1595904770),
+        ownership("B", Some(&colliding_sha1), 1595904771),
+    ];
+    assert_eq!(match_commits(&vec![&old_commit], ownerships.clone()), (vec![ownerships[0].clone()], 0));
+
+    assert_eq!(match_commits(&Vec::new(), ownerships), (Vec::new(), 0));
+}
+
+#[test]
+fn parse_event_test() {
+    // a direct S3 event with 2 records, one of them without a key
+    let event = serde_json::json!({
+        "Records": [
+            { "eventSource": "aws:s3", "s3": { "object": { "key": "queue/1621680890_a.gz", "size": 100 } } },
+            { "eventSource": "aws:s3", "s3": { "object": { "size": 200 } } }
+        ]
+    });
+    let event = parse_event(event).expect("Failed to parse S3 event");
+    assert!(!event.is_sqs);
+    assert_eq!(
+        event.records,
+        vec![InboxRecord {
+            message_id: None,
+            s3_key: "queue/1621680890_a.gz".to_owned(),
+            object_size: 100
+        }]
+    );
+
+    // an SQS batch with 2 S3 records in one message, a test event and an invalid body
+    let s3_event = serde_json::json!({
+        "Records": [
+            { "s3": { "object": { "key": "queue/1621680890_a.gz", "size": 100 } } },
+            { "s3": { "object": { "key": "queue/1621680891_b.gz", "size": 200 } } }
+        ]
+    });
+    let event = serde_json::json!({
+        "Records": [
+            { "eventSource": "aws:sqs", "messageId": "m1", "body": s3_event.to_string() },
+            { "eventSource": "aws:sqs", "messageId": "m2", "body": r#"{"Service":"Amazon S3","Event":"s3:TestEvent"}"# },
+            { "eventSource": "aws:sqs", "messageId": "m3", "body": "not json" }
+        ]
+    });
+    let event = parse_event(event).expect("Failed to parse SQS event");
+    assert!(event.is_sqs);
+    assert_eq!(event.records.len(), 2);
+    assert_eq!(event.records[1].message_id, Some("m1".to_owned()));
+    assert_eq!(event.records[1].s3_key, "queue/1621680891_b.gz");
+    assert_eq!(event.failed_message_ids, vec!["m3".to_owned()].into_iter().collect::<HashSet<String>>());
+
+    // not an event at all
+    assert!(parse_event(serde_json::json!({ "Records": "x" })).is_err());
+}
+
+#[test]
+fn group_records_test() {
+    let record = |s3_key: &str| InboxRecord {
+        message_id: None,
+        s3_key: s3_key.to_owned(),
+        object_size: 100,
+    };
+    let records = vec![
+        record("queue/1621680892_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz"),
+        record("queue/1621680890_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz"),
+        record("queue/1621680891_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz"),
+        record("queue/1621680893.gz"),
+        // a key linked to 9PdH...LeFK
+        record("queue/1621680889_EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB.gz"),
+    ];
+
+    let mut groups = group_records(records, |record| match parse_inbox_key(&record.s3_key) {
+        Some((signer_id, _)) if signer_id == "EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB" => {
+            "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned()
         }
-    }
+        Some((signer_id, _)) => signer_id,
+        None => record.s3_key.clone(),
+    })
+    .into_iter()
+    .map(|group| group.into_iter().map(|record| record.s3_key).collect::<Vec<String>>())
+    .collect::<Vec<Vec<String>>>();
+    groups.sort();
+
+    assert_eq!(
+        groups,
+        vec![
+            vec![
+                "queue/1621680889_EFY9NXEytYgBgGsyAeGfXzkBEBQzC9NXFyj47EPdmVLB.gz".to_owned(),
+                "queue/1621680891_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+                "queue/1621680892_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+            ],
+            vec!["queue/1621680890_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz".to_owned()],
+            vec!["queue/1621680893.gz".to_owned()],
+        ]
+    );
 }
@@ -1,10 +1,14 @@
 use crate::config::Config;
 use lambda_runtime::Error;
 
+mod commit_dispute;
 mod config;
 mod handler;
 mod postgres;
+mod project_merge;
+mod report_store;
 mod s3;
+mod sqs;
 
 /// Boilerplate Lambda runtime code with conditional debug proxy
 #[tokio::main]
@@ -69,7 +73,8 @@ mod proxy {
             let (payload, receipt_handle) = get_input().await?;
             info!("New msg");
             // invoke the handler
-            crate::handler::my_handler(payload.event, payload.ctx, config).await?;
+            let response = crate::handler::my_handler(payload.event, payload.ctx, config).await?;
+            info!("Response: {}", response);
 
             delete_message(receipt_handle).await?;
             info!("Msg deleted");
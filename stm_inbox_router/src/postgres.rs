@@ -1,3 +1,4 @@
+use crate::commit_dispute::Dispute;
 use lambda_runtime::Error;
 use log::warn;
 use serde::{Deserialize, Serialize};
@@ -11,6 +12,8 @@ pub(crate) struct CommitOwnership {
     pub project_id: String,
     pub commit_hash: String,
     pub commit_ts: i64,
+    /// The full SHA1 or None if the commit was added from a report without SHA1s
+    pub commit_sha1: Option<String>,
 }
 
 impl From<&Row> for CommitOwnership {
@@ -21,15 +24,59 @@ impl From<&Row> for CommitOwnership {
             project_id: row.get("project_id"),
             commit_hash: row.get("commit_hash"),
             commit_ts: row.get("commit_ts"),
+            commit_sha1: row.get("commit_sha1"),
         }
     }
 }
 
-/// Corresponds to `t_email_ownership` table
-pub(crate) struct EmailOwnership {}
+/// PG writes of a single routed report to `t_commit_ownership`, `t_email_ownership`, `t_dev` and `t_report_hash`
+/// with the merges of the projects the report matched in `t_project_merge` and `t_project_merge_review`,
+/// its fingerprint in `t_project_fingerprint` and its dispute in `t_commit_dispute`.
+/// Every commit hash must have a corresponding timestamp and a full SHA1 or None, every email an `is_primary` flag.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct ReportWrites {
+    /// The canonical owner_id
+    pub owner_id: String,
+    pub project_id: String,
+    /// Projects to merge into `project_id`, their S3 reports must be already copied into the `project_id` folder
+    pub merged_project_ids: Vec<String>,
+    /// Projects matched by the report that have owners outside of the member account, with the list of those owners
+    pub merge_reviews: Vec<(String, Vec<String>)>,
+    /// The fingerprint of the remote URL of the report to be assigned to `project_id`
+    pub fingerprint: Option<String>,
+    /// Commits of the report already owned by other owners
+    pub dispute: Option<Dispute>,
+    pub commit_hashes: Vec<String>,
+    pub commit_timestamps: Vec<i64>,
+    pub commit_sha1s: Vec<Option<String>>,
+    pub emails: Vec<String>,
+    pub is_primary: Vec<bool>,
+    /// The GitHub validation gist ID from the report
+    pub gh_login_gist_latest: Option<String>,
+    /// An unvalidated GitHub login from a noreply commit email in the report, e.g. `rimutaka`
+    pub gh_login_noreply: Option<String>,
+    pub report_hash: String,
+    /// Out of order reports only add their commits. The emails, the dev and the hash are updated for the latest report.
+    pub is_latest: bool,
+}
+
+/// Corresponds to `t_key_link` table
+pub(crate) struct KeyLink {}
+
+/// Corresponds to `t_submission_status` table
+pub(crate) struct SubmissionStatus {}
+
+/// Corresponds to `t_report_hash` table
+pub(crate) struct ReportHash {}
+
+/// Corresponds to `t_project_merge` table
+pub(crate) struct ProjectMerge {}
 
-/// Corresponds to `t_dev` table
-pub(crate) struct Dev {}
+/// Corresponds to `t_project_fingerprint` table
+pub(crate) struct ProjectFingerprint {}
+
+/// Corresponds to `t_commit_dispute` table
+pub(crate) struct CommitDispute {}
 
 impl CommitOwnership {
     /// Returns a list of all matching commit details, incl project, owner and timestamp.
@@ -63,55 +110,20 @@ impl CommitOwnership {
             .collect::<Vec<CommitOwnership>>())
     }
 
-    /// Adds a list of commits from a report.
-    /// Every commit hash must have a corresponding timestamp or None.
-    /// Do not use with en empty `commit_hash`.
-    pub(crate) async fn add_commits(
-        pg_client: &Client,
-        owner_id: &String,
-        project_id: &String,
-        commit_hashes: &Vec<String>,
-        commit_ts: &Vec<i64>,
-    ) -> Result<(), Error> {
-        // make sure the list is not empty
-        if commit_hashes.is_empty() {
-            warn!("Empty list of commits to add to DB. It's a bug!");
-            return Ok(());
-        }
-
-        info!("Adding {} commits starting from {}", commit_hashes.len(), &commit_hashes[0]);
-
-        // push the data to PG, log the result, nothing to return
-        let rows = match pg_client
-            .execute(
-                "select stm_add_commits($1::varchar, $2::varchar, $3::varchar[], $4::bigint[])",
-                &[owner_id, project_id, commit_hashes, commit_ts],
-            )
-            .await
-        {
-            Ok(v) => v,
-            Err(e) => {
-                error!("stm_add_commits failed with {}", e);
-                return Err(Error::from(e));
-            }
-        };
-
-        debug!("Rows updated: {}", rows);
-        Ok(())
-    }
-
-    /// Returns the latest timestamp for the specified owner/project ids.
-    /// Returns an error if the DB has no matching data.
+    /// Returns the latest timestamp for the specified owner/project ids within `min_ts` .. `max_ts` range
+    /// or 0 if there are no commits in that range.
     pub(crate) async fn get_latest_project_commit(
         pg_client: &Client,
         owner_id: &String,
         project_id: &String,
+        min_ts: i64,
+        max_ts: i64,
     ) -> Result<i64, Error> {
         // get the data from PG
         let rows = match pg_client
             .query(
-                "select * from stm_get_latest_project_commit($1::varchar, $2::varchar)",
-                &[owner_id, project_id],
+                "select * from stm_get_latest_project_commit($1::varchar, $2::varchar, $3::bigint, $4::bigint)",
+                &[owner_id, project_id, &min_ts, &max_ts],
             )
             .await
         {
@@ -142,27 +154,102 @@ impl CommitOwnership {
     }
 }
 
-impl EmailOwnership {
-    /// Associates an email address with a public key or updates `is_primary` flag for existing records.
-    pub(crate) async fn add_email(
+impl ReportHash {
+    /// Returns TRUE if the latest processed report for the owner/project has the same hash.
+    pub(crate) async fn is_unchanged(
         pg_client: &Client,
         owner_id: &String,
-        email: &String,
-        is_primary: &bool,
-    ) -> Result<(), Error> {
-        info!("Adding email {}, primary: {}", email, is_primary);
+        project_id: &String,
+        report_hash: &String,
+    ) -> Result<bool, Error> {
+        let rows = match pg_client
+            .query(
+                "select stm_is_report_unchanged($1::varchar, $2::varchar, $3::varchar)",
+                &[owner_id, project_id, report_hash],
+            )
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_is_report_unchanged failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        // the SP always returns a single boolean
+        match rows.get(0) {
+            Some(row) => match row.try_get::<_, bool>(0) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(Error::from(format!("Cannot convert stm_is_report_unchanged result to bool: {}", e))),
+            },
+            None => Err(Error::from("stm_is_report_unchanged returned no rows")),
+        }
+    }
+}
+
+impl ReportWrites {
+    /// Applies all writes in a single transaction via `stm_commit_report`. Nothing is written if any of the steps fails.
+    /// It is safe to repeat the call for the same report.
+    pub(crate) async fn commit(&self, pg_client: &Client) -> Result<(), Error> {
+        info!(
+            "Committing report for {}/{}: {} merged projects, {} reviews, {} commits, {} emails, latest: {}",
+            self.owner_id,
+            self.project_id,
+            self.merged_project_ids.len(),
+            self.merge_reviews.len(),
+            self.commit_hashes.len(),
+            self.emails.len(),
+            self.is_latest
+        );
+
+        // the reviews are passed as flat (project, owner) pairs because PG arrays of arrays must have the same length
+        let mut review_project_ids: Vec<&String> = Vec::new();
+        let mut review_owner_ids: Vec<&String> = Vec::new();
+        for (review_project_id, other_owner_ids) in &self.merge_reviews {
+            for other_owner_id in other_owner_ids {
+                review_project_ids.push(review_project_id);
+                review_owner_ids.push(other_owner_id);
+            }
+        }
+        let dispute_type = self.dispute.as_ref().map(|dispute| dispute.dispute_type);
+        let dispute_owner_ids = self
+            .dispute
+            .as_ref()
+            .map(|dispute| dispute.other_owner_ids.clone())
+            .unwrap_or_default();
+        let dispute_shared_commits = self.dispute.as_ref().map(|dispute| dispute.shared_commits as i32);
+        let dispute_report_commits = self.dispute.as_ref().map(|dispute| dispute.report_commits as i32);
 
-        // push the data to PG, log the result, nothing to return
         let rows = match pg_client
             .execute(
-                "select stm_add_email($1::varchar, $2::varchar, $3::boolean)",
-                &[owner_id, email, is_primary],
+                "select stm_commit_report($1::varchar, $2::varchar, $3::varchar[], $4::varchar[], $5::varchar[], $6::varchar, $7::varchar, $8::varchar[], $9::integer, $10::integer, $11::varchar[], $12::bigint[], $13::varchar[], $14::varchar[], $15::boolean[], $16::varchar, $17::varchar, $18::varchar, $19::boolean)",
+                &[
+                    &self.owner_id,
+                    &self.project_id,
+                    &self.merged_project_ids,
+                    &review_project_ids,
+                    &review_owner_ids,
+                    &self.fingerprint,
+                    &dispute_type,
+                    &dispute_owner_ids,
+                    &dispute_shared_commits,
+                    &dispute_report_commits,
+                    &self.commit_hashes,
+                    &self.commit_timestamps,
+                    &self.commit_sha1s,
+                    &self.emails,
+                    &self.is_primary,
+                    &self.gh_login_gist_latest,
+                    &self.gh_login_noreply,
+                    &self.report_hash,
+                    &self.is_latest,
+                ],
             )
             .await
         {
             Ok(v) => v,
             Err(e) => {
-                error!("stm_add_email failed with {}", e);
+                error!("stm_commit_report failed with {}", e);
                 return Err(Error::from(e));
             }
         };
@@ -172,25 +259,102 @@ impl EmailOwnership {
     }
 }
 
-impl Dev {
-    /// Updates the developer record to make it selectable for report update after a new submission.
-    pub(crate) async fn queue_up_for_update(pg_client: &Client, owner_id: &String, gh_login_gist_latest: &Option<String>) -> Result<(), Error> {
-        info!("Queueing up report dev {}", owner_id);
+impl ProjectMerge {
+    /// Returns a list of (owner_id, project_id) pairs for all owners of the specified projects.
+    pub(crate) async fn get_project_owners(
+        pg_client: &Client,
+        project_ids: &Vec<String>,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let rows = match pg_client
+            .query("select * from stm_get_project_owners($1::varchar[])", &[project_ids])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("stm_get_project_owners failed with {}", e);
+                return Err(Error::from(e));
+            }
+        };
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("owner_id"), row.get("project_id")))
+            .collect::<Vec<(String, String)>>())
+    }
+}
 
-        // push the data to PG, log the result, nothing to return
+impl ProjectFingerprint {
+    /// Returns the project ID assigned to the fingerprint of the owner with a flag if the project has any commits
+    /// or None if the fingerprint is not known.
+    pub(crate) async fn get_project(
+        pg_client: &Client,
+        owner_id: &String,
+        fingerprint: &String,
+    ) -> Result<Option<(String, bool)>, Error> {
         let rows = match pg_client
-            .execute("select stm_queue_up_dev_report($1::varchar, $2::varchar)", &[owner_id, gh_login_gist_latest])
+            .query(
+                "select * from stm_get_fingerprint_project($1::varchar, $2::varchar)",
+                &[owner_id, fingerprint],
+            )
             .await
         {
             Ok(v) => v,
             Err(e) => {
-                error!("stm_queue_up_dev_report failed with {}", e);
+                error!("stm_get_fingerprint_project failed with {}", e);
                 return Err(Error::from(e));
             }
         };
 
-        debug!("Rows updated: {}", rows);
-        Ok(())
+        // there is at most one row per owner/fingerprint
+        match rows.get(0) {
+            Some(row) => Ok(Some((row.get("project_id"), row.get("has_commits")))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl CommitDispute {
+    /// The project is shared with other members, which is normal for team projects.
+    pub(crate) const TYPE_CO_CONTRIBUTORS: &'static str = "co_contributors";
+    /// The report is made of commits owned by others, e.g. a clone of someone else's repo.
+    pub(crate) const TYPE_FULL_HISTORY_CLAIM: &'static str = "full_history_claim";
+}
+
+impl KeyLink {
+    /// Returns the owner_id of the member account the key is linked to or the key itself if it is not linked.
+    pub(crate) async fn get_canonical_owner_id(pg_client: &Client, owner_id: &String) -> Result<String, Error> {
+        let rows = match pg_client
+            .query("select stm_get_canonical_owner_id($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(Error::from(format!("stm_get_canonical_owner_id for {} failed with {}", owner_id, e)));
+            }
+        };
+
+        // the SP always returns a value, even if the key is not linked
+        match rows.get(0) {
+            Some(row) => match row.try_get(0) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(Error::from(format!(
+                    "Cannot convert canonical owner_id to String for {} with {}",
+                    owner_id, e
+                ))),
+            },
+            None => Err(Error::from(format!("No canonical owner_id for {}", owner_id))),
+        }
+    }
+
+    /// Returns the canonical owner_id of the member account of the key followed by all keys linked to it.
+    pub(crate) async fn get_linked_keys(pg_client: &Client, owner_id: &String) -> Result<Vec<String>, Error> {
+        match pg_client
+            .query("select * from stm_get_linked_keys($1::varchar)", &[owner_id])
+            .await
+        {
+            Ok(rows) => Ok(rows.iter().map(|row| row.get(0)).collect::<Vec<String>>()),
+            Err(e) => Err(Error::from(format!("stm_get_linked_keys for {} failed with {}", owner_id, e))),
+        }
     }
 }
 
@@ -213,3 +377,60 @@ pub(crate) async fn get_pg_client(connection_string: &String) -> tokio_postgres:
     // return the client to the caller
     client
 }
+
+impl SubmissionStatus {
+    /// The router picked up the submission from the inbox and unzipped it.
+    pub(crate) const STAGE_ROUTED: &'static str = "routed";
+    /// The report was matched to an existing project or a new project was created for it.
+    pub(crate) const STAGE_PROJECT_ASSIGNED: &'static str = "project_assigned";
+    /// The report is identical to the previous one for the same project and was not processed any further.
+    pub(crate) const STAGE_UNCHANGED: &'static str = "unchanged";
+    /// The dev was queued up in `t_dev` for the profile to be regenerated.
+    pub(crate) const STAGE_QUEUED: &'static str = "queued";
+    /// The report was routed and its commits were added, but a newer report for the same project was received earlier,
+    /// so it did not replace the latest report of the project.
+    pub(crate) const STAGE_SUPERSEDED: &'static str = "superseded";
+    /// The router gave up on the submission. The reason is in `details`.
+    pub(crate) const STAGE_FAILED: &'static str = "failed";
+
+    /// Records a new stage for the submission. Submissions made by older versions of the inbox have no ID and are ignored.
+    /// The status is for the benefit of the member and a failure to record it is logged, but not propagated
+    /// to avoid failing the processing of the report itself.
+    /// * `owner_id`: the key that signed the submission, not the canonical owner_id
+    pub(crate) async fn add_status(
+        pg_client: &Client,
+        submission_id: &Option<String>,
+        owner_id: &String,
+        stage: &str,
+        project_id: Option<&String>,
+        details: Option<&str>,
+    ) {
+        let submission_id = match submission_id {
+            Some(v) => v,
+            None => return,
+        };
+
+        info!("Submission {} stage: {}", submission_id, stage);
+
+        if let Err(e) = pg_client
+            .execute(
+                "select stm_add_submission_status($1::varchar, $2::varchar, $3::varchar, $4::varchar, $5::varchar)",
+                &[submission_id, owner_id, &stage, &project_id, &details],
+            )
+            .await
+        {
+            error!("stm_add_submission_status failed with {}", e);
+        };
+    }
+
+    /// A shortcut for `add_status` with `STAGE_FAILED` and the reason of the failure.
+    pub(crate) async fn add_failure(
+        pg_client: &Client,
+        submission_id: &Option<String>,
+        owner_id: &String,
+        project_id: Option<&String>,
+        details: &str,
+    ) {
+        Self::add_status(pg_client, submission_id, owner_id, Self::STAGE_FAILED, project_id, Some(details)).await;
+    }
+}
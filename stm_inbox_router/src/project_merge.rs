@@ -0,0 +1,327 @@
+use crate::config::Config;
+use crate::postgres::{CommitOwnership, KeyLink, ProjectMerge};
+use crate::s3::REPORT_FILE_EXT_IN_S3;
+use lambda_runtime::Error;
+use std::collections::{HashMap, HashSet};
+use stm_shared::s3::{delete_from_s3, list_objects_from_s3};
+use tracing::{error, info, warn};
+
+/// S3 DeleteObjects API accepts up to 1000 keys per call
+const S3_DELETE_BATCH_SIZE: usize = 1000;
+
+/// Picks the project to keep when the report commits match more than one existing project
+/// and returns its ID with the IDs of the projects to be merged into it.
+/// The project with the most matching commits wins. A tie goes to the project with the latest matching commit,
+/// then to the lowest project ID to make the choice the same for any order of the input.
+/// * `matching_commits`: commits from PG that match the report on both, the hash and the timestamp
+fn resolve_project_conflict(matching_commits: &Vec<CommitOwnership>) -> Option<(String, Vec<String>)> {
+    // the same commit may be owned by several members, so only distinct hashes are counted
+    let mut projects: HashMap<&String, (HashSet<&String>, i64)> = HashMap::new();
+    for commit in matching_commits {
+        let project = projects
+            .entry(&commit.project_id)
+            .or_insert_with(|| (HashSet::new(), 0));
+        project.0.insert(&commit.commit_hash);
+        project.1 = project.1.max(commit.commit_ts);
+    }
+
+    let mut projects = projects
+        .into_iter()
+        .map(|(project_id, (commits, latest_ts))| (project_id, commits.len(), latest_ts))
+        .collect::<Vec<(&String, usize, i64)>>();
+    projects.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+
+    let mut project_ids = projects.into_iter().map(|project| project.0.clone());
+    let project_id = project_ids.next()?;
+
+    Some((project_id, project_ids.collect()))
+}
+
+/// Projects that are about to be merged into the project of a report. Nothing is written when the merges are planned.
+/// `copies` are made by `store_report` together with the report itself and undone if it fails, the commits are moved
+/// by `stm_commit_report` in the same transaction as the rest of the report and then `merged_s3_keys` can be deleted.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ProjectMerges {
+    /// Projects merged into the surviving project
+    pub merged_project_ids: Vec<String>,
+    /// Projects with owners outside of the member account that are sent for an admin review instead of merging,
+    /// with the list of those owners
+    pub reviews: Vec<(String, Vec<String>)>,
+    /// (source, dest) keys of the reports of the merged projects to be copied into the surviving project folders
+    pub copies: Vec<(String, String)>,
+    /// The original reports of the merged projects, to be deleted once PG points at the copies
+    pub merged_s3_keys: Vec<String>,
+}
+
+impl ProjectMerges {
+    /// Adds the merges from another call of `plan_merges` for the same report.
+    pub(crate) fn extend(&mut self, other: ProjectMerges) {
+        self.merged_project_ids.extend(other.merged_project_ids);
+        self.reviews.extend(other.reviews);
+        self.copies.extend(other.copies);
+        self.merged_s3_keys.extend(other.merged_s3_keys);
+    }
+
+    /// Returns TRUE if the project was either merged or sent for review.
+    pub(crate) fn contains(&self, project_id: &String) -> bool {
+        self.merged_project_ids.contains(project_id)
+            || self.reviews.iter().any(|(review_id, _)| review_id == project_id)
+    }
+
+    /// Returns the IDs of the projects sent for review.
+    pub(crate) fn review_project_ids(&self) -> Vec<String> {
+        self.reviews.iter().map(|(project_id, _)| project_id.clone()).collect()
+    }
+}
+
+/// Resolves a conflict between multiple projects matching the same report by planning the merge of the smaller projects
+/// into the one with the most matching commits. Returns the ID of the surviving project and the merges.
+pub(crate) async fn plan_conflicting_merges(
+    config: &Config,
+    owner_id: &String,
+    matching_commits: &Vec<CommitOwnership>,
+) -> Result<(String, ProjectMerges), Error> {
+    let (project_id, merged_project_ids) = match resolve_project_conflict(matching_commits) {
+        Some(v) => v,
+        None => return Err(Error::from("No projects to resolve the conflict between")),
+    };
+
+    let merges = plan_merges(config, owner_id, &project_id, &merged_project_ids).await?;
+
+    Ok((project_id, merges))
+}
+
+/// Lists the reports of `merged_project_ids` to be copied into `project_id` folder for all keys of the member. Only projects
+/// owned by the member account of `owner_id` are merged, the others are sent for an admin review and stay as they are.
+/// This function only reads from PG and S3. The copies and the reviews are written by `store_report`, so that
+/// a failure of the report leaves neither of them behind, and the originals are deleted by `delete_merged_reports` after that.
+pub(crate) async fn plan_merges(
+    config: &Config,
+    owner_id: &String,
+    project_id: &String,
+    merged_project_ids: &Vec<String>,
+) -> Result<ProjectMerges, Error> {
+    let project_owners = ProjectMerge::get_project_owners(&config.pg_client, merged_project_ids).await?;
+    let linked_keys = KeyLink::get_linked_keys(&config.pg_client, owner_id).await?;
+
+    // a member cannot move projects of other members, even if the report matches them
+    let mut merges = ProjectMerges::default();
+    for (merged_project_id, other_owner_ids) in split_by_ownership(merged_project_ids, &project_owners, &linked_keys) {
+        if other_owner_ids.is_empty() {
+            merges.merged_project_ids.push(merged_project_id);
+        } else {
+            warn!(
+                "Project {} is shared with {}, not merging into {}",
+                merged_project_id,
+                other_owner_ids.join(","),
+                project_id
+            );
+            merges.reviews.push((merged_project_id, other_owner_ids));
+        }
+    }
+
+    if merges.merged_project_ids.is_empty() {
+        return Ok(merges);
+    }
+
+    info!("Merging projects {} into {}", merges.merged_project_ids.join(","), project_id);
+
+    // every key of the member may have a folder with reports of a merged project
+    // PG returns only the canonical owner after the keys were linked, so the folders are listed for all linked keys
+    for merged_project_id in &merges.merged_project_ids {
+        for linked_key in &linked_keys {
+            let (merged_s3_keys, copies) =
+                list_project_folder_copies(config, linked_key, merged_project_id, project_id).await?;
+            merges.merged_s3_keys.extend(merged_s3_keys);
+            merges.copies.extend(copies);
+        }
+    }
+
+    info!("S3 objects to copy: {}", merges.copies.len());
+
+    Ok(merges)
+}
+
+/// Deletes the original reports of merged projects after `stm_commit_report` moved their commits.
+/// The submission is already out of the inbox at this point and cannot be retried, so the failures are only logged.
+pub(crate) async fn delete_merged_reports(config: &Config, merged_s3_keys: &Vec<String>) {
+    for chunk in merged_s3_keys.chunks(S3_DELETE_BATCH_SIZE) {
+        if delete_from_s3(&config.s3_client, &config.s3_report_bucket, chunk.to_vec())
+            .await
+            .is_err()
+        {
+            error!("Failed to delete the reports of merged projects. Delete manually: {}", chunk.join(", "));
+        }
+    }
+}
+
+/// Returns every merged project with the list of its owners outside of `linked_keys`, in the same order.
+/// An empty list means the project belongs to the member account and can be merged.
+/// * `project_owners`: (owner_id, project_id) pairs of the merged projects
+/// * `linked_keys`: all keys of the member account that sent the report
+fn split_by_ownership(
+    merged_project_ids: &Vec<String>,
+    project_owners: &Vec<(String, String)>,
+    linked_keys: &Vec<String>,
+) -> Vec<(String, Vec<String>)> {
+    merged_project_ids
+        .iter()
+        .map(|merged_project_id| {
+            let mut other_owner_ids = project_owners
+                .iter()
+                .filter(|(owner_id, project_id)| project_id == merged_project_id && !linked_keys.contains(owner_id))
+                .map(|(owner_id, _)| owner_id.clone())
+                .collect::<Vec<String>>();
+            other_owner_ids.sort();
+            other_owner_ids.dedup();
+            (merged_project_id.clone(), other_owner_ids)
+        })
+        .collect()
+}
+
+/// Returns all reports of the owner in the merged project folder with the list of (source, dest) copies into
+/// the surviving project folder. The combined report of the surviving project is not overwritten
+/// if it already exists because it is the latest for that project.
+async fn list_project_folder_copies(
+    config: &Config,
+    owner_id: &String,
+    merged_project_id: &String,
+    project_id: &String,
+) -> Result<(Vec<String>, Vec<(String, String)>), Error> {
+    let owner_s3_key = [config.s3_report_prefix.as_str(), "/", owner_id.as_str(), "/"].concat();
+    let merged_s3_key = [owner_s3_key.as_str(), merged_project_id.as_str(), "/"].concat();
+    let dest_s3_key = [owner_s3_key.as_str(), project_id.as_str(), "/"].concat();
+    let combined_report_s3_key = [dest_s3_key.as_str(), "report", REPORT_FILE_EXT_IN_S3].concat();
+
+    let merged_s3_keys = list_report_keys(config, merged_s3_key.clone()).await?;
+    let dest_has_combined_report = list_report_keys(config, dest_s3_key.clone())
+        .await?
+        .contains(&combined_report_s3_key);
+
+    let mut copies: Vec<(String, String)> = Vec::with_capacity(merged_s3_keys.len());
+    for source_key in &merged_s3_keys {
+        let dest_key = source_key.replacen(&merged_s3_key, &dest_s3_key, 1);
+        if dest_key == combined_report_s3_key && dest_has_combined_report {
+            continue;
+        }
+        copies.push((source_key.clone(), dest_key));
+    }
+
+    Ok((merged_s3_keys, copies))
+}
+
+/// Returns the keys of all objects under the prefix in the member reports bucket.
+async fn list_report_keys(config: &Config, s3_prefix: String) -> Result<Vec<String>, Error> {
+    match list_objects_from_s3(&config.s3_client, &config.s3_report_bucket, s3_prefix.clone(), None).await {
+        Ok(v) => Ok(v.into_iter().map(|s3_object| s3_object.key).collect()),
+        Err(_) => Err(Error::from(format!("Failed to list S3 objects in {}", s3_prefix))),
+    }
+}
+
+#[cfg(test)]
+fn commit(project_id: &str, commit_hash: &str, commit_ts: i64) -> CommitOwnership {
+    CommitOwnership {
+        owner_id: "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        project_id: project_id.to_owned(),
+        commit_hash: commit_hash.to_owned(),
+        commit_ts,
+        commit_sha1: None,
+    }
+}
+
+#[test]
+fn resolve_two_way_conflict_test() {
+    // the project with more matching commits wins regardless of the timestamps
+    let commits = vec![commit("A", "c1", 100), commit("A", "c2", 200), commit("B", "c3", 300)];
+    assert_eq!(resolve_project_conflict(&commits), Some(("A".to_owned(), vec!["B".to_owned()])));
+
+    // a tie goes to the project with the latest commit
+    let commits = vec![commit("A", "c1", 100), commit("B", "c2", 300)];
+    assert_eq!(resolve_project_conflict(&commits), Some(("B".to_owned(), vec!["A".to_owned()])));
+
+    // the same commit owned by several members is counted once
+    let mut shared_commit = commit("B", "c2", 100);
+    shared_commit.owner_id = "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7".to_owned();
+    let commits = vec![commit("A", "c1", 300), commit("B", "c2", 100), shared_commit];
+    assert_eq!(resolve_project_conflict(&commits), Some(("A".to_owned(), vec!["B".to_owned()])));
+
+    // a full tie is resolved by the project ID, so the input order does not matter
+    let commits = vec![commit("B", "c2", 100), commit("A", "c1", 100)];
+    assert_eq!(resolve_project_conflict(&commits), Some(("A".to_owned(), vec!["B".to_owned()])));
+
+    assert_eq!(resolve_project_conflict(&Vec::new()), None);
+}
+
+#[test]
+fn resolve_three_way_conflict_test() {
+    let commits = vec![
+        commit("A", "c1", 100),
+        commit("B", "c2", 500),
+        commit("B", "c3", 400),
+        commit("C", "c4", 200),
+        commit("C", "c5", 300),
+        commit("C", "c6", 50),
+    ];
+    assert_eq!(
+        resolve_project_conflict(&commits),
+        Some(("C".to_owned(), vec!["B".to_owned(), "A".to_owned()]))
+    );
+
+    // B and C are tied on commits, B has the latest one and A comes last
+    let commits = vec![
+        commit("C", "c4", 200),
+        commit("A", "c1", 900),
+        commit("C", "c5", 300),
+        commit("B", "c2", 500),
+        commit("B", "c3", 400),
+    ];
+    assert_eq!(
+        resolve_project_conflict(&commits),
+        Some(("B".to_owned(), vec!["C".to_owned(), "A".to_owned()]))
+    );
+}
+
+#[test]
+fn split_by_ownership_test() {
+    let owner_id = "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned();
+    let linked_key = "7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7".to_owned();
+    let other_owner_id = "BvQaGKkCGhXaVbYbjaKy6pW7EYAbSEPgoYV5EqAW3zsu".to_owned();
+    let linked_keys = vec![owner_id.clone(), linked_key.clone()];
+    let owners = |pairs: &[(&String, &str)]| {
+        pairs
+            .iter()
+            .map(|(owner_id, project_id)| (owner_id.to_string(), project_id.to_string()))
+            .collect::<Vec<(String, String)>>()
+    };
+
+    // projects of the member under any of its keys are merged
+    let project_owners = owners(&[(&owner_id, "A"), (&linked_key, "A"), (&linked_key, "B")]);
+    assert_eq!(
+        split_by_ownership(&vec!["A".to_owned(), "B".to_owned()], &project_owners, &linked_keys),
+        vec![("A".to_owned(), Vec::new()), ("B".to_owned(), Vec::new())]
+    );
+
+    // a project shared with another member or a GitHub owner goes for review, listing only the other owners once
+    let project_owners = owners(&[
+        (&owner_id, "A"),
+        (&other_owner_id, "A"),
+        (&other_owner_id, "A"),
+        (&owner_id, "B"),
+        (&"gh:stackmuncher".to_owned(), "C"),
+    ]);
+    assert_eq!(
+        split_by_ownership(&vec!["A".to_owned(), "B".to_owned(), "C".to_owned()], &project_owners, &linked_keys),
+        vec![
+            ("A".to_owned(), vec![other_owner_id.clone()]),
+            ("B".to_owned(), Vec::new()),
+            ("C".to_owned(), vec!["gh:stackmuncher".to_owned()]),
+        ]
+    );
+
+    // another member cannot merge the projects of this member either
+    let project_owners = owners(&[(&owner_id, "A")]);
+    assert_eq!(
+        split_by_ownership(&vec!["A".to_owned()], &project_owners, &vec![other_owner_id.clone()]),
+        vec![("A".to_owned(), vec![owner_id.clone()])]
+    );
+}
@@ -0,0 +1,488 @@
+use crate::config::Config;
+use crate::postgres::ReportWrites;
+use crate::s3::{copy_report_object, copy_within_s3, delete_report_object, delete_s3_object, report_object_exists};
+use futures::future::BoxFuture;
+use lambda_runtime::Error;
+use tracing::{error, info, warn};
+
+/// The previous `report.gz` of the project is kept under this name while the new one is being stored.
+/// The name does not end with `.gz`, so it is never mistaken for a report.
+const REPORT_BACKUP_EXT: &str = ".prev";
+
+/// S3 keys of a single routed report.
+#[derive(Debug, Clone)]
+pub(crate) struct ReportS3Keys {
+    /// The submission in the inbox bucket, e.g. `queue/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz`
+    pub inbox: String,
+    /// The copy named after the last commit, e.g. `reports/<owner_id>/<project_id>/1627380297_<sha1>.gz`
+    pub with_ts: String,
+    /// `reports/<owner_id>/<project_id>/report.gz` for the latest report of the project or None for out of order reports
+    pub latest: Option<String>,
+    /// (source, dest) keys of the reports of merged projects to be copied into the `<project_id>` folders
+    pub merge_copies: Vec<(String, String)>,
+}
+
+/// S3 and PG operations needed to store a routed report. Implemented by `Config` and by a mock in tests
+/// to inject failures at every step.
+pub(crate) trait ReportStorage {
+    /// Returns TRUE if the object exists in the member reports bucket.
+    fn report_exists<'a>(&'a self, s3_key: &'a String) -> BoxFuture<'a, Result<bool, Error>>;
+    /// Copies the submission from the inbox into the member reports bucket, overwriting the destination.
+    fn copy_from_inbox<'a>(&'a self, inbox_key: &'a String, dest_key: &'a String) -> BoxFuture<'a, Result<(), Error>>;
+    /// Copies an object within the member reports bucket, overwriting the destination.
+    fn copy_report<'a>(&'a self, source_key: &'a String, dest_key: &'a String) -> BoxFuture<'a, Result<(), Error>>;
+    /// Deletes an object from the member reports bucket. A missing object is not an error.
+    fn delete_report<'a>(&'a self, s3_key: &'a String) -> BoxFuture<'a, Result<(), Error>>;
+    /// Applies all PG writes in a single transaction.
+    fn commit_writes<'a>(&'a self, writes: &'a ReportWrites) -> BoxFuture<'a, Result<(), Error>>;
+    /// Deletes the submission from the inbox queue.
+    fn delete_from_inbox<'a>(&'a self, inbox_key: &'a String) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+impl ReportStorage for Config {
+    fn report_exists<'a>(&'a self, s3_key: &'a String) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(report_object_exists(self, s3_key.clone()))
+    }
+
+    fn copy_from_inbox<'a>(&'a self, inbox_key: &'a String, dest_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(copy_within_s3(self, inbox_key.clone(), dest_key.clone()))
+    }
+
+    fn copy_report<'a>(&'a self, source_key: &'a String, dest_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(copy_report_object(self, source_key.clone(), dest_key.clone()))
+    }
+
+    fn delete_report<'a>(&'a self, s3_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(delete_report_object(self, s3_key.clone()))
+    }
+
+    fn commit_writes<'a>(&'a self, writes: &'a ReportWrites) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(writes.commit(&self.pg_client))
+    }
+
+    fn delete_from_inbox<'a>(&'a self, inbox_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(delete_s3_object(self, inbox_key.clone()))
+    }
+}
+
+/// Copies the report into the member's folder together with the reports of the merged projects, applies the PG writes
+/// in a single transaction and removes the submission from the inbox, in that order. If the copies or the PG writes fail,
+/// the copies are undone and the previous `report.gz` is restored, so that a retry of the same S3 event starts
+/// from a clean state. All steps overwrite their targets, so a retry after a failed cleanup converges to the same result.
+/// The submission is only removed from the inbox once everything else succeeded.
+pub(crate) async fn store_report<S: ReportStorage>(
+    storage: &S,
+    s3_keys: &ReportS3Keys,
+    writes: &ReportWrites,
+) -> Result<(), Error> {
+    // copies that existed before this attempt are not deleted by the cleanup
+    let with_ts_existed = storage.report_exists(&s3_keys.with_ts).await?;
+
+    // the previous latest report is kept aside to be restored if anything fails
+    let backup = match s3_keys.latest.as_ref() {
+        Some(latest_key) => {
+            let backup_key = [latest_key.as_str(), REPORT_BACKUP_EXT].concat();
+            if storage.report_exists(latest_key).await? {
+                storage.copy_report(latest_key, &backup_key).await?;
+                Some(backup_key)
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+
+    // the merged reports go first because `report.gz` of a merged project is replaced by the new one if it is the latest
+    let mut merge_copies_made: Vec<&String> = Vec::new();
+    let mut result = copy_merged_reports(storage, &s3_keys.merge_copies, &mut merge_copies_made).await;
+    if result.is_ok() {
+        result = storage.copy_from_inbox(&s3_keys.inbox, &s3_keys.with_ts).await;
+    }
+    if result.is_ok() {
+        if let Some(latest_key) = s3_keys.latest.as_ref() {
+            result = storage.copy_from_inbox(&s3_keys.inbox, latest_key).await;
+        }
+    }
+    if result.is_ok() {
+        result = storage.commit_writes(writes).await;
+    }
+
+    if let Err(e) = result {
+        error!("Storing {} failed: {}", s3_keys.inbox, e);
+        undo_copies(storage, s3_keys, with_ts_existed, backup.as_ref(), &merge_copies_made).await;
+        return Err(e);
+    }
+
+    // the backup is no longer needed, but a leftover does no harm because it is overwritten next time
+    if let Some(backup_key) = backup.as_ref() {
+        if storage.delete_report(backup_key).await.is_err() {
+            warn!("Failed to delete backup {}", backup_key);
+        }
+    }
+
+    // a retry after this point finds the report unchanged and only removes it from the inbox
+    storage.delete_from_inbox(&s3_keys.inbox).await
+}
+
+/// Copies the reports of merged projects and adds the destinations that did not exist before to `copies_made`
+/// for the cleanup. Stops at the first failure.
+async fn copy_merged_reports<'a, S: ReportStorage>(
+    storage: &S,
+    merge_copies: &'a Vec<(String, String)>,
+    copies_made: &mut Vec<&'a String>,
+) -> Result<(), Error> {
+    for (source_key, dest_key) in merge_copies {
+        let dest_existed = storage.report_exists(dest_key).await?;
+        storage.copy_report(source_key, dest_key).await?;
+        if !dest_existed {
+            copies_made.push(dest_key);
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the copies made by a failed attempt and restores the previous `report.gz` from the backup.
+/// Errors are logged and ignored because the original error is what goes back to the caller.
+/// * `merge_copies_made`: copies of the merged reports that did not exist before this attempt
+async fn undo_copies<S: ReportStorage>(
+    storage: &S,
+    s3_keys: &ReportS3Keys,
+    with_ts_existed: bool,
+    backup: Option<&String>,
+    merge_copies_made: &Vec<&String>,
+) {
+    info!("Undoing copies of {}", s3_keys.inbox);
+
+    if !with_ts_existed && storage.delete_report(&s3_keys.with_ts).await.is_err() {
+        error!("Failed to delete {} during cleanup", s3_keys.with_ts);
+    }
+
+    // `report.gz` is restored from the backup or deleted below, even if it came from a merged project
+    for dest_key in merge_copies_made {
+        if Some(*dest_key) != s3_keys.latest.as_ref() && storage.delete_report(dest_key).await.is_err() {
+            error!("Failed to delete {} during cleanup", dest_key);
+        }
+    }
+
+    if let Some(latest_key) = s3_keys.latest.as_ref() {
+        match backup {
+            Some(backup_key) => {
+                if storage.copy_report(backup_key, latest_key).await.is_err() {
+                    error!("Failed to restore {} from {}", latest_key, backup_key);
+                } else if storage.delete_report(backup_key).await.is_err() {
+                    warn!("Failed to delete backup {}", backup_key);
+                }
+            }
+            None => {
+                if storage.delete_report(latest_key).await.is_err() {
+                    error!("Failed to delete {} during cleanup", latest_key);
+                }
+            }
+        }
+    }
+}
+
+/// An in-memory storage that fails the call number `fail_at`, counting from 0, and with `fail_after`
+/// all the calls after it as well, e.g. when S3 becomes unavailable half-way.
+#[cfg(test)]
+#[derive(Default)]
+struct MockStorage {
+    /// The member reports bucket with the contents of every object
+    reports: std::sync::Mutex<std::collections::BTreeMap<String, String>>,
+    /// The inbox bucket with the contents of every object
+    inbox: std::sync::Mutex<std::collections::BTreeMap<String, String>>,
+    /// Committed PG writes
+    pg: std::sync::Mutex<Vec<ReportWrites>>,
+    calls: std::sync::Mutex<usize>,
+    fail_at: Option<usize>,
+    fail_after: bool,
+}
+
+#[cfg(test)]
+impl MockStorage {
+    /// Counts the call and returns an error if it is meant to fail.
+    fn call(&self, step: &str) -> Result<(), Error> {
+        let mut calls = self.calls.lock().unwrap();
+        let call = *calls;
+        *calls += 1;
+        match self.fail_at {
+            Some(fail_at) if call == fail_at || (self.fail_after && call > fail_at) => {
+                Err(Error::from(format!("Injected failure at {} #{}", step, call)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns a storage with the same contents and no failures to retry the event.
+    fn for_retry(&self) -> Self {
+        MockStorage {
+            reports: std::sync::Mutex::new(self.reports.lock().unwrap().clone()),
+            inbox: std::sync::Mutex::new(self.inbox.lock().unwrap().clone()),
+            pg: std::sync::Mutex::new(self.pg.lock().unwrap().clone()),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl ReportStorage for MockStorage {
+    fn report_exists<'a>(&'a self, s3_key: &'a String) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            self.call("report_exists")?;
+            Ok(self.reports.lock().unwrap().contains_key(s3_key))
+        })
+    }
+
+    fn copy_from_inbox<'a>(&'a self, inbox_key: &'a String, dest_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.call("copy_from_inbox")?;
+            let contents = self.inbox.lock().unwrap().get(inbox_key).cloned().ok_or("NoSuchKey")?;
+            self.reports.lock().unwrap().insert(dest_key.clone(), contents);
+            Ok(())
+        })
+    }
+
+    fn copy_report<'a>(&'a self, source_key: &'a String, dest_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.call("copy_report")?;
+            let mut reports = self.reports.lock().unwrap();
+            let contents = reports.get(source_key).cloned().ok_or("NoSuchKey")?;
+            reports.insert(dest_key.clone(), contents);
+            Ok(())
+        })
+    }
+
+    fn delete_report<'a>(&'a self, s3_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.call("delete_report")?;
+            self.reports.lock().unwrap().remove(s3_key);
+            Ok(())
+        })
+    }
+
+    fn commit_writes<'a>(&'a self, writes: &'a ReportWrites) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.call("commit_writes")?;
+            self.pg.lock().unwrap().push(writes.clone());
+            Ok(())
+        })
+    }
+
+    fn delete_from_inbox<'a>(&'a self, inbox_key: &'a String) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.call("delete_from_inbox")?;
+            self.inbox.lock().unwrap().remove(inbox_key);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+fn report_writes(commit_ts: i64, is_latest: bool) -> ReportWrites {
+    ReportWrites {
+        owner_id: "9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK".to_owned(),
+        project_id: "Wgx98Rbi8nQuL9ddn3mTk1".to_owned(),
+        merged_project_ids: Vec::new(),
+        merge_reviews: Vec::new(),
+        fingerprint: None,
+        dispute: None,
+        commit_hashes: vec!["7474684a".to_owned()],
+        commit_timestamps: vec![commit_ts],
+        commit_sha1s: vec![None],
+        emails: vec!["max@onebro.me".to_owned()],
+        is_primary: vec![true],
+        gh_login_gist_latest: None,
+        gh_login_noreply: None,
+        report_hash: "8sHzVnWSxMgfBRc3oNJ6udnEdbSYDCp3Kqn94oejDnWq".to_owned(),
+        is_latest,
+    }
+}
+
+/// Fails every step of `store_report` in turn, with and without the following steps failing too,
+/// checks the state after the failure and that a retry of the event ends up in the same state as the happy path.
+#[cfg(test)]
+fn assert_store_report_converges(
+    s3_keys: &ReportS3Keys,
+    writes: &ReportWrites,
+    initial_reports: &std::collections::BTreeMap<String, String>,
+    expected_reports: &std::collections::BTreeMap<String, String>,
+) -> usize {
+    let mut initial_inbox = std::collections::BTreeMap::new();
+    initial_inbox.insert(s3_keys.inbox.clone(), "new".to_owned());
+    let without_backups = |reports: &std::collections::BTreeMap<String, String>| {
+        let mut reports = reports.clone();
+        reports.retain(|k, _| !k.ends_with(REPORT_BACKUP_EXT));
+        reports
+    };
+
+    let mut fail_at = 0usize;
+    loop {
+        for fail_after in &[false, true] {
+            let storage = MockStorage {
+                reports: std::sync::Mutex::new(initial_reports.clone()),
+                inbox: std::sync::Mutex::new(initial_inbox.clone()),
+                fail_at: Some(fail_at),
+                fail_after: *fail_after,
+                ..Default::default()
+            };
+            let result = futures::executor::block_on(store_report(&storage, s3_keys, writes));
+            let calls = *storage.calls.lock().unwrap();
+
+            if calls <= fail_at {
+                // the failure was never reached - the happy path is complete
+                assert!(result.is_ok());
+                assert_eq!(*storage.reports.lock().unwrap(), *expected_reports);
+                assert!(storage.inbox.lock().unwrap().is_empty());
+                assert_eq!(*storage.pg.lock().unwrap(), vec![writes.clone()]);
+                return fail_at;
+            }
+
+            if result.is_ok() {
+                // only the deletion of the backup is allowed to fail, the leftover is overwritten next time
+                assert_eq!(without_backups(&*storage.reports.lock().unwrap()), *expected_reports);
+                assert!(storage.inbox.lock().unwrap().is_empty());
+                assert_eq!(*storage.pg.lock().unwrap(), vec![writes.clone()]);
+                continue;
+            }
+
+            // the submission stays in the inbox for the retry
+            assert_eq!(*storage.inbox.lock().unwrap(), initial_inbox, "step {}", fail_at);
+
+            // with a working cleanup either nothing was committed and S3 is back to where it was
+            // or everything but the inbox deletion is done
+            if !*fail_after {
+                if storage.pg.lock().unwrap().is_empty() {
+                    assert_eq!(*storage.reports.lock().unwrap(), *initial_reports, "step {}", fail_at);
+                } else {
+                    assert_eq!(without_backups(&*storage.reports.lock().unwrap()), *expected_reports);
+                }
+            }
+
+            // S3 retries the same event
+            let retry_storage = storage.for_retry();
+            assert!(futures::executor::block_on(store_report(&retry_storage, s3_keys, writes)).is_ok());
+            assert_eq!(
+                *retry_storage.reports.lock().unwrap(),
+                *expected_reports,
+                "retry after step {}, fail after: {}",
+                fail_at,
+                fail_after
+            );
+            assert!(retry_storage.inbox.lock().unwrap().is_empty());
+            assert!(retry_storage.pg.lock().unwrap().contains(writes));
+        }
+
+        fail_at += 1;
+    }
+}
+
+#[test]
+fn store_report_failure_injection_test() {
+    let project_s3_key = "reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/Wgx98Rbi8nQuL9ddn3mTk1/";
+    let latest_s3_key = [project_s3_key, "report.gz"].concat();
+    let s3_keys = ReportS3Keys {
+        inbox: "queue/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+        with_ts: [project_s3_key, "1627380297_7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4.gz"].concat(),
+        latest: Some(latest_s3_key.clone()),
+        merge_copies: Vec::new(),
+    };
+    let writes = report_writes(1627380297, true);
+
+    // a project with an older report gets a new latest report
+    // report_exists x2, backup, 2 copies, PG, backup deletion and inbox deletion
+    let mut initial_reports = std::collections::BTreeMap::new();
+    initial_reports.insert([project_s3_key, "1627000000_old.gz"].concat(), "old".to_owned());
+    initial_reports.insert(latest_s3_key.clone(), "old".to_owned());
+    let mut expected_reports = initial_reports.clone();
+    expected_reports.insert(s3_keys.with_ts.clone(), "new".to_owned());
+    expected_reports.insert(latest_s3_key.clone(), "new".to_owned());
+    assert_eq!(assert_store_report_converges(&s3_keys, &writes, &initial_reports, &expected_reports), 8);
+
+    // the first report of a new project has nothing to back up
+    let initial_reports = std::collections::BTreeMap::new();
+    let mut expected_reports = std::collections::BTreeMap::new();
+    expected_reports.insert(s3_keys.with_ts.clone(), "new".to_owned());
+    expected_reports.insert(latest_s3_key, "new".to_owned());
+    assert_eq!(assert_store_report_converges(&s3_keys, &writes, &initial_reports, &expected_reports), 6);
+}
+
+#[test]
+fn store_out_of_order_report_test() {
+    let project_s3_key = "reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/Wgx98Rbi8nQuL9ddn3mTk1/";
+    let latest_s3_key = [project_s3_key, "report.gz"].concat();
+    let s3_keys = ReportS3Keys {
+        inbox: "queue/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+        with_ts: [project_s3_key, "1500000000_7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4.gz"].concat(),
+        latest: None,
+        merge_copies: Vec::new(),
+    };
+    let writes = report_writes(1500000000, false);
+
+    // report.gz is never touched by an out of order report
+    // report_exists, copy, PG and inbox deletion
+    let mut initial_reports = std::collections::BTreeMap::new();
+    initial_reports.insert(latest_s3_key, "newer".to_owned());
+    let mut expected_reports = initial_reports.clone();
+    expected_reports.insert(s3_keys.with_ts.clone(), "new".to_owned());
+    assert_eq!(assert_store_report_converges(&s3_keys, &writes, &initial_reports, &expected_reports), 4);
+}
+
+#[test]
+fn store_report_with_merges_test() {
+    let owner_s3_key = "reports/9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK/";
+    let project_s3_key = [owner_s3_key, "Wgx98Rbi8nQuL9ddn3mTk1/"].concat();
+    let merged_s3_key = [owner_s3_key, "FZ8zezMFji6VXcWEDxckwy/"].concat();
+    let latest_s3_key = [project_s3_key.as_str(), "report.gz"].concat();
+    let merged_with_ts_s3_key = [merged_s3_key.as_str(), "1627000000_old.gz"].concat();
+    let merged_latest_s3_key = [merged_s3_key.as_str(), "report.gz"].concat();
+    let copied_with_ts_s3_key = [project_s3_key.as_str(), "1627000000_old.gz"].concat();
+    let mut writes = report_writes(1627380297, true);
+    writes.merged_project_ids = vec!["FZ8zezMFji6VXcWEDxckwy".to_owned()];
+
+    // the merged project has no report.gz in the surviving project, so its report.gz is copied and then replaced
+    // report_exists x2, 2 x (report_exists + copy), 2 copies, PG and inbox deletion
+    let s3_keys = ReportS3Keys {
+        inbox: "queue/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+        with_ts: [
+            project_s3_key.as_str(),
+            "1627380297_7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4.gz",
+        ]
+        .concat(),
+        latest: Some(latest_s3_key.clone()),
+        merge_copies: vec![
+            (merged_with_ts_s3_key.clone(), copied_with_ts_s3_key.clone()),
+            (merged_latest_s3_key.clone(), latest_s3_key.clone()),
+        ],
+    };
+    let mut initial_reports = std::collections::BTreeMap::new();
+    initial_reports.insert(merged_with_ts_s3_key.clone(), "merged".to_owned());
+    initial_reports.insert(merged_latest_s3_key.clone(), "merged".to_owned());
+    // the originals are deleted by delete_merged_reports after store_report
+    let mut expected_reports = initial_reports.clone();
+    expected_reports.insert(copied_with_ts_s3_key.clone(), "merged".to_owned());
+    expected_reports.insert(s3_keys.with_ts.clone(), "new".to_owned());
+    expected_reports.insert(latest_s3_key.clone(), "new".to_owned());
+    assert_eq!(assert_store_report_converges(&s3_keys, &writes, &initial_reports, &expected_reports), 10);
+
+    // an out of order report leaves report.gz of the merged project in place
+    // report_exists, 2 x (report_exists + copy), copy, PG and inbox deletion
+    let s3_keys = ReportS3Keys {
+        with_ts: [
+            project_s3_key.as_str(),
+            "1500000000_7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4.gz",
+        ]
+        .concat(),
+        latest: None,
+        ..s3_keys
+    };
+    let writes = ReportWrites {
+        is_latest: false,
+        ..writes
+    };
+    let mut expected_reports = initial_reports.clone();
+    expected_reports.insert(copied_with_ts_s3_key, "merged".to_owned());
+    expected_reports.insert(s3_keys.with_ts.clone(), "new".to_owned());
+    expected_reports.insert(latest_s3_key, "merged".to_owned());
+    assert_eq!(assert_store_report_converges(&s3_keys, &writes, &initial_reports, &expected_reports), 8);
+}
@@ -1,9 +1,15 @@
 use crate::config::Config;
+use chrono::Utc;
 use futures_util::stream::TryStreamExt;
 use lambda_runtime::Error;
-use rusoto_s3::{CopyObjectRequest, DeleteObjectRequest, GetObjectRequest, S3};
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectError, HeadObjectRequest,
+    PutObjectRequest, S3,
+};
 use serde::Deserialize;
-use tracing::info;
+use stm_shared::s3::{FailedSubmission, S3_FAILED_SUBMISSION_SIDECAR_EXT, S3_FOLDER_FAILED_SUBMISSIONS};
+use tracing::{info, warn};
 
 /// This const must be in sync with the same constant in other crates.
 pub(crate) const REPORT_FILE_EXT_IN_S3: &str = ".gz";
@@ -55,6 +61,139 @@ pub(crate) async fn copy_within_s3(config: &Config, source_key: String, dest_key
     Ok(())
 }
 
+/// Copy an object between two folders of the member reports bucket, e.g. when projects are merged.
+/// * `source_key` and `dest_key` must be the full object keys, including the prefix and the file extension
+pub(crate) async fn copy_report_object(config: &Config, source_key: String, dest_key: String) -> Result<(), Error> {
+    info!("Copying report from {} to {}", source_key, dest_key);
+    if let Err(e) = config
+        .s3_client
+        .copy_object(CopyObjectRequest {
+            bucket: config.s3_report_bucket.clone(),
+            copy_source: [config.s3_report_bucket.as_str(), source_key.as_str()].join("/"),
+            key: dest_key.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        return Err(Error::from(format!("Copying from {} to {} failed with {}", source_key, dest_key, e)));
+    };
+
+    Ok(())
+}
+
+/// Delete an object from the member reports bucket. Deleting a missing object is not an error.
+/// * `s3_key` must be the full object key, including the prefix and the file extension
+pub(crate) async fn delete_report_object(config: &Config, s3_key: String) -> Result<(), Error> {
+    info!("Deleting report {}", s3_key);
+    if let Err(e) = config
+        .s3_client
+        .delete_object(DeleteObjectRequest {
+            bucket: config.s3_report_bucket.clone(),
+            key: s3_key.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        return Err(Error::from(format!("Deletion {} failed with {}", s3_key, e)));
+    };
+
+    Ok(())
+}
+
+/// Returns TRUE if the object exists in the member reports bucket.
+/// * `s3_key` must be the full object key, including the prefix and the file extension
+pub(crate) async fn report_object_exists(config: &Config, s3_key: String) -> Result<bool, Error> {
+    match config
+        .s3_client
+        .head_object(HeadObjectRequest {
+            bucket: config.s3_report_bucket.clone(),
+            key: s3_key.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(_) => Ok(true),
+        // HEAD responses have no body, so a missing key usually comes back as a bare 404
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+        Err(RusotoError::Unknown(resp)) if resp.status.as_u16() == 404 => Ok(false),
+        Err(e) => Err(Error::from(format!("HEAD {} failed with {}", s3_key, e))),
+    }
+}
+
+/// Moves a submission that cannot be processed from the inbox queue to `failed/` prefix in the same bucket
+/// for review and a possible re-drive by `failed` flow of stm_inbox_flows. The reason is stored next to it
+/// in a JSON sidecar with the same file name, e.g. `failed/1627801778_9PdH...LeFK.gz` + `failed/1627801778_9PdH...LeFK.json`.
+/// * `s3_key` must be the full object key, including the prefix and the file extension
+/// * `owner_id` is the key that signed the submission or an empty string if the inbox key has no valid key in it
+pub(crate) async fn move_to_failed(
+    config: &Config,
+    s3_key: String,
+    owner_id: &str,
+    submission_id: &Option<String>,
+    reason: &str,
+) -> Result<(), Error> {
+    let (dest_key, sidecar_key) = failed_submission_keys(&s3_key);
+
+    warn!("Moving {} to {}: {}", s3_key, dest_key, reason);
+
+    let sidecar = serde_json::to_vec(&FailedSubmission {
+        s3_key: s3_key.clone(),
+        owner_id: owner_id.to_owned(),
+        submission_id: submission_id.clone(),
+        reason: reason.to_owned(),
+        failed_ts: Utc::now().timestamp(),
+    })?;
+
+    // the sidecar goes first because a failed submission without it cannot be re-driven with confidence
+    if let Err(e) = config
+        .s3_client
+        .put_object(PutObjectRequest {
+            bucket: config.s3_inbox_bucket.clone(),
+            key: sidecar_key.clone(),
+            body: Some(sidecar.into()),
+            content_type: Some("application/json".to_owned()),
+            ..Default::default()
+        })
+        .await
+    {
+        return Err(Error::from(format!("Saving {} failed with {}", sidecar_key, e)));
+    };
+
+    if let Err(e) = config
+        .s3_client
+        .copy_object(CopyObjectRequest {
+            bucket: config.s3_inbox_bucket.clone(),
+            copy_source: [config.s3_inbox_bucket.as_str(), s3_key.as_str()].join("/"),
+            key: dest_key.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        return Err(Error::from(format!("Copying from {} to {} failed with {}", s3_key, dest_key, e)));
+    };
+
+    delete_s3_object(config, s3_key).await
+}
+
+/// Returns the keys of the failed submission and its sidecar for a submission from the inbox queue, e.g.
+/// `queue/1627801778_9PdH...LeFK.gz` -> `failed/1627801778_9PdH...LeFK.gz` + `failed/1627801778_9PdH...LeFK.json`.
+/// The file name is kept as-is for the submission to be re-driven under the same name. Only a single `.gz`
+/// is replaced in the sidecar name, anything else keeps its full name, e.g. `failed/bad_key.txt.json`,
+/// which is what `failed` flow of stm_inbox_flows expects when it pairs them up.
+fn failed_submission_keys(s3_key: &str) -> (String, String) {
+    let file_name = s3_key.rsplit("/").next().unwrap_or(s3_key);
+    let dest_key = [S3_FOLDER_FAILED_SUBMISSIONS, "/", file_name].concat();
+    let sidecar_key = [
+        S3_FOLDER_FAILED_SUBMISSIONS,
+        "/",
+        file_name.strip_suffix(REPORT_FILE_EXT_IN_S3).unwrap_or(file_name),
+        S3_FAILED_SUBMISSION_SIDECAR_EXT,
+    ]
+    .concat();
+
+    (dest_key, sidecar_key)
+}
+
 /// Delete an object from the inbox bucket.
 /// * `s3_key` must be the full object keys, including the prefix and the file extension
 pub(crate) async fn delete_s3_object(config: &Config, s3_key: String) -> Result<(), Error> {
@@ -75,9 +214,9 @@ pub(crate) async fn delete_s3_object(config: &Config, s3_key: String) -> Result<
 }
 
 /// Return the contents of the object as non-empty String, otherwise return an error.
-/// An empty object is an error.
-/// * *missing_is_error*: set to true if the object must exist to log an ERROR if it's missing, otherwise it will log it as INFO
-pub(crate) async fn get_bytes_from_s3(config: &Config, s3_key: String) -> Result<Vec<u8>, Error> {
+/// An empty object is an error. A missing object returns None because it was most likely processed already
+/// by an earlier invocation for the same event, e.g. a retry of a partially failed batch.
+pub(crate) async fn get_bytes_from_s3(config: &Config, s3_key: String) -> Result<Option<Vec<u8>>, Error> {
     info!("Getting S3 object {}", s3_key);
 
     let s3_resp = match config
@@ -89,6 +228,9 @@ pub(crate) async fn get_bytes_from_s3(config: &Config, s3_key: String) -> Result
         })
         .await
     {
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+            return Ok(None);
+        }
         Err(e) => {
             return Err(Error::from(e));
         }
@@ -104,7 +246,7 @@ pub(crate) async fn get_bytes_from_s3(config: &Config, s3_key: String) -> Result
                 return Err(Error::from("Zero length object."));
             }
 
-            return Ok(data);
+            return Ok(Some(data));
         }
     };
 
@@ -114,7 +256,8 @@ pub(crate) async fn get_bytes_from_s3(config: &Config, s3_key: String) -> Result
 /// `S3Event` which wrap an array of `S3Event`Record
 #[derive(Deserialize)]
 pub(crate) struct S3Event {
-    #[serde(rename = "Records")]
+    /// May contain more than one record or none at all, e.g. `s3:TestEvent` sent to SQS when the notification is set up
+    #[serde(rename = "Records", default)]
     pub records: Vec<S3EventRecord>,
 }
 
@@ -136,3 +279,41 @@ pub(crate) struct S3Object {
     /// The object size in bytes, e.g. 7172
     pub size: Option<i64>,
 }
+
+#[test]
+fn failed_submission_keys_test() {
+    assert_eq!(
+        failed_submission_keys(
+            "queue/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz"
+        ),
+        (
+            "failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+            "failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.json".to_owned()
+        )
+    );
+
+    // only the file name is kept, whatever the prefix
+    assert_eq!(
+        failed_submission_keys("inbox/queue/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz"),
+        (
+            "failed/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.gz".to_owned(),
+            "failed/1627801778_9PdHabyyhf4KhHAE1SqdpnbAZEXTHhpkermwfPQcLeFK.json".to_owned()
+        )
+    );
+    assert_eq!(
+        failed_submission_keys("1627801778.gz"),
+        ("failed/1627801778.gz".to_owned(), "failed/1627801778.json".to_owned())
+    );
+
+    // a single `.gz` is replaced, so that the sidecar pairs up with the submission by the name without the extension
+    assert_eq!(
+        failed_submission_keys("queue/1627801778.gz.gz"),
+        ("failed/1627801778.gz.gz".to_owned(), "failed/1627801778.gz.json".to_owned())
+    );
+
+    // objects with unexpected names keep their full name
+    assert_eq!(
+        failed_submission_keys("queue/1627801778.txt"),
+        ("failed/1627801778.txt".to_owned(), "failed/1627801778.txt.json".to_owned())
+    );
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// The value of `eventSource` in the records of events delivered to Lambda by an SQS trigger.
+pub(crate) const SQS_EVENT_SOURCE: &str = "aws:sqs";
+
+/// A batch of SQS messages delivered to Lambda by an SQS trigger.
+/// Every message body is an S3 event notification sent to the queue by the inbox bucket.
+#[derive(Deserialize)]
+pub(crate) struct SqsEvent {
+    #[serde(rename = "Records")]
+    pub records: Vec<SqsMessage>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SqsMessage {
+    /// The ID to return in `batchItemFailures` if the message has to be retried
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+    /// JSON of `S3Event`, e.g. `{"Records":[{"s3":{"object":{"key":"queue/...gz","size":7172}}}]}`
+    pub body: Option<String>,
+}
+
+/// The response expected by Lambda from an SQS trigger with `ReportBatchItemFailures` enabled.
+/// Only the listed messages are returned to the queue, the rest of the batch is deleted.
+#[derive(Serialize, Default, Debug)]
+pub(crate) struct SqsBatchResponse {
+    #[serde(rename = "batchItemFailures")]
+    pub batch_item_failures: Vec<BatchItemFailure>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct BatchItemFailure {
+    /// `messageId` of the failed message
+    #[serde(rename = "itemIdentifier")]
+    pub item_identifier: String,
+}
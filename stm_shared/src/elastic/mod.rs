@@ -70,6 +70,66 @@ async fn call_es_api_put(es_api_endpoint: String, payload: Vec<u8>) -> Result<()
     Err(())
 }
 
+/// Deletes a single document by ID. Returns `Ok(true)` if the doc was deleted and `Ok(false)` if it did not exist.
+/// * es_url: elastucsearch url
+/// * idx: ES index name
+/// * doc_id: the ID of the doc, e.g. owner_id
+pub async fn delete_doc_by_id(es_url: &String, idx: &String, doc_id: &String) -> Result<bool, ()> {
+    info!("Deleting doc {} from ES idx {}", doc_id, idx);
+
+    let es_api_endpoint = [es_url.as_ref(), "/", idx, "/_doc/", doc_id].concat();
+    let uri = Uri::from_maybe_shared(es_api_endpoint).expect("Invalid ES URL");
+
+    let req = match Request::builder().uri(uri).method("DELETE").body(Body::empty()) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Invalid request. {}", e);
+            return Err(());
+        }
+    };
+
+    let res = match Client::builder()
+        .build::<_, hyper::Body>(
+            HttpsConnectorBuilder::new()
+                .with_native_roots()
+                .https_only()
+                .enable_http1()
+                .build(),
+        )
+        .request(req)
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!("ES request failed with {}", e);
+            return Err(());
+        }
+    };
+
+    // a missing doc is not an error - there is nothing to delete
+    if res.status().is_success() {
+        return Ok(true);
+    } else if res.status() == hyper::StatusCode::NOT_FOUND {
+        info!("Doc {} not found in {}", doc_id, idx);
+        return Ok(false);
+    }
+
+    error!("ES DELETE failed. Status {}", res.status());
+
+    // there should be at least some reason in the response - log what we can
+    let buf = match hyper::body::to_bytes(res).await {
+        Err(e) => {
+            error!("Cannot convert response body to bytes. {}", e);
+            return Err(());
+        }
+        Ok(v) => v,
+    };
+
+    super::log_http_body(&buf);
+
+    Err(())
+}
+
 /// Put the JSON string into the specified ES index. The string must be deserialisable into a valid JSON.
 pub async fn upload_serialized_object_to_es(
     es_url: &String,
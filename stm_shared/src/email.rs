@@ -0,0 +1,342 @@
+use tracing::warn;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Postgres DB does not allow more than 150 unicode chars per email in `t_email_ownership`
+const MAX_EMAIL_LENGTH: usize = 150;
+/// RFC 5321 limits the local part to 64 octets
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+/// RFC 1035 limits the domain to 253 octets and every label to 63 octets
+const MAX_DOMAIN_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+/// GitHub logins are up to 39 chars long
+const MAX_GH_LOGIN_LENGTH: usize = 39;
+/// Commits made via GitHub UI or with the email privacy setting use `<id>+<login>@users.noreply.github.com`
+/// or `<login>@users.noreply.github.com` for older accounts
+const GH_NOREPLY_DOMAIN: &str = "users.noreply.github.com";
+
+/// How a rule in `PLACEHOLDER_DOMAINS` is matched against the ASCII form of the domain.
+#[derive(Debug, Clone, Copy)]
+enum DomainMatch {
+    /// The whole domain, e.g. `example.com`
+    Exact,
+    /// The domain or any of its subdomains, e.g. `local` matches `my-laptop.local`
+    Suffix,
+}
+
+/// Domains git fills in from the hostname or the hosting platforms use for their noreply addresses.
+/// Nothing sent to them can reach the committer. GitHub noreply addresses are handled separately
+/// because they identify the GitHub login. Single-label domains from this list, e.g. `root@localhost`,
+/// are placeholders, any other single-label domain is invalid.
+const PLACEHOLDER_DOMAINS: [(&str, DomainMatch); 14] = [
+    // reserved by RFC 2606 and RFC 6761
+    ("localhost", DomainMatch::Suffix),
+    ("invalid", DomainMatch::Suffix),
+    ("test", DomainMatch::Suffix),
+    ("example.com", DomainMatch::Suffix),
+    ("example.net", DomainMatch::Suffix),
+    ("example.org", DomainMatch::Suffix),
+    // hostnames of machines on a local network, e.g. `root@my-laptop.local`
+    ("localdomain", DomainMatch::Suffix),
+    ("local", DomainMatch::Suffix),
+    ("lan", DomainMatch::Suffix),
+    ("internal", DomainMatch::Suffix),
+    ("home.arpa", DomainMatch::Suffix),
+    // noreply addresses of code hosting platforms
+    ("noreply.github.com", DomainMatch::Exact),
+    ("users.noreply.gitlab.com", DomainMatch::Exact),
+    ("noreply.gitlab.com", DomainMatch::Exact),
+];
+
+/// Local parts of automated senders that do not accept replies, e.g. `noreply@company.com`, compared in lower case.
+const PLACEHOLDER_LOCAL_PARTS: [&str; 6] = [
+    "noreply",
+    "no-reply",
+    "no_reply",
+    "donotreply",
+    "do-not-reply",
+    "do_not_reply",
+];
+
+/// An email address from a report after normalisation.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReportEmail {
+    /// A normalised address that may be deliverable, e.g. `max@onebro.me` or `max@xn--bcher-kva.de`
+    Mailbox(String),
+    /// The GitHub login encoded in a GitHub noreply address, e.g. `rimutaka` for `12345+rimutaka@users.noreply.github.com`
+    GitHubNoReply(String),
+    /// A well-formed address at a placeholder domain or of an automated sender, normalised for logging
+    Placeholder(String),
+    /// Not an email address
+    Invalid,
+}
+
+/// Parses an email address from `git_ids_included` or `primary_email` of a report and normalises it
+/// for storing in `t_email_ownership`. Used by stm_inbox to validate the report and by stm_inbox_router to store it.
+/// * the domain is converted to its ASCII (punycode) form with IDNA, which also lowercases it,
+///   so both spellings of an IDN match
+/// * the local part must be an RFC 5322 dot-atom, quoted local parts and address literals are not accepted
+/// * the local part is lowercased as well, same as the emails already stored in `t_email_ownership`, so that
+///   addresses that differ only by case are the same email for confirmations and for matching identities.
+///   RFC 5321 leaves the case up to the receiving server, but hardly any server treats it as significant.
+/// * the domain must have at least 2 labels and a TLD that is not numeric, so `a@b` and `root@127.0.0.1` are rejected,
+///   except for single-label placeholder domains, e.g. `root@localhost`
+pub fn normalize_email(email: &str) -> ReportEmail {
+    let email = email.trim();
+
+    // the local part may not contain @ unless quoted, which is not accepted here
+    let (local_part, domain) = match email.split_once("@") {
+        Some((local_part, domain)) if !domain.contains("@") => (local_part, domain),
+        _ => return ReportEmail::Invalid,
+    };
+
+    let local_part = match normalize_local_part(local_part) {
+        Some(v) => v,
+        None => return ReportEmail::Invalid,
+    };
+
+    let domain = match normalize_domain(domain) {
+        Some(v) => v,
+        None => return ReportEmail::Invalid,
+    };
+
+    let email = [local_part.as_str(), "@", domain.as_str()].concat();
+    if email.graphemes(true).count() > MAX_EMAIL_LENGTH {
+        return ReportEmail::Invalid;
+    }
+
+    if domain == GH_NOREPLY_DOMAIN {
+        return match gh_login_from_noreply(&local_part) {
+            Some(v) => ReportEmail::GitHubNoReply(v),
+            None => {
+                // e.g. bots or the old format GitHub no longer issues
+                warn!("Unrecognised GitHub noreply address: {}", email);
+                ReportEmail::Placeholder(email)
+            }
+        };
+    }
+
+    if is_placeholder_domain(&domain) || PLACEHOLDER_LOCAL_PARTS.contains(&local_part.as_str()) {
+        return ReportEmail::Placeholder(email);
+    }
+
+    ReportEmail::Mailbox(email)
+}
+
+/// Returns the local part in lower case if it is a valid dot-atom (RFC 5322) with UTF-8 allowed (RFC 6531).
+fn normalize_local_part(local_part: &str) -> Option<String> {
+    if local_part.is_empty() || local_part.len() > MAX_LOCAL_PART_LENGTH {
+        return None;
+    }
+
+    // dots may not lead, trail or follow each other
+    if local_part.split(".").any(|atom| atom.is_empty()) {
+        return None;
+    }
+
+    let is_atext = |c: char| {
+        c.is_ascii_alphanumeric()
+            || "!#$%&'*+-/=?^_`{|}~.".contains(c)
+            || (!c.is_ascii() && !c.is_control() && !c.is_whitespace())
+    };
+    if !local_part.chars().all(is_atext) {
+        return None;
+    }
+
+    Some(local_part.to_lowercase())
+}
+
+/// Returns the ASCII form of the domain or None if it is not a valid public host name.
+fn normalize_domain(domain: &str) -> Option<String> {
+    // address literals like `[127.0.0.1]` and a trailing root dot are legal, but not accepted
+    if domain.is_empty() || domain.starts_with("[") || domain.ends_with(".") {
+        return None;
+    }
+
+    // IDNA mapping lowercases the domain, converts unicode labels to punycode and rejects disallowed chars
+    let domain = idna::domain_to_ascii(domain).ok()?;
+    if domain.len() > MAX_DOMAIN_LENGTH {
+        return None;
+    }
+
+    let labels = domain.split(".").collect::<Vec<&str>>();
+    if labels.len() < 2 && !is_placeholder_domain(&domain) {
+        return None;
+    }
+
+    for label in &labels {
+        if label.is_empty()
+            || label.len() > MAX_LABEL_LENGTH
+            || label.starts_with("-")
+            || label.ends_with("-")
+            || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return None;
+        }
+    }
+
+    // a numeric TLD means it is an IP address
+    if labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(domain)
+}
+
+/// Returns TRUE if the domain matches any of `PLACEHOLDER_DOMAINS`.
+fn is_placeholder_domain(domain: &str) -> bool {
+    PLACEHOLDER_DOMAINS
+        .iter()
+        .any(|&(rule, domain_match)| match domain_match {
+            DomainMatch::Exact => domain == rule,
+            DomainMatch::Suffix => domain == rule || domain.ends_with(&[".", rule].concat()),
+        })
+}
+
+/// Extracts the GitHub login from the local part of a noreply address, `<id>+<login>` or `<login>`.
+/// GitHub logins are alphanumeric with single hyphens in between, e.g. `rimutaka` or `max-onebro`.
+/// They are not case-sensitive and are returned in lower case.
+fn gh_login_from_noreply(local_part: &str) -> Option<String> {
+    let login = match local_part.split_once("+") {
+        Some((id, login)) if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) => login,
+        Some(_) => return None,
+        None => local_part,
+    };
+
+    if login.is_empty()
+        || login.len() > MAX_GH_LOGIN_LENGTH
+        || login.starts_with("-")
+        || login.ends_with("-")
+        || login.contains("--")
+        || !login.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+
+    Some(login.to_lowercase())
+}
+
+#[test]
+fn normalize_email_test() {
+    let mailbox = |v: &str| ReportEmail::Mailbox(v.to_owned());
+    let placeholder = |v: &str| ReportEmail::Placeholder(v.to_owned());
+    let gh_login = |v: &str| ReportEmail::GitHubNoReply(v.to_owned());
+
+    let cases = vec![
+        // normalisation
+        ("max@onebro.me", mailbox("max@onebro.me")),
+        ("  Max@OneBro.ME ", mailbox("max@onebro.me")),
+        ("MAX@onebro.me", mailbox("max@onebro.me")),
+        ("first.last+tag@sub.domain.co.nz", mailbox("first.last+tag@sub.domain.co.nz")),
+        ("o'neil@example.ie", mailbox("o'neil@example.ie")),
+        // IDN domains are stored in the ASCII form
+        ("max@bücher.de", mailbox("max@xn--bcher-kva.de")),
+        ("max@BÜCHER.de", mailbox("max@xn--bcher-kva.de")),
+        ("max@xn--bcher-kva.de", mailbox("max@xn--bcher-kva.de")),
+        ("пётр@почта.рф", mailbox("пётр@xn--80a1acny.xn--p1ai")),
+        ("Пётр@Почта.рф", mailbox("пётр@xn--80a1acny.xn--p1ai")),
+        // syntax
+        ("a@b", ReportEmail::Invalid),
+        ("max", ReportEmail::Invalid),
+        ("@onebro.me", ReportEmail::Invalid),
+        ("max@", ReportEmail::Invalid),
+        ("max@@onebro.me", ReportEmail::Invalid),
+        ("max@one@bro.me", ReportEmail::Invalid),
+        ("max smith@onebro.me", ReportEmail::Invalid),
+        ("max\t@onebro.me", ReportEmail::Invalid),
+        (".max@onebro.me", ReportEmail::Invalid),
+        ("max.@onebro.me", ReportEmail::Invalid),
+        ("max..smith@onebro.me", ReportEmail::Invalid),
+        ("\"max smith\"@onebro.me", ReportEmail::Invalid),
+        ("max\\smith@onebro.me", ReportEmail::Invalid),
+        ("max@onebro..me", ReportEmail::Invalid),
+        ("max@onebro.me.", ReportEmail::Invalid),
+        ("max@-onebro.me", ReportEmail::Invalid),
+        ("max@onebro-.me", ReportEmail::Invalid),
+        ("max@one_bro.me", ReportEmail::Invalid),
+        ("max@[127.0.0.1]", ReportEmail::Invalid),
+        ("root@127.0.0.1", ReportEmail::Invalid),
+        ("user@my-laptop.(none)", ReportEmail::Invalid),
+        // placeholders
+        ("root@localhost", placeholder("root@localhost")),
+        ("Max@LocalHost", placeholder("max@localhost")),
+        ("max@local", placeholder("max@local")),
+        ("max@invalid", placeholder("max@invalid")),
+        ("root@localhost.localdomain", placeholder("root@localhost.localdomain")),
+        ("max@my-laptop.local", placeholder("max@my-laptop.local")),
+        ("max@nas.lan", placeholder("max@nas.lan")),
+        ("max@build.internal", placeholder("max@build.internal")),
+        ("you@example.com", placeholder("you@example.com")),
+        ("you@mail.example.org", placeholder("you@mail.example.org")),
+        ("max@onebro.test", placeholder("max@onebro.test")),
+        ("noreply@github.com", placeholder("noreply@github.com")),
+        ("No-Reply@onebro.me", placeholder("no-reply@onebro.me")),
+        ("NOREPLY@onebro.me", placeholder("noreply@onebro.me")),
+        ("1234-max@users.noreply.gitlab.com", placeholder("1234-max@users.noreply.gitlab.com")),
+        ("max@noreply.github.com", placeholder("max@noreply.github.com")),
+        // example.company.com is a real domain
+        ("max@example.company.com", mailbox("max@example.company.com")),
+        ("max@notlocal", ReportEmail::Invalid),
+        ("max@example", ReportEmail::Invalid),
+        ("max@onebro.example", mailbox("max@onebro.example")),
+        ("max@local.onebro.me", mailbox("max@local.onebro.me")),
+        // GitHub noreply
+        ("12345+rimutaka@users.noreply.github.com", gh_login("rimutaka")),
+        ("12345+RimuTaka@Users.NoReply.GitHub.com", gh_login("rimutaka")),
+        ("RimuTaka@users.noreply.github.com", gh_login("rimutaka")),
+        ("rimutaka@users.noreply.github.com", gh_login("rimutaka")),
+        ("12345+max-onebro@users.noreply.github.com", gh_login("max-onebro")),
+        ("12345+-max@users.noreply.github.com", placeholder("12345+-max@users.noreply.github.com")),
+        ("12345+-Max@users.noreply.github.com", placeholder("12345+-max@users.noreply.github.com")),
+        (
+            "12345+max--onebro@users.noreply.github.com",
+            placeholder("12345+max--onebro@users.noreply.github.com"),
+        ),
+        ("abc+max@users.noreply.github.com", placeholder("abc+max@users.noreply.github.com")),
+        ("+max@users.noreply.github.com", placeholder("+max@users.noreply.github.com")),
+        ("49699333+dependabot[bot]@users.noreply.github.com", ReportEmail::Invalid),
+    ];
+
+    for (email, expected) in cases {
+        assert_eq!(normalize_email(email), expected, "{}", email);
+    }
+}
+
+#[test]
+fn normalize_email_length_test() {
+    // the local part is limited by RFC 5321
+    let email = ["a".repeat(64).as_str(), "@onebro.me"].concat();
+    assert_eq!(normalize_email(&email), ReportEmail::Mailbox(email.clone()));
+    let email = ["a".repeat(65).as_str(), "@onebro.me"].concat();
+    assert_eq!(normalize_email(&email), ReportEmail::Invalid);
+
+    // so is every label of the domain
+    let email = ["max@", "a".repeat(63).as_str(), ".me"].concat();
+    assert_eq!(normalize_email(&email), ReportEmail::Mailbox(email.clone()));
+    let email = ["max@", "a".repeat(64).as_str(), ".me"].concat();
+    assert_eq!(normalize_email(&email), ReportEmail::Invalid);
+
+    // and the whole address by the DB
+    let email = [
+        "max@",
+        "a".repeat(60).as_str(),
+        ".",
+        "b".repeat(60).as_str(),
+        ".",
+        "c".repeat(21).as_str(),
+        ".me",
+    ]
+    .concat();
+    assert_eq!(email.len(), 150);
+    assert_eq!(normalize_email(&email), ReportEmail::Mailbox(email.clone()));
+    let email = [
+        "max@",
+        "a".repeat(60).as_str(),
+        ".",
+        "b".repeat(60).as_str(),
+        ".",
+        "c".repeat(22).as_str(),
+        ".me",
+    ]
+    .concat();
+    assert_eq!(normalize_email(&email), ReportEmail::Invalid);
+}
@@ -0,0 +1,90 @@
+use flate2::read::GzDecoder;
+use std::fmt;
+use std::io::Read;
+use tracing::{error, info};
+
+/// The default max size of a gzipped report as submitted by the app, in bytes.
+/// This value is shared by stm_inbox and stm_inbox_router to stay in sync.
+pub const MAX_COMPRESSED_REPORT_SIZE_DEFAULT: usize = 5 * 1024 * 1024;
+/// The default max size of a report after it was unzipped, in bytes.
+/// This value is shared by stm_inbox and stm_inbox_router to stay in sync.
+pub const MAX_DECOMPRESSED_REPORT_SIZE_DEFAULT: usize = 50 * 1024 * 1024;
+
+/// Reasons for a gzipped payload to be rejected by `decompress_with_limit`.
+#[derive(Debug, PartialEq)]
+pub enum GzipError {
+    /// The compressed payload is larger than the limit, in bytes.
+    CompressedTooLarge(usize),
+    /// The payload decompresses into more bytes than the limit, in bytes.
+    /// It may be a decompression bomb.
+    DecompressedTooLarge(usize),
+    /// Not a valid gzip stream. Contains the error message from the decoder.
+    Corrupt(String),
+}
+
+impl fmt::Display for GzipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GzipError::CompressedTooLarge(limit) => {
+                write!(f, "the gzipped report is larger than the limit of {} bytes", limit)
+            }
+            GzipError::DecompressedTooLarge(limit) => {
+                write!(f, "the report is larger than the limit of {} bytes after unzipping", limit)
+            }
+            GzipError::Corrupt(e) => write!(f, "the report is not a valid gzip file: {}", e),
+        }
+    }
+}
+
+/// Unzips `compressed` into a Vec without letting it grow beyond `max_decompressed` bytes.
+/// The decoding is streamed and stops as soon as the limit is exceeded, so the memory used never exceeds the limit
+/// no matter what the compression ratio is.
+pub fn decompress_with_limit(
+    compressed: &[u8],
+    max_compressed: usize,
+    max_decompressed: usize,
+) -> Result<Vec<u8>, GzipError> {
+    if compressed.len() > max_compressed {
+        error!("Compressed size {} > {}", compressed.len(), max_compressed);
+        return Err(GzipError::CompressedTooLarge(max_compressed));
+    }
+
+    // read 1 byte past the limit to tell if the payload is exactly at the limit or over it
+    let mut decoder = GzDecoder::new(compressed).take(max_decompressed as u64 + 1);
+    let mut buffer: Vec<u8> = Vec::new();
+    if let Err(e) = decoder.read_to_end(&mut buffer) {
+        error!("Failed to unzip the report: {}", e);
+        return Err(GzipError::Corrupt(e.to_string()));
+    }
+
+    if buffer.len() > max_decompressed {
+        error!("Decompressed size > {}", max_decompressed);
+        return Err(GzipError::DecompressedTooLarge(max_decompressed));
+    }
+
+    info!("Decoded {} bytes", buffer.len());
+
+    Ok(buffer)
+}
+
+#[test]
+fn decompress_with_limit_test() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&vec![0u8; 10_000]).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    assert_eq!(decompress_with_limit(&compressed, 10_000, 10_000).unwrap().len(), 10_000);
+    assert_eq!(
+        decompress_with_limit(&compressed, 10_000, 9_999),
+        Err(GzipError::DecompressedTooLarge(9_999))
+    );
+    assert_eq!(
+        decompress_with_limit(&compressed, compressed.len() - 1, 10_000),
+        Err(GzipError::CompressedTooLarge(compressed.len() - 1))
+    );
+    assert!(matches!(decompress_with_limit(b"not a gzip", 10_000, 10_000), Err(GzipError::Corrupt(_))));
+}
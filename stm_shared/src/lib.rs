@@ -3,6 +3,8 @@ use tracing::{error, info, warn};
 
 pub mod aws_events;
 pub mod elastic;
+pub mod email;
+pub mod gzip;
 pub mod pgsql;
 pub mod s3;
 pub mod sqs;
@@ -51,7 +53,6 @@ pub fn validate_gh_login_format(gh_login: &String, gh_login_invalidation_regex:
 
 /// Returns TRUE if the owner_id decodes from base58 into exactly 256 bytes.
 /// Logs a warning and returns FALSE otherwise.
-/// TODO: this should be a shared utility function!!!
 pub fn validate_owner_id(owner_id: &str) -> bool {
     match bs58::decode(owner_id).into_vec() {
         Err(e) => {
@@ -68,3 +69,110 @@ pub fn validate_owner_id(owner_id: &str) -> bool {
         }
     }
 }
+
+/// Commits dated before 2005-01-01 are treated as implausible. Git itself was released in April 2005.
+pub const MIN_PLAUSIBLE_COMMIT_TS: i64 = 1104537600;
+/// Commits dated later than now plus this number of seconds are treated as implausible, e.g. made on a machine with a wrong clock.
+pub const MAX_FUTURE_COMMIT_TS_DRIFT_SECS: i64 = 86400;
+
+/// Returns TRUE if the commit timestamp is within `MIN_PLAUSIBLE_COMMIT_TS` .. `now` + `MAX_FUTURE_COMMIT_TS_DRIFT_SECS`.
+/// Git accepts any date, so commits with implausible dates are still valid, but their dates cannot be trusted.
+pub fn is_plausible_commit_ts(commit_ts: i64, now: i64) -> bool {
+    commit_ts >= MIN_PLAUSIBLE_COMMIT_TS && commit_ts <= now + MAX_FUTURE_COMMIT_TS_DRIFT_SECS
+}
+
+/// A validated member of `recent_project_commits` of a report.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReportCommit {
+    /// The first 8 chars of the commit SHA1, e.g. `7474684a`. Older reports only have this part.
+    pub hash: String,
+    /// The full 40-char SHA1, if the report has it, e.g. `7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4`
+    pub sha1: Option<String>,
+    /// The commit timestamp, which can be any i64 number, e.g. `1595904770`
+    pub ts: i64,
+    /// FALSE if the timestamp is not a realistic date, see `is_plausible_commit_ts`
+    pub is_plausible: bool,
+}
+
+/// Returns the commit if both, the hash and the timestamp seem to be valid.
+/// The hash can be an 8-char prefix, e.g. `7474684a_1595904770`, or a full SHA1 in newer reports,
+/// e.g. `7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4_1595904770`.
+/// * `commit_hash_regex_short`: a regex matching 8 hex chars, e.g. `[a-f0-9]{8}`
+/// * `commit_hash_regex_full`: a regex matching 40 hex chars, e.g. `[a-f0-9]{40}`
+pub fn validate_commit_hash_with_ts(
+    commit_hash_with_ts: &String,
+    commit_hash_regex_short: &Regex,
+    commit_hash_regex_full: &Regex,
+) -> Option<ReportCommit> {
+    let split = commit_hash_with_ts.split("_").collect::<Vec<&str>>();
+    if split.len() != 2 {
+        return None;
+    }
+
+    let sha1 = if split[0].len() == 8 && commit_hash_regex_short.is_match(split[0]) {
+        None
+    } else if validate_full_commit_hash(split[0], commit_hash_regex_full) {
+        Some(split[0].to_string())
+    } else {
+        return None;
+    };
+
+    // there should be no commits with no dates
+    if let Ok(ts) = i64::from_str_radix(split[1], 10) {
+        return Some(ReportCommit {
+            hash: split[0][..8].to_string(),
+            sha1,
+            ts,
+            is_plausible: is_plausible_commit_ts(ts, chrono::Utc::now().timestamp()),
+        });
+    }
+
+    None
+}
+
+/// Returns TRUE if the commit hash is a full 40-char SHA1 in lower case.
+/// * `commit_hash_regex_full`: a regex matching 40 hex chars, e.g. `[a-f0-9]{40}`
+pub fn validate_full_commit_hash(commit_hash: &str, commit_hash_regex_full: &Regex) -> bool {
+    commit_hash.len() == 40 && commit_hash_regex_full.is_match(commit_hash)
+}
+
+#[test]
+fn is_plausible_commit_ts_test() {
+    let now = 1627380297;
+    assert!(is_plausible_commit_ts(1595904770, now));
+    assert!(is_plausible_commit_ts(MIN_PLAUSIBLE_COMMIT_TS, now));
+    assert!(is_plausible_commit_ts(now + MAX_FUTURE_COMMIT_TS_DRIFT_SECS, now));
+    // before git existed
+    assert!(!is_plausible_commit_ts(MIN_PLAUSIBLE_COMMIT_TS - 1, now));
+    assert!(!is_plausible_commit_ts(0, now));
+    assert!(!is_plausible_commit_ts(-1, now));
+    // 100 years ahead
+    assert!(!is_plausible_commit_ts(now + 100 * 365 * 86400, now));
+    assert!(!is_plausible_commit_ts(now + MAX_FUTURE_COMMIT_TS_DRIFT_SECS + 1, now));
+}
+
+#[test]
+fn validate_commit_hash_with_ts_test() {
+    let short = Regex::new("[a-f0-9]{8}").unwrap();
+    let full = Regex::new("[a-f0-9]{40}").unwrap();
+
+    let commit = validate_commit_hash_with_ts(&"7474684a_1595904770".to_owned(), &short, &full).unwrap();
+    assert_eq!(commit.hash, "7474684a");
+    assert_eq!(commit.sha1, None);
+    assert_eq!(commit.ts, 1595904770);
+    assert!(commit.is_plausible);
+
+    let commit =
+        validate_commit_hash_with_ts(&"7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4_1595904770".to_owned(), &short, &full)
+            .unwrap();
+    assert_eq!(commit.hash, "7474684a");
+    assert_eq!(commit.sha1, Some("7474684a65e5f0b7a8e3ed7b5fc5f7c4cc1bdbd4".to_owned()));
+
+    // neither 8 nor 40 chars
+    assert_eq!(validate_commit_hash_with_ts(&"7474684a65_1595904770".to_owned(), &short, &full), None);
+    // upper case
+    assert_eq!(validate_commit_hash_with_ts(&"7474684A_1595904770".to_owned(), &short, &full), None);
+    // no timestamp
+    assert_eq!(validate_commit_hash_with_ts(&"7474684a_".to_owned(), &short, &full), None);
+    assert_eq!(validate_commit_hash_with_ts(&"7474684a".to_owned(), &short, &full), None);
+}
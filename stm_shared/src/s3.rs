@@ -6,7 +6,8 @@ use hyper_rustls::HttpsConnectorBuilder;
 use regex::Regex;
 use rusoto_core::credential::DefaultCredentialsProvider;
 use rusoto_core::HttpClient;
-use rusoto_s3::{GetObjectRequest, ListObjectsV2Request, S3Client, S3};
+use rusoto_s3::{CopyObjectRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+use serde::{Deserialize, Serialize};
 use stackmuncher_lib::report::Report;
 use std::io::Read;
 use std::time::Duration;
@@ -19,6 +20,30 @@ pub const S3_COMBINED_DEV_REPORT_FILE_NAME: &str = "report.gz";
 pub const S3_FOLDER_GH_REPOS: &str = "repos";
 /// The name of a user profile file for GitHubUser
 pub const S3_OBJ_NAME_GH_USER: &str = "user.json";
+/// An S3 prefix in the inbox bucket for submissions the router could not process
+pub const S3_FOLDER_FAILED_SUBMISSIONS: &str = "failed";
+/// An S3 prefix in the inbox bucket for failed submissions on their way back to the inbox queue.
+/// They are moved out of `failed/` before the router is triggered, so that a repeated failure does not overwrite them.
+pub const S3_FOLDER_REDRIVING_SUBMISSIONS: &str = "redriving";
+/// The extension of the sidecar file with `FailedSubmission` stored next to every failed submission
+pub const S3_FAILED_SUBMISSION_SIDECAR_EXT: &str = ".json";
+
+/// The reason a submission was moved from the inbox queue to `failed/` prefix by the router.
+/// It is stored as a JSON sidecar with the same name as the submission, e.g.
+/// `failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdH...LeFK.gz` + `failed/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdH...LeFK.json`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FailedSubmission {
+    /// The key of the submission in the queue before it failed, e.g. `queue/1627801778_Wgx98Rbi8nQuL9ddn3mTk1_9PdH...LeFK.gz`
+    pub s3_key: String,
+    /// The key that signed the submission or an empty string if the inbox key has no valid key in it
+    pub owner_id: String,
+    /// Older submissions have no ID
+    pub submission_id: Option<String>,
+    /// A human-readable explanation, same as in the submission status
+    pub reason: String,
+    /// Unix timestamp of when the submission was moved to `failed/`
+    pub failed_ts: i64,
+}
 
 /// Contains some of the object properties returned by S3 ListObjectV2
 /// There are also size, owner and etag props that were not included
@@ -242,29 +267,78 @@ pub async fn delete_from_s3(s3_client: &S3Client, s3_bucket: &String, s3_keys: V
     Ok(())
 }
 
-// /// Uploads the payload to S3.
-// pub async fn upload_to_s3(
-//     s3_client: &S3Client,
-//     s3_bucket: &String,
-//     s3_key: String,
-//     payload: Vec<u8>,
-// ) -> Result<(), ()> {
-//     info!("Uploading to S3: {}", s3_key);
-//     if let Err(e) = s3_client
-//         .put_object(PutObjectRequest {
-//             bucket: s3_bucket.clone(),
-//             key: s3_key,
-//             body: Some(payload.into()),
-//             ..Default::default()
-//         })
-//         .await
-//     {
-//         error!("Uploading failed: {}", e);
-//         return Err(());
-//     }
+/// Uploads the payload to S3.
+pub async fn upload_to_s3(
+    s3_client: &S3Client,
+    s3_bucket: &String,
+    s3_key: String,
+    payload: Vec<u8>,
+) -> Result<(), ()> {
+    info!("Uploading to S3: {}", s3_key);
+    if let Err(e) = s3_client
+        .put_object(PutObjectRequest {
+            bucket: s3_bucket.clone(),
+            key: s3_key,
+            body: Some(payload.into()),
+            ..Default::default()
+        })
+        .await
+    {
+        error!("Uploading failed: {}", e);
+        return Err(());
+    }
 
-//     Ok(())
-// }
+    Ok(())
+}
+
+/// Copies an object within the same bucket.
+pub async fn copy_within_s3(
+    s3_client: &S3Client,
+    s3_bucket: &String,
+    source_key: String,
+    dest_key: String,
+) -> Result<(), ()> {
+    info!("Copying {} to {}", source_key, dest_key);
+    if let Err(e) = s3_client
+        .copy_object(CopyObjectRequest {
+            bucket: s3_bucket.clone(),
+            copy_source: [s3_bucket.as_str(), source_key.as_str()].join("/"),
+            key: dest_key,
+            ..Default::default()
+        })
+        .await
+    {
+        error!("Copying failed: {}", e);
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Copies an S3 object into another bucket. Errors are logged inside the function.
+pub async fn copy_to_other_bucket(
+    s3_client: &S3Client,
+    source_bucket: &String,
+    source_key: String,
+    dest_bucket: &String,
+    dest_key: String,
+) -> Result<(), ()> {
+    info!("Copying {}/{} to {}/{}", source_bucket, source_key, dest_bucket, dest_key);
+    if let Err(e) = s3_client
+        .copy_object(CopyObjectRequest {
+            bucket: dest_bucket.clone(),
+            copy_source: [source_bucket.as_str(), source_key.as_str()].join("/"),
+            key: dest_key,
+            ..Default::default()
+        })
+        .await
+    {
+        error!("Copying failed: {}", e);
+        return Err(());
+    }
+
+    Ok(())
+}
 
 /// Generates an S3Client with custom settings to match AWS server defaults.
 /// AWS times out idle connections after 20s as per https://aws.amazon.com/premiumsupport/knowledge-center/s3-socket-connection-timeout-error/
@@ -3,6 +3,7 @@ use rusoto_sqs::{
     SendMessageRequest, Sqs, SqsClient,
 };
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use tokio::time::{sleep, Duration};
 use tracing::{error, info};
 
@@ -14,6 +15,23 @@ pub struct SqsMessage<T> {
     pub receipt_handle: String,
 }
 
+/// An S3 object that could not be saved in S3 at the time and was sent to an SQS queue to be saved later.
+/// SQS messages are limited to 256KB, so only small objects can be spooled.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpooledS3Object {
+    /// The bucket the object should have been saved to, e.g. `stm-reports-prod`
+    pub s3_bucket: String,
+    /// The full key of the object, e.g. `queue/1621680890_Wgx98Rbi8nQuL9ddn3mTk1_7prBWD7pzYk2czeXZeXzjxjDQbnuka2RLShdW5AxWuk7.gz`
+    pub s3_key: String,
+    /// The contents of the object, base64 encoded
+    pub payload: String,
+}
+
+impl SpooledS3Object {
+    /// The max size of an SQS message body in bytes.
+    pub const MAX_SQS_MSG_SIZE: usize = 256 * 1024;
+}
+
 /// A batch of messages received from a queue.
 pub struct SqsMessages<T> {
     pub messages: Vec<SqsMessage<T>>,